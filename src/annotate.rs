@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+/// Writes `fasta_out`: the sequences of `fasta_in` with each header replaced
+/// by `>FeatureID total=<count> taxonomy=<lineage>`, for BLAST and other tools
+/// that only take a FASTA. The input is streamed line by line.
+///
+/// Features missing from `totals` get `total=0`; features missing from
+/// `taxa` get `taxonomy=Unassigned`. Spaces around the lineage's `;`
+/// separators are dropped so the header stays one token per field. Returns
+/// the number of sequences written.
+pub fn write_annotated_fasta(
+    fasta_in: &str,
+    fasta_out: &str,
+    totals: &HashMap<String, f64>,
+    taxa: &HashMap<String, String>,
+) -> Result<usize, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(fasta_in)?);
+    let mut writer = BufWriter::new(File::create(fasta_out)?);
+    let mut written = 0;
+    for line in reader.lines() {
+        let line = line?;
+        let Some(header) = line.strip_prefix('>') else {
+            writeln!(writer, "{}", line)?;
+            continue;
+        };
+        let feature_id = header.split_whitespace().next().unwrap_or("");
+        let total = totals.get(feature_id).copied().unwrap_or(0.0);
+        let lineage = taxa.get(feature_id).map_or("Unassigned".to_string(), |taxon| {
+            taxon.split(';').map(str::trim).collect::<Vec<_>>().join(";")
+        });
+        writeln!(writer, ">{} total={} taxonomy={}", feature_id, total, lineage)?;
+        written += 1;
+    }
+    writer.flush()?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::write_file;
+    use std::fs;
+
+    #[test]
+    fn headers_carry_total_and_taxonomy() {
+        let dir = tempfile::tempdir().unwrap();
+        let fasta_in = write_file(dir.path(), "dna-sequences.fasta", ">asv1 extra\nACGT\nTTGG\n>asv2\nGGCC\n>asv3\nAATT\n");
+        let fasta_out = dir.path().join("asvs_annotated.fasta").to_string_lossy().into_owned();
+        let totals = HashMap::from([("asv1".to_string(), 1234.0), ("asv2".to_string(), 7.0)]);
+        let taxa = HashMap::from([
+            ("asv1".to_string(), "Eukaryota; TSAR; Alveolata".to_string()),
+            ("asv3".to_string(), "Eukaryota".to_string()),
+        ]);
+
+        assert_eq!(write_annotated_fasta(&fasta_in, &fasta_out, &totals, &taxa).unwrap(), 3);
+        assert_eq!(
+            fs::read_to_string(&fasta_out).unwrap(),
+            ">asv1 total=1234 taxonomy=Eukaryota;TSAR;Alveolata\nACGT\nTTGG\n\
+             >asv2 total=7 taxonomy=Unassigned\nGGCC\n\
+             >asv3 total=0 taxonomy=Eukaryota\nAATT\n"
+        );
+    }
+}
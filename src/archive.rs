@@ -0,0 +1,221 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tar::{Builder, Header};
+
+use crate::audit::sha256_file;
+use crate::color_print::{print_info, print_success};
+use crate::estimate::format_bytes;
+use crate::logger::log_action;
+use crate::output_dir;
+
+/// Name of the contents listing added at the top of the archive.
+pub const CONTENTS_FILE: &str = "ARCHIVE_CONTENTS.tsv";
+
+/// Whether `relative` (a path under OUTPUT_DIR) is a large intermediate that
+/// `--exclude-intermediates` leaves out: QIIME artifacts (`.qza`), FASTQs,
+/// the reference databases and `--skip-existing` parameter records. The
+/// visualizations, tables, FASTA exports, reports and logs are kept.
+fn is_intermediate(relative: &Path) -> bool {
+    let name = relative.to_string_lossy();
+    relative.starts_with("db")
+        || name.ends_with(".qza")
+        || name.ends_with(".params")
+        || [".fastq", ".fq", ".fastq.gz", ".fq.gz", ".fastq.bz2", ".fastq.zst"].iter().any(|ext| name.ends_with(ext))
+}
+
+/// Files under `dir`, relative to `root`, in sorted order so archives are
+/// reproducible. Symlinks are followed only for files.
+pub(crate) fn collect_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?.map(|entry| entry.map(|e| e.path())).collect::<Result<_, _>>()?;
+    entries.sort();
+    for path in entries {
+        let meta = fs::symlink_metadata(&path)?;
+        if meta.is_dir() {
+            collect_files(root, &path, files)?;
+        } else if path.is_file() {
+            files.push(path.strip_prefix(root).unwrap_or(&path).to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// Top-level directory of the archive's entries: the output directory's own
+/// name, so an absolute `--output-dir` still unpacks to a relative path.
+fn entry_root() -> String {
+    Path::new(output_dir())
+        .file_name()
+        .map_or_else(|| "windchime_out".to_string(), |name| name.to_string_lossy().into_owned())
+}
+
+/// The files under OUTPUT_DIR an archive written to `output` contains: all
+/// of them but the run lock and the archive itself, and without
+/// intermediates when `exclude_intermediates` is set.
+pub fn archive_entries(output: &str, exclude_intermediates: bool) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let root = Path::new(output_dir());
+    let mut files = Vec::new();
+    collect_files(root, root, &mut files)?;
+    let output_abs = std::path::absolute(output)?;
+    files.retain(|relative| {
+        relative != Path::new(".lock")
+            && std::path::absolute(root.join(relative)).map_or(true, |abs| abs != output_abs)
+            && !(exclude_intermediates && is_intermediate(relative))
+    });
+    Ok(files)
+}
+
+/// Packs OUTPUT_DIR (or, with `exclude_intermediates`, its deliverables) into
+/// the gzipped tarball `output`. Entries are stored under the output
+/// directory's name (see [`entry_root`]), preceded by [`CONTENTS_FILE`]
+/// listing each one's size and SHA-256.
+pub fn write_archive(output: &str, exclude_intermediates: bool) -> Result<(), Box<dyn Error>> {
+    let files = archive_entries(output, exclude_intermediates)?;
+    if files.is_empty() {
+        return Err(format!("Nothing to archive in '{}'", output_dir()).into());
+    }
+    print_info(&format!(
+        "Archiving {} files from '{}'{} into '{}'...",
+        files.len(),
+        output_dir(),
+        if exclude_intermediates { " (without intermediates)" } else { "" },
+        output
+    ));
+    log_action(&format!("Archiving {} files from {} into {}", files.len(), output_dir(), output));
+
+    // Sizes are taken once so the listing and the tar headers agree, even
+    // for a log that's still being appended to
+    let root = Path::new(output_dir());
+    let entry_root = entry_root();
+    let mut contents = String::from("path\tsize_bytes\tsha256\n");
+    let mut sizes = Vec::with_capacity(files.len());
+    for relative in &files {
+        let path = root.join(relative);
+        let size = fs::metadata(&path)?.len();
+        let sha256 = sha256_file(&path.to_string_lossy())?;
+        contents.push_str(&format!("{}/{}\t{}\t{}\n", entry_root, relative.display(), size, sha256));
+        sizes.push(size);
+    }
+
+    let mut builder = Builder::new(GzEncoder::new(File::create(output)?, Compression::default()));
+    let mut header = Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp().max(0) as u64);
+    builder.append_data(&mut header, format!("{}/{}", entry_root, CONTENTS_FILE), contents.as_bytes())?;
+    for (relative, size) in files.iter().zip(sizes) {
+        let path = root.join(relative);
+        let file = File::open(&path)?;
+        let mut header = Header::new_gnu();
+        header.set_metadata(&file.metadata()?);
+        header.set_size(size);
+        builder.append_data(&mut header, Path::new(&entry_root).join(relative), file.take(size))?;
+    }
+    builder.into_inner()?.finish()?;
+
+    let archive_size = fs::metadata(output)?.len();
+    log_action(&format!("Wrote archive {} ({} bytes)", output, archive_size));
+    print_success(&format!(
+        "Wrote '{}' ({}, {} files).",
+        output,
+        format_bytes(archive_size as f64),
+        files.len()
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use flate2::read::GzDecoder;
+
+    /// Writes a few deliverables and intermediates into the output directory.
+    fn populate_output_dir() {
+        let root = test_support::output_dir();
+        for (relative, contents) in [
+            ("asv_count_tax.tsv", "asv\ts1\n"),
+            ("asvs/table-dada2.qzv", "visualization"),
+            ("asvs/table-dada2.qza", "artifact"),
+            ("asvs/table-dada2.qza.params", "params"),
+            ("demultiplexed/s1_R1_001.fastq.gz", "reads"),
+            ("db/pr2/pr2.fasta", ">a\nACGT\n"),
+            ("windchime.log", "log\n"),
+            (".lock", "1\tnow\n"),
+        ] {
+            let path = root.join(relative);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    /// `(path, contents)` of every entry of a gzipped tarball.
+    fn read_archive(path: &str) -> Vec<(String, String)> {
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(path).unwrap()));
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents).unwrap();
+                (path, contents)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn archive_lists_and_packs_every_output() {
+        let _state = test_support::global_state();
+        populate_output_dir();
+        // Written inside the output directory, so it must leave itself out
+        let output = test_support::output_dir().join("windchime_out.tar.gz").to_string_lossy().into_owned();
+
+        write_archive(&output, false).unwrap();
+
+        let root = entry_root();
+        let entries = read_archive(&output);
+        let paths: Vec<&str> = entries.iter().map(|(path, _)| path.strip_prefix(&format!("{}/", root)).unwrap()).collect();
+        assert_eq!(
+            paths,
+            [
+                CONTENTS_FILE,
+                "asv_count_tax.tsv",
+                "asvs/table-dada2.qza",
+                "asvs/table-dada2.qza.params",
+                "asvs/table-dada2.qzv",
+                "db/pr2/pr2.fasta",
+                "demultiplexed/s1_R1_001.fastq.gz",
+                "windchime.log",
+            ]
+        );
+        assert_eq!(entries[1].1, "asv\ts1\n");
+        let listing: Vec<&str> = entries[0].1.lines().collect();
+        assert_eq!(listing.len(), paths.len());
+        assert_eq!(listing[0], "path\tsize_bytes\tsha256");
+        let asv_sha = sha256_file(&test_support::output_dir().join("asv_count_tax.tsv").to_string_lossy()).unwrap();
+        assert_eq!(listing[1], format!("{}/asv_count_tax.tsv\t7\t{}", root, asv_sha));
+    }
+
+    #[test]
+    fn intermediates_can_be_left_out() {
+        let _state = test_support::global_state();
+        populate_output_dir();
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("deliverables.tar.gz").to_string_lossy().into_owned();
+
+        write_archive(&output, true).unwrap();
+
+        let root = entry_root();
+        let paths: Vec<String> = read_archive(&output).into_iter().map(|(path, _)| path).collect();
+        let expected: Vec<String> = [CONTENTS_FILE, "asv_count_tax.tsv", "asvs/table-dada2.qzv", "windchime.log"]
+            .iter()
+            .map(|relative| format!("{}/{}", root, relative))
+            .collect();
+        assert_eq!(paths, expected);
+    }
+}
@@ -0,0 +1,97 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+
+use chrono::{DateTime, Local};
+use sha2::{Digest, Sha256};
+
+use crate::logger::log_action;
+use crate::output_dir;
+
+/// Size, modification time and (optionally) SHA-256 of one input file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputRecord {
+    pub path: String,
+    pub size: u64,
+    pub modified: String,
+    pub sha256: Option<String>,
+}
+
+/// Streams `path` through SHA-256 and returns the hex digest.
+pub fn sha256_file(path: &str) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Describes one input file, hashing it if `hash` is set.
+pub fn audit_file(path: &str, hash: bool) -> io::Result<InputRecord> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()
+        .map(|t| DateTime::<Local>::from(t).to_rfc3339())
+        .unwrap_or_default();
+    Ok(InputRecord {
+        path: fs::canonicalize(path).map_or(path.to_string(), |p| p.display().to_string()),
+        size: metadata.len(),
+        modified,
+        sha256: if hash { Some(sha256_file(path)?) } else { None },
+    })
+}
+
+/// Records every input in `OUTPUT_DIR/input_audit.tsv` and the log before
+/// they're processed. Returns the path of the audit file.
+pub fn write_input_audit(paths: &[String], hash: bool) -> io::Result<String> {
+    let audit_path = format!("{}/input_audit.tsv", output_dir());
+    let mut out = File::create(&audit_path)?;
+    writeln!(out, "path\tsize_bytes\tmodified\tsha256")?;
+    for path in paths {
+        let record = audit_file(path, hash)?;
+        let sha256 = record.sha256.as_deref().unwrap_or("");
+        writeln!(out, "{}\t{}\t{}\t{}", record.path, record.size, record.modified, sha256)?;
+        log_action(&format!(
+            "Input audit: {} ({} bytes, modified {}{}{})",
+            record.path,
+            record.size,
+            record.modified,
+            if hash { ", sha256 " } else { "" },
+            sha256
+        ));
+    }
+    Ok(audit_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, write_file};
+
+    #[test]
+    fn audit_lists_each_input_with_size_and_hash() {
+        let _guard = test_support::global_state();
+        let dir = tempfile::tempdir().unwrap();
+        let hello = write_file(dir.path(), "hello.txt", "hello\n");
+        let empty = write_file(dir.path(), "empty.txt", "");
+
+        let audit = fs::read_to_string(write_input_audit(&[hello.clone(), empty.clone()], true).unwrap()).unwrap();
+        let rows: Vec<Vec<&str>> = audit.lines().map(|line| line.split('\t').collect()).collect();
+        assert_eq!(rows[0], ["path", "size_bytes", "modified", "sha256"]);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[1][0], fs::canonicalize(&hello).unwrap().to_string_lossy());
+        assert_eq!(rows[1][1], "6");
+        assert!(DateTime::parse_from_rfc3339(rows[1][2]).is_ok(), "{}", rows[1][2]);
+        assert_eq!(rows[1][3], "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03");
+        assert_eq!(rows[2][1..], ["0", rows[2][2], "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"]);
+
+        // Hashing is optional
+        let unhashed = audit_file(&hello, false).unwrap();
+        assert_eq!((unhashed.size, unhashed.sha256), (6, None));
+    }
+}
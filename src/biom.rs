@@ -0,0 +1,202 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+
+use flate2::read::MultiGzDecoder;
+use serde::Deserialize;
+
+/// First bytes of an HDF5 file (BIOM 2.x), which this module can't read.
+const HDF5_MAGIC: &[u8] = b"\x89HDF\r\n\x1a\n";
+/// First bytes of a gzip stream.
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+
+#[derive(Deserialize)]
+struct BiomEntry {
+    id: String,
+}
+
+/// The parts of a JSON BIOM 1.0 table needed for a TSV export.
+#[derive(Deserialize)]
+struct BiomTable {
+    rows: Vec<BiomEntry>,
+    columns: Vec<BiomEntry>,
+    matrix_type: String,
+    shape: (usize, usize),
+    data: Vec<Vec<f64>>,
+}
+
+/// Reads the first bytes of `reader` into `magic`, returning how many there were.
+fn read_magic(reader: &mut impl Read, magic: &mut [u8; 8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < magic.len() {
+        match reader.read(&mut magic[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+/// Converts a JSON (BIOM 1.0) feature table, plain or gzipped, to the same
+/// TSV `biom convert --to-tsv` writes. HDF5 (BIOM 2.x) tables are recognised
+/// by their magic bytes and rejected with an error saying so, rather than
+/// failing as malformed JSON; `biom convert` handles those.
+pub fn convert_biom_to_tsv(biom_in: &str, tsv_out: &str) -> Result<(), Box<dyn Error>> {
+    let mut reader: Box<dyn Read> = Box::new(BufReader::new(File::open(biom_in)?));
+    let mut magic = [0u8; 8];
+    let mut read = read_magic(&mut reader, &mut magic)?;
+    let gzipped = magic[..read].starts_with(GZIP_MAGIC);
+    if gzipped {
+        reader = Box::new(MultiGzDecoder::new(Cursor::new(magic[..read].to_vec()).chain(reader)));
+        read = read_magic(&mut reader, &mut magic)
+            .map_err(|e| format!("{} looks gzipped but can't be decompressed: {}", biom_in, e))?;
+    }
+    if magic[..read] == *HDF5_MAGIC {
+        let how = if gzipped { "decompress it and convert it" } else { "convert it" };
+        return Err(format!(
+            "{} is {} HDF5 (BIOM 2.x) table, which the built-in converter can't read; \
+             {} with `biom convert --to-tsv` in the QIIME environment",
+            biom_in,
+            if gzipped { "a gzipped" } else { "an" },
+            how
+        )
+        .into());
+    }
+    let table: BiomTable = serde_json::from_reader(magic[..read].chain(reader))
+        .map_err(|e| format!("{} is not a JSON BIOM table: {}", biom_in, e))?;
+
+    let (n_rows, n_cols) = table.shape;
+    if table.rows.len() != n_rows || table.columns.len() != n_cols {
+        return Err(format!(
+            "{}: shape {}x{} doesn't match {} rows and {} columns",
+            biom_in,
+            n_rows,
+            n_cols,
+            table.rows.len(),
+            table.columns.len()
+        )
+        .into());
+    }
+
+    let mut matrix = vec![vec![0.0; n_cols]; n_rows];
+    match table.matrix_type.as_str() {
+        "sparse" => {
+            for entry in &table.data {
+                let &[row, col, value] = entry.as_slice() else {
+                    return Err(format!("{}: sparse entry {:?} isn't [row, column, value]", biom_in, entry).into());
+                };
+                let (row, col) = (row as usize, col as usize);
+                if row >= n_rows || col >= n_cols {
+                    return Err(format!("{}: sparse entry {:?} is outside the table", biom_in, entry).into());
+                }
+                matrix[row][col] = value;
+            }
+        }
+        "dense" => {
+            if table.data.len() != n_rows || table.data.iter().any(|row| row.len() != n_cols) {
+                return Err(format!("{}: dense data doesn't match shape {}x{}", biom_in, n_rows, n_cols).into());
+            }
+            matrix = table.data;
+        }
+        other => return Err(format!("{}: unknown matrix_type '{}'", biom_in, other).into()),
+    }
+
+    let mut out = BufWriter::new(File::create(tsv_out)?);
+    writeln!(out, "# Constructed from biom file")?;
+    write!(out, "#OTU ID")?;
+    for column in &table.columns {
+        write!(out, "\t{}", column.id)?;
+    }
+    writeln!(out)?;
+    for (row, values) in table.rows.iter().zip(&matrix) {
+        write!(out, "{}", row.id)?;
+        for value in values {
+            write!(out, "\t{:?}", value)?;
+        }
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{write_file, write_gz};
+    use flate2::{write::GzEncoder, Compression};
+    use std::fs;
+    use tempfile::TempDir;
+
+    const SPARSE: &str = r#"{
+        "id": null, "format": "Biological Observation Matrix 1.0.0", "type": "OTU table",
+        "rows": [{"id": "asv1", "metadata": null}, {"id": "asv2", "metadata": null}],
+        "columns": [{"id": "s1", "metadata": null}, {"id": "s2", "metadata": null}],
+        "matrix_type": "sparse", "shape": [2, 2], "data": [[0, 0, 5.0], [1, 1, 12.0]]
+    }"#;
+    const TSV: &str = "# Constructed from biom file\n#OTU ID\ts1\ts2\nasv1\t5.0\t0.0\nasv2\t0.0\t12.0\n";
+
+    /// Converts `biom` in `dir`, returning the TSV or the error message.
+    fn convert(dir: &TempDir, biom: &str) -> Result<String, String> {
+        let tsv = dir.path().join("table.tsv").to_string_lossy().into_owned();
+        convert_biom_to_tsv(biom, &tsv).map_err(|e| e.to_string())?;
+        Ok(fs::read_to_string(tsv).unwrap())
+    }
+
+    #[test]
+    fn plain_and_gzipped_json_tables_convert_alike() {
+        let dir = TempDir::new().unwrap();
+        let plain = write_file(dir.path(), "table.biom", SPARSE);
+        assert_eq!(convert(&dir, &plain).unwrap(), TSV);
+        let gzipped = write_gz(dir.path(), "table.biom.gz", SPARSE);
+        assert_eq!(convert(&dir, gzipped.to_str().unwrap()).unwrap(), TSV);
+
+        let dense = SPARSE.replace(r#""sparse""#, r#""dense""#).replace("[[0, 0, 5.0], [1, 1, 12.0]]", "[[5, 0], [0, 12]]");
+        let dense = write_file(dir.path(), "dense.biom", &dense);
+        assert_eq!(convert(&dir, &dense).unwrap(), TSV);
+    }
+
+    #[test]
+    fn hdf5_tables_are_told_apart_from_json_by_their_magic_bytes() {
+        let dir = TempDir::new().unwrap();
+        let hdf5 = dir.path().join("feature-table.biom");
+        fs::write(&hdf5, HDF5_MAGIC).unwrap();
+        let hdf5 = hdf5.to_string_lossy().into_owned();
+        assert_eq!(
+            convert(&dir, &hdf5).unwrap_err(),
+            format!(
+                "{} is an HDF5 (BIOM 2.x) table, which the built-in converter can't read; \
+                 convert it with `biom convert --to-tsv` in the QIIME environment",
+                hdf5
+            )
+        );
+
+        let gzipped = dir.path().join("feature-table.biom.gz");
+        let mut encoder = GzEncoder::new(fs::File::create(&gzipped).unwrap(), Compression::fast());
+        encoder.write_all(HDF5_MAGIC).unwrap();
+        encoder.finish().unwrap();
+        let err = convert(&dir, gzipped.to_str().unwrap()).unwrap_err();
+        assert!(err.contains("is a gzipped HDF5 (BIOM 2.x) table") && err.contains("decompress it and convert it"), "{}", err);
+
+        // Anything else is reported as bad JSON, not as HDF5
+        let text = write_file(dir.path(), "notes.biom", "#OTU ID\ts1\n");
+        let err = convert(&dir, &text).unwrap_err();
+        assert!(err.starts_with(&format!("{} is not a JSON BIOM table: ", text)), "{}", err);
+        assert!(!err.contains("HDF5"));
+    }
+
+    #[test]
+    fn inconsistent_tables_are_rejected() {
+        let dir = TempDir::new().unwrap();
+        let cases = [
+            (SPARSE.replace("[2, 2]", "[3, 2]"), "shape 3x2 doesn't match 2 rows and 2 columns"),
+            (SPARSE.replace("[1, 1, 12.0]", "[2, 1, 12.0]"), "sparse entry [2.0, 1.0, 12.0] is outside the table"),
+            (SPARSE.replace("[1, 1, 12.0]", "[1, 12.0]"), "sparse entry [1.0, 12.0] isn't [row, column, value]"),
+            (SPARSE.replace(r#""sparse""#, r#""coo""#), "unknown matrix_type 'coo'"),
+        ];
+        for (json, expected) in cases {
+            let biom = write_file(dir.path(), "table.biom", &json);
+            let err = convert(&dir, &biom).unwrap_err();
+            assert_eq!(err, format!("{}: {}", biom, expected));
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use chrono::Utc;
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+use crate::logger::log_action;
+use crate::output_dir;
+
+/// Writes `windchime_failure_<timestamp>.zip` into the output directory for bug reports.
+///
+/// The archive contains `windchime.log`, the manifest (if one was in use and
+/// exists), the captured stderr of the failing step (if any), the error
+/// message, and the effective configuration. Returns the path of the bundle.
+pub fn write_failure_bundle(
+    error: &str,
+    effective_config: &str,
+    manifest: Option<&str>,
+    failing_stderr: Option<&str>,
+) -> Result<String, Box<dyn Error>> {
+    let bundle_path = format!(
+        "{}/windchime_failure_{}.zip",
+        output_dir(),
+        Utc::now().format("%Y%m%dT%H%M%SZ")
+    );
+    let mut zip = ZipWriter::new(File::create(&bundle_path)?);
+    let options = SimpleFileOptions::default();
+
+    let log_path = format!("{}/windchime.log", output_dir());
+    if Path::new(&log_path).is_file() {
+        zip.start_file("windchime.log", options)?;
+        zip.write_all(&std::fs::read(&log_path)?)?;
+    }
+
+    if let Some(manifest) = manifest.filter(|m| Path::new(m).is_file()) {
+        let name = Path::new(manifest)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "manifest.tsv".to_string());
+        zip.start_file(name, options)?;
+        zip.write_all(&std::fs::read(manifest)?)?;
+    }
+
+    if let Some(stderr) = failing_stderr {
+        zip.start_file("failing_step_stderr.txt", options)?;
+        zip.write_all(stderr.as_bytes())?;
+    }
+
+    zip.start_file("error.txt", options)?;
+    writeln!(zip, "{}", error)?;
+
+    zip.start_file("effective_config.txt", options)?;
+    zip.write_all(effective_config.as_bytes())?;
+
+    zip.finish()?;
+    log_action(&format!("Wrote failure bundle: {}", bundle_path));
+    Ok(bundle_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    use crate::command::{run_command, CommandOptions};
+    use crate::test_support::{self, write_file};
+
+    /// Name and contents of every entry in the zip at `path`.
+    fn entries(path: &str) -> Vec<(String, String)> {
+        let mut archive = zip::ZipArchive::new(File::open(path).unwrap()).unwrap();
+        (0..archive.len())
+            .map(|i| {
+                let mut entry = archive.by_index(i).unwrap();
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents).unwrap();
+                (entry.name().to_string(), contents)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn failed_step_is_bundled_with_log_manifest_and_config() {
+        let _state = test_support::global_state();
+        let dir = test_support::output_dir();
+        write_file(dir, "windchime.log", "Running DADA2 denoise-paired\n");
+        let manifest = write_file(dir, "manifest.tsv", "sample-id\tforward-absolute-filepath\n");
+        let failed = run_command(
+            "sh",
+            &["-c", "echo 'Plugin error from dada2' >&2; exit 1"],
+            &CommandOptions { capture: true, ..Default::default() },
+        )
+        .unwrap();
+        assert!(!failed.success);
+
+        let bundle = write_failure_bundle(
+            "Running DADA2 denoise-paired failed",
+            "cores = 4\n",
+            Some(&manifest),
+            Some(&String::from_utf8_lossy(&failed.stderr)),
+        )
+        .unwrap();
+
+        let name = Path::new(&bundle).file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.starts_with("windchime_failure_") && name.ends_with(".zip"), "{}", name);
+        let entries = entries(&bundle);
+        let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["windchime.log", "manifest.tsv", "failing_step_stderr.txt", "error.txt", "effective_config.txt"]
+        );
+        assert_eq!(entries[0].1, "Running DADA2 denoise-paired\n");
+        assert_eq!(entries[2].1, "Plugin error from dada2\n");
+        assert_eq!(entries[3].1, "Running DADA2 denoise-paired failed\n");
+        assert_eq!(entries[4].1, "cores = 4\n");
+    }
+
+    #[test]
+    fn missing_files_are_left_out() {
+        let _state = test_support::global_state();
+        let bundle = write_failure_bundle("no manifest", "", Some("/nonexistent/manifest.tsv"), None).unwrap();
+        let names: Vec<String> = entries(&bundle).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["error.txt", "effective_config.txt"]);
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::color_print::{print_info, print_success};
+use crate::logger::log_action;
+use crate::output_dir;
+
+/// Which pipeline outputs `--cleanup-on-success` removes once a run succeeds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CleanupProfile {
+    /// Keep everything.
+    #[default]
+    None,
+    /// Keep the `.qzv` visualizations and the final tables; remove `.qza`
+    /// artifacts and exported intermediates.
+    QzvOnly,
+    /// Keep only the final tables.
+    FinalTsvOnly,
+}
+
+impl FromStr for CleanupProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(CleanupProfile::None),
+            "qzv-only" => Ok(CleanupProfile::QzvOnly),
+            "final-tsv-only" => Ok(CleanupProfile::FinalTsvOnly),
+            other => Err(format!(
+                "Unknown cleanup profile '{}'; expected none, qzv-only or final-tsv-only",
+                other
+            )),
+        }
+    }
+}
+
+impl CleanupProfile {
+    /// The profile's command-line spelling.
+    pub fn name(self) -> &'static str {
+        match self {
+            CleanupProfile::None => "none",
+            CleanupProfile::QzvOnly => "qzv-only",
+            CleanupProfile::FinalTsvOnly => "final-tsv-only",
+        }
+    }
+
+    /// Whether `path`, one of the run's outputs, is removed. `deliverables`
+    /// are always kept.
+    fn removes(self, path: &str, deliverables: &[&str]) -> bool {
+        if deliverables.contains(&path) {
+            return false;
+        }
+        match self {
+            CleanupProfile::None => false,
+            CleanupProfile::QzvOnly => !path.ends_with(".qzv"),
+            CleanupProfile::FinalTsvOnly => true,
+        }
+    }
+}
+
+/// Size of a file, or of everything under a directory.
+fn disk_usage(path: &Path) -> u64 {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::read_dir(path)
+            .map(|entries| entries.flatten().map(|entry| disk_usage(&entry.path())).sum())
+            .unwrap_or(0),
+        Ok(meta) => meta.len(),
+        Err(_) => 0,
+    }
+}
+
+/// Removes the run's `outputs` that `profile` doesn't keep, then any of their
+/// parent directories left empty. `deliverables` are never removed. Returns
+/// the bytes reclaimed.
+pub fn cleanup_outputs(outputs: &[String], deliverables: &[&str], profile: CleanupProfile) -> io::Result<u64> {
+    let mut reclaimed = 0;
+    let mut removed = 0;
+    let mut parents = BTreeSet::new();
+    for output in outputs {
+        let path = Path::new(output);
+        if !profile.removes(output, deliverables) || fs::symlink_metadata(path).is_err() {
+            continue;
+        }
+        let size = disk_usage(path);
+        if path.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        log_action(&format!("Cleanup removed {} ({} bytes)", output, size));
+        reclaimed += size;
+        removed += 1;
+        if let Some(parent) = path.parent() {
+            parents.insert(parent.to_path_buf());
+        }
+    }
+    // Deepest first; `remove_dir` only succeeds on empty directories
+    for parent in parents.iter().rev().filter(|parent| *parent != Path::new(output_dir())) {
+        let _ = fs::remove_dir(parent);
+    }
+
+    if removed > 0 {
+        print_success(&format!(
+            "Cleanup ({}) removed {} intermediate outputs, reclaiming {:.1} MB.",
+            profile.name(),
+            removed,
+            reclaimed as f64 / 1_048_576.0
+        ));
+    } else {
+        print_info("Cleanup found no intermediate outputs to remove.");
+    }
+    Ok(reclaimed)
+}
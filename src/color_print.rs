@@ -1,16 +1,119 @@
-use colored::{Colorize};
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use colored::{ColoredString, Colorize};
+
+/// When to color terminal output (`--color`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorChoice {
+    /// Color when writing to a terminal and `NO_COLOR` isn't set.
+    #[default]
+    Auto,
+    /// Always color, even when piped (e.g. into `less -R`).
+    Always,
+    Never,
+}
+
+impl std::str::FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            other => Err(format!("expected always, auto or never, got '{}'", other)),
+        }
+    }
+}
+
+/// GLOBAL COLOR CHOICE, stored as the `ColorChoice` discriminant.
+static COLOR_CHOICE: AtomicU8 = AtomicU8::new(ColorChoice::Auto as u8);
+
+/// Sets when the `print_*` functions color their output.
+pub fn set_color_choice(choice: ColorChoice) {
+    COLOR_CHOICE.store(choice as u8, Ordering::Relaxed);
+    // We decide per stream ourselves; stop `colored` second-guessing it
+    colored::control::set_override(true);
+}
+
+fn color_choice() -> ColorChoice {
+    match COLOR_CHOICE.load(Ordering::Relaxed) {
+        x if x == ColorChoice::Always as u8 => ColorChoice::Always,
+        x if x == ColorChoice::Never as u8 => ColorChoice::Never,
+        _ => ColorChoice::Auto,
+    }
+}
+
+/// Whether to color output written to a stream that is (or isn't) a terminal.
+fn use_color(is_terminal: bool) -> bool {
+    match color_choice() {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => is_terminal && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+    }
+}
+
+fn paint(msg: &str, enabled: bool, style: fn(&str) -> ColoredString) -> String {
+    if enabled { style(msg).to_string() } else { msg.to_string() }
+}
 
 /// Print an informational message in cyan.
 pub fn print_info(msg: &str) {
-    println!("{}", msg.cyan().bold());
+    println!("{}", paint(msg, use_color(io::stdout().is_terminal()), |m| m.cyan().bold()));
 }
 
 /// Print a success message in green.
 pub fn print_success(msg: &str) {
-    println!("{}", msg.green().bold());
+    println!("{}", paint(msg, use_color(io::stdout().is_terminal()), |m| m.green().bold()));
 }
 
 /// Print an error message in red to stderr.
 pub fn print_error(msg: &str) {
-    eprintln!("{}", msg.red().bold());
+    eprintln!("{}", paint(msg, use_color(io::stderr().is_terminal()), |m| m.red().bold()));
+}
+
+/// `msg` in green, or in red unless `ok`, for a table cell printed to stdout.
+pub fn status_text(msg: &str, ok: bool) -> String {
+    let enabled = use_color(io::stdout().is_terminal());
+    if ok {
+        paint(msg, enabled, |m| m.green().bold())
+    } else {
+        paint(msg, enabled, |m| m.red().bold())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    /// `msg` styled as [`print_error`] would, for a stream that is (or isn't) a terminal.
+    fn error_text(msg: &str, is_terminal: bool) -> String {
+        paint(msg, use_color(is_terminal), |m| m.red().bold())
+    }
+
+    #[test]
+    fn always_colors_even_when_piped_and_never_does_not() {
+        let _state = test_support::global_state();
+        set_color_choice(ColorChoice::Always);
+        let piped = error_text("failed", false);
+        set_color_choice(ColorChoice::Never);
+        let terminal = error_text("failed", true);
+        set_color_choice(ColorChoice::Auto);
+
+        assert!(piped.starts_with("\x1b["), "{:?} has no ANSI escape", piped);
+        assert!(piped.contains("failed"));
+        assert_eq!(terminal, "failed");
+        // Auto never colors a pipe
+        assert_eq!(error_text("failed", false), "failed");
+    }
+
+    #[test]
+    fn color_choice_parses_case_insensitively() {
+        assert_eq!("Always".parse::<ColorChoice>(), Ok(ColorChoice::Always));
+        assert_eq!("never".parse::<ColorChoice>(), Ok(ColorChoice::Never));
+        assert_eq!("AUTO".parse::<ColorChoice>(), Ok(ColorChoice::Auto));
+        assert_eq!("sometimes".parse::<ColorChoice>(), Err("expected always, auto or never, got 'sometimes'".to_string()));
+    }
 }
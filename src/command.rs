@@ -0,0 +1,188 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::logger::log_action;
+
+/// How often a command with a timeout is polled for completion.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How [`run_command`] runs a child process.
+#[derive(Debug, Clone, Default)]
+pub struct CommandOptions {
+    /// Extra environment variables for the child.
+    pub env: Vec<(String, String)>,
+    /// Capture stdout and stderr and return them. Otherwise, in verbose mode
+    /// both go to the terminal; in quiet mode stdout is discarded and stderr
+    /// is captured so failures can be reported.
+    pub capture: bool,
+    /// Let the child's output through to the terminal (verbose mode).
+    /// Ignored when `capture` is set.
+    pub verbose: bool,
+    /// Kill the child and fail if it runs longer than this.
+    pub timeout: Option<Duration>,
+    /// Log the command and report success without running it.
+    pub dry_run: bool,
+}
+
+/// Result of a command that ran to completion (successfully or not).
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    pub success: bool,
+    /// Exit code, if the process exited normally (`None` for dry runs and signals).
+    pub code: Option<i32>,
+    /// Captured stdout (empty unless `capture` was set).
+    pub stdout: Vec<u8>,
+    /// Captured stderr (empty if it went to the terminal).
+    pub stderr: Vec<u8>,
+}
+
+/// Runs `program` with `args`, stdin closed. Returns an error only if the
+/// process couldn't be started or timed out; a non-zero exit is reported in
+/// [`CommandOutput::success`].
+pub fn run_command<S: AsRef<OsStr>>(
+    program: &str,
+    args: &[S],
+    opts: &CommandOptions,
+) -> Result<CommandOutput, Box<dyn Error>> {
+    let display = command_line(program, args);
+    if opts.dry_run {
+        log_action(&format!("Dry run, not executing: {}", display));
+        return Ok(CommandOutput { success: true, ..Default::default() });
+    }
+
+    let mut command = Command::new(program);
+    command.args(args).envs(opts.env.iter().map(|(k, v)| (k, v))).stdin(Stdio::null());
+    if opts.capture {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    } else if opts.verbose {
+        command.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+    } else {
+        command.stdout(Stdio::null()).stderr(Stdio::piped());
+    }
+
+    let mut child = command.spawn().map_err(|e| format!("Failed to run {}: {}", display, e))?;
+    // Drain the pipes on their own threads so a chatty child can't block
+    let stdout = child.stdout.take().map(spawn_reader);
+    let stderr = child.stderr.take().map(spawn_reader);
+
+    let status = match opts.timeout {
+        None => child.wait()?,
+        Some(timeout) => {
+            let started = Instant::now();
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    break status;
+                }
+                if started.elapsed() >= timeout {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(format!("Command timed out after {}s: {}", timeout.as_secs(), display).into());
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    };
+
+    let join = |reader: Option<thread::JoinHandle<Vec<u8>>>| {
+        reader.map(|r| r.join().unwrap_or_default()).unwrap_or_default()
+    };
+    Ok(CommandOutput {
+        success: status.success(),
+        code: status.code(),
+        stdout: join(stdout),
+        stderr: join(stderr),
+    })
+}
+
+fn spawn_reader<R: Read + Send + 'static>(mut pipe: R) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+/// The command as it would be typed, for logs and error messages. Arguments
+/// with spaces or shell metacharacters are quoted.
+pub fn command_line<S: AsRef<OsStr>>(program: &str, args: &[S]) -> String {
+    let mut line = program.to_string();
+    for arg in args {
+        line.push(' ');
+        line.push_str(&shell_quote(&arg.as_ref().to_string_lossy()));
+    }
+    line
+}
+
+/// Quotes `arg` for a POSIX shell, leaving plain words (paths, flags) as they are.
+pub fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg.chars().all(|c| c.is_ascii_alphanumeric() || "_-./:=,@%+^".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str, opts: &CommandOptions) -> Result<CommandOutput, Box<dyn Error>> {
+        run_command("sh", &["-c", script], opts)
+    }
+
+    #[test]
+    fn success_captures_stdout_and_stderr() {
+        let opts = CommandOptions { capture: true, env: vec![("GREETING".into(), "hello".into())], ..Default::default() };
+        let output = sh("echo $GREETING; echo warning >&2", &opts).unwrap();
+        assert!(output.success);
+        assert_eq!(output.code, Some(0));
+        assert_eq!(output.stdout, b"hello\n");
+        assert_eq!(output.stderr, b"warning\n");
+    }
+
+    #[test]
+    fn failure_is_reported_with_its_code_and_stderr() {
+        // Quiet mode still keeps stderr for the error message
+        let output = sh("echo ignored; echo broken >&2; exit 3", &CommandOptions::default()).unwrap();
+        assert!(!output.success);
+        assert_eq!(output.code, Some(3));
+        assert!(output.stdout.is_empty());
+        assert_eq!(output.stderr, b"broken\n");
+
+        let err = run_command("/nonexistent/program", &["x"], &CommandOptions::default()).unwrap_err();
+        assert!(err.to_string().starts_with("Failed to run /nonexistent/program x: "));
+    }
+
+    #[test]
+    fn timeout_kills_the_child() {
+        let opts = CommandOptions { timeout: Some(Duration::from_millis(100)), ..Default::default() };
+        let started = Instant::now();
+        let err = sh("sleep 10", &opts).unwrap_err();
+        assert_eq!(err.to_string(), "Command timed out after 0s: sh -c 'sleep 10'");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn dry_run_does_not_execute() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let opts = CommandOptions { dry_run: true, capture: true, ..Default::default() };
+        let output = sh(&format!("touch {}", marker.display()), &opts).unwrap();
+        assert!(output.success);
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn command_line_quotes_only_what_needs_it() {
+        assert_eq!(
+            command_line("qiime", &["tools", "import", "--input-path", "my reads/it's.tsv", ""]),
+            r"qiime tools import --input-path 'my reads/it'\''s.tsv' ''"
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use crate::logger::log_action;
+
+/// `--conda-path`, if given.
+static CONDA_OVERRIDE: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+/// The conda binary every invocation uses, resolved on first use.
+static CONDA_BINARY: Lazy<String> = Lazy::new(|| {
+    let conda = resolve_conda(
+        CONDA_OVERRIDE.lock().unwrap().as_deref(),
+        env::var_os("PATH").as_deref().map(Path::new),
+        env::var("CONDA_EXE").ok().as_deref(),
+        env::var_os("HOME").as_deref().map(Path::new),
+    );
+    log_action(&format!("Using conda: {}", conda));
+    conda
+});
+
+/// `--solver`.
+static SOLVER: Lazy<Mutex<CondaSolver>> = Lazy::new(|| Mutex::new(CondaSolver::default()));
+
+/// Dependency solver `conda env create` uses when installing the QIIME
+/// environment (`--solver`).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CondaSolver {
+    /// Conda's configured solver, retried with libmamba if it fails to solve.
+    #[default]
+    Auto,
+    Classic,
+    Libmamba,
+}
+
+impl CondaSolver {
+    /// Value for conda's `--solver` option, or `None` to use conda's own setting.
+    pub fn flag(self) -> Option<&'static str> {
+        match self {
+            CondaSolver::Auto => None,
+            CondaSolver::Classic => Some("classic"),
+            CondaSolver::Libmamba => Some("libmamba"),
+        }
+    }
+}
+
+impl std::str::FromStr for CondaSolver {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(CondaSolver::Auto),
+            "classic" => Ok(CondaSolver::Classic),
+            "libmamba" | "mamba" => Ok(CondaSolver::Libmamba),
+            other => Err(format!("expected auto, classic or libmamba, got '{}'", other)),
+        }
+    }
+}
+
+/// Sets the solver from `--solver`.
+pub fn set_solver(solver: CondaSolver) {
+    *SOLVER.lock().unwrap() = solver;
+}
+
+/// The solver chosen with `--solver`.
+pub fn solver() -> CondaSolver {
+    *SOLVER.lock().unwrap()
+}
+
+/// Whether the stderr of a failed `conda env create` shows the solver giving
+/// up, as opposed to e.g. a network error that a second solver won't fix.
+pub fn is_solver_failure(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    [
+        "unsatisfiableerror",
+        "resolvepackagenotfound",
+        "libmambaunsatisfiableerror",
+        "found conflicts",
+        "failed to solve",
+        "solving environment: failed",
+        "could not solve",
+    ]
+    .iter()
+    .any(|pattern| stderr.contains(pattern))
+}
+
+/// Sets the conda binary from `--conda-path`. Must be called before the
+/// first conda invocation.
+pub fn set_conda_path(path: Option<String>) {
+    *CONDA_OVERRIDE.lock().unwrap() = path;
+}
+
+/// The conda binary to run: `--conda-path`, `conda` on `PATH`, or the first
+/// of the usual install locations that exists (see [`resolve_conda`]).
+pub fn conda_binary() -> &'static str {
+    &CONDA_BINARY
+}
+
+/// Install locations tried when `conda` isn't on `PATH`, in order:
+/// `$CONDA_EXE`, then `~/miniconda3`, `~/anaconda3`, `~/miniforge3` and
+/// `/opt/conda`.
+fn fallback_locations(conda_exe: Option<&str>, home: Option<&Path>) -> Vec<PathBuf> {
+    let mut locations: Vec<PathBuf> = conda_exe.filter(|exe| !exe.is_empty()).map(PathBuf::from).into_iter().collect();
+    if let Some(home) = home {
+        for dir in ["miniconda3", "anaconda3", "miniforge3"] {
+            locations.push(home.join(dir).join("bin").join("conda"));
+        }
+    }
+    locations.push(PathBuf::from("/opt/conda/bin/conda"));
+    locations
+}
+
+/// Picks the conda binary. An explicit `override_path` always wins. Otherwise
+/// plain `conda` is used when it is on `path_var`, then the first existing
+/// [`fallback_locations`] entry; if nothing is found, `conda` is returned so
+/// the usual "not found" error surfaces.
+fn resolve_conda(
+    override_path: Option<&str>,
+    path_var: Option<&Path>,
+    conda_exe: Option<&str>,
+    home: Option<&Path>,
+) -> String {
+    if let Some(path) = override_path {
+        return path.to_string();
+    }
+    let on_path = path_var.is_some_and(|path_var| env::split_paths(path_var).any(|dir| dir.join("conda").is_file()));
+    if on_path {
+        return "conda".to_string();
+    }
+    fallback_locations(conda_exe, home)
+        .into_iter()
+        .find(|candidate| candidate.is_file())
+        .map(|candidate| candidate.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "conda".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Creates an (empty) conda binary at `dir/relative`.
+    fn install(dir: &Path, relative: &str) -> PathBuf {
+        let path = dir.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "").unwrap();
+        path
+    }
+
+    #[test]
+    fn conda_off_path_is_found_in_a_home_install() {
+        let home = tempfile::tempdir().unwrap();
+        let bin = tempfile::tempdir().unwrap();
+        let anaconda = install(home.path(), "anaconda3/bin/conda");
+        let found = resolve_conda(None, Some(bin.path()), None, Some(home.path()));
+        assert_eq!(found, anaconda.to_string_lossy());
+
+        // miniconda3 comes first, and $CONDA_EXE before both
+        let miniconda = install(home.path(), "miniconda3/bin/conda");
+        let found = resolve_conda(None, Some(bin.path()), None, Some(home.path()));
+        assert_eq!(found, miniconda.to_string_lossy());
+        let exe = install(bin.path(), "envs/base/bin/conda");
+        let found = resolve_conda(None, Some(bin.path()), exe.to_str(), Some(home.path()));
+        assert_eq!(found, exe.to_string_lossy());
+    }
+
+    #[test]
+    fn path_and_override_take_precedence() {
+        let home = tempfile::tempdir().unwrap();
+        let bin = tempfile::tempdir().unwrap();
+        install(home.path(), "miniconda3/bin/conda");
+        install(bin.path(), "conda");
+        assert_eq!(resolve_conda(None, Some(bin.path()), None, Some(home.path())), "conda");
+        assert_eq!(resolve_conda(Some("/custom/conda"), Some(bin.path()), None, Some(home.path())), "/custom/conda");
+    }
+
+    #[test]
+    fn missing_conda_falls_back_to_the_bare_name() {
+        let empty = tempfile::tempdir().unwrap();
+        let missing_exe = empty.path().join("conda").to_string_lossy().into_owned();
+        if !Path::new("/opt/conda/bin/conda").exists() {
+            assert_eq!(resolve_conda(None, Some(empty.path()), Some(&missing_exe), Some(empty.path())), "conda");
+        }
+    }
+
+    #[test]
+    fn only_solver_errors_count_as_solver_failures() {
+        assert!(is_solver_failure("LibMambaUnsatisfiableError: Encountered problems while solving"));
+        assert!(is_solver_failure("Solving environment: failed\n\nResolvePackageNotFound:"));
+        assert!(!is_solver_failure("CondaHTTPError: HTTP 000 CONNECTION FAILED for url"));
+        assert_eq!("mamba".parse::<CondaSolver>(), Ok(CondaSolver::Libmamba));
+        assert_eq!(CondaSolver::Auto.flag(), None);
+    }
+}
@@ -3,6 +3,8 @@ use serde::Deserialize;
 use std::error::Error;
 use config::{Config, File};
 
+// Only printed by `info` so far
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct WindchimeConfig {
     pub demultiplex_barcodes: Option<String>,
@@ -10,6 +12,7 @@ pub struct WindchimeConfig {
     pub skip_existing: Option<bool>,
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for WindchimeConfig {
     fn default() -> Self {
         WindchimeConfig {
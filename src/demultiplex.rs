@@ -1,25 +1,678 @@
+use std::collections::HashMap;
+use std::env;
 use std::fs::{self, File};
-use std::io::{self, BufRead, BufReader, Write};
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::SystemTime;
+use std::sync::{Arc, Mutex};
 
 use bio::io::fastq;
-use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
-use indicatif::{ProgressBar, ProgressStyle};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use flate2::{write::GzEncoder, Compression};
 use rayon::prelude::*;
 
-use crate::{logger::log_action, color_print::{print_error, print_success}, OUTPUT_DIR};
+use crate::ordered_writer::OrderedPairWriter;
+use crate::primers;
+use crate::audit;
+use crate::estimate::ReadFiles;
+use crate::fastq_io::{self, Codec, FastqReader};
+use crate::progress::CountProgress;
+use crate::qc_report;
+use crate::summary;
+use crate::{logger::{log_action, log_event, LogLevel}, color_print::{print_error, print_info, print_success}, output_dir};
 
 /// Simple helper for constructing an output path (as a `String`).
 fn out_path(filename: &str) -> String {
-    format!("{}/{}", OUTPUT_DIR, filename)
+    format!("{}/{}", output_dir(), filename)
 }
 
-/// Runs the demultiplexing logic using the provided barcodes file.
+/// Paths of the R1 and R2 FASTQs demultiplexing writes for `sample_id`. The
+/// manifest, QC report and incremental checks all locate the outputs through
+/// this, so they can't drift from where demux actually writes.
+pub(crate) fn demux_output_paths(sample_id: &str) -> (String, String) {
+    (
+        out_path(&format!("{}_L001_R1_001.fastq.gz", sample_id)),
+        out_path(&format!("{}_L001_R2_001.fastq.gz", sample_id)),
+    )
+}
+
+/// The FASTQs demultiplexing writes for `sample_id`: both of
+/// [`demux_output_paths`], or only R1 for single-end reads.
+pub(crate) fn demux_output_files(sample_id: &str, single_end: bool) -> Vec<String> {
+    let (r1, r2) = demux_output_paths(sample_id);
+    if single_end { vec![r1] } else { vec![r1, r2] }
+}
+
+/// Tunable settings for the custom demultiplexer.
+#[derive(Debug, Clone, Default)]
+pub struct DemuxOptions {
+    /// Skip the whole step if demultiplexed output already exists.
+    pub skip_existing: bool,
+    /// Minimum number of leading index bases that must match the expected
+    /// barcode. `None` requires the full index to match. The full index
+    /// length is always trimmed from R1, regardless of how many bases matched.
+    pub min_index_match: Option<usize>,
+    /// Mismatches (Hamming distance) allowed between the compared index bases
+    /// of a read and the expected barcode. 0 requires an exact match.
+    pub barcode_mismatches: usize,
+    /// Header-based column mapping for the barcodes file, e.g.
+    /// `name=Sample,file=FileBase,index=I5`. `None` uses the positional layout.
+    pub barcode_columns: Option<String>,
+    /// Also write read pairs whose index matches no sample sharing their input
+    /// files to `unassigned_L001_R{1,2}_001.fastq.gz`.
+    pub keep_unassigned: bool,
+    /// Stop at the first failed sample instead of processing the rest first.
+    /// Either way, any failure makes the run return an error.
+    pub fail_fast: bool,
+    /// Forward primer expected in R1 right after the index (IUPAC codes
+    /// allowed, see [`primers::primer_matches`]). Pairs that match the index but not this primer are written
+    /// to `{sample}_suspect_R{1,2}.fastq.gz` instead of the sample's output.
+    pub suspect_primer: Option<String>,
+    /// Incremental mode: only (re)process samples whose input FASTQs changed
+    /// (see [`Since`]). Samples with missing outputs are always processed.
+    pub since: Option<Since>,
+    /// Pairs whose R1 is shorter than this after trimming the index are
+    /// discarded and counted. Values below 1 are treated as 1, so zero-length
+    /// records (which break DADA2) are never written.
+    pub min_trimmed_length: usize,
+    /// Each sample's input is one interleaved FASTQ (`{file_name}.fastq[.gz]`,
+    /// or `{file_name}_001.fastq[.gz]`) holding R1 and R2 records alternately.
+    pub interleaved: bool,
+    /// Write per-sample read statistics to `windchime_mqc.txt` for MultiQC.
+    pub qc_report: bool,
+    /// Copy samples that need no per-record changes (see [`passthrough_blocker`])
+    /// straight to the output instead of parsing and recompressing them.
+    pub passthrough: bool,
+    /// Record the inputs in `input_audit.tsv` before processing them.
+    pub audit: bool,
+    /// Include a SHA-256 of each input in the audit.
+    pub audit_hash: bool,
+    /// Reject blank lines inside FASTQs, `+` lines naming another read, and
+    /// R1/R2 files that disagree in record count or read IDs.
+    pub strict_fastq: bool,
+    /// Build sample IDs from this template instead of `{name}_{seq2}`.
+    pub sample_id_template: Option<SampleIdTemplate>,
+    /// Single-end reads: only `{file_name}_R1_001.fastq[.gz]` (or
+    /// `{file_name}.fastq[.gz]`) is read, and only R1 is written.
+    pub single_end: bool,
+}
+
+/// Sample IDs built from barcodes fields (`--sample-id-template`), e.g.
+/// `{plate}_{name}_{seq2}`. Placeholders are `{name}`, `{file}`, `{index}`
+/// (or `{seq2}`), `{lane}` (1-based position of the barcodes file),
+/// `{barcodes}` (its file name without extension), `{tag}` and any column
+/// of the barcodes file header.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SampleIdTemplate {
+    pub template: String,
+    /// `--sample-tag` values for `{tag}`: one for all barcodes files, or one per file.
+    pub tags: Vec<String>,
+}
+
+/// Names QIIME reserves for the ID column header, compared case-insensitively.
+const RESERVED_SAMPLE_IDS: [&str; 12] = [
+    "id", "sampleid", "sample id", "sample-id", "sample_id", "featureid", "feature id", "feature-id",
+    "feature_id", "#sampleid", "#sample id", "#otuid",
+];
+
+/// Why `id` can't be used as a QIIME sample ID (and output file name), if
+/// it can't: IDs must be non-empty, use only letters, digits, `.`, `-` and
+/// `_`, and not be one of QIIME's reserved header names.
+fn unsafe_sample_id(id: &str) -> Option<String> {
+    if id.is_empty() {
+        return Some("it is empty".to_string());
+    }
+    if let Some(c) = id.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))) {
+        return Some(format!("'{}' is not a letter, digit, '.', '-' or '_'", c));
+    }
+    if RESERVED_SAMPLE_IDS.contains(&id.to_lowercase().as_str()) {
+        return Some("QIIME reserves it for the ID column header".to_string());
+    }
+    None
+}
+
+impl SampleIdTemplate {
+    /// The ID of `row`, read from barcodes file number `lane` (1-based), `barcodes_file`.
+    fn render(&self, row: &BarcodeRow, lane: usize, barcodes_file: &str) -> Result<String, String> {
+        let mut id = String::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            id.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed '{{' in sample ID template '{}'", self.template))?;
+            let key = &rest[start + 1..start + end];
+            let value = match key {
+                "name" => row.name.clone(),
+                "file" | "file_name" => row.file_name.clone(),
+                "index" | "seq2" => row.index_seq.clone(),
+                "lane" => lane.to_string(),
+                "barcodes" => Path::new(barcodes_file)
+                    .file_stem()
+                    .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned()),
+                "tag" => match self.tags.as_slice() {
+                    [] => return Err("the sample ID template uses {tag} but no --sample-tag was given".to_string()),
+                    [tag] => tag.clone(),
+                    tags => tags[lane - 1].clone(),
+                },
+                column => match row.fields.iter().find(|(header, _)| header == column) {
+                    Some((_, value)) => value.clone(),
+                    None => {
+                        return Err(format!(
+                            "unknown placeholder {{{}}} in sample ID template (use name, file, index, lane, \
+                             barcodes, tag or a barcodes file column: {})",
+                            column,
+                            row.fields.iter().map(|(header, _)| header.as_str()).collect::<Vec<_>>().join(", ")
+                        ))
+                    }
+                },
+            };
+            id.push_str(&value);
+            rest = &rest[start + end + 1..];
+        }
+        id.push_str(rest);
+        Ok(id)
+    }
+}
+
+/// Which samples an incremental demux (`--since`) reprocesses.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Since {
+    /// Inputs modified after the sample's existing demux output.
+    Outputs,
+    /// Inputs modified after this time.
+    Time(SystemTime),
+}
+
+impl FromStr for Since {
+    type Err = String;
+
+    /// Accepts `outputs`, an RFC 3339 timestamp, or a local
+    /// `YYYY-MM-DD[ HH:MM:SS]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("outputs") {
+            return Ok(Since::Outputs);
+        }
+        if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Since::Time(time.into()));
+        }
+        let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+            .or_else(|_| NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|d| d.and_hms_opt(0, 0, 0).unwrap()))
+            .map_err(|_| format!("expected 'outputs', an RFC 3339 timestamp or YYYY-MM-DD[ HH:MM:SS], got '{}'", s))?;
+        Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(|time| Since::Time(time.into()))
+            .ok_or_else(|| format!("'{}' is not a valid local time", s))
+    }
+}
+
+/// A stream of `(R1, R2)` read pairs; R2 is `None` for single-end input.
+type PairIter = Box<dyn Iterator<Item = io::Result<(fastq::Record, Option<fastq::Record>)>> + Send>;
+
+/// Where a sample's read pairs come from.
+#[derive(Debug, Clone, PartialEq)]
+enum PairInput {
+    /// Separate `{file_name}_R1_001.fastq[.gz]` and `_R2_` files.
+    Split { r1: String, r2: String },
+    /// One file with R1 and R2 records alternating.
+    Interleaved(String),
+    /// Single-end reads: R1 only.
+    Single(String),
+}
+
+impl PairInput {
+    /// Locates the input FASTQ(s) for `file_name` in the layout `options`
+    /// asks for, or explains what is missing.
+    fn find(file_name: &str, options: &DemuxOptions) -> Result<Self, String> {
+        if options.single_end {
+            return find_fastq(&format!("{}_R1_001.fastq", file_name))
+                .or_else(|| find_fastq(&format!("{}.fastq", file_name)))
+                .or_else(|| find_fastq(&format!("{}_001.fastq", file_name)))
+                .map(PairInput::Single)
+                .ok_or_else(|| format!("R1 file does not exist for {}", file_name));
+        }
+        if options.interleaved {
+            return find_fastq(&format!("{}.fastq", file_name))
+                .or_else(|| find_fastq(&format!("{}_001.fastq", file_name)))
+                .map(PairInput::Interleaved)
+                .ok_or_else(|| format!("Interleaved FASTQ does not exist for {}", file_name));
+        }
+        let Some(r1) = find_fastq(&format!("{}_R1_001.fastq", file_name)) else {
+            // A single file whose first two records are mates is interleaved
+            let single = find_fastq(&format!("{}.fastq", file_name))
+                .or_else(|| find_fastq(&format!("{}_001.fastq", file_name)));
+            if let Some(path) = single
+                && fastq_io::looks_interleaved(&path).unwrap_or(false)
+            {
+                log_action(&format!("{} looks interleaved; reading R1 and R2 from it.", path));
+                return Ok(PairInput::Interleaved(path));
+            }
+            return Err(format!("R1 file does not exist for {}", file_name));
+        };
+        let r2 = find_fastq(&format!("{}_R2_001.fastq", file_name))
+            .ok_or_else(|| format!("R2 file does not exist for {}", file_name))?;
+        Ok(PairInput::Split { r1, r2 })
+    }
+
+    fn files(&self) -> Vec<&str> {
+        match self {
+            PairInput::Split { r1, r2 } => vec![r1, r2],
+            PairInput::Interleaved(path) | PairInput::Single(path) => vec![path],
+        }
+    }
+
+    /// Opens the input and yields its read pairs. Split inputs stop at the end
+    /// of the shorter file, unless `strict` (`--strict-fastq`), which also
+    /// requires matching mate IDs; interleaved inputs must alternate mates
+    /// with matching IDs and hold an even number of records. Single-end
+    /// inputs yield every record with no mate.
+    fn read_pairs(&self, strict: bool) -> io::Result<PairIter> {
+        let with_mate = |pair: io::Result<(fastq::Record, fastq::Record)>| pair.map(|(rec1, rec2)| (rec1, Some(rec2)));
+        match self {
+            PairInput::Split { r1, r2 } if strict => Ok(Box::new(fastq_io::strict_split_pairs(r1, r2)?.map(with_mate))),
+            PairInput::Split { r1, r2 } => {
+                let records1 = FastqReader::open(r1)?;
+                let records2 = FastqReader::open(r2)?;
+                Ok(Box::new(records1.zip(records2).map(|(rec1, rec2)| Ok((rec1?, Some(rec2?))))))
+            }
+            PairInput::Interleaved(path) => Ok(Box::new(fastq_io::interleaved_pairs(path, strict)?.map(with_mate))),
+            PairInput::Single(path) => {
+                let records = FastqReader::open(path)?.with_strict(strict);
+                Ok(Box::new(records.map(|rec| Ok((rec?, None)))))
+            }
+        }
+    }
+}
+
+/// Read pairs seen and written for one sample.
+#[derive(Debug, Clone, Copy, Default)]
+struct PairCounts {
+    /// Pairs read from the sample's input, whichever sample they belong to.
+    seen: u64,
+    clean: u64,
+    suspect: u64,
+    /// Discarded for being shorter than `min_trimmed_length` after trimming.
+    too_short: u64,
+    /// Pairs whose index matched exactly, and with 1 to `barcode_mismatches`
+    /// mismatches (whichever of the above they ended up in).
+    exact_index: u64,
+    mismatched_index: u64,
+    /// The input was copied unchanged, without counting records.
+    copied: bool,
+}
+
+impl PairCounts {
+    /// Pairs that didn't carry the sample's index (or were too short to hold it).
+    fn skipped(&self) -> u64 {
+        self.seen - self.exact_index - self.mismatched_index
+    }
+}
+
+/// Positions of the fields Windchime needs within a barcodes file row.
+#[derive(Debug, Clone, PartialEq)]
+struct BarcodeColumns {
+    name: usize,
+    file_name: usize,
+    index: usize,
+    /// Exact number of fields a row must have (positional layout only).
+    exact_len: Option<usize>,
+}
+
+impl BarcodeColumns {
+    /// The fixed six-column `name, file_name, idx1, seq1, idx2, seq2` layout.
+    fn positional() -> Self {
+        BarcodeColumns { name: 0, file_name: 1, index: 5, exact_len: Some(6) }
+    }
+
+    /// Resolves a `name=<header>,file=<header>,index=<header>` mapping against
+    /// the header row of the barcodes file. All three keys are required.
+    fn from_mapping(spec: &str, header: &str) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let headers: Vec<&str> = header.split('\t').map(str::trim).collect();
+        let mut name = None;
+        let mut file_name = None;
+        let mut index = None;
+        for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, column) = pair
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("Invalid barcode column mapping '{}': expected key=column", pair)))?;
+            let position = headers
+                .iter()
+                .position(|h| *h == column.trim())
+                .ok_or_else(|| invalid(format!("Column '{}' not found in barcodes header", column.trim())))?;
+            match key.trim() {
+                "name" => name = Some(position),
+                "file" | "file_name" => file_name = Some(position),
+                "index" | "seq2" => index = Some(position),
+                other => {
+                    return Err(invalid(format!(
+                        "Unknown barcode column key '{}' (expected name, file, or index)",
+                        other
+                    )))
+                }
+            }
+        }
+        let missing = |key: &str| invalid(format!("Barcode column mapping is missing '{}='", key));
+        Ok(BarcodeColumns {
+            name: name.ok_or_else(|| missing("name"))?,
+            file_name: file_name.ok_or_else(|| missing("file"))?,
+            index: index.ok_or_else(|| missing("index"))?,
+            exact_len: None,
+        })
+    }
+}
+
+/// One sample row of the barcodes file.
+#[derive(Debug, Clone, PartialEq)]
+struct BarcodeRow {
+    name: String,
+    file_name: String,
+    /// The index sequence (`seq2` in the positional layout).
+    index_seq: String,
+    /// 1-based position of the barcodes file this row came from, set only when
+    /// the same sample appears in several barcodes files (lanes).
+    lane: Option<usize>,
+    /// Every field of the row, by barcodes header name, for sample ID templates.
+    fields: Vec<(String, String)>,
+    /// ID from `--sample-id-template`, used instead of the default naming.
+    template_id: Option<String>,
+}
+
+impl BarcodeRow {
+    /// Sample ID (and output base name) as `"{name}_{index_seq}"` (or just
+    /// `"{name}"` without an index), with a `_lane{n}` suffix when the sample
+    /// appears in several barcodes files. A `--sample-id-template` ID replaces all of this.
+    fn sample_id(&self) -> String {
+        if let Some(id) = &self.template_id {
+            return id.clone();
+        }
+        if self.index_seq.is_empty() {
+            return match self.lane {
+                Some(lane) => format!("{}_lane{}", self.name, lane),
+                None => self.name.clone(),
+            };
+        }
+        match self.lane {
+            Some(lane) => format!("{}_{}_lane{}", self.name, self.index_seq, lane),
+            None => format!("{}_{}", self.name, self.index_seq),
+        }
+    }
+}
+
+/// Expands the given barcodes files: a directory stands for the `.tsv` files
+/// directly inside it, in name order.
+fn expand_barcode_files(barcodes_files: &[String]) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    for path in barcodes_files {
+        if Path::new(path).is_dir() {
+            let mut in_dir: Vec<String> = fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.is_file() && p.extension().is_some_and(|ext| ext == "tsv"))
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect();
+            if in_dir.is_empty() {
+                let msg = format!("No .tsv barcodes files found in directory '{}'", path);
+                print_error(&msg);
+                return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+            }
+            in_dir.sort();
+            files.extend(in_dir);
+        } else {
+            files.push(path.clone());
+        }
+    }
+    Ok(files)
+}
+
+/// Reads the rows of several barcodes files (one per lane; see
+/// [`expand_barcode_files`]). A sample ID that appears in more than one file
+/// gets a `_lane{n}` suffix in each, so the lanes' outputs don't collide.
+/// `allow_empty_index` accepts rows without an index sequence (`--passthrough`).
+/// With `sample_ids`, IDs come from the template instead and must be
+/// QIIME-safe and unique.
+fn read_barcode_sets(
+    barcodes_files: &[String],
+    column_mapping: Option<&str>,
+    allow_empty_index: bool,
+    sample_ids: Option<&SampleIdTemplate>,
+) -> io::Result<Vec<BarcodeRow>> {
+    let files = expand_barcode_files(barcodes_files)?;
+    let mut per_file = Vec::new();
+    for file in &files {
+        per_file.push(read_barcode_rows(file, column_mapping, allow_empty_index)?);
+    }
+    if let Some(template) = sample_ids {
+        return apply_sample_id_template(template, &files, per_file);
+    }
+
+    let mut files_per_sample: HashMap<String, usize> = HashMap::new();
+    for rows in &per_file {
+        let mut ids: Vec<String> = rows.iter().map(BarcodeRow::sample_id).collect();
+        ids.sort();
+        ids.dedup();
+        for id in ids {
+            *files_per_sample.entry(id).or_default() += 1;
+        }
+    }
+
+    let mut all_rows = Vec::new();
+    for (lane, rows) in per_file.into_iter().enumerate() {
+        for mut row in rows {
+            if files_per_sample[&row.sample_id()] > 1 {
+                row.lane = Some(lane + 1);
+            }
+            all_rows.push(row);
+        }
+    }
+    if files.len() > 1 {
+        log_action(&format!("Read {} samples from {} barcodes files.", all_rows.len(), files.len()));
+    }
+    Ok(all_rows)
+}
+
+/// Sets each row's ID from `template` and checks the IDs are QIIME-safe and
+/// unique across all barcodes files.
+fn apply_sample_id_template(
+    template: &SampleIdTemplate,
+    files: &[String],
+    per_file: Vec<Vec<BarcodeRow>>,
+) -> io::Result<Vec<BarcodeRow>> {
+    let invalid = |msg: String| {
+        print_error(&msg);
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    };
+    if template.tags.len() > 1 && template.tags.len() != files.len() {
+        return Err(invalid(format!(
+            "{} --sample-tag values given for {} barcodes files; give one for all or one per file",
+            template.tags.len(),
+            files.len()
+        )));
+    }
+
+    let mut all_rows: Vec<BarcodeRow> = Vec::new();
+    // (ID, sample name, barcodes file) of every row so far, to name duplicates
+    let mut seen: Vec<(String, String, &str)> = Vec::new();
+    let mut problems = Vec::new();
+    for (lane, (file, rows)) in files.iter().zip(per_file).enumerate() {
+        for mut row in rows {
+            let id = template.render(&row, lane + 1, file).map_err(invalid)?;
+            if let Some(reason) = unsafe_sample_id(&id) {
+                problems.push(format!("'{}' ({} in {}): {}", id, row.name, file, reason));
+            } else if let Some((_, name, earlier_file)) = seen.iter().find(|(earlier, _, _)| *earlier == id) {
+                problems.push(format!("'{}' is given to {} in {} and {} in {}", id, name, earlier_file, row.name, file));
+            }
+            seen.push((id.clone(), row.name.clone(), file));
+            row.template_id = Some(id);
+            all_rows.push(row);
+        }
+    }
+    if !problems.is_empty() {
+        return Err(invalid(format!(
+            "Sample ID template '{}' gives unusable IDs: {}",
+            template.template,
+            problems.join("; ")
+        )));
+    }
+    log_action(&format!(
+        "Named {} samples from {} barcodes file(s) with template '{}'.",
+        all_rows.len(),
+        files.len(),
+        template.template
+    ));
+    Ok(all_rows)
+}
+
+/// Reads a sample sheet or manifest as text. A UTF-8 byte order mark (which
+/// spreadsheet exports often add, and which would otherwise end up in the
+/// first column name) is dropped. Files that aren't valid UTF-8 are decoded
+/// as Latin-1, with a warning naming the first offending byte.
+pub(crate) fn read_text_file(path: &str) -> io::Result<String> {
+    let bytes = fs::read(path)?;
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Ok(text.to_string()),
+        Err(e) => {
+            let offset = e.valid_up_to();
+            let line = bytes[..offset].iter().filter(|&&b| b == b'\n').count() + 1;
+            let msg = format!(
+                "Warning: '{}' is not valid UTF-8 (byte 0x{:02X} at offset {}, line {}); reading it as Latin-1",
+                path, bytes[offset], offset, line
+            );
+            print_error(&msg);
+            log_event(LogLevel::Warning, None, &msg);
+            Ok(bytes.iter().map(|&b| b as char).collect())
+        }
+    }
+}
+
+/// Reads and checks the barcodes file at `path`, skipping its header. Every
+/// row must fit the column layout (exactly six tab-separated fields unless
+/// `column_mapping` names the columns), have a non-empty name and file name,
+/// a sample name not used by another row, and an index sequence of only
+/// `A`, `C`, `G` and `T` (or empty, with `allow_empty_index`). All problems
+/// are reported together, one per line.
+fn validate_barcodes_file(
+    path: &str,
+    column_mapping: Option<&str>,
+    allow_empty_index: bool,
+) -> Result<Vec<BarcodeRow>, String> {
+    let text = read_text_file(path).map_err(|e| format!("Unable to open barcodes file '{}': {}", path, e))?;
+    let mut lines = text.lines();
+
+    let header = lines.next().unwrap_or_default();
+    let columns = match column_mapping {
+        Some(spec) => BarcodeColumns::from_mapping(spec, header).map_err(|e| e.to_string())?,
+        None => BarcodeColumns::positional(),
+    };
+    let min_len = columns.name.max(columns.file_name).max(columns.index) + 1;
+    let headers: Vec<&str> = header.trim_end_matches('\r').split('\t').map(str::trim).collect();
+
+    let mut rows: Vec<BarcodeRow> = Vec::new();
+    let mut problems = Vec::new();
+    // Line each sample name was first seen on, to point duplicates at it
+    let mut names: HashMap<String, usize> = HashMap::new();
+    for (i, line) in lines.enumerate() {
+        let line_no = i + 2;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        match columns.exact_len {
+            Some(n) if fields.len() != n => {
+                problems.push(format!("line {}: {} tab-separated fields, expected {}", line_no, fields.len(), n));
+                continue;
+            }
+            None if fields.len() < min_len => {
+                problems.push(format!("line {}: {} tab-separated fields, expected at least {}", line_no, fields.len(), min_len));
+                continue;
+            }
+            _ => {}
+        }
+        // An empty index would match every read at offset 4, and an empty
+        // name yields a bare "_seq2" sample ID, so reject both outright.
+        // Passthrough takes an empty index to mean "the whole file is one sample".
+        let mut row_ok = true;
+        for (column, label) in [
+            (columns.name, "sample name"),
+            (columns.file_name, "file name"),
+            (columns.index, "index sequence"),
+        ] {
+            if fields[column].is_empty() && !(allow_empty_index && column == columns.index) {
+                problems.push(format!("line {}: empty {} (column {})", line_no, label, column + 1));
+                row_ok = false;
+            }
+        }
+        let index_seq = fields[columns.index];
+        if let Some(base) = index_seq.chars().find(|c| !matches!(c, 'A' | 'C' | 'G' | 'T')) {
+            problems.push(format!(
+                "line {}: index sequence '{}' contains '{}'; only A, C, G and T are allowed",
+                line_no, index_seq, base
+            ));
+            row_ok = false;
+        }
+        let name = fields[columns.name];
+        if !name.is_empty() {
+            if let Some(first) = names.get(name) {
+                problems.push(format!("line {}: sample name '{}' is already used on line {}", line_no, name, first));
+                row_ok = false;
+            } else {
+                names.insert(name.to_string(), line_no);
+            }
+        }
+        if !row_ok {
+            continue;
+        }
+        rows.push(BarcodeRow {
+            name: name.to_string(),
+            file_name: fields[columns.file_name].to_string(),
+            index_seq: index_seq.to_string(),
+            lane: None,
+            fields: headers
+                .iter()
+                .zip(&fields)
+                .map(|(header, field)| (header.to_string(), field.to_string()))
+                .collect(),
+            template_id: None,
+        });
+    }
+    if !problems.is_empty() {
+        return Err(format!(
+            "Barcodes file '{}' has {} problem(s):\n  {}",
+            path,
+            problems.len(),
+            problems.join("\n  ")
+        ));
+    }
+    if rows.is_empty() {
+        return Err(format!("Barcodes file '{}' lists no samples below its header", path));
+    }
+    Ok(rows)
+}
+
+/// Reads the barcodes file via [`validate_barcodes_file`], failing with every
+/// problem it finds before any reads are touched.
+fn read_barcode_rows(
+    barcodes_file: &str,
+    column_mapping: Option<&str>,
+    allow_empty_index: bool,
+) -> io::Result<Vec<BarcodeRow>> {
+    validate_barcodes_file(barcodes_file, column_mapping, allow_empty_index).map_err(|msg| {
+        print_error(&msg);
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    })
+}
+
+/// Runs the demultiplexing logic using the provided barcodes files (one per
+/// lane, or directories of them).
 ///
 /// # Assumptions
 ///
-/// - The `barcodes_file` is a tab-separated file with six columns:
+/// - Each barcodes file is a tab-separated file with six columns:
 ///   1) `name`
 ///   2) `file_name`
 ///   3) `idx1`
@@ -27,18 +680,38 @@ fn out_path(filename: &str) -> String {
 ///   5) `idx2`
 ///   6) `seq2`
 /// - The first line is a header and will be skipped.
+/// - Every row is checked by `validate_barcodes_file` first; any malformed
+///   row fails the run before reads are touched, listing all problems.
+/// - With `options.barcode_columns` (e.g. `name=Sample,file=FileBase,index=I5`)
+///   the fields are instead looked up by header name, and extra columns are ignored.
 /// - This function will look for `"{file_name}_R1_001.fastq.gz"`, then for `"{file_name}_R1_001.fastq"`.
 /// - The output file names are constructed as `"{name}_{seq2}_L001_R1_001.fastq.gz"` (and `_R2_`).
+///   A sample listed in several barcodes files gets a `_lane{n}` suffix after `{seq2}`.
 ///
 /// # Errors
 ///
-/// Returns an `io::Error` if any file cannot be read or written.
-pub fn run_demultiplex_combined(barcodes_file: &str, skip_existing: bool) -> io::Result<()> {
-    log_action(&format!("Demultiplex started with barcodes file: {}", barcodes_file));
+/// Returns an `io::Error` if any file cannot be read or written, if
+/// `options.min_index_match` is zero, or if any sample failed. Without
+/// `options.fail_fast` every sample is attempted before the failures are
+/// reported together.
+pub fn run_demultiplex_combined(barcodes_files: &[String], options: &DemuxOptions) -> io::Result<()> {
+    log_action(&format!("Demultiplex started with barcodes file(s): {}", barcodes_files.join(", ")));
+
+    if options.min_index_match == Some(0) {
+        let msg = "--min-index-match must be at least 1";
+        print_error(msg);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+    if options.single_end && options.interleaved {
+        let msg = "--single-end and --interleaved can't be combined: an interleaved FASTQ holds both mates";
+        print_error(msg);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
 
     // Check if we want to skip entirely if we detect previously demultiplexed files
     // (This is just a simplistic approach—adjust logic as needed.)
-    if skip_existing {
+    // Incremental runs decide per sample instead.
+    if options.skip_existing && options.since.is_none() {
         // For instance, if you expect certain files to exist or some sentinel. This is user-defined.
         let test_file = out_path("ANY_SAMPLE_L001_R1_001.fastq.gz");
         if Path::new(&test_file).exists() {
@@ -48,244 +721,1869 @@ pub fn run_demultiplex_combined(barcodes_file: &str, skip_existing: bool) -> io:
         }
     }
 
-    // Open the barcodes file
-    let file = File::open(barcodes_file).map_err(|e| {
-        print_error(&format!("Unable to open barcodes file '{}': {}", barcodes_file, e));
-        e
-    })?;
-    let reader = BufReader::new(file);
+    let barcode_rows = read_barcode_sets(
+        barcodes_files,
+        options.barcode_columns.as_deref(),
+        options.passthrough,
+        options.sample_id_template.as_ref(),
+    )?;
+    check_index_lengths(&barcode_rows, options.min_index_match, options.barcode_mismatches)?;
 
-    // Read all lines (skipping the header)
-    let barcode_lines: Vec<_> = reader
-        .lines()
-        .enumerate()
-        .filter_map(|(i, line_res)| {
-            // Skip the first (header) line
-            if i == 0 {
-                return None;
-            }
-            match line_res {
-                Ok(line) => Some(line),
-                Err(e) => {
-                    print_error(&format!("Error reading barcodes file at line {}: {}", i + 1, e));
-                    None
+    if options.audit {
+        let mut inputs = expand_barcode_files(barcodes_files)?;
+        for row in &barcode_rows {
+            if let Ok(input) = PairInput::find(&row.file_name, options) {
+                for file in input.files() {
+                    if !inputs.iter().any(|seen| seen == file) {
+                        inputs.push(file.to_string());
+                    }
                 }
             }
-        })
-        .collect();
-
-    // Setup a progress bar
-    let pb = Arc::new(
-        ProgressBar::new(barcode_lines.len() as u64).with_message("Processing barcodes...")
-    );
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>3}/{len:3} {msg}")
-            .unwrap()
-    );
-
-    // Process each barcode line in parallel
-    barcode_lines.par_iter().for_each(|barcode_line| {
-        let pb_clone = Arc::clone(&pb);
-        let fields: Vec<&str> = barcode_line.trim().split('\t').collect();
-
-        if fields.len() != 6 {
-            print_error(&format!("Invalid line: {}", barcode_line));
-            pb_clone.inc(1);
-            return;
         }
+        let audit_path = audit::write_input_audit(&inputs, options.audit_hash)?;
+        print_info(&format!("Recorded {} inputs in {}", inputs.len(), audit_path));
+    }
+    summary::record_setting("demux gzip level", "best", Compression::best().level());
+    summary::record_setting("demux threads", "auto", rayon::current_num_threads());
 
-        let name = fields[0];
-        let file_name = fields[1];
-        let seq2 = fields[5];
+    // Progress bar on a terminal, plain lines in logs
+    let pb = CountProgress::new(barcode_rows.len() as u64, "Processing barcodes");
 
-        // Determine the forward (R1) file
-        let fq_r1_file = find_fastq(&format!("{}_R1_001.fastq", file_name));
-        if fq_r1_file.is_none() {
-            print_error(&format!("R1 file does not exist for {}", file_name));
-            pb_clone.inc(1);
-            return;
-        }
+    // Failures are collected rather than aborting, unless fail_fast is set
+    let failures: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+    let stop = AtomicBool::new(false);
+    let suspect_total = AtomicU64::new(0);
+    let too_short_total = AtomicU64::new(0);
+    let sample_counts: Arc<Mutex<HashMap<String, PairCounts>>> = Arc::new(Mutex::new(HashMap::new()));
+    let exact_total = AtomicU64::new(0);
+    let mismatched_total = AtomicU64::new(0);
+    let unchanged = AtomicUsize::new(0);
 
-        // Determine the reverse (R2) file
-        let fq_r2_file = find_fastq(&format!("{}_R2_001.fastq", file_name));
-        if fq_r2_file.is_none() {
-            print_error(&format!("R2 file does not exist for {}", file_name));
-            pb_clone.inc(1);
+    // Process each barcode row in parallel
+    barcode_rows.par_iter().for_each(|row| {
+        if stop.load(Ordering::Relaxed) {
+            pb.inc();
             return;
         }
+        let file_name = row.file_name.as_str();
+        let seq2 = row.index_seq.as_str();
+
+        let record_failure = |msg: String| {
+            print_error(&msg);
+            failures.lock().unwrap().push(msg);
+            if options.fail_fast {
+                stop.store(true, Ordering::Relaxed);
+            }
+        };
+
+        // Determine the input FASTQ(s)
+        let input = match PairInput::find(file_name, options) {
+            Ok(input) => input,
+            Err(msg) => {
+                record_failure(msg);
+                pb.inc();
+                return;
+            }
+        };
 
         // Create output base (and sample ID) as "name_seq2"
-        let outbase = format!("{}_{}", name, seq2);
+        let outbase = row.sample_id();
+
+        if let Some(since) = &options.since {
+            match inputs_changed(&input, &outbase, since) {
+                Ok(true) => {}
+                Ok(false) => {
+                    log_action(&format!("Skipping {} (inputs unchanged).", outbase));
+                    unchanged.fetch_add(1, Ordering::Relaxed);
+                    pb.inc();
+                    return;
+                }
+                Err(e) => {
+                    record_failure(format!("Error checking modification times for {}: {}", file_name, e));
+                    pb.inc();
+                    return;
+                }
+            }
+        }
 
         // Demultiplex
-        if let Err(e) = demultiplex_fastq_files(
-            &fq_r1_file.unwrap(),
-            &fq_r2_file.unwrap(),
-            seq2,
-            &outbase,
-        ) {
-            print_error(&format!("Error processing {}: {}", file_name, e));
+        let result = demultiplex_fastq_files(&input, seq2, &outbase, options);
+        if let Ok(counts) = &result {
+            sample_counts.lock().unwrap().insert(outbase.clone(), *counts);
+        }
+        match result {
+            Ok(counts) if counts.copied => {
+                log_action(&format!("{}: copied input unchanged (passthrough)", outbase));
+            }
+            Ok(counts) => {
+                suspect_total.fetch_add(counts.suspect, Ordering::Relaxed);
+                too_short_total.fetch_add(counts.too_short, Ordering::Relaxed);
+                exact_total.fetch_add(counts.exact_index, Ordering::Relaxed);
+                mismatched_total.fetch_add(counts.mismatched_index, Ordering::Relaxed);
+                log_action(&format!(
+                    "{}: {} read pairs written, {} suspect primer, {} too short after trimming; \
+                     index matched exactly in {}, with mismatches in {}",
+                    outbase, counts.clean, counts.suspect, counts.too_short, counts.exact_index, counts.mismatched_index
+                ));
+            }
+            Err(e) => record_failure(format!("Error processing {}: {}", file_name, e)),
         }
 
-        pb_clone.inc(1);
+        pb.inc();
     });
 
-    pb.finish_with_message("Done processing barcodes");
+    let failures = failures.lock().unwrap();
+    if !failures.is_empty() {
+        pb.abandon("Demultiplexing failed");
+        let msg = if options.fail_fast {
+            format!("Demultiplexing stopped after a failure: {}", failures[0])
+        } else {
+            format!(
+                "Demultiplexing failed for {} of {} samples: {}",
+                failures.len(),
+                barcode_rows.len(),
+                failures.join("; ")
+            )
+        };
+        log_action(&msg);
+        return Err(io::Error::other(msg));
+    }
+
+    pb.finish("Done processing barcodes");
+
+    if options.since.is_some() {
+        let unchanged = unchanged.load(Ordering::Relaxed);
+        print_success(&format!(
+            "Processed {} of {} samples ({} unchanged).",
+            barcode_rows.len() - unchanged,
+            barcode_rows.len(),
+            unchanged
+        ));
+    }
+
+    let too_short = too_short_total.load(Ordering::Relaxed);
+    if too_short > 0 {
+        let msg = format!(
+            "Discarded {} read pairs shorter than {} bases after trimming.",
+            too_short,
+            options.min_trimmed_length.max(1)
+        );
+        log_action(&msg);
+        print_success(&msg);
+    }
+    if options.barcode_mismatches > 0 {
+        let msg = format!(
+            "Index matched exactly in {} read pairs and with up to {} mismatches in {} (see windchime.log for each sample).",
+            exact_total.load(Ordering::Relaxed),
+            options.barcode_mismatches,
+            mismatched_total.load(Ordering::Relaxed)
+        );
+        log_action(&msg);
+        print_success(&msg);
+    }
+    if options.suspect_primer.is_some() {
+        let suspect = suspect_total.load(Ordering::Relaxed);
+        log_action(&format!("Flagged {} read pairs with a suspect primer.", suspect));
+        print_success(&format!("Flagged {} read pairs with a suspect primer.", suspect));
+    }
+
+    if options.keep_unassigned {
+        write_unassigned_reads(&barcode_rows, options)?;
+    }
+
+    if options.qc_report {
+        let samples: Vec<(String, Vec<String>)> = barcode_rows
+            .iter()
+            .map(|row| {
+                let sample_id = row.sample_id();
+                let files = demux_output_files(&sample_id, options.single_end);
+                (sample_id, files)
+            })
+            .collect();
+        let report = qc_report::write_qc_report(&samples)?;
+        print_success(&format!("QC report written to {}", report));
+    }
+
+    let provenance = write_sample_provenance(&barcode_rows, options)?;
+    print_info(&format!("Sample provenance written to {}", provenance));
+
+    let sample_counts = sample_counts.lock().unwrap();
+    let stats = write_demux_stats(&barcode_rows, &sample_counts)?;
+    print_demux_stats(&barcode_rows, &sample_counts);
+    print_info(&format!("Per-sample demultiplexing statistics written to {}", stats));
+
     log_action("Demultiplex completed successfully.");
     print_success("Demultiplex completed!");
     Ok(())
 }
 
-/// Generates a QIIME2 manifest file from the barcodes file.
-/// Written to `qiime_manifest` in [`OUTPUT_DIR`].
-///
-/// # Errors
-///
-/// Returns an `io::Error` if reading the barcodes file or writing the manifest fails.
-pub fn generate_qiime_manifest(barcodes_file: &str, qiime_manifest: &str) -> io::Result<()> {
-    log_action("Generating QIIME2 manifest file.");
-    let infile = File::open(barcodes_file)?;
-    let reader = BufReader::new(infile);
-    let manifest_path = out_path(qiime_manifest);
-    let mut writer = File::create(manifest_path)?;
+/// Writes `OUTPUT_DIR/sample_provenance.tsv`, mapping each sample ID back to
+/// its barcodes row and the input FASTQs it was read from (the same path in
+/// both columns for an interleaved input, no R2 path for a single-end one).
+/// Returns the path written.
+fn write_sample_provenance(barcode_rows: &[BarcodeRow], options: &DemuxOptions) -> io::Result<String> {
+    let path = out_path("sample_provenance.tsv");
+    let mut out = File::create(&path)?;
+    writeln!(out, "sample_id\tname\tfile_name\tseq2\tr1_path\tr2_path")?;
+    for row in barcode_rows {
+        let (r1, r2) = match PairInput::find(&row.file_name, options) {
+            Ok(PairInput::Split { r1, r2 }) => (r1, Some(r2)),
+            Ok(PairInput::Interleaved(path)) => (path.clone(), Some(path)),
+            Ok(PairInput::Single(path)) => (path, None),
+            Err(msg) => return Err(io::Error::new(io::ErrorKind::NotFound, msg)),
+        };
+        let r2 = match r2 {
+            Some(r2) => absolute_path(&r2)?.display().to_string(),
+            None => String::new(),
+        };
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            row.sample_id(),
+            row.name,
+            row.file_name,
+            row.index_seq,
+            absolute_path(&r1)?.display(),
+            r2
+        )?;
+    }
+    Ok(path)
+}
 
-    // Write the QIIME2 manifest header
+/// Writes `OUTPUT_DIR/demux_stats.tsv` with one row per sample processed by
+/// this run, in barcodes file order: read pairs seen in its input, written to
+/// its output, and skipped because they didn't carry its index, followed by
+/// the suspect-primer, too-short and exact/mismatched index counts. Copied
+/// (`--passthrough`) samples aren't counted and get `NA`. Returns the path written.
+fn write_demux_stats(barcode_rows: &[BarcodeRow], sample_counts: &HashMap<String, PairCounts>) -> io::Result<String> {
+    let path = out_path("demux_stats.tsv");
+    let mut out = File::create(&path)?;
     writeln!(
-        writer,
-        "sample-id\tforward-absolute-filepath\treverse-absolute-filepath"
+        out,
+        "sample_id\tfile_name\tpairs_seen\tpairs_written\tpairs_skipped\tsuspect_primer\ttoo_short\tindex_exact\tindex_mismatched"
     )?;
-
-    for (i, line_res) in reader.lines().enumerate() {
-        let line = line_res?;
-        // Skip the header line
-        if i == 0 {
+    for row in barcode_rows {
+        let sample_id = row.sample_id();
+        let Some(counts) = sample_counts.get(&sample_id) else {
+            continue;
+        };
+        if counts.copied {
+            writeln!(out, "{}\t{}{}", sample_id, row.file_name, "\tNA".repeat(7))?;
             continue;
         }
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            sample_id,
+            row.file_name,
+            counts.seen,
+            counts.clean,
+            counts.skipped(),
+            counts.suspect,
+            counts.too_short,
+            counts.exact_index,
+            counts.mismatched_index
+        )?;
+    }
+    Ok(path)
+}
 
-        let fields: Vec<&str> = line.split('\t').collect();
-        if fields.len() != 6 {
-            print_error(&format!("Skipping invalid line in barcodes file: {}", line));
+/// Prints how many read pairs each processed sample received, as a table.
+fn print_demux_stats(barcode_rows: &[BarcodeRow], sample_counts: &HashMap<String, PairCounts>) {
+    if sample_counts.is_empty() {
+        return;
+    }
+    print_info("Read pairs per sample:");
+    println!("  {:<32} {:>12} {:>12} {:>12}", "sample", "seen", "written", "skipped");
+    for row in barcode_rows {
+        let sample_id = row.sample_id();
+        match sample_counts.get(&sample_id) {
+            Some(counts) if counts.copied => println!("  {:<32} {:>12}", sample_id, "(copied)"),
+            Some(counts) => println!(
+                "  {:<32} {:>12} {:>12} {:>12}",
+                sample_id,
+                counts.seen,
+                counts.clean,
+                counts.skipped()
+            ),
+            None => {}
+        }
+    }
+}
+
+/// How many of a sample's previewed read pairs carry its index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreviewCount {
+    pub sample_id: String,
+    pub file_name: String,
+    /// Pairs whose R1 carries the index at the expected position.
+    pub matched: u64,
+    /// Pairs carrying the reverse complement of the index there instead,
+    /// which points at an index given in the wrong orientation.
+    pub rc_matched: u64,
+}
+
+/// Read pairs previewed from one input and how many matched no sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreviewInput {
+    pub file_name: String,
+    pub pairs: u64,
+    pub unassigned: u64,
+}
+
+fn reverse_complement(seq: &str) -> String {
+    seq.chars()
+        .rev()
+        .map(|base| match base.to_ascii_uppercase() {
+            'A' => 'T',
+            'C' => 'G',
+            'G' => 'C',
+            'T' => 'A',
+            other => other,
+        })
+        .collect()
+}
+
+/// Tallies, over the first `max_pairs` read pairs of each input, how many
+/// match each sample's index the way demultiplexing would. Nothing is written.
+pub fn preview_barcode_counts(
+    barcodes_files: &[String],
+    options: &DemuxOptions,
+    max_pairs: usize,
+) -> io::Result<(Vec<PreviewCount>, Vec<PreviewInput>)> {
+    let barcode_rows = read_barcode_sets(
+        barcodes_files,
+        options.barcode_columns.as_deref(),
+        options.passthrough,
+        options.sample_id_template.as_ref(),
+    )?;
+    check_index_lengths(&barcode_rows, options.min_index_match, options.barcode_mismatches)?;
+    let mut counts: Vec<PreviewCount> = barcode_rows
+        .iter()
+        .map(|row| PreviewCount {
+            sample_id: row.sample_id(),
+            file_name: row.file_name.clone(),
+            matched: 0,
+            rc_matched: 0,
+        })
+        .collect();
+    let mut inputs: Vec<PreviewInput> = Vec::new();
+
+    for row in &barcode_rows {
+        if inputs.iter().any(|input| input.file_name == row.file_name) {
             continue;
         }
+        let input = PairInput::find(&row.file_name, options)
+            .map_err(|msg| io::Error::new(io::ErrorKind::NotFound, msg))?;
+        // Samples sharing this input, with their indexes in both orientations
+        let samples: Vec<(usize, &[u8], String)> = barcode_rows
+            .iter()
+            .enumerate()
+            .filter(|(_, other)| other.file_name == row.file_name)
+            .map(|(i, other)| (i, other.index_seq.as_bytes(), reverse_complement(&other.index_seq)))
+            .collect();
+        let mut previewed = PreviewInput { file_name: row.file_name.clone(), pairs: 0, unassigned: 0 };
+        for pair in input.read_pairs(options.strict_fastq)?.take(max_pairs) {
+            let (rec1, _) = pair?;
+            let seq1 = rec1.seq();
+            previewed.pairs += 1;
+            let mut assigned = false;
+            for (i, index, rc_index) in &samples {
+                let end_idx = 4 + index.len();
+                if seq1.len() < end_idx {
+                    continue;
+                }
+                if index_matches(&seq1[4..end_idx], index, options.min_index_match, options.barcode_mismatches) {
+                    counts[*i].matched += 1;
+                    assigned = true;
+                } else if !index.is_empty()
+                    && index_matches(&seq1[4..end_idx], rc_index.as_bytes(), options.min_index_match, options.barcode_mismatches)
+                {
+                    counts[*i].rc_matched += 1;
+                }
+            }
+            if !assigned {
+                previewed.unassigned += 1;
+            }
+        }
+        inputs.push(previewed);
+    }
+    Ok((counts, inputs))
+}
 
-        let name = fields[0];
-        let seq2 = fields[5];
-        let sample_id = format!("{}_{}", name, seq2);
+/// `--preview`: prints how the first `max_pairs` read pairs of each input
+/// would be assigned, to catch a wrong barcodes file or index orientation
+/// before a full demultiplex. Writes no output files.
+pub fn preview_barcodes(barcodes_files: &[String], options: &DemuxOptions, max_pairs: usize) -> io::Result<()> {
+    let (counts, inputs) = preview_barcode_counts(barcodes_files, options, max_pairs)?;
+    let percent = |n: u64, total: u64| if total == 0 { 0.0 } else { 100.0 * n as f64 / total as f64 };
+    print_info(&format!("Barcode preview of up to {} read pairs per input (nothing written):", max_pairs));
+    for input in &inputs {
+        println!("  {} ({} read pairs)", input.file_name, input.pairs);
+        for count in counts.iter().filter(|count| count.file_name == input.file_name) {
+            let rc = if count.rc_matched > 0 {
+                format!("   ({} reverse-complement matches)", count.rc_matched)
+            } else {
+                String::new()
+            };
+            println!(
+                "    {:<32} {:>10} {:>6.1}%{}",
+                count.sample_id,
+                count.matched,
+                percent(count.matched, input.pairs),
+                rc
+            );
+        }
+        println!(
+            "    {:<32} {:>10} {:>6.1}%",
+            "unassigned",
+            input.unassigned,
+            percent(input.unassigned, input.pairs)
+        );
+    }
+    let rc_total: u64 = counts.iter().map(|count| count.rc_matched).sum();
+    let matched_total: u64 = counts.iter().map(|count| count.matched).sum();
+    if rc_total > matched_total {
+        print_error(&format!(
+            "Warning: more reads carry the reverse complement of their sample's index ({}) than the index itself ({}); \
+             the indexes in the barcodes file may be in the wrong orientation.",
+            rc_total, matched_total
+        ));
+    }
+    log_action(&format!(
+        "Barcode preview: {} of {} read pairs matched a sample.",
+        matched_total,
+        inputs.iter().map(|input| input.pairs).sum::<u64>()
+    ));
+    Ok(())
+}
 
-        // Our demultiplexed FASTQ files are in OUTPUT_DIR, compressed .gz
-        let forward_rel = format!("{}_L001_R1_001.fastq.gz", sample_id);
-        let reverse_rel = format!("{}_L001_R2_001.fastq.gz", sample_id);
+/// Whether a sample needs (re)processing in an incremental run: its demux
+/// output is missing, or an input FASTQ was modified after `since`.
+fn inputs_changed(input: &PairInput, outbase: &str, since: &Since) -> io::Result<bool> {
+    let outputs = demux_output_files(outbase, matches!(input, PairInput::Single(_)));
+    if outputs.iter().any(|o| !Path::new(o).is_file()) {
+        return Ok(true);
+    }
+    let mut input_modified = SystemTime::UNIX_EPOCH;
+    for file in input.files() {
+        input_modified = input_modified.max(fs::metadata(file)?.modified()?);
+    }
+    let threshold = match since {
+        Since::Outputs => {
+            let mut oldest = SystemTime::now();
+            for output in &outputs {
+                oldest = oldest.min(fs::metadata(output)?.modified()?);
+            }
+            oldest
+        }
+        Since::Time(time) => *time,
+    };
+    Ok(input_modified > threshold)
+}
 
-        let forward_abs = fs::canonicalize(out_path(&forward_rel))?;
-        let reverse_abs = fs::canonicalize(out_path(&reverse_rel))?;
+/// Returns the number of samples in the barcodes file and the distinct
+/// `(R1, R2)` input FASTQs they read from (pairs that can't be found are left
+/// out). With `single_end`, only R1 is looked for and R2 is always `None`.
+pub fn barcode_input_files(
+    barcodes_files: &[String],
+    barcode_columns: Option<&str>,
+    allow_empty_index: bool,
+    single_end: bool,
+) -> io::Result<(usize, Vec<ReadFiles>)> {
+    let rows = read_barcode_sets(barcodes_files, barcode_columns, allow_empty_index, None)?;
+    let mut pairs: Vec<ReadFiles> = Vec::new();
+    for row in &rows {
+        let Some(r1) = find_fastq(&format!("{}_R1_001.fastq", row.file_name)) else {
+            continue;
+        };
+        let r2 = if single_end {
+            None
+        } else {
+            match find_fastq(&format!("{}_R2_001.fastq", row.file_name)) {
+                Some(r2) => Some(r2),
+                None => continue,
+            }
+        };
+        if !pairs.iter().any(|(seen, _)| *seen == r1) {
+            pairs.push((r1, r2));
+        }
+    }
+    Ok((rows.len(), pairs))
+}
+
+/// Generates a QIIME2 manifest file from the barcodes files, covering the
+/// samples of every lane, named as demultiplexing names them (see `sample_ids`)
+/// and pointing at the files [`demux_output_paths`] gives.
+/// Written to `qiime_manifest` in the output directory. With `single_end` the
+/// manifest has only the `forward-absolute-filepath` column, as QIIME's
+/// `SingleEndFastqManifestPhred33V2` format expects.
+///
+/// # Errors
+///
+/// Returns an `io::Error` if reading the barcodes file or writing the manifest fails,
+/// or if any demultiplexed FASTQ it would list is missing.
+pub fn generate_qiime_manifest(
+    barcodes_files: &[String],
+    qiime_manifest: &str,
+    barcode_columns: Option<&str>,
+    allow_empty_index: bool,
+    sample_ids: Option<&SampleIdTemplate>,
+    single_end: bool,
+) -> io::Result<()> {
+    log_action("Generating QIIME2 manifest file.");
+    let barcode_rows = read_barcode_sets(barcodes_files, barcode_columns, allow_empty_index, sample_ids)?;
+    let missing: Vec<String> = barcode_rows
+        .iter()
+        .flat_map(|row| demux_output_files(&row.sample_id(), single_end))
+        .filter(|path| !Path::new(path).is_file())
+        .collect();
+    if !missing.is_empty() {
+        let shown: Vec<&str> = missing.iter().take(5).map(String::as_str).collect();
+        let msg = format!(
+            "Cannot write manifest '{}': {} demultiplexed FASTQ(s) don't exist ({}{}). Demultiplex first, \
+             with the same barcodes files, --barcode-columns and --sample-id-template.",
+            qiime_manifest,
+            missing.len(),
+            shown.join(", "),
+            if missing.len() > shown.len() { ", ..." } else { "" }
+        );
+        print_error(&msg);
+        return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+    }
 
+    let manifest_path = out_path(qiime_manifest);
+    if let Some(parent) = Path::new(&manifest_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut writer = File::create(&manifest_path)?;
+
+    // Write the QIIME2 manifest header
+    if single_end {
+        writeln!(writer, "sample-id\tforward-absolute-filepath")?;
+    } else {
         writeln!(
             writer,
-            "{}\t{}\t{}",
-            sample_id,
-            forward_abs.display(),
-            reverse_abs.display()
+            "sample-id\tforward-absolute-filepath\treverse-absolute-filepath"
         )?;
     }
 
+    // Sorted by sample ID so the manifest is stable; QIIME rejects repeated IDs
+    let mut sample_ids: Vec<String> = barcode_rows.iter().map(|row| row.sample_id()).collect();
+    sample_ids.sort();
+    let mut duplicates: Vec<String> = sample_ids.windows(2).filter(|w| w[0] == w[1]).map(|w| w[0].clone()).collect();
+    duplicates.dedup();
+    sample_ids.dedup();
+    if !duplicates.is_empty() {
+        let msg = format!(
+            "Warning: barcodes list these sample IDs more than once; each is written to the manifest once: {}",
+            duplicates.join(", ")
+        );
+        print_error(&msg);
+        log_event(LogLevel::Warning, None, &msg);
+    }
+
+    for sample_id in &sample_ids {
+        // The same paths demultiplexing writes to
+        write!(writer, "{}", sample_id)?;
+        for path in demux_output_files(sample_id, single_end) {
+            write!(writer, "\t{}", absolute_path(&path)?.display())?;
+        }
+        writeln!(writer)?;
+    }
+
     print_success("Manifest generated successfully.");
     Ok(())
 }
 
+/// Checks that every FASTQ a QIIME2 manifest (paired- or single-end) lists exists, for
+/// runs that start from an existing manifest. `$PWD` in a path is expanded as
+/// QIIME does. Returns the number of samples.
+///
+/// # Errors
+///
+/// Returns an `io::Error` if the manifest can't be read, has no samples, or
+/// lists files that don't exist (up to five are named).
+pub fn check_manifest_files(manifest: &str) -> io::Result<usize> {
+    let cwd = env::current_dir()?;
+    let text = read_text_file(manifest)
+        .map_err(|e| io::Error::new(e.kind(), format!("Unable to open manifest '{}': {}", manifest, e)))?;
+    let mut samples = 0;
+    let mut missing = Vec::new();
+    for line in text.lines().skip(1) {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        samples += 1;
+        for path in line.split('\t').skip(1) {
+            let path = path.trim().replace("$PWD", &cwd.to_string_lossy());
+            if !path.is_empty() && !Path::new(&path).is_file() {
+                missing.push(path);
+            }
+        }
+    }
+    if samples == 0 {
+        let msg = format!("Manifest '{}' lists no samples", manifest);
+        print_error(&msg);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    if !missing.is_empty() {
+        let msg = format!(
+            "Manifest '{}' lists {} missing file(s): {}",
+            manifest,
+            missing.len(),
+            missing.iter().take(5).cloned().collect::<Vec<_>>().join(", ")
+        );
+        print_error(&msg);
+        return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+    }
+    Ok(samples)
+}
+
+/// Absolute path of an existing file for the manifest. Uses `fs::canonicalize`,
+/// but if that fails on a path that does exist (as it can on NFS or overlay
+/// filesystems), joins it onto the current directory and resolves `.` and
+/// `..` lexically instead.
+pub(crate) fn absolute_path(path: &str) -> io::Result<PathBuf> {
+    let error = match fs::canonicalize(path) {
+        Ok(abs) => return Ok(abs),
+        Err(e) => e,
+    };
+    if fs::symlink_metadata(path).is_err() {
+        return Err(error);
+    }
+    let mut abs = PathBuf::new();
+    for component in env::current_dir()?.join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                abs.pop();
+            }
+            other => abs.push(other),
+        }
+    }
+    log_action(&format!(
+        "canonicalize failed for {} ({}); using {} instead.",
+        path,
+        error,
+        abs.display()
+    ));
+    Ok(abs)
+}
+
 /// Helper to locate FASTQ files with an optional `.gz` extension.
 fn find_fastq(base_name: &str) -> Option<String> {
-    let gz = format!("{}.gz", base_name);
-    if Path::new(&gz).is_file() {
-        Some(gz)
-    } else if Path::new(base_name).is_file() {
-        Some(base_name.to_string())
+    ["gz", "bz2", "zst"]
+        .iter()
+        .map(|ext| format!("{}.{}", base_name, ext))
+        .chain(std::iter::once(base_name.to_string()))
+        .find(|path| Path::new(path).is_file())
+}
+
+/// Checks that samples sharing an input file can still be told apart. Two
+/// indexes whose compared bases (after `min_index_match` truncation) are the
+/// same would both match every read carrying either. So would indexes of
+/// different lengths where one is a prefix of the other, as each sample trims
+/// its own index length. Such plates are rejected.
+///
+/// With `mismatches` allowed, two indexes sharing an input must also differ
+/// in more than `2 * mismatches` of their compared bases, or one read could
+/// be within `mismatches` of both; and every index must be longer than
+/// `mismatches`, or it would match any read.
+fn check_index_lengths(barcode_rows: &[BarcodeRow], min_index_match: Option<usize>, mismatches: usize) -> io::Result<()> {
+    let compared = |index: &str| -> usize { min_index_match.map_or(index.len(), |n| n.min(index.len())) };
+    let reject = |msg: String| {
+        print_error(&msg);
+        Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+    };
+
+    if mismatches > 0
+        && let Some(row) = barcode_rows
+            .iter()
+            .find(|row| !row.index_seq.is_empty() && compared(&row.index_seq) <= mismatches)
+    {
+        return reject(format!(
+            "--barcode-mismatches {} would accept any read for {} ({}): only {} index bases are compared",
+            mismatches,
+            row.sample_id(),
+            row.index_seq,
+            compared(&row.index_seq)
+        ));
+    }
+
+    let mut lengths: Vec<usize> = barcode_rows
+        .iter()
+        .filter(|row| !row.index_seq.is_empty())
+        .map(|row| row.index_seq.len())
+        .collect();
+    lengths.sort_unstable();
+    lengths.dedup();
+    if lengths.len() > 1 {
+        log_action(&format!(
+            "Index sequences vary in length ({}); each sample trims its own index length.",
+            lengths.iter().map(usize::to_string).collect::<Vec<_>>().join(", ")
+        ));
+    }
+
+    for (i, a) in barcode_rows.iter().enumerate() {
+        for b in &barcode_rows[i + 1..] {
+            if a.file_name != b.file_name || a.index_seq.is_empty() || b.index_seq.is_empty() {
+                continue;
+            }
+            let key_a = &a.index_seq.as_bytes()[..compared(&a.index_seq)];
+            let key_b = &b.index_seq.as_bytes()[..compared(&b.index_seq)];
+            let shared = key_a.len().min(key_b.len());
+            let distance = hamming_distance(&key_a[..shared], &key_b[..shared]);
+            if distance == 0 {
+                let why = if key_a.len() == key_b.len() {
+                    "compare the same bases, so every read matching one matches both"
+                } else {
+                    "differ in length but one is a prefix of the other"
+                };
+                return reject(format!(
+                    "Ambiguous indexes in '{}': {} ({}) and {} ({}) {}",
+                    a.file_name,
+                    a.sample_id(),
+                    a.index_seq,
+                    b.sample_id(),
+                    b.index_seq,
+                    why
+                ));
+            }
+            if mismatches > 0 && distance <= 2 * mismatches {
+                return reject(format!(
+                    "Ambiguous indexes in '{}': {} ({}) and {} ({}) differ in {} compared base(s); with \
+                     --barcode-mismatches {} they must differ in more than {}, or a read could match both",
+                    a.file_name,
+                    a.sample_id(),
+                    a.index_seq,
+                    b.sample_id(),
+                    b.index_seq,
+                    distance,
+                    mismatches,
+                    2 * mismatches
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Number of positions at which `a` and `b` differ, over the shorter of the two.
+fn hamming_distance(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).filter(|(x, y)| x != y).count()
+}
+
+/// Number of mismatches between `observed` and the leading bases of
+/// `expected`, or `None` if `observed` is too short to compare.
+///
+/// With `min_index_match = Some(n)` only the first `n` bases are compared
+/// (useful when the 3' end of the index is noisy); `None`, or any `n` longer
+/// than the index, compares the whole index.
+fn index_mismatches(observed: &[u8], expected: &[u8], min_index_match: Option<usize>) -> Option<usize> {
+    let required = min_index_match.map_or(expected.len(), |n| n.min(expected.len()));
+    (observed.len() >= required).then(|| hamming_distance(&observed[..required], &expected[..required]))
+}
+
+/// Returns `true` if `observed` matches the leading bases of `expected` (see
+/// [`index_mismatches`]) with at most `max_mismatches` mismatches.
+fn index_matches(observed: &[u8], expected: &[u8], min_index_match: Option<usize>, max_mismatches: usize) -> bool {
+    index_mismatches(observed, expected, min_index_match).is_some_and(|mismatches| mismatches <= max_mismatches)
+}
+
+/// Why a sample's reads can't be copied unchanged under `--passthrough`, or
+/// `None` if they can: the barcodes row has no inline index to strip and no
+/// per-record filter is enabled.
+fn passthrough_blocker(adaptseq: &str, options: &DemuxOptions) -> Option<&'static str> {
+    if !adaptseq.is_empty() {
+        Some("the barcodes row has an inline index to trim")
+    } else if options.suspect_primer.is_some() {
+        Some("--flag-suspect-primers checks every read")
+    } else if options.min_trimmed_length > 1 {
+        Some("--min-trimmed-length checks every read")
+    } else if options.strict_fastq {
+        Some("--strict-fastq checks every record")
     } else {
         None
     }
 }
 
+/// Copies a FASTQ to a gzipped output: byte for byte if it's already
+/// gzipped, otherwise decompressed if needed and compressed at the fastest
+/// level without parsing it.
+pub(crate) fn copy_gzipped(input: &str, output: &str) -> io::Result<()> {
+    if Codec::detect(input)? == Codec::Gzip {
+        fs::copy(input, output)?;
+    } else {
+        let mut encoder = GzEncoder::new(File::create(output)?, Compression::fast());
+        io::copy(&mut fastq_io::open_bufread(input)?, &mut encoder)?;
+        encoder.finish()?;
+    }
+    Ok(())
+}
+
 /// Reads two FASTQ files (R1, R2) and trims the adapter sequence from R1
-/// (when present after the first 4 bases), then writes the resulting
-/// demultiplexed FASTQ records to `"{outbase}_L001_R1_001.fastq.gz"` and `_R2_`.
+/// (when present after the first 4 bases; the trimmed window is this sample's
+/// own index length, which may differ between samples), then writes the resulting
+/// demultiplexed FASTQ records to `"{outbase}_L001_R1_001.fastq.gz"` and `_R2_`
+/// (R1 only for single-end input).
+///
+/// With [`DemuxOptions::suspect_primer`] set, pairs whose trimmed R1 doesn't
+/// start with the primer go to `"{outbase}_suspect_R1.fastq.gz"` and `_R2` instead.
 fn demultiplex_fastq_files(
-    fq_r1_file: &str,
-    fq_r2_file: &str,
+    input: &PairInput,
     adaptseq: &str,
     outbase: &str,
-) -> io::Result<()> {
-    // Verify both files exist
-    if !Path::new(fq_r1_file).exists() || !Path::new(fq_r2_file).exists() {
+    options: &DemuxOptions,
+) -> io::Result<PairCounts> {
+    // Verify the input files exist
+    if input.files().iter().any(|file| !Path::new(file).exists()) {
         return Err(io::Error::new(
             io::ErrorKind::NotFound,
-            format!("File(s) do not exist: {}, {}", fq_r1_file, fq_r2_file),
+            format!("File(s) do not exist: {}", input.files().join(", ")),
         ));
     }
 
     // Compute final (gzipped) output file names
-    let outfile1 = out_path(&format!("{}_L001_R1_001.fastq.gz", outbase));
-    let outfile2 = out_path(&format!("{}_L001_R2_001.fastq.gz", outbase));
+    let (outfile1, outfile2) = demux_output_paths(outbase);
 
-    // Open input FASTQ readers
-    let in1 = open_fastq_reader(fq_r1_file)?;
-    let in2 = open_fastq_reader(fq_r2_file)?;
+    if options.passthrough {
+        match (input, passthrough_blocker(adaptseq, options)) {
+            (PairInput::Split { r1, r2 }, None) => {
+                copy_gzipped(r1, &outfile1)?;
+                copy_gzipped(r2, &outfile2)?;
+                return Ok(PairCounts { copied: true, ..Default::default() });
+            }
+            (PairInput::Single(r1), None) => {
+                copy_gzipped(r1, &outfile1)?;
+                return Ok(PairCounts { copied: true, ..Default::default() });
+            }
+            (PairInput::Interleaved(_), _) => {
+                log_action(&format!("{}: no passthrough for interleaved input; demultiplexing normally.", outbase))
+            }
+            (_, Some(reason)) => {
+                log_action(&format!("{}: no passthrough ({}); demultiplexing normally.", outbase, reason))
+            }
+        }
+    }
 
-    // Prepare gzip-compressed output writers
-    let gz1 = GzEncoder::new(File::create(&outfile1)?, Compression::best());
-    let gz2 = GzEncoder::new(File::create(&outfile2)?, Compression::best());
+    // Open the input FASTQ reader(s)
+    let pairs = input.read_pairs(options.strict_fastq)?;
 
-    let mut out1 = fastq::Writer::new(gz1);
-    let mut out2 = fastq::Writer::new(gz2);
+    // Prepare gzip-compressed output writers; single-end input has no R2
+    let paired = !matches!(input, PairInput::Single(_));
+    let gz_writer = |path: &str| -> io::Result<_> {
+        Ok(fastq::Writer::new(GzEncoder::new(File::create(path)?, Compression::best())))
+    };
 
-    let mut records1 = in1.records();
-    let mut records2 = in2.records();
+    let mut out1 = gz_writer(&outfile1)?;
+    let mut out2 = if paired { Some(gz_writer(&outfile2)?) } else { None };
+
+    let mut suspect_out = match &options.suspect_primer {
+        Some(_) => {
+            let suspect1 = out_path(&format!("{}_suspect_R1.fastq.gz", outbase));
+            let suspect2 = out_path(&format!("{}_suspect_R2.fastq.gz", outbase));
+            Some((gz_writer(&suspect1)?, if paired { Some(gz_writer(&suspect2)?) } else { None }))
+        }
+        None => None,
+    };
+    let mut counts = PairCounts::default();
 
+    let min_trimmed_length = options.min_trimmed_length.max(1);
     let adaptseq_bytes = adaptseq.as_bytes();
     let index_len = adaptseq_bytes.len();
     let start_idx = 4;
     let end_idx = start_idx + index_len;
 
     // Read pairs in lockstep
-    while let Some(rec1_result) = records1.next() {
-        let rec1 = rec1_result.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        let rec2 = match records2.next() {
-            Some(Ok(r)) => r,
-            Some(Err(e)) => return Err(io::Error::new(io::ErrorKind::Other, e)),
-            None => break, // no matching second read
-        };
+    for pair in pairs {
+        let (rec1, rec2) = pair?;
+        counts.seen += 1;
 
         // If R1 has enough length and the adapter is found, trim it
         let seq1 = rec1.seq();
         let qual1 = rec1.qual();
-        if seq1.len() >= end_idx && &seq1[start_idx..end_idx] == adaptseq_bytes {
+        let mismatches = if seq1.len() >= end_idx {
+            index_mismatches(&seq1[start_idx..end_idx], adaptseq_bytes, options.min_index_match)
+        } else {
+            None
+        };
+        if let Some(mismatches) = mismatches.filter(|&m| m <= options.barcode_mismatches) {
+            if mismatches == 0 {
+                counts.exact_index += 1;
+            } else {
+                counts.mismatched_index += 1;
+            }
+            // `seq1.len() >= end_idx` above keeps these slices in bounds
             let new_seq1 = &seq1[end_idx..];
-            let new_qual1 = &qual1[end_idx..];
+            let new_qual1 = qual1.get(end_idx..).unwrap_or_default();
+            if new_seq1.len() < min_trimmed_length || new_qual1.len() != new_seq1.len() {
+                counts.too_short += 1;
+                continue;
+            }
             let new_rec1 = fastq::Record::with_attrs(rec1.id(), rec1.desc(), new_seq1, new_qual1);
 
-            out1.write_record(&new_rec1)?;
-            out2.write_record(&rec2)?;
+            match (&options.suspect_primer, &mut suspect_out) {
+                (Some(primer), Some((suspect1, suspect2))) if !primers::primer_matches(new_seq1, primer.as_bytes()) => {
+                    suspect1.write_record(&new_rec1)?;
+                    if let (Some(suspect2), Some(rec2)) = (suspect2, &rec2) {
+                        suspect2.write_record(rec2)?;
+                    }
+                    counts.suspect += 1;
+                }
+                _ => {
+                    out1.write_record(&new_rec1)?;
+                    if let (Some(out2), Some(rec2)) = (&mut out2, &rec2) {
+                        out2.write_record(rec2)?;
+                    }
+                    counts.clean += 1;
+                }
+            }
         }
         // Otherwise, skip this pair or handle it differently if desired
     }
 
     out1.flush()?;
-    out2.flush()?;
-    Ok(())
+    if let Some(out2) = &mut out2 {
+        out2.flush()?;
+    }
+    if let Some((mut suspect1, suspect2)) = suspect_out {
+        suspect1.flush()?;
+        if let Some(mut suspect2) = suspect2 {
+            suspect2.flush()?;
+        }
+    }
+    Ok(counts)
 }
 
-/// Opens a file (gzipped or not) and returns a BufRead for FASTQ.
-fn open_bufread(filename: &str) -> io::Result<Box<dyn io::BufRead + Send>> {
-    if filename.ends_with(".gz") {
-        let file = File::open(filename)?;
-        let decoder = MultiGzDecoder::new(file);
-        Ok(Box::new(BufReader::new(decoder)))
-    } else {
-        let file = File::open(filename)?;
-        Ok(Box::new(BufReader::new(file)))
+/// Writes the read pairs that match none of the samples sharing their input
+/// files to `unassigned_L001_R1_001.fastq.gz` (and `_R2_`, unless single-end).
+///
+/// Input files are scanned in parallel, one source per distinct `file_name`,
+/// and written through an [`OrderedPairWriter`] so the shared output has the
+/// same record order on every run. Sources that can't be read are reported
+/// together once the others are written, and fail the call.
+fn write_unassigned_reads(barcode_rows: &[BarcodeRow], options: &DemuxOptions) -> io::Result<()> {
+    // Group the expected indexes by input file, keeping first-seen order
+    let mut groups: Vec<(&str, Vec<&[u8]>)> = Vec::new();
+    for row in barcode_rows {
+        match groups.iter_mut().find(|(file_name, _)| *file_name == row.file_name) {
+            Some((_, indexes)) => indexes.push(row.index_seq.as_bytes()),
+            None => groups.push((&row.file_name, vec![row.index_seq.as_bytes()])),
+        }
+    }
+
+    let r2_path = out_path("unassigned_L001_R2_001.fastq.gz");
+    let writer = OrderedPairWriter::create(
+        &out_path("unassigned_L001_R1_001.fastq.gz"),
+        (!options.single_end).then_some(r2_path.as_str()),
+        Compression::best(),
+    )?;
+
+    let failures: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    groups.par_iter().enumerate().for_each(|(source, (file_name, indexes))| {
+        let mut sender = writer.sender(source);
+        let result = (|| -> io::Result<()> {
+            let Ok(input) = PairInput::find(file_name, options) else {
+                return Ok(());
+            };
+            for pair in input.read_pairs(options.strict_fastq)? {
+                let (rec1, rec2) = pair?;
+                let seq1 = rec1.seq();
+                let assigned = indexes.iter().any(|index| {
+                    let end_idx = 4 + index.len();
+                    seq1.len() >= end_idx
+                        && index_matches(&seq1[4..end_idx], index, options.min_index_match, options.barcode_mismatches)
+                });
+                if !assigned {
+                    sender.push(rec1, rec2)?;
+                }
+            }
+            Ok(())
+        })();
+        // Always close the source so the writer can move past it
+        let finished = sender.finish();
+        if let Err(e) = result.and(finished) {
+            let msg = format!("{}: {}", file_name, e);
+            print_error(&format!("Error collecting unassigned reads from {}", msg));
+            failures.lock().unwrap().push(msg);
+        }
+    });
+
+    let written = writer.finish()?;
+    let failures = failures.into_inner().unwrap();
+    if !failures.is_empty() {
+        return Err(io::Error::other(format!(
+            "Could not collect unassigned reads from {} input(s): {}",
+            failures.len(),
+            failures.join("; ")
+        )));
     }
+    log_action(&format!("Wrote {} unassigned read pairs.", written));
+    print_success(&format!("Wrote {} unassigned read pairs.", written));
+    Ok(())
 }
 
-/// Creates a FASTQ reader from a given filename (gz or not).
-fn open_fastq_reader(filename: &str) -> io::Result<fastq::Reader<Box<dyn io::BufRead + Send>>> {
-    open_bufread(filename).map(fastq::Reader::from_bufread)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, read_fastq_gz, write_fastq_gz, write_file};
+    use tempfile::TempDir;
+
+    /// A positional barcodes file in `dir` with one `(name, file_name, seq2)` row per sample.
+    fn barcodes_file(dir: &Path, rows: &[(&str, &str, &str)]) -> String {
+        let mut text = "name\tfile_name\tidx1\tseq1\tidx2\tseq2\n".to_string();
+        for (name, file_name, seq2) in rows {
+            text.push_str(&format!("{}\t{}\tN701\tTAAGGCGA\tS501\t{}\n", name, file_name, seq2));
+        }
+        write_file(dir, "barcodes.tsv", &text)
+    }
+
+    /// Writes `{base}_R1_001.fastq.gz` and `_R2_` in `dir` with one pair per R1
+    /// sequence (R2 gets the same ID and a fixed sequence); returns the
+    /// `file_name` to list in the barcodes file.
+    fn paired_input(dir: &Path, base: &str, r1_seqs: &[&str]) -> String {
+        let ids: Vec<String> = (1..=r1_seqs.len()).map(|i| format!("read{}", i)).collect();
+        let r1: Vec<(&str, &str)> = ids.iter().map(String::as_str).zip(r1_seqs.iter().copied()).collect();
+        let r2: Vec<(&str, &str)> = ids.iter().map(|id| (id.as_str(), "GGGGCCCCAAAA")).collect();
+        write_fastq_gz(dir, &format!("{}_R1_001.fastq.gz", base), &r1);
+        write_fastq_gz(dir, &format!("{}_R2_001.fastq.gz", base), &r2);
+        dir.join(base).to_string_lossy().into_owned()
+    }
+
+    /// `(id, sequence)` of the R1 records demultiplexing wrote for `sample_id`.
+    fn written_r1(sample_id: &str) -> Vec<(String, String)> {
+        read_fastq_gz(&demux_output_paths(sample_id).0)
+    }
+
+    #[test]
+    fn prefix_of_the_index_is_enough_with_min_index_match() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let input = paired_input(
+            dir.path(),
+            "plate",
+            &[
+                "ACGTAACCGGTTTTGGGG", // the full index
+                "ACGTAACCTTTTTTGGGG", // only its first four bases
+                "ACGTGGGGGGTTTTGGGG", // none of it
+            ],
+        );
+        let barcodes = barcodes_file(dir.path(), &[("s1", &input, "AACCGG")]);
+
+        run_demultiplex_combined(std::slice::from_ref(&barcodes), &DemuxOptions::default()).unwrap();
+        assert_eq!(written_r1("s1_AACCGG"), vec![("read1".to_string(), "TTTTGGGG".to_string())]);
+
+        let options = DemuxOptions { min_index_match: Some(4), ..Default::default() };
+        run_demultiplex_combined(&[barcodes], &options).unwrap();
+        // The whole six-base index is trimmed, however many bases were compared
+        assert_eq!(
+            written_r1("s1_AACCGG"),
+            vec![
+                ("read1".to_string(), "TTTTGGGG".to_string()),
+                ("read2".to_string(), "TTTTGGGG".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn min_index_match_longer_than_the_index_compares_all_of_it() {
+        assert!(index_matches(b"AACCGG", b"AACCGG", Some(10), 0));
+        assert!(!index_matches(b"AACCGT", b"AACCGG", Some(10), 0));
+        assert!(index_matches(b"AACCGT", b"AACCGG", Some(5), 0));
+        assert!(!index_matches(b"AACC", b"AACCGG", None, 0));
+    }
+
+    #[test]
+    fn indexes_identical_in_their_compared_prefix_are_ambiguous() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let input = paired_input(dir.path(), "plate", &["ACGTAACCGGTTTTGGGG"]);
+        let barcodes = barcodes_file(dir.path(), &[("s1", &input, "AACCGG"), ("s2", &input, "AACCTT")]);
+
+        let options = DemuxOptions { min_index_match: Some(4), ..Default::default() };
+        let err = run_demultiplex_combined(std::slice::from_ref(&barcodes), &options).unwrap_err();
+        assert!(err.to_string().contains("Ambiguous indexes"), "{}", err);
+        assert!(!Path::new(&demux_output_paths("s1_AACCGG").0).exists());
+
+        run_demultiplex_combined(&[barcodes], &DemuxOptions::default()).unwrap();
+        assert_eq!(written_r1("s1_AACCGG").len(), 1);
+        assert!(written_r1("s2_AACCTT").is_empty());
+    }
+
+    #[test]
+    fn barcode_mismatches_accepts_reads_within_the_limit_and_counts_them() {
+        let _guard = test_support::global_state();
+        crate::logger::init_log(false);
+        let dir = TempDir::new().unwrap();
+        let input = paired_input(
+            dir.path(),
+            "plate",
+            &[
+                "ACGTAACCGGTTTT", // exact
+                "ACGTATCCGGTTTT", // one mismatch
+                "ACGTATCCGATTTT", // two mismatches
+            ],
+        );
+        let barcodes = barcodes_file(dir.path(), &[("s1", &input, "AACCGG")]);
+
+        // The default only takes exact matches
+        run_demultiplex_combined(std::slice::from_ref(&barcodes), &DemuxOptions::default()).unwrap();
+        assert_eq!(written_r1("s1_AACCGG"), [("read1".to_string(), "TTTT".to_string())]);
+
+        let options = DemuxOptions { barcode_mismatches: 1, ..Default::default() };
+        run_demultiplex_combined(&[barcodes], &options).unwrap();
+        let ids: Vec<String> = written_r1("s1_AACCGG").into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, ["read1", "read2"]);
+        let log = fs::read_to_string(out_path("windchime.log")).unwrap();
+        assert!(log.contains("index matched exactly in 1, with mismatches in 0"), "{}", log);
+        assert!(log.contains("index matched exactly in 1, with mismatches in 1"), "{}", log);
+        assert!(log.contains("Index matched exactly in 1 read pairs and with up to 1 mismatches in 1"), "{}", log);
+    }
+
+    #[test]
+    fn indexes_too_close_for_the_mismatch_limit_are_ambiguous() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let input = paired_input(dir.path(), "plate", &["ACGTAACCGGTTTT"]);
+        let demux = |rows: &[(&str, &str, &str)], mismatches| {
+            let barcodes = barcodes_file(dir.path(), rows);
+            let options = DemuxOptions { barcode_mismatches: mismatches, ..Default::default() };
+            run_demultiplex_combined(&[barcodes], &options).map_err(|e| e.to_string())
+        };
+
+        // Two mismatches apart: a read one off from both would fit either sample
+        let err = demux(&[("s1", &input, "AACCGG"), ("s2", &input, "AACCTT")], 1).unwrap_err();
+        let expected = "s1_AACCGG (AACCGG) and s2_AACCTT (AACCTT) differ in 2 compared base(s); with --barcode-mismatches 1";
+        assert!(err.contains(expected), "{}", err);
+        assert!(!Path::new(&demux_output_paths("s1_AACCGG").0).exists());
+        // Three apart is enough for one mismatch, and any difference for exact matching
+        demux(&[("s1", &input, "AACCGG"), ("s2", &input, "AATTTG")], 1).unwrap();
+        demux(&[("s1", &input, "AACCGG"), ("s2", &input, "AACCTT")], 0).unwrap();
+
+        // Identical indexes are ambiguous whatever the limit
+        for mismatches in [0, 2] {
+            let err = demux(&[("s1", &input, "AACCGG"), ("s2", &input, "AACCGG")], mismatches).unwrap_err();
+            assert!(err.contains("compare the same bases"), "{}", err);
+        }
+        let err = demux(&[("s1", &input, "AC")], 2).unwrap_err();
+        assert!(err.contains("--barcode-mismatches 2 would accept any read for s1_AC (AC)"), "{}", err);
+    }
+
+    #[test]
+    fn column_mapping_reads_a_reordered_sheet_with_extra_columns() {
+        let dir = TempDir::new().unwrap();
+        let sheet = write_file(
+            dir.path(),
+            "sheet.tsv",
+            "Plate\tI5\tSample\tNotes\tFileBase\n\
+             P1\tAACCGG\ts1\tfirst run\tplate1\n\
+             P1\tTTGGCC\ts2\t\tplate1\n",
+        );
+
+        let rows = validate_barcodes_file(&sheet, Some("name=Sample, file=FileBase, index=I5"), false).unwrap();
+        let parsed: Vec<(&str, &str, &str)> = rows
+            .iter()
+            .map(|row| (row.name.as_str(), row.file_name.as_str(), row.index_seq.as_str()))
+            .collect();
+        assert_eq!(parsed, [("s1", "plate1", "AACCGG"), ("s2", "plate1", "TTGGCC")]);
+        assert_eq!(rows[0].sample_id(), "s1_AACCGG");
+
+        // The same sheet read positionally has the wrong number of columns
+        let err = validate_barcodes_file(&sheet, None, false).unwrap_err();
+        assert!(err.contains("line 2: 5 tab-separated fields, expected 6"), "{}", err);
+    }
+
+    #[test]
+    fn column_mapping_names_unknown_and_missing_columns() {
+        let header = "Sample\tFileBase\tI5";
+        assert_eq!(
+            BarcodeColumns::from_mapping("index=I5,file=FileBase,name=Sample", header).unwrap(),
+            BarcodeColumns { name: 0, file_name: 1, index: 2, exact_len: None }
+        );
+        let err = BarcodeColumns::from_mapping("name=Sample,file=FileBase,index=I7", header).unwrap_err();
+        assert_eq!(err.to_string(), "Column 'I7' not found in barcodes header");
+        let err = BarcodeColumns::from_mapping("name=Sample,file=FileBase", header).unwrap_err();
+        assert_eq!(err.to_string(), "Barcode column mapping is missing 'index='");
+    }
+
+    #[test]
+    fn a_sample_id_template_tells_the_same_sample_on_two_plates_apart() {
+        let _guard = test_support::global_state();
+        let (plate1, plate2) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input1 = paired_input(plate1.path(), "plate1", &["ACGTAACCGGTTTTGGGG"]);
+        let input2 = paired_input(plate2.path(), "plate2", &["ACGTAACCGGTTTTCCCC"]);
+        let barcodes = [
+            barcodes_file(plate1.path(), &[("s1", &input1, "AACCGG")]),
+            barcodes_file(plate2.path(), &[("s1", &input2, "AACCGG")]),
+        ];
+        let tags = vec!["P1".to_string(), "P2".to_string()];
+        let template = SampleIdTemplate { template: "{tag}_{name}_{seq2}".to_string(), tags };
+        let options = DemuxOptions { sample_id_template: Some(template.clone()), ..Default::default() };
+
+        run_demultiplex_combined(&barcodes, &options).unwrap();
+        assert_eq!(written_r1("P1_s1_AACCGG"), vec![("read1".to_string(), "TTTTGGGG".to_string())]);
+        assert_eq!(written_r1("P2_s1_AACCGG"), vec![("read1".to_string(), "TTTTCCCC".to_string())]);
+
+        // The manifest and provenance name the samples the same way
+        generate_qiime_manifest(&barcodes, "qiime_manifest.tsv", None, false, Some(&template), false).unwrap();
+        let first_column = |file: &str| -> Vec<String> {
+            let text = fs::read_to_string(out_path(file)).unwrap();
+            text.lines().skip(1).map(|line| line.split('\t').next().unwrap().to_string()).collect()
+        };
+        assert_eq!(first_column("qiime_manifest.tsv"), ["P1_s1_AACCGG", "P2_s1_AACCGG"]);
+        assert_eq!(first_column("sample_provenance.tsv"), ["P1_s1_AACCGG", "P2_s1_AACCGG"]);
+    }
+
+    #[test]
+    fn a_sample_id_template_must_give_safe_unique_ids() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let barcodes = barcodes_file(dir.path(), &[("s1", "plate1", "AACCGG"), ("s 2", "plate1", "CCAATT")]);
+        let read = |template: &str, tags: &[&str]| {
+            let template = SampleIdTemplate {
+                template: template.to_string(),
+                tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            };
+            read_barcode_sets(std::slice::from_ref(&barcodes), None, false, Some(&template))
+                .map(|rows| rows.iter().map(BarcodeRow::sample_id).collect::<Vec<_>>())
+                .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(read("{tag}-{index}", &["P1"]).unwrap(), ["P1-AACCGG", "P1-CCAATT"]);
+        let unsafe_id = read("{name}", &[]).unwrap_err();
+        assert!(unsafe_id.contains("'s 2' (s 2 in"), "{}", unsafe_id);
+        let duplicate = read("{file}", &[]).unwrap_err();
+        assert!(duplicate.contains("'plate1' is given to s1 in"), "{}", duplicate);
+        assert!(read("{tag}_{name}", &[]).unwrap_err().contains("no --sample-tag was given"));
+        assert!(read("{plate}", &[]).unwrap_err().contains("unknown placeholder {plate}"));
+    }
+
+    #[test]
+    fn unassigned_reads_keep_their_order_across_runs() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let plate1 = paired_input(dir.path(), "plate1", &["ACGTAACCGGTTTT", "ACGTCCCCCCTTTT", "ACGTGGGGGGTTTT"]);
+        let plate2 = paired_input(dir.path(), "plate2", &["ACGTTTGGCCAAAA", "ACGTAAAAAAAAAA"]);
+        let barcodes = barcodes_file(dir.path(), &[("s1", &plate1, "AACCGG"), ("s2", &plate2, "TTGGCC")]);
+        let rows = read_barcode_rows(&barcodes, None, false).unwrap();
+        let options = DemuxOptions { keep_unassigned: true, ..Default::default() };
+
+        let unassigned = || read_fastq_gz(&out_path("unassigned_L001_R1_001.fastq.gz"));
+        write_unassigned_reads(&rows, &options).unwrap();
+        let first = unassigned();
+        let ids: Vec<&str> = first.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, ["read2", "read3", "read2"]);
+        for _ in 0..3 {
+            write_unassigned_reads(&rows, &options).unwrap();
+            assert_eq!(unassigned(), first);
+        }
+    }
+
+    #[test]
+    fn unreadable_unassigned_source_fails_after_the_others_are_written() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let plate1 = paired_input(dir.path(), "plate1", &["ACGTCCCCCCTTTT"]);
+        let plate2 = paired_input(dir.path(), "plate2", &["ACGTCCCCCCTTTT"]);
+        fs::write(dir.path().join("plate2_R1_001.fastq.gz"), "not gzip").unwrap();
+        let barcodes = barcodes_file(dir.path(), &[("s1", &plate1, "AACCGG"), ("s2", &plate2, "TTGGCC")]);
+        let rows = read_barcode_rows(&barcodes, None, false).unwrap();
+
+        let err = write_unassigned_reads(&rows, &DemuxOptions::default()).unwrap_err();
+        assert!(err.to_string().starts_with("Could not collect unassigned reads from 1 input(s):"), "{}", err);
+        assert!(err.to_string().contains("plate2"), "{}", err);
+        assert_eq!(read_fastq_gz(&out_path("unassigned_L001_R1_001.fastq.gz")).len(), 1);
+    }
+
+    #[test]
+    fn failed_samples_fail_the_run_after_the_rest_are_written() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let plate = paired_input(dir.path(), "plate", &["ACGTAACCGGTTTT"]);
+        let missing = dir.path().join("missing").to_string_lossy().into_owned();
+        let barcodes = barcodes_file(dir.path(), &[("s1", &plate, "AACCGG"), ("s2", &missing, "TTGGCC")]);
+
+        let err = run_demultiplex_combined(std::slice::from_ref(&barcodes), &DemuxOptions::default()).unwrap_err();
+        assert!(err.to_string().starts_with("Demultiplexing failed for 1 of 2 samples:"), "{}", err);
+        assert!(err.to_string().contains("missing"), "{}", err);
+        assert_eq!(written_r1("s1_AACCGG").len(), 1);
+
+        let options = DemuxOptions { fail_fast: true, ..Default::default() };
+        let err = run_demultiplex_combined(&[barcodes], &options).unwrap_err();
+        assert!(err.to_string().starts_with("Demultiplexing stopped after a failure:"), "{}", err);
+    }
+
+    #[test]
+    fn blank_index_and_name_are_rejected_with_their_line() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let plate = paired_input(dir.path(), "plate", &["ACGTAACCGGTTTT", "ACGTCCCCCCTTTT"]);
+        let barcodes = write_file(
+            dir.path(),
+            "barcodes.tsv",
+            &format!(
+                "name\tfile_name\tidx1\tseq1\tidx2\tseq2\n\
+                 s1\t{plate}\tN701\tTAAGGCGA\tS501\t  \n\
+                 \t{plate}\tN701\tTAAGGCGA\tS502\tAACCGG\n"
+            ),
+        );
+
+        let err = run_demultiplex_combined(&[barcodes], &DemuxOptions::default()).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("has 2 problem(s)"), "{}", msg);
+        assert!(msg.contains("line 2: empty index sequence (column 6)"), "{}", msg);
+        assert!(msg.contains("line 3: empty sample name (column 1)"), "{}", msg);
+        // Nothing was assigned to a catch-all "s1_" sample
+        assert!(!Path::new(&demux_output_paths("s1_").0).exists());
+        assert!(!Path::new(&demux_output_paths("s1").0).exists());
+    }
+
+    #[test]
+    fn every_barcodes_file_problem_is_reported_before_any_reads_are_touched() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let plate = paired_input(dir.path(), "plate", &["ACGTAACCGGTTTT", "ACGTCCCCCCTTTT"]);
+        let barcodes = write_file(
+            dir.path(),
+            "barcodes.tsv",
+            &format!(
+                "name\tfile_name\tidx1\tseq1\tidx2\tseq2\n\
+                 s1\t{plate}\tN701\tTAAGGCGA\tS501\tAACCGG\n\
+                 s2\t{plate}\tN701\tTAAGGCGA\tS502\n\
+                 s1\t{plate}\tN701\tTAAGGCGA\tS503\tCCCCCC\n\
+                 s3\t{plate}\tN701\tTAAGGCGA\tS504\tAACNGG\n\
+                 \n\
+                 s4\t{plate}\tN701\tTAAGGCGA\tS505\tGGTTAA\textra\n"
+            ),
+        );
+
+        let err = validate_barcodes_file(&barcodes, None, false).unwrap_err();
+        assert_eq!(
+            err,
+            format!(
+                "Barcodes file '{}' has 4 problem(s):\n  \
+                 line 3: 5 tab-separated fields, expected 6\n  \
+                 line 4: sample name 's1' is already used on line 2\n  \
+                 line 5: index sequence 'AACNGG' contains 'N'; only A, C, G and T are allowed\n  \
+                 line 7: 7 tab-separated fields, expected 6",
+                barcodes
+            )
+        );
+        // The run stops with that error; even the valid row's sample isn't written
+        let run = run_demultiplex_combined(&[barcodes], &DemuxOptions::default()).unwrap_err();
+        assert_eq!(run.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(run.to_string(), err);
+        assert!(!Path::new(&demux_output_paths("s1_AACCGG").0).exists());
+
+        let header_only = write_file(dir.path(), "empty.tsv", "name\tfile_name\tidx1\tseq1\tidx2\tseq2\n\n");
+        let err = validate_barcodes_file(&header_only, None, false).unwrap_err();
+        assert!(err.ends_with("lists no samples below its header"), "{}", err);
+        let missing = dir.path().join("missing.tsv").to_string_lossy().into_owned();
+        assert!(validate_barcodes_file(&missing, None, false).unwrap_err().starts_with("Unable to open barcodes file"));
+    }
+
+    #[test]
+    fn reads_with_a_wrong_primer_are_flagged_as_suspect() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let primer = "GTGYCAGCMGCCGCGGTAA";
+        let plate = paired_input(
+            dir.path(),
+            "plate",
+            &[
+                "ACGTAACCGGGTGCCAGCAGCCGCGGTAATAC", // the primer
+                "ACGTAACCGGTTTTTTTTTTTTTTTTTTTTTT", // not the primer
+                "ACGTAACCGGGTGTCAGCCGCCGCGGTAATAC", // the primer, IUPAC codes resolved differently
+            ],
+        );
+        let barcodes = barcodes_file(dir.path(), &[("s1", &plate, "AACCGG")]);
+
+        let options = DemuxOptions { suspect_primer: Some(primer.to_string()), ..Default::default() };
+        run_demultiplex_combined(&[barcodes], &options).unwrap();
+
+        let ids = |records: Vec<(String, String)>| records.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids(written_r1("s1_AACCGG")), ["read1", "read3"]);
+        assert_eq!(ids(read_fastq_gz(&out_path("s1_AACCGG_suspect_R1.fastq.gz"))), ["read2"]);
+        assert_eq!(ids(read_fastq_gz(&out_path("s1_AACCGG_suspect_R2.fastq.gz"))), ["read2"]);
+        let stats = fs::read_to_string(out_path("demux_stats.tsv")).unwrap();
+        let row: Vec<&str> = stats.lines().nth(1).unwrap().split('\t').collect();
+        // pairs_written and suspect_primer
+        assert_eq!((row[3], row[5]), ("2", "1"));
+    }
+
+    /// Sets the modification time of `base`'s R1 and R2 inputs.
+    fn set_input_mtime(base: &str, time: SystemTime) {
+        for read in ["R1", "R2"] {
+            let file = File::options().write(true).open(format!("{}_{}_001.fastq.gz", base, read)).unwrap();
+            file.set_modified(time).unwrap();
+        }
+    }
+
+    #[test]
+    fn since_outputs_reprocesses_only_samples_with_newer_inputs() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let hour_ago = SystemTime::now() - std::time::Duration::from_secs(3600);
+        let plate1 = paired_input(dir.path(), "plate1", &["ACGTAACCGGTTTT"]);
+        let plate2 = paired_input(dir.path(), "plate2", &["ACGTTTGGCCAAAA"]);
+        set_input_mtime(&plate1, hour_ago);
+        set_input_mtime(&plate2, hour_ago);
+        let barcodes = barcodes_file(dir.path(), &[("s1", &plate1, "AACCGG"), ("s2", &plate2, "TTGGCC")]);
+        run_demultiplex_combined(std::slice::from_ref(&barcodes), &DemuxOptions::default()).unwrap();
+
+        // Both inputs change, but only plate2's modification time moves past its output
+        paired_input(dir.path(), "plate1", &["ACGTAACCGGTTTT", "ACGTAACCGGCCCC"]);
+        paired_input(dir.path(), "plate2", &["ACGTTTGGCCAAAA", "ACGTTTGGCCGGGG"]);
+        set_input_mtime(&plate1, hour_ago);
+        set_input_mtime(&plate2, SystemTime::now() + std::time::Duration::from_secs(60));
+
+        let options = DemuxOptions { skip_existing: true, since: Some(Since::Outputs), ..Default::default() };
+        run_demultiplex_combined(std::slice::from_ref(&barcodes), &options).unwrap();
+        assert_eq!(written_r1("s1_AACCGG").len(), 1);
+        assert_eq!(written_r1("s2_TTGGCC").len(), 2);
+
+        // A sample without output is always processed
+        fs::remove_file(demux_output_paths("s1_AACCGG").0).unwrap();
+        run_demultiplex_combined(&[barcodes], &options).unwrap();
+        assert_eq!(written_r1("s1_AACCGG").len(), 2);
+    }
+
+    #[test]
+    fn since_accepts_outputs_and_timestamps() {
+        assert_eq!("outputs".parse::<Since>(), Ok(Since::Outputs));
+        let rfc3339: SystemTime = DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z").unwrap().into();
+        assert_eq!("2024-03-01T12:00:00Z".parse::<Since>(), Ok(Since::Time(rfc3339)));
+        assert!(matches!("2024-03-01".parse::<Since>(), Ok(Since::Time(_))));
+        assert!("yesterday".parse::<Since>().unwrap_err().contains("got 'yesterday'"));
+    }
+
+    #[test]
+    fn reads_too_short_after_trimming_are_discarded_and_counted() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let plate = paired_input(
+            dir.path(),
+            "plate",
+            &[
+                "ACGTAACC",         // ends inside the index
+                "ACGTAACCGG",       // nothing left after the index
+                "ACGTAACCGGTT",     // two bases left
+                "ACGTAACCGGTTTTGG", // six bases left
+            ],
+        );
+        let barcodes = barcodes_file(dir.path(), &[("s1", &plate, "AACCGG")]);
+
+        run_demultiplex_combined(std::slice::from_ref(&barcodes), &DemuxOptions::default()).unwrap();
+        let ids = || written_r1("s1_AACCGG").into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids(), ["read3", "read4"]);
+        let stats = fs::read_to_string(out_path("demux_stats.tsv")).unwrap();
+        let row: Vec<&str> = stats.lines().nth(1).unwrap().split('\t').collect();
+        // pairs_seen, pairs_written and too_short; read1 never matched the index
+        assert_eq!((row[2], row[3], row[6]), ("4", "2", "1"));
+
+        let options = DemuxOptions { min_trimmed_length: 4, ..Default::default() };
+        run_demultiplex_combined(&[barcodes], &options).unwrap();
+        assert_eq!(ids(), ["read4"]);
+    }
+
+    #[test]
+    fn demux_stats_has_a_row_of_counts_per_sample() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let input = paired_input(
+            dir.path(),
+            "plate",
+            &[
+                "ACGTAACCGGTTTTGG", // s1
+                "ACGTCCCCCCTTTTGG", // s2
+                "ACGTAACCGGTT",     // s1, but too short once trimmed
+                "ACGTGGGGGGTTTTGG", // neither
+            ],
+        );
+        let barcodes = barcodes_file(dir.path(), &[("s1", &input, "AACCGG"), ("s2", &input, "CCCCCC")]);
+        let options = DemuxOptions { min_trimmed_length: 4, ..Default::default() };
+
+        run_demultiplex_combined(&[barcodes], &options).unwrap();
+        let stats = fs::read_to_string(out_path("demux_stats.tsv")).unwrap();
+        let rows: Vec<Vec<&str>> = stats.lines().map(|line| line.split('\t').collect()).collect();
+        assert_eq!(
+            rows,
+            [
+                vec![
+                    "sample_id",
+                    "file_name",
+                    "pairs_seen",
+                    "pairs_written",
+                    "pairs_skipped",
+                    "suspect_primer",
+                    "too_short",
+                    "index_exact",
+                    "index_mismatched",
+                ],
+                vec!["s1_AACCGG", &input, "4", "1", "2", "0", "1", "2", "0"],
+                vec!["s2_CCCCCC", &input, "4", "1", "3", "0", "0", "1", "0"],
+            ]
+        );
+    }
+
+    #[test]
+    fn interleaved_input_is_split_into_r1_and_r2() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        write_fastq_gz(
+            dir.path(),
+            "plate.fastq.gz",
+            &[
+                ("read1/1", "ACGTAACCGGTTTT"),
+                ("read1/2", "GGGGCCCC"),
+                ("read2/1", "ACGTCCCCCCTTTT"),
+                ("read2/2", "AAAACCCC"),
+                ("read3/1", "ACGTAACCGGCCCC"),
+                ("read3/2", "TTTTGGGG"),
+            ],
+        );
+        let plate = dir.path().join("plate").to_string_lossy().into_owned();
+        let barcodes = barcodes_file(dir.path(), &[("s1", &plate, "AACCGG")]);
+
+        let options = DemuxOptions { interleaved: true, ..Default::default() };
+        run_demultiplex_combined(std::slice::from_ref(&barcodes), &options).unwrap();
+        let (r1, r2) = demux_output_paths("s1_AACCGG");
+        assert_eq!(
+            read_fastq_gz(&r1),
+            [("read1/1".to_string(), "TTTT".to_string()), ("read3/1".to_string(), "CCCC".to_string())]
+        );
+        assert_eq!(
+            read_fastq_gz(&r2),
+            [("read1/2".to_string(), "GGGGCCCC".to_string()), ("read3/2".to_string(), "TTTTGGGG".to_string())]
+        );
+
+        // Records that don't alternate mates fail the sample
+        write_fastq_gz(dir.path(), "plate.fastq.gz", &[("read1/1", "ACGTAACCGGTTTT"), ("read2/1", "ACGTAACCGGTTTT")]);
+        let err = run_demultiplex_combined(&[barcodes], &options).unwrap_err();
+        assert!(err.to_string().contains("Demultiplexing failed for 1 of 1 samples"), "{}", err);
+    }
+
+    #[test]
+    fn single_end_runs_read_and_write_r1_only_and_get_a_single_end_manifest() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        write_fastq_gz(dir.path(), "plate_R1_001.fastq.gz", &[("read1", "ACGTAACCGGTTTT"), ("read2", "ACGTCCCCCCGGGG")]);
+        let plate = dir.path().join("plate").to_string_lossy().into_owned();
+        let barcodes = barcodes_file(dir.path(), &[("s1", &plate, "AACCGG"), ("s2", &plate, "CCCCCC")]);
+        let options = DemuxOptions { single_end: true, ..Default::default() };
+
+        run_demultiplex_combined(std::slice::from_ref(&barcodes), &options).unwrap();
+        assert_eq!(written_r1("s1_AACCGG"), [("read1".to_string(), "TTTT".to_string())]);
+        assert_eq!(written_r1("s2_CCCCCC"), [("read2".to_string(), "GGGG".to_string())]);
+        assert!(!Path::new(&demux_output_paths("s1_AACCGG").1).exists());
+        assert_eq!(demux_output_files("s1_AACCGG", true), [demux_output_paths("s1_AACCGG").0]);
+
+        generate_qiime_manifest(std::slice::from_ref(&barcodes), "manifest.tsv", None, false, None, true).unwrap();
+        let manifest = fs::read_to_string(out_path("manifest.tsv")).unwrap();
+        let lines: Vec<&str> = manifest.lines().collect();
+        assert_eq!(lines[0], "sample-id\tforward-absolute-filepath");
+        let row = |id: &str| format!("{}\t{}", id, absolute_path(&demux_output_paths(id).0).unwrap().display());
+        assert_eq!(lines[1..], [row("s1_AACCGG"), row("s2_CCCCCC")]);
+
+        // Without --single-end the same input is missing its R2 files
+        let err = run_demultiplex_combined(&[barcodes], &DemuxOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("R2"), "{}", err);
+    }
+
+    #[test]
+    fn manifest_lists_every_lane_without_collisions() {
+        let _guard = test_support::global_state();
+        let (lane1, lane2) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let input1 = paired_input(lane1.path(), "lane1", &["ACGTAACCGGTTTTGGGG", "ACGTCCAATTTTTTGGGG"]);
+        let input2 = paired_input(lane2.path(), "lane2", &["ACGTAACCGGTTTTCCCC", "ACGTGGTTAATTTTCCCC"]);
+        let barcodes = [
+            barcodes_file(lane1.path(), &[("s1", &input1, "AACCGG"), ("s2", &input1, "CCAATT")]),
+            barcodes_file(lane2.path(), &[("s1", &input2, "AACCGG"), ("s3", &input2, "GGTTAA")]),
+        ];
+
+        run_demultiplex_combined(&barcodes, &DemuxOptions::default()).unwrap();
+        // The sample in both lanes gets a lane suffix; the others keep their plain IDs
+        assert_eq!(written_r1("s1_AACCGG_lane1"), vec![("read1".to_string(), "TTTTGGGG".to_string())]);
+        assert_eq!(written_r1("s1_AACCGG_lane2"), vec![("read1".to_string(), "TTTTCCCC".to_string())]);
+
+        generate_qiime_manifest(&barcodes, "qiime_manifest.tsv", None, false, None, false).unwrap();
+        let manifest = fs::read_to_string(out_path("qiime_manifest.tsv")).unwrap();
+        let ids: Vec<&str> = manifest.lines().skip(1).map(|line| line.split('\t').next().unwrap()).collect();
+        assert_eq!(ids, ["s1_AACCGG_lane1", "s1_AACCGG_lane2", "s2_CCAATT", "s3_GGTTAA"]);
+        let lane2_forward = absolute_path(&demux_output_paths("s1_AACCGG_lane2").0).unwrap();
+        assert!(manifest.contains(&format!("s1_AACCGG_lane2\t{}\t", lane2_forward.display())));
+    }
+
+    #[test]
+    fn manifest_is_sorted_and_deduplicated_with_a_warning() {
+        let _guard = test_support::global_state();
+        crate::logger::init_log(false);
+        let dir = TempDir::new().unwrap();
+        // A blank index leaves the name as the ID, so the first row clashes with the third
+        let barcodes = barcodes_file(
+            dir.path(),
+            &[("s1_AACCGG", "plate2", ""), ("s3", "plate1", "GGTTAA"), ("s1", "plate1", "AACCGG"), ("s2", "plate1", "CCAATT")],
+        );
+        for id in ["s1_AACCGG", "s2_CCAATT", "s3_GGTTAA"] {
+            let (r1, r2) = demux_output_paths(id);
+            fs::create_dir_all(Path::new(&r1).parent().unwrap()).unwrap();
+            fs::write(r1, "").unwrap();
+            fs::write(r2, "").unwrap();
+        }
+
+        generate_qiime_manifest(&[barcodes], "qiime_manifest.tsv", None, true, None, false).unwrap();
+        let manifest = fs::read_to_string(out_path("qiime_manifest.tsv")).unwrap();
+        let ids: Vec<&str> = manifest.lines().map(|line| line.split('\t').next().unwrap()).collect();
+        assert_eq!(ids, ["sample-id", "s1_AACCGG", "s2_CCAATT", "s3_GGTTAA"]);
+        let log = fs::read_to_string(out_path("windchime.log")).unwrap();
+        assert!(log.contains(
+            "Warning: barcodes list these sample IDs more than once; each is written to the manifest once: s1_AACCGG"
+        ));
+    }
+
+    #[test]
+    fn manifest_paths_are_the_files_demux_wrote() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let input = paired_input(dir.path(), "plate", &["ACGTAACCGGTTTTGGGG", "ACGTCCAATTTTTTGGGG"]);
+        let barcodes = [barcodes_file(dir.path(), &[("s1", &input, "AACCGG"), ("s2", &input, "CCAATT")])];
+        let template = SampleIdTemplate { template: "plateA.{name}".to_string(), tags: Vec::new() };
+        let options = DemuxOptions { sample_id_template: Some(template.clone()), ..Default::default() };
+        run_demultiplex_combined(&barcodes, &options).unwrap();
+
+        generate_qiime_manifest(&barcodes, "manifests/qiime_manifest.tsv", None, false, Some(&template), false).unwrap();
+        let manifest = fs::read_to_string(out_path("manifests/qiime_manifest.tsv")).unwrap();
+        let rows: Vec<Vec<&str>> = manifest.lines().skip(1).map(|line| line.split('\t').collect()).collect();
+        assert_eq!(rows.iter().map(|row| row[0]).collect::<Vec<_>>(), ["plateA.s1", "plateA.s2"]);
+        for row in &rows {
+            for path in &row[1..] {
+                assert!(Path::new(path).is_absolute() && Path::new(path).is_file(), "{} doesn't exist", path);
+            }
+        }
+
+        // Without a file demux should have written, no manifest is written at all
+        fs::remove_file(out_path("manifests/qiime_manifest.tsv")).unwrap();
+        let (_, reverse) = demux_output_paths("plateA.s2");
+        fs::remove_file(&reverse).unwrap();
+        let error = generate_qiime_manifest(&barcodes, "manifests/qiime_manifest.tsv", None, false, Some(&template), false)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().contains(&format!("1 demultiplexed FASTQ(s) don't exist ({})", reverse)), "{}", error);
+        assert!(!Path::new(&out_path("manifests/qiime_manifest.tsv")).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn absolute_path_survives_a_canonicalize_failure() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        // A dangling symlink exists but can't be canonicalized
+        let link = dir.path().join("reads_R1.fastq.gz");
+        std::os::unix::fs::symlink(dir.path().join("gone.fastq.gz"), &link).unwrap();
+        let roundabout = format!("{}/sub/./../reads_R1.fastq.gz", dir.path().display());
+        assert!(fs::canonicalize(&roundabout).is_err());
+
+        assert_eq!(absolute_path(&roundabout).unwrap(), link);
+        let missing = format!("{}/missing.fastq.gz", dir.path().display());
+        assert_eq!(absolute_path(&missing).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn passthrough_copies_index_less_samples_unchanged() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let reads = ["ACGTAACCGGTTTTGGGG", "ACGTCCAATTTTTTGGGG"];
+        let gzipped = paired_input(dir.path(), "gz", &reads);
+        let plain = dir.path().join("plain").to_string_lossy().into_owned();
+        for read in ["R1", "R2"] {
+            let records = read_fastq_gz(&format!("{}_{}_001.fastq.gz", gzipped, read));
+            let records: Vec<(&str, &str)> = records.iter().map(|(id, seq)| (id.as_str(), seq.as_str())).collect();
+            fs::write(format!("{}_{}_001.fastq", plain, read), test_support::fastq(&records)).unwrap();
+        }
+        let indexed = paired_input(dir.path(), "indexed", &reads);
+        let barcodes = barcodes_file(dir.path(), &[("gz", &gzipped, ""), ("plain", &plain, ""), ("indexed", &indexed, "AACCGG")]);
+
+        let options = DemuxOptions { passthrough: true, ..Default::default() };
+        run_demultiplex_combined(&[barcodes], &options).unwrap();
+        // Gzipped input is copied byte for byte; plain input is only compressed
+        let (r1, r2) = demux_output_paths("gz");
+        assert_eq!(fs::read(&r1).unwrap(), fs::read(format!("{}_R1_001.fastq.gz", gzipped)).unwrap());
+        assert_eq!(fs::read(&r2).unwrap(), fs::read(format!("{}_R2_001.fastq.gz", gzipped)).unwrap());
+        let expected: Vec<(String, String)> =
+            ["read1", "read2"].iter().zip(reads).map(|(id, seq)| (id.to_string(), seq.to_string())).collect();
+        assert_eq!(written_r1("plain"), expected);
+        assert_eq!(read_fastq_gz(&demux_output_paths("plain").1).len(), 2);
+        // A sample with an inline index is still demultiplexed and trimmed
+        assert_eq!(written_r1("indexed_AACCGG"), vec![("read1".to_string(), "TTTTGGGG".to_string())]);
+    }
+
+    #[test]
+    fn audit_records_the_barcodes_file_and_every_fastq() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let input = paired_input(dir.path(), "plate", &["ACGTAACCGGTTTTGGGG"]);
+        let barcodes = barcodes_file(dir.path(), &[("s1", &input, "AACCGG"), ("s2", &input, "CCAATT")]);
+
+        let options = DemuxOptions { audit: true, ..Default::default() };
+        run_demultiplex_combined(std::slice::from_ref(&barcodes), &options).unwrap();
+        let audit = fs::read_to_string(out_path("input_audit.tsv")).unwrap();
+        let audited: Vec<(String, String)> = audit
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let fields: Vec<&str> = line.split('\t').collect();
+                assert_eq!(fields[3], "", "hashed without --audit-hash");
+                (fields[0].to_string(), fields[1].to_string())
+            })
+            .collect();
+        // The shared input pair is listed once
+        let expected: Vec<(String, String)> = [barcodes, format!("{}_R1_001.fastq.gz", input), format!("{}_R2_001.fastq.gz", input)]
+            .iter()
+            .map(|path| {
+                let size = fs::metadata(path).unwrap().len();
+                (fs::canonicalize(path).unwrap().to_string_lossy().into_owned(), size.to_string())
+            })
+            .collect();
+        assert_eq!(audited, expected);
+    }
+
+    #[test]
+    fn indexes_of_different_lengths_trim_their_own_length() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let input = paired_input(
+            dir.path(),
+            "plate",
+            &[
+                "ACGTGGTTTTTTGGGG",   // four-base index of s1
+                "ACGTCCAATTCCCCAAAA", // six-base index of s2
+                "ACGTGGTAAAAAAA",     // neither
+            ],
+        );
+        let barcodes = barcodes_file(dir.path(), &[("s1", &input, "GGTT"), ("s2", &input, "CCAATT")]);
+
+        run_demultiplex_combined(std::slice::from_ref(&barcodes), &DemuxOptions::default()).unwrap();
+        assert_eq!(written_r1("s1_GGTT"), vec![("read1".to_string(), "TTTTGGGG".to_string())]);
+        assert_eq!(written_r1("s2_CCAATT"), vec![("read2".to_string(), "CCCCAAAA".to_string())]);
+
+        // An index that starts another would claim that sample's reads too
+        let prefixed = barcodes_file(dir.path(), &[("s1", &input, "CCAA"), ("s2", &input, "CCAATT")]);
+        let err = run_demultiplex_combined(&[prefixed], &DemuxOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("Ambiguous indexes"), "{}", err);
+    }
+
+    #[test]
+    fn a_bom_prefixed_barcodes_file_parses_its_first_column_cleanly() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("barcodes.tsv");
+        fs::write(
+            &path,
+            b"\xEF\xBB\xBFname\tfile_name\tidx1\tseq1\tidx2\tseq2\ns1\tplate\tN701\tTAAGGCGA\tS501\tAACCGG\n",
+        )
+        .unwrap();
+        let path = path.to_string_lossy();
+        for mapping in [None, Some("name=name,file=file_name,index=seq2")] {
+            let rows = read_barcode_rows(&path, mapping, false).unwrap();
+            assert_eq!(rows.len(), 1);
+            assert_eq!(rows[0].name, "s1");
+            assert_eq!(rows[0].index_seq, "AACCGG");
+        }
+    }
+
+    #[test]
+    fn a_latin1_barcodes_file_is_decoded_with_a_warning() {
+        let _guard = test_support::global_state();
+        crate::logger::init_log(false);
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("barcodes.tsv");
+        fs::write(&path, b"name\tfile_name\tidx1\tseq1\tidx2\tseq2\ncaf\xE9\tplate\tN701\tTAAGGCGA\tS501\tAACCGG\n")
+            .unwrap();
+        let path = path.to_string_lossy();
+        let rows = read_barcode_rows(&path, None, false).unwrap();
+        assert_eq!(rows[0].name, "café");
+        let log = fs::read_to_string(out_path("windchime.log")).unwrap();
+        assert!(log.contains("byte 0xE9 at offset 38, line 2"), "{}", log);
+    }
+
+    #[test]
+    fn provenance_maps_each_sample_to_its_source_files() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let plate1 = paired_input(dir.path(), "plate1", &["ACGTAACCGGTTTT", "ACGTCCAATTTTTT"]);
+        let plate2 = paired_input(dir.path(), "plate2", &["ACGTGGTTAATTTT"]);
+        let barcodes = barcodes_file(
+            dir.path(),
+            &[("s1", &plate1, "AACCGG"), ("s2", &plate1, "CCAATT"), ("s3", &plate2, "GGTTAA")],
+        );
+
+        run_demultiplex_combined(&[barcodes], &DemuxOptions::default()).unwrap();
+
+        let provenance = fs::read_to_string(out_path("sample_provenance.tsv")).unwrap();
+        let mut lines = provenance.lines();
+        assert_eq!(lines.next(), Some("sample_id\tname\tfile_name\tseq2\tr1_path\tr2_path"));
+        let rows: Vec<&str> = lines.collect();
+        let source = |base: &str, read: &str| {
+            absolute_path(&format!("{}_{}_001.fastq.gz", base, read)).unwrap().display().to_string()
+        };
+        assert_eq!(
+            rows,
+            [
+                format!("s1_AACCGG\ts1\t{0}\tAACCGG\t{1}\t{2}", plate1, source(&plate1, "R1"), source(&plate1, "R2")),
+                format!("s2_CCAATT\ts2\t{0}\tCCAATT\t{1}\t{2}", plate1, source(&plate1, "R1"), source(&plate1, "R2")),
+                format!("s3_GGTTAA\ts3\t{0}\tGGTTAA\t{1}\t{2}", plate2, source(&plate2, "R1"), source(&plate2, "R2")),
+            ]
+        );
+    }
+
+    #[test]
+    fn preview_counts_matches_per_sample_without_writing_output() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let reads = [
+            "ACGTAACCGGTTTT", // s1
+            "ACGTCCAATTTTTT", // s2
+            "ACGTAACCGGAAAA", // s1
+            "ACGTCCGGTTTTTT", // reverse complement of s1's index
+            "ACGTGGGGGGTTTT", // no sample
+        ];
+        let plate = paired_input(dir.path(), "plate", &reads);
+        let barcodes = barcodes_file(dir.path(), &[("s1", &plate, "AACCGG"), ("s2", &plate, "CCAATT")]);
+
+        let (counts, inputs) = preview_barcode_counts(std::slice::from_ref(&barcodes), &DemuxOptions::default(), 100).unwrap();
+        let tallies: Vec<(&str, u64, u64)> =
+            counts.iter().map(|count| (count.sample_id.as_str(), count.matched, count.rc_matched)).collect();
+        assert_eq!(tallies, [("s1_AACCGG", 2, 1), ("s2_CCAATT", 1, 0)]);
+        assert_eq!(inputs, [PreviewInput { file_name: plate.clone(), pairs: 5, unassigned: 2 }]);
+
+        // Only the first pairs are sampled
+        let (counts, inputs) = preview_barcode_counts(&[barcodes], &DemuxOptions::default(), 2).unwrap();
+        assert_eq!((counts[0].matched, counts[1].matched, inputs[0].pairs), (1, 1, 2));
+        for sample_id in ["s1_AACCGG", "s2_CCAATT"] {
+            assert!(!Path::new(&demux_output_paths(sample_id).0).exists());
+        }
+    }
+
+    #[test]
+    fn strict_fastq_rejects_a_malformed_record() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let plate = dir.path().join("plate").to_string_lossy().into_owned();
+        let r1 = "@read1\nACGTAACCGGTTTT\n+\nIIIIIIIIIIIIII\n\n@read2\nACGTAACCGGAAAA\n+read9\nIIIIIIIIIIIIII\n";
+        test_support::write_gz(dir.path(), "plate_R1_001.fastq.gz", r1);
+        write_fastq_gz(dir.path(), "plate_R2_001.fastq.gz", &[("read1", "GGGG"), ("read2", "CCCC")]);
+        let barcodes = barcodes_file(dir.path(), &[("s1", &plate, "AACCGG")]);
+
+        let strict = DemuxOptions { strict_fastq: true, ..Default::default() };
+        let err = run_demultiplex_combined(std::slice::from_ref(&barcodes), &strict).unwrap_err();
+        let expected = format!(
+            "Error processing {0}: {0}_R1_001.fastq.gz: malformed FASTQ record 2 at line 5 (byte 39): blank line before the record",
+            plate
+        );
+        assert!(err.to_string().ends_with(&expected), "{}", err);
+
+        // Without --strict-fastq the blank line and the separator are tolerated
+        run_demultiplex_combined(&[barcodes], &DemuxOptions::default()).unwrap();
+        assert_eq!(written_r1("s1_AACCGG").len(), 2);
+    }
 }
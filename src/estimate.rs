@@ -0,0 +1,301 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::color_print::{print_info, print_success};
+use crate::demultiplex::read_text_file;
+use crate::fastq_io::{self, Codec, FastqReader};
+
+// Heuristic coefficients. They are deliberately rough (aiming for ±2x) and
+// easy to tune as real timings come in. Times are in seconds.
+
+/// Demultiplexing, per million read pairs on one core.
+const DEMUX_SECS_PER_M_PAIRS: f64 = 25.0;
+/// `qiime tools import` + validate + summarize, per million read pairs.
+const IMPORT_SECS_PER_M_PAIRS: f64 = 20.0;
+/// Cutadapt, per million read pairs on one core.
+const CUTADAPT_SECS_PER_M_PAIRS: f64 = 60.0;
+/// DADA2 denoise-paired, per million read pairs on one core.
+const DADA2_SECS_PER_M_PAIRS: f64 = 400.0;
+/// DADA2 fixed overhead per sample.
+const DADA2_SECS_PER_SAMPLE: f64 = 5.0;
+/// Importing references and downloading the pre-trained classifier.
+const REFERENCE_SETUP_SECS: f64 = 300.0;
+/// Training a PR2 classifier from scratch.
+const CLASSIFIER_TRAINING_SECS: f64 = 4.0 * 3600.0;
+/// classify-sklearn plus exports and merging.
+const CLASSIFY_SECS: f64 = 900.0;
+
+/// Size of the reference database directory after download and import.
+const REFERENCE_DB_BYTES: f64 = 1.5e9;
+/// DADA2 and downstream outputs, per sample.
+const DOWNSTREAM_BYTES_PER_SAMPLE: f64 = 2.0e6;
+
+/// How much decompressed FASTQ to read from each file to estimate its record count.
+const SAMPLE_BYTES: u64 = 4 * 1024 * 1024;
+
+/// An R1 FASTQ and its R2 mate, which is `None` for single-end reads.
+pub type ReadFiles = (String, Option<String>);
+
+/// Measured size of a run's input.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunSize {
+    pub samples: usize,
+    pub read_pairs: u64,
+    /// Bytes of input FASTQ (R1 + R2), as stored on disk.
+    pub input_bytes: u64,
+}
+
+/// Predicted wall-clock time and disk usage of one stage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageEstimate {
+    pub stage: &'static str,
+    pub seconds: f64,
+    /// Bytes this stage leaves on disk.
+    pub disk_bytes: f64,
+}
+
+/// Counts bytes read through it, so we know how much compressed input a
+/// sample of decompressed records corresponds to. The count is shared so it
+/// can be read while a decoder owns the reader.
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Estimates the number of records in a FASTQ file (plain or compressed) by
+/// reading its first few megabytes and extrapolating by file size.
+pub fn estimate_fastq_records(path: &str) -> io::Result<u64> {
+    let file_size = fs::metadata(path)?.len();
+    if file_size == 0 {
+        return Ok(0);
+    }
+    let count = Arc::new(AtomicU64::new(0));
+    let counting = CountingReader { inner: File::open(path)?, count: Arc::clone(&count) };
+    let mut reader = fastq_io::decompress(counting, Codec::detect(path)?)?;
+    let records = count_sample_records(&mut reader)?;
+    let consumed = count.load(Ordering::Relaxed);
+    if consumed == 0 {
+        return Ok(0);
+    }
+    Ok((records as f64 * file_size as f64 / consumed as f64).round() as u64)
+}
+
+/// Counts complete 4-line records in the first [`SAMPLE_BYTES`] of `reader`.
+fn count_sample_records<R: BufRead>(reader: &mut R) -> io::Result<u64> {
+    let mut lines = 0u64;
+    let mut bytes = 0u64;
+    let mut line = String::new();
+    while bytes < SAMPLE_BYTES {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+        if n == 0 {
+            break;
+        }
+        bytes += n as u64;
+        lines += 1;
+    }
+    Ok(lines / 4)
+}
+
+/// Reads examined by [`typical_read_length`].
+const LENGTH_SAMPLE_READS: usize = 1_000;
+
+/// Median length of the first reads of a FASTQ file (plain or compressed),
+/// or `None` if it has no reads.
+pub fn typical_read_length(path: &str) -> io::Result<Option<usize>> {
+    let mut lengths = Vec::new();
+    for record in FastqReader::open(path)?.take(LENGTH_SAMPLE_READS) {
+        lengths.push(record?.seq().len());
+    }
+    lengths.sort_unstable();
+    Ok(lengths.get(lengths.len() / 2).copied())
+}
+
+/// Measures the inputs given as `(R1, R2)` file pairs, one pair per sample.
+pub fn measure_inputs(pairs: &[ReadFiles], samples: usize) -> io::Result<RunSize> {
+    let mut size = RunSize { samples, ..RunSize::default() };
+    for (r1, r2) in pairs {
+        size.read_pairs += estimate_fastq_records(r1)?;
+        size.input_bytes += fs::metadata(r1)?.len();
+        if let Some(r2) = r2 {
+            size.input_bytes += fs::metadata(r2)?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Reads the R1/R2 paths of a QIIME2 manifest; R2 is `None` in a
+/// single-end manifest.
+pub fn manifest_inputs(manifest: &str) -> io::Result<Vec<ReadFiles>> {
+    let text = read_text_file(manifest)?;
+    let mut pairs = Vec::new();
+    for line in text.lines().skip(1) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields[..] {
+            [_, r1, r2, ..] if Path::new(r1).is_file() && Path::new(r2).is_file() => {
+                pairs.push((r1.to_string(), Some(r2.to_string())))
+            }
+            [_, r1] if Path::new(r1).is_file() => pairs.push((r1.to_string(), None)),
+            _ => {}
+        }
+    }
+    Ok(pairs)
+}
+
+/// Predicts per-stage runtime and disk usage for a run of `size` on `cores` cores.
+///
+/// `include_demux` adds the custom demultiplexing stage (RunAll);
+/// `train_classifier` replaces the pre-trained download with training.
+pub fn estimate_run(
+    size: &RunSize,
+    cores: usize,
+    include_demux: bool,
+    train_classifier: bool,
+) -> Vec<StageEstimate> {
+    let cores = cores.max(1) as f64;
+    let m_pairs = size.read_pairs as f64 / 1e6;
+    let input = size.input_bytes as f64;
+    let samples = size.samples as f64;
+
+    let mut stages = Vec::new();
+    if include_demux {
+        // Demux runs one rayon task per sample
+        let parallel = cores.min(samples.max(1.0));
+        stages.push(StageEstimate {
+            stage: "Demultiplex",
+            seconds: DEMUX_SECS_PER_M_PAIRS * m_pairs / parallel,
+            disk_bytes: input,
+        });
+    }
+    stages.push(StageEstimate {
+        stage: "Import & summarize",
+        seconds: IMPORT_SECS_PER_M_PAIRS * m_pairs,
+        disk_bytes: input,
+    });
+    stages.push(StageEstimate {
+        stage: "Cutadapt",
+        seconds: CUTADAPT_SECS_PER_M_PAIRS * m_pairs / cores,
+        disk_bytes: 0.9 * input,
+    });
+    stages.push(StageEstimate {
+        stage: "DADA2",
+        seconds: DADA2_SECS_PER_M_PAIRS * m_pairs / cores + DADA2_SECS_PER_SAMPLE * samples,
+        disk_bytes: DOWNSTREAM_BYTES_PER_SAMPLE * samples,
+    });
+    stages.push(StageEstimate {
+        stage: if train_classifier { "Reference import & classifier training" } else { "Reference import & classifier download" },
+        seconds: REFERENCE_SETUP_SECS + if train_classifier { CLASSIFIER_TRAINING_SECS } else { 0.0 },
+        disk_bytes: REFERENCE_DB_BYTES,
+    });
+    stages.push(StageEstimate {
+        stage: "Classify, export & merge",
+        seconds: CLASSIFY_SECS / cores.sqrt(),
+        disk_bytes: DOWNSTREAM_BYTES_PER_SAMPLE * samples,
+    });
+    stages
+}
+
+fn format_duration(seconds: f64) -> String {
+    let total = seconds.round() as u64;
+    format!("{}h{:02}m{:02}s", total / 3600, (total % 3600) / 60, total % 60)
+}
+
+pub(crate) fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Prints the estimate as a table with totals.
+pub fn print_estimate(size: &RunSize, cores: usize, stages: &[StageEstimate]) {
+    print_info(&format!(
+        "Input: {} samples, ~{} read pairs, {} on disk, {} core(s)",
+        size.samples,
+        size.read_pairs,
+        format_bytes(size.input_bytes as f64),
+        cores
+    ));
+    let mut total_secs = 0.0;
+    let mut total_disk = 0.0;
+    for stage in stages {
+        total_secs += stage.seconds;
+        total_disk += stage.disk_bytes;
+        println!(
+            "  {:<42} {:>12}   {:>10}",
+            stage.stage,
+            format_duration(stage.seconds),
+            format_bytes(stage.disk_bytes)
+        );
+    }
+    // Nothing is cleaned up between stages, so peak disk usage is the total
+    print_success(&format!(
+        "Estimated total: {} wall-clock, {} peak disk usage. Expect ±2x.",
+        format_duration(total_secs),
+        format_bytes(total_disk)
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{fastq, write_fastq_gz};
+
+    fn total_seconds(stages: &[StageEstimate]) -> f64 {
+        stages.iter().map(|stage| stage.seconds).sum()
+    }
+
+    #[test]
+    fn estimate_grows_with_read_count() {
+        let mut previous: Option<Vec<StageEstimate>> = None;
+        for read_pairs in [0, 100_000, 1_000_000, 10_000_000, 100_000_000] {
+            let size = RunSize { samples: 24, read_pairs, input_bytes: read_pairs * 150 };
+            let stages = estimate_run(&size, 8, true, false);
+            if let Some(previous) = &previous {
+                assert!(total_seconds(&stages) > total_seconds(previous), "{} pairs", read_pairs);
+                for (stage, before) in stages.iter().zip(previous) {
+                    assert!(stage.seconds >= before.seconds, "{} at {} pairs", stage.stage, read_pairs);
+                    assert!(stage.disk_bytes >= before.disk_bytes, "{} at {} pairs", stage.stage, read_pairs);
+                }
+            }
+            previous = Some(stages);
+        }
+    }
+
+    #[test]
+    fn more_cores_and_a_pretrained_classifier_are_faster() {
+        let size = RunSize { samples: 24, read_pairs: 5_000_000, input_bytes: 1_500_000_000 };
+        assert!(total_seconds(&estimate_run(&size, 16, true, false)) < total_seconds(&estimate_run(&size, 1, true, false)));
+        let trained = estimate_run(&size, 8, false, true);
+        let downloaded = estimate_run(&size, 8, false, false);
+        assert!(total_seconds(&trained) > total_seconds(&downloaded));
+        assert!(!downloaded.iter().any(|stage| stage.stage == "Demultiplex"));
+    }
+
+    #[test]
+    fn small_files_are_counted_exactly() {
+        let dir = tempfile::tempdir().unwrap();
+        let records: Vec<(String, &str)> = (0..250).map(|i| (format!("read{}", i), "ACGTACGTAC")).collect();
+        let records: Vec<(&str, &str)> = records.iter().map(|(id, seq)| (id.as_str(), *seq)).collect();
+        let gz = write_fastq_gz(dir.path(), "r1.fastq.gz", &records);
+        assert_eq!(estimate_fastq_records(gz.to_str().unwrap()).unwrap(), 250);
+        let plain = dir.path().join("r1.fastq");
+        fs::write(&plain, fastq(&records)).unwrap();
+        assert_eq!(estimate_fastq_records(plain.to_str().unwrap()).unwrap(), 250);
+    }
+}
@@ -0,0 +1,415 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+
+use bio::io::fastq;
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+
+/// Compression of an input file, detected from its first bytes rather than
+/// its name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Plain,
+    Gzip,
+    Bzip2,
+    Zstd,
+}
+
+impl Codec {
+    /// Identifies the codec from a file's leading bytes.
+    pub fn from_magic(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Codec::Gzip
+        } else if magic.starts_with(b"BZh") {
+            Codec::Bzip2
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Codec::Zstd
+        } else {
+            Codec::Plain
+        }
+    }
+
+    /// Reads the first bytes of `path` to identify its codec.
+    pub fn detect(path: &str) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        let mut file = File::open(path)?;
+        let mut read = 0;
+        while read < magic.len() {
+            match file.read(&mut magic[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        Ok(Codec::from_magic(&magic[..read]))
+    }
+}
+
+/// Wraps `reader` in the decoder for `codec`. Concatenated gzip and bzip2
+/// members (as written by parallel compressors) are read as one stream.
+pub fn decompress<R: Read + Send + 'static>(reader: R, codec: Codec) -> io::Result<Box<dyn BufRead + Send>> {
+    Ok(match codec {
+        Codec::Plain => Box::new(BufReader::new(reader)),
+        Codec::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+        Codec::Bzip2 => Box::new(BufReader::new(MultiBzDecoder::new(reader))),
+        Codec::Zstd => Box::new(BufReader::new(zstd::stream::read::Decoder::new(reader)?)),
+    })
+}
+
+/// Opens a file (plain, gzip, bzip2 or zstd) for reading decompressed lines.
+pub fn open_bufread(path: &str) -> io::Result<Box<dyn BufRead + Send>> {
+    let codec = Codec::detect(path)?;
+    decompress(File::open(path)?, codec)
+}
+
+/// Streams FASTQ records from a file of any supported [`Codec`], checking
+/// each record's structure. A malformed record is an `InvalidData` error
+/// naming the file, the record's index, and the line and byte offset (in the
+/// decompressed stream) where it starts.
+pub struct FastqReader {
+    path: String,
+    inner: Box<dyn BufRead + Send>,
+    /// Lines consumed so far.
+    line_no: u64,
+    /// Decompressed bytes consumed so far.
+    offset: u64,
+    /// Records read so far.
+    records: u64,
+    /// Also reject blank lines before a record and a `+` line naming another read.
+    strict: bool,
+    done: bool,
+}
+
+impl FastqReader {
+    pub fn open(path: &str) -> io::Result<Self> {
+        Ok(FastqReader {
+            path: path.to_string(),
+            inner: open_bufread(path)?,
+            line_no: 0,
+            offset: 0,
+            records: 0,
+            strict: false,
+            done: false,
+        })
+    }
+
+    /// Enables the `--strict-fastq` checks: no blank lines before a record,
+    /// and a `+` line that is bare or repeats its own record's header.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Reads one line without its line ending, or `None` at end of input.
+    fn next_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        let n = self.inner.read_line(&mut line)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        self.line_no += 1;
+        self.offset += n as u64;
+        let trimmed = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(trimmed);
+        Ok(Some(line))
+    }
+
+    fn malformed(&self, line_no: u64, offset: u64, reason: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{}: malformed FASTQ record {} at line {} (byte {}): {}",
+                self.path,
+                self.records + 1,
+                line_no,
+                offset,
+                reason
+            ),
+        )
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<fastq::Record>> {
+        // Blank lines between records (and at the end) are tolerated, except
+        // before a record in strict mode
+        let mut blank = None;
+        let (header, start_offset) = loop {
+            let line_start = self.offset;
+            match self.next_line()? {
+                None => return Ok(None),
+                Some(line) if line.trim().is_empty() => {
+                    blank.get_or_insert((self.line_no, line_start));
+                    continue;
+                }
+                Some(line) => break (line, line_start),
+            }
+        };
+        if self.strict
+            && let Some((line_no, offset)) = blank
+        {
+            return Err(self.malformed(line_no, offset, "blank line before the record"));
+        }
+        let start_line = self.line_no;
+
+        let Some(header) = header.strip_prefix('@') else {
+            return Err(self.malformed(start_line, start_offset, "header does not start with '@'"));
+        };
+        let (id, desc) = match header.split_once(char::is_whitespace) {
+            Some((id, desc)) => (id, Some(desc.trim()).filter(|d| !d.is_empty())),
+            None => (header, None),
+        };
+        if id.is_empty() {
+            return Err(self.malformed(start_line, start_offset, "empty read ID"));
+        }
+        let (id, desc) = (id.to_string(), desc.map(str::to_string));
+
+        let truncated = |reader: &Self| reader.malformed(start_line, start_offset, "truncated record");
+        let seq = self.next_line()?.ok_or_else(|| truncated(self))?;
+        let separator = self.next_line()?.ok_or_else(|| truncated(self))?;
+        if !separator.starts_with('+') {
+            return Err(self.malformed(start_line, start_offset, "third line does not start with '+'"));
+        }
+        // The separator may repeat the header, but only that of its own record
+        let repeated = &separator[1..];
+        if self.strict && !repeated.is_empty() && repeated != header && repeated != id {
+            return Err(self.malformed(
+                start_line,
+                start_offset,
+                &format!("separator line '{}' names a different read than '@{}'", separator, header),
+            ));
+        }
+        let qual = self.next_line()?.ok_or_else(|| truncated(self))?;
+        if seq.len() != qual.len() {
+            return Err(self.malformed(
+                start_line,
+                start_offset,
+                &format!("sequence has {} bases but quality has {}", seq.len(), qual.len()),
+            ));
+        }
+        if let Some(bad) = qual.bytes().find(|q| !(b'!'..=b'~').contains(q)) {
+            return Err(self.malformed(start_line, start_offset, &format!("invalid quality character {:?}", bad as char)));
+        }
+        self.records += 1;
+        Ok(Some(fastq::Record::with_attrs(&id, desc.as_deref(), seq.as_bytes(), qual.as_bytes())))
+    }
+}
+
+impl Iterator for FastqReader {
+    type Item = io::Result<fastq::Record>;
+
+    /// Yields records until the end of input or the first error.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.read_record().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+/// A read ID without its `/1` or `/2` mate suffix.
+fn mate_id(id: &str) -> &str {
+    id.strip_suffix("/1").or_else(|| id.strip_suffix("/2")).unwrap_or(id)
+}
+
+/// Read pairs from one file with R1 and R2 records alternating. Mates must
+/// have matching IDs and the file an even number of records.
+pub fn interleaved_pairs(
+    path: &str,
+    strict: bool,
+) -> io::Result<impl Iterator<Item = io::Result<(fastq::Record, fastq::Record)>> + Send + use<>> {
+    let path = path.to_string();
+    let mut records = FastqReader::open(&path)?.with_strict(strict);
+    Ok(std::iter::from_fn(move || {
+        let rec1 = match records.next()? {
+            Ok(rec) => rec,
+            Err(e) => return Some(Err(e)),
+        };
+        let rec2 = match records.next() {
+            Some(Ok(rec)) => rec,
+            Some(Err(e)) => return Some(Err(e)),
+            None => {
+                return Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: odd number of records, '{}' has no mate", path, rec1.id()),
+                )));
+            }
+        };
+        if mate_id(rec1.id()) != mate_id(rec2.id()) {
+            return Some(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: records are not interleaved, '{}' is followed by '{}'", path, rec1.id(), rec2.id()),
+            )));
+        }
+        Some(Ok((rec1, rec2)))
+    }))
+}
+
+/// Read pairs from separate R1 and R2 files, checked strictly: both files
+/// must hold the same number of records, and each pair's IDs must match.
+pub fn strict_split_pairs(
+    r1: &str,
+    r2: &str,
+) -> io::Result<impl Iterator<Item = io::Result<(fastq::Record, fastq::Record)>> + Send + use<>> {
+    let (path1, path2) = (r1.to_string(), r2.to_string());
+    let mut records1 = FastqReader::open(r1)?.with_strict(true);
+    let mut records2 = FastqReader::open(r2)?.with_strict(true);
+    let mut pairs: u64 = 0;
+    Ok(std::iter::from_fn(move || {
+        let (rec1, rec2) = match (records1.next(), records2.next()) {
+            (None, None) => return None,
+            (Some(Err(e)), _) | (_, Some(Err(e))) => return Some(Err(e)),
+            (Some(Ok(rec1)), Some(Ok(rec2))) => (rec1, rec2),
+            (Some(Ok(_)), None) | (None, Some(Ok(_))) => {
+                let (longer, shorter) = if records1.records > pairs { (&path1, &path2) } else { (&path2, &path1) };
+                return Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} has more records than {}, which ends after record {}", longer, shorter, pairs),
+                )));
+            }
+        };
+        pairs += 1;
+        if mate_id(rec1.id()) != mate_id(rec2.id()) {
+            return Some(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("record {} of {} is '{}' but its mate in {} is '{}'", pairs, path1, rec1.id(), path2, rec2.id()),
+            )));
+        }
+        Some(Ok((rec1, rec2)))
+    }))
+}
+
+/// Whether `path` looks interleaved: its first two records are mates.
+pub fn looks_interleaved(path: &str) -> io::Result<bool> {
+    let mut records = FastqReader::open(path)?;
+    match (records.next().transpose()?, records.next().transpose()?) {
+        (Some(rec1), Some(rec2)) => Ok(mate_id(rec1.id()) == mate_id(rec2.id())),
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{fastq, write_file};
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+
+    const RECORDS: &[(&str, &str)] = &[("read1/1", "ACGT"), ("read1/2", "GGCC"), ("read2/1", "TTAA"), ("read2/2", "CCGG")];
+
+    /// `(id, sequence)` of every record of `path`.
+    fn read_all(path: &str) -> Vec<(String, String)> {
+        FastqReader::open(path)
+            .unwrap()
+            .map(|rec| {
+                let rec = rec.unwrap();
+                (rec.id().to_string(), String::from_utf8(rec.seq().to_vec()).unwrap())
+            })
+            .collect()
+    }
+
+    /// Writes `contents` compressed with `codec` to `name` in `dir`.
+    fn write_compressed(dir: &Path, name: &str, contents: &str, codec: Codec) -> String {
+        let bytes = match codec {
+            Codec::Plain => contents.as_bytes().to_vec(),
+            Codec::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(contents.as_bytes()).unwrap();
+                encoder.finish().unwrap()
+            }
+            Codec::Bzip2 => {
+                let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+                encoder.write_all(contents.as_bytes()).unwrap();
+                encoder.finish().unwrap()
+            }
+            Codec::Zstd => zstd::encode_all(contents.as_bytes(), 1).unwrap(),
+        };
+        let path = dir.join(name);
+        fs::write(&path, bytes).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn every_codec_is_detected_by_its_magic_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let expected: Vec<(String, String)> = RECORDS.iter().map(|(id, seq)| (id.to_string(), seq.to_string())).collect();
+        for codec in [Codec::Plain, Codec::Gzip, Codec::Bzip2, Codec::Zstd] {
+            // The name says nothing about the compression
+            let path = write_compressed(dir.path(), &format!("{:?}.fastq", codec), &fastq(RECORDS), codec);
+            assert_eq!(Codec::detect(&path).unwrap(), codec);
+            assert_eq!(read_all(&path), expected, "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn malformed_record_names_its_line_and_byte_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = "@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\nIII\n";
+        let path = write_compressed(dir.path(), "reads.fastq.gz", text, Codec::Gzip);
+        let mut records = FastqReader::open(&path).unwrap();
+        assert_eq!(records.next().unwrap().unwrap().id(), "r1");
+        let err = records.next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            format!("{}: malformed FASTQ record 2 at line 5 (byte 16): sequence has 4 bases but quality has 3", path)
+        );
+        assert!(records.next().is_none());
+
+        let path = write_file(dir.path(), "truncated.fastq", "@r1\nACGT\n");
+        let err = FastqReader::open(&path).unwrap().next().unwrap().unwrap_err();
+        assert!(err.to_string().ends_with("record 1 at line 1 (byte 0): truncated record"), "{}", err);
+    }
+
+    #[test]
+    fn strict_mode_rejects_blank_lines_and_foreign_separators() {
+        let dir = tempfile::tempdir().unwrap();
+        let blank = write_file(dir.path(), "blank.fastq", "@r1\nACGT\n+\nIIII\n\n@r2\nACGT\n+\nIIII\n");
+        assert_eq!(read_all(&blank).len(), 2);
+        let err = FastqReader::open(&blank).unwrap().with_strict(true).nth(1).unwrap().unwrap_err();
+        assert!(err.to_string().ends_with("record 2 at line 5 (byte 16): blank line before the record"), "{}", err);
+
+        let foreign = write_file(dir.path(), "foreign.fastq", "@r1\nACGT\n+r9\nIIII\n");
+        let err = FastqReader::open(&foreign).unwrap().with_strict(true).next().unwrap().unwrap_err();
+        assert!(err.to_string().ends_with("separator line '+r9' names a different read than '@r1'"), "{}", err);
+    }
+
+    #[test]
+    fn interleaved_input_is_detected_and_paired() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_compressed(dir.path(), "interleaved.fastq.bz2", &fastq(RECORDS), Codec::Bzip2);
+        assert!(looks_interleaved(&path).unwrap());
+        let pairs: Vec<(String, String)> = interleaved_pairs(&path, true)
+            .unwrap()
+            .map(|pair| {
+                let (rec1, rec2) = pair.unwrap();
+                (rec1.id().to_string(), rec2.id().to_string())
+            })
+            .collect();
+        assert_eq!(pairs, [("read1/1".to_string(), "read1/2".to_string()), ("read2/1".to_string(), "read2/2".to_string())]);
+
+        let split = write_file(dir.path(), "r1.fastq", &fastq(&[("read1", "ACGT"), ("read2", "ACGT")]));
+        assert!(!looks_interleaved(&split).unwrap());
+        let err = interleaved_pairs(&split, false).unwrap().next().unwrap().unwrap_err();
+        assert_eq!(err.to_string(), format!("{}: records are not interleaved, 'read1' is followed by 'read2'", split));
+    }
+
+    #[test]
+    fn strict_split_pairs_need_matching_mates() {
+        let dir = tempfile::tempdir().unwrap();
+        let r1 = write_file(dir.path(), "r1.fastq", &fastq(&[("read1/1", "ACGT"), ("read2/1", "TTAA")]));
+        let swapped = write_file(dir.path(), "swapped.fastq", &fastq(&[("read1/2", "GGCC"), ("read3/2", "CCGG")]));
+        let err = strict_split_pairs(&r1, &swapped).unwrap().nth(1).unwrap().unwrap_err();
+        assert!(
+            err.to_string().ends_with(&format!("record 2 of {} is 'read2/1' but its mate in {} is 'read3/2'", r1, swapped)),
+            "{}",
+            err
+        );
+
+        let short = write_file(dir.path(), "short.fastq", &fastq(&[("read1/2", "GGCC")]));
+        let err = strict_split_pairs(&r1, &short).unwrap().nth(1).unwrap().unwrap_err();
+        assert_eq!(err.to_string(), format!("{} has more records than {}, which ends after record 1", r1, short));
+    }
+}
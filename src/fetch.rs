@@ -0,0 +1,379 @@
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::color_print::{print_error, print_info, print_success};
+use crate::command::{run_command, CommandOptions};
+use crate::demultiplex::{absolute_path, copy_gzipped, read_text_file};
+use crate::logger::log_action;
+use crate::pipeline::{download_file, verbose_mode};
+use crate::output_dir;
+
+/// ENA's file report endpoint, asked for each run's FASTQ locations.
+pub const ENA_PORTAL_URL: &str = "https://www.ebi.ac.uk/ena/portal/api/filereport";
+
+/// Where `fetch` gets the reads from (`--method`).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FetchMethod {
+    /// sra-tools when `fasterq-dump` is on PATH, otherwise ENA.
+    #[default]
+    Auto,
+    Ena,
+    SraTools,
+}
+
+impl std::str::FromStr for FetchMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(FetchMethod::Auto),
+            "ena" => Ok(FetchMethod::Ena),
+            "sra-tools" | "sra" => Ok(FetchMethod::SraTools),
+            other => Err(format!("expected auto, ena or sra-tools, got '{}'", other)),
+        }
+    }
+}
+
+/// How [`fetch_runs`] downloads the runs.
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    pub method: FetchMethod,
+    /// Directory the FASTQ pairs are written to (`--fastq-dir`).
+    pub fastq_dir: String,
+    /// File report endpoint for the ENA method.
+    pub ena_portal: String,
+    /// Download again even if the pair is already there.
+    pub force: bool,
+    /// Write a QIIME2 manifest of the fetched runs under this name in OUTPUT_DIR.
+    pub manifest: Option<String>,
+}
+
+/// One run to fetch and the name its files get.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunAccession {
+    pub accession: String,
+    pub name: String,
+}
+
+/// SRA, ENA and DDBJ run accessions: SRR, ERR or DRR followed by digits.
+fn is_run_accession(s: &str) -> bool {
+    let prefix = s.get(..3).unwrap_or("").to_ascii_uppercase();
+    matches!(prefix.as_str(), "SRR" | "ERR" | "DRR")
+        && s.len() > 3
+        && s[3..].bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Reads the accessions to fetch. Each argument is a run accession or a file
+/// listing one per line, optionally followed by a tab and the name to give
+/// its files; blank lines and lines starting with `#` are skipped.
+pub fn parse_accessions(args: &[String]) -> Result<Vec<RunAccession>, Box<dyn Error>> {
+    let mut runs = Vec::new();
+    let mut invalid = Vec::new();
+    for arg in args {
+        if Path::new(arg).is_file() {
+            for line in read_text_file(arg)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let mut fields = line.split('\t').map(str::trim);
+                let accession = fields.next().unwrap_or_default();
+                let name = fields.next().filter(|name| !name.is_empty()).map(str::to_string);
+                if is_run_accession(accession) {
+                    let accession = accession.to_uppercase();
+                    let name = name.unwrap_or_else(|| accession.clone());
+                    runs.push(RunAccession { accession, name });
+                } else {
+                    invalid.push(format!("{} (in {})", accession, arg));
+                }
+            }
+        } else if is_run_accession(arg) {
+            runs.push(RunAccession { accession: arg.to_uppercase(), name: arg.to_uppercase() });
+        } else {
+            invalid.push(arg.clone());
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(format!(
+            "Not run accessions (SRR/ERR/DRR followed by digits) or readable files: {}",
+            invalid.join(", ")
+        )
+        .into());
+    }
+    let mut names: Vec<&str> = runs.iter().map(|run| run.name.as_str()).collect();
+    names.sort();
+    let mut duplicates: Vec<&str> = names.windows(2).filter(|w| w[0] == w[1]).map(|w| w[0]).collect();
+    duplicates.dedup();
+    if !duplicates.is_empty() {
+        return Err(format!("Several runs would be written under the same name: {}", duplicates.join(", ")).into());
+    }
+    Ok(runs)
+}
+
+/// Whether `fasterq-dump` can be run.
+fn sra_tools_available() -> bool {
+    let opts = CommandOptions { capture: true, timeout: Some(Duration::from_secs(30)), ..Default::default() };
+    run_command("fasterq-dump", &["--version"], &opts).is_ok_and(|output| output.success)
+}
+
+/// Asks ENA where a run's paired FASTQ files are and returns their HTTPS URLs
+/// (R1, R2). Runs without a `_1`/`_2` pair are reported as not paired-end.
+fn ena_fastq_urls(portal: &str, accession: &str) -> Result<(String, String), Box<dyn Error>> {
+    let client = reqwest::blocking::Client::builder().timeout(Duration::from_secs(60)).build()?;
+    let resp = client
+        .get(portal)
+        .query(&[("accession", accession), ("result", "read_run"), ("fields", "fastq_ftp"), ("format", "tsv")])
+        .send()?;
+    if !resp.status().is_success() {
+        return Err(format!("ENA file report returned {}", resp.status()).into());
+    }
+    let text = resp.text()?;
+    let mut lines = text.lines();
+    let header: Vec<&str> = lines.next().unwrap_or_default().split('\t').collect();
+    let column = header
+        .iter()
+        .position(|&field| field == "fastq_ftp")
+        .ok_or("ENA file report has no fastq_ftp column")?;
+    let files: Vec<String> = lines
+        .filter_map(|line| line.split('\t').nth(column))
+        .flat_map(|field| field.split(';'))
+        .map(str::trim)
+        .filter(|file| !file.is_empty())
+        .map(|file| if file.contains("://") { file.to_string() } else { format!("https://{}", file) })
+        .collect();
+    if files.is_empty() {
+        return Err("ENA lists no FASTQ files for this run".into());
+    }
+    let mate = |suffix: &str| files.iter().find(|file| file.ends_with(suffix)).cloned();
+    match (mate("_1.fastq.gz"), mate("_2.fastq.gz")) {
+        (Some(r1), Some(r2)) => Ok((r1, r2)),
+        _ => Err(format!("not a paired-end run (ENA lists {})", files.join(", ")).into()),
+    }
+}
+
+fn fetch_ena(portal: &str, accession: &str, r1: &str, r2: &str, force: bool) -> Result<(), Box<dyn Error>> {
+    let (url_r1, url_r2) = ena_fastq_urls(portal, accession)?;
+    download_file(&url_r1, r1, force)?;
+    download_file(&url_r2, r2, force)?;
+    Ok(())
+}
+
+/// Dumps a run with `fasterq-dump --split-files` into a scratch directory next
+/// to the outputs, then gzips the two mates into place.
+fn fetch_sra_tools(accession: &str, fastq_dir: &str, r1: &str, r2: &str) -> Result<(), Box<dyn Error>> {
+    let scratch = format!("{}/.fetch-{}", fastq_dir, accession);
+    fs::create_dir_all(&scratch)?;
+    let opts = CommandOptions { verbose: verbose_mode(), ..Default::default() };
+    let result = run_command(
+        "fasterq-dump",
+        &["--split-files", "--outdir", scratch.as_str(), "--temp", scratch.as_str(), accession],
+        &opts,
+    )
+    .and_then(|output| {
+        if !output.success {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("fasterq-dump failed: {}", stderr.trim()).into());
+        }
+        let dumped_r1 = format!("{}/{}_1.fastq", scratch, accession);
+        let dumped_r2 = format!("{}/{}_2.fastq", scratch, accession);
+        if !Path::new(&dumped_r1).exists() || !Path::new(&dumped_r2).exists() {
+            return Err("not a paired-end run (fasterq-dump wrote no _1/_2 pair)".into());
+        }
+        copy_gzipped(&dumped_r1, r1)?;
+        copy_gzipped(&dumped_r2, r2)?;
+        Ok(())
+    });
+    let _ = fs::remove_dir_all(&scratch);
+    result
+}
+
+/// Downloads each run as `{name}_R1_001.fastq.gz` / `{name}_R2_001.fastq.gz`
+/// in `options.fastq_dir`, the layout `demux` looks for, and reports what
+/// happened to each. Fails if any run could not be fetched.
+pub fn fetch_runs(args: &[String], options: &FetchOptions) -> Result<(), Box<dyn Error>> {
+    let runs = parse_accessions(args)?;
+    if runs.is_empty() {
+        return Err("No run accessions to fetch".into());
+    }
+
+    let use_sra_tools = match options.method {
+        FetchMethod::Ena => false,
+        FetchMethod::SraTools => {
+            if !sra_tools_available() {
+                return Err("--method sra-tools needs fasterq-dump on PATH (install sra-tools)".into());
+            }
+            true
+        }
+        FetchMethod::Auto => sra_tools_available(),
+    };
+    let source = if use_sra_tools { "fasterq-dump" } else { "ENA" };
+    print_info(&format!("Fetching {} run(s) into '{}' with {}.", runs.len(), options.fastq_dir, source));
+    log_action(&format!("Fetching {} run(s) into '{}' with {}.", runs.len(), options.fastq_dir, source));
+    fs::create_dir_all(&options.fastq_dir)?;
+
+    // (run, R1, R2, error)
+    let mut results: Vec<(&RunAccession, String, String, Option<String>)> = Vec::new();
+    for run in &runs {
+        let r1 = format!("{}/{}_R1_001.fastq.gz", options.fastq_dir, run.name);
+        let r2 = format!("{}/{}_R2_001.fastq.gz", options.fastq_dir, run.name);
+        let fetched = if !options.force && Path::new(&r1).exists() && Path::new(&r2).exists() {
+            print_info(&format!("{}: '{}' and '{}' already exist, skipping.", run.accession, r1, r2));
+            Ok(())
+        } else if use_sra_tools {
+            fetch_sra_tools(&run.accession, &options.fastq_dir, &r1, &r2)
+        } else {
+            fetch_ena(&options.ena_portal, &run.accession, &r1, &r2, options.force)
+        };
+        match fetched {
+            Ok(()) => {
+                log_action(&format!("Fetched {} to '{}' and '{}'.", run.accession, r1, r2));
+                results.push((run, r1, r2, None));
+            }
+            Err(e) => {
+                // Don't leave half a pair behind for demux to pick up
+                let _ = fs::remove_file(&r1);
+                let _ = fs::remove_file(&r2);
+                print_error(&format!("{}: {}", run.accession, e));
+                log_action(&format!("Failed to fetch {}: {}", run.accession, e));
+                results.push((run, r1, r2, Some(e.to_string())));
+            }
+        }
+    }
+
+    println!("{:<14} {:<24} Status", "Accession", "Name");
+    for (run, _, _, error) in &results {
+        let status = match error {
+            None => "fetched".to_string(),
+            Some(e) => format!("FAILED: {}", e),
+        };
+        println!("{:<14} {:<24} {}", run.accession, run.name, status);
+    }
+
+    if let Some(manifest) = &options.manifest {
+        let path = format!("{}/{}", output_dir(), manifest);
+        let mut writer = File::create(&path)?;
+        writeln!(writer, "sample-id\tforward-absolute-filepath\treverse-absolute-filepath")?;
+        for (run, r1, r2, error) in &results {
+            if error.is_none() {
+                writeln!(
+                    writer,
+                    "{}\t{}\t{}",
+                    run.name,
+                    absolute_path(r1)?.display(),
+                    absolute_path(r2)?.display()
+                )?;
+            }
+        }
+        print_success(&format!("Wrote manifest '{}'.", path));
+    }
+
+    let failed = results.iter().filter(|(_, _, _, error)| error.is_some()).count();
+    if failed > 0 {
+        return Err(format!("{} of {} run(s) could not be fetched", failed, results.len()).into());
+    }
+    print_success(&format!("Fetched {} run(s).", results.len()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, http_response, request_path, write_file, MockServer};
+
+    /// `Host` of an HTTP request head, so the mocked file report can point
+    /// back at the same server.
+    fn host(head: &str) -> &str {
+        head.lines()
+            .find_map(|line| line.split_once(':').filter(|(name, _)| name.eq_ignore_ascii_case("host")))
+            .map_or("", |(_, value)| value.trim())
+    }
+
+    /// Mocked ENA: the file report lists a `_1`/`_2` pair for every run but
+    /// SRR3, which is single-end; each FASTQ's body names its file.
+    fn mock_ena() -> MockServer {
+        MockServer::start(|head| {
+            let path = request_path(head);
+            let body = if let Some(query) = path.strip_prefix("/filereport?") {
+                let accession = query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("accession="))
+                    .unwrap_or_default();
+                let base = format!("http://{}/fastq/{}", host(head), accession);
+                let files = if accession == "SRR3" {
+                    format!("{}.fastq.gz", base)
+                } else {
+                    format!("{0}_1.fastq.gz;{0}_2.fastq.gz", base)
+                };
+                format!("run_accession\tfastq_ftp\n{}\t{}\n", accession, files)
+            } else {
+                format!("reads of {}", path)
+            };
+            http_response("200 OK", &[("Content-Length", &body.len().to_string())], body.as_bytes())
+        })
+    }
+
+    #[test]
+    fn runs_fetched_from_ena_land_under_the_demux_names() {
+        let _state = test_support::global_state();
+        let server = mock_ena();
+        let dir = tempfile::tempdir().unwrap();
+        let list = write_file(dir.path(), "runs.txt", "# study runs\nSRR1\tsoil\n\nerr2\n");
+        let fastq_dir = dir.path().join("reads").to_string_lossy().into_owned();
+        let options = FetchOptions {
+            method: FetchMethod::Ena,
+            fastq_dir: fastq_dir.clone(),
+            ena_portal: format!("{}/filereport", server.url),
+            force: false,
+            manifest: Some("fetched.tsv".to_string()),
+        };
+
+        fetch_runs(std::slice::from_ref(&list), &options).unwrap();
+
+        let read = |name: &str| fs::read_to_string(format!("{}/{}", fastq_dir, name)).unwrap();
+        assert_eq!(read("soil_R1_001.fastq.gz"), "reads of /fastq/SRR1_1.fastq.gz");
+        assert_eq!(read("soil_R2_001.fastq.gz"), "reads of /fastq/SRR1_2.fastq.gz");
+        assert_eq!(read("ERR2_R1_001.fastq.gz"), "reads of /fastq/ERR2_1.fastq.gz");
+        assert_eq!(read("ERR2_R2_001.fastq.gz"), "reads of /fastq/ERR2_2.fastq.gz");
+        let manifest = fs::read_to_string(test_support::output_dir().join("fetched.tsv")).unwrap();
+        let samples: Vec<&str> = manifest.lines().skip(1).map(|line| line.split('\t').next().unwrap()).collect();
+        assert_eq!(samples, ["soil", "ERR2"]);
+
+        // Existing pairs are kept without asking ENA again
+        let requests = server.request_lines().len();
+        fetch_runs(&[list], &options).unwrap();
+        assert_eq!(server.request_lines().len(), requests);
+    }
+
+    #[test]
+    fn a_single_end_run_fails_without_leaving_files() {
+        let _state = test_support::global_state();
+        let server = mock_ena();
+        let dir = tempfile::tempdir().unwrap();
+        let fastq_dir = dir.path().to_string_lossy().into_owned();
+        let options = FetchOptions {
+            method: FetchMethod::Ena,
+            fastq_dir: fastq_dir.clone(),
+            ena_portal: format!("{}/filereport", server.url),
+            force: false,
+            manifest: None,
+        };
+
+        let err = fetch_runs(&["SRR1".to_string(), "SRR3".to_string()], &options).unwrap_err();
+
+        assert_eq!(err.to_string(), "1 of 2 run(s) could not be fetched");
+        assert!(Path::new(&format!("{}/SRR1_R2_001.fastq.gz", fastq_dir)).exists());
+        assert!(!Path::new(&format!("{}/SRR3_R1_001.fastq.gz", fastq_dir)).exists());
+        assert!(server.request_lines().iter().all(|line| !line.contains("/fastq/SRR3")));
+    }
+
+    #[test]
+    fn accessions_are_validated() {
+        let err = parse_accessions(&["SRR12".to_string(), "GSM123".to_string()]).unwrap_err();
+        assert!(err.to_string().ends_with(": GSM123"), "{}", err);
+        let err = parse_accessions(&["SRR1".to_string(), "srr1".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("same name: SRR1"), "{}", err);
+    }
+}
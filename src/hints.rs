@@ -0,0 +1,76 @@
+use std::path::Path;
+
+/// Words new users type for a subcommand, and the subcommand they mean.
+const NEAR_MISSES: [(&str, &str); 16] = [
+    ("run", "run-all"),
+    ("all", "run-all"),
+    ("runall", "run-all"),
+    ("start", "run-all"),
+    ("install", "install-env"),
+    ("setup", "install-env"),
+    ("env", "install-env"),
+    ("demultiplex", "demux"),
+    ("download", "download-d-bs"),
+    ("download-dbs", "download-d-bs"),
+    ("db", "download-d-bs"),
+    ("dbs", "download-d-bs"),
+    ("taxonomy", "classify"),
+    ("manifest", "validate-manifest"),
+    ("init", "wizard"),
+    ("guide", "wizard"),
+];
+
+/// What to print after clap rejects `word` as a subcommand: the subcommand
+/// it most likely stands for, if it's a known near-miss, and the wizard.
+pub fn unknown_subcommand_hint(word: &str) -> String {
+    let wizard = "New to Windchime? `windchime wizard` walks through setup, demultiplexing and the pipeline.";
+    match NEAR_MISSES.iter().find(|(typo, _)| typo.eq_ignore_ascii_case(word)) {
+        Some((_, subcommand)) => format!(
+            "'{}' is not a subcommand; did you mean `windchime {}`? See `windchime {} --help`. {}",
+            word, subcommand, subcommand, wizard
+        ),
+        None => format!("Run `windchime --help` for the list of subcommands. {}", wizard),
+    }
+}
+
+/// A hint for `pipeline` run without its manifest at `manifest`, which it
+/// needs before anything else can happen.
+pub fn missing_manifest_hint(manifest: &str) -> Option<String> {
+    if Path::new(manifest).is_file() {
+        return None;
+    }
+    Some(format!(
+        "No QIIME2 manifest at '{}'. `windchime pipeline` starts from already demultiplexed reads: \
+         point --manifest at your manifest, start from raw reads and a barcodes file with \
+         `windchime run-all --barcodes-file <file>`, or run `windchime wizard` to be guided through it.",
+        manifest
+    ))
+}
+
+/// A hint for `run-all` when none of its barcodes files exist (and it isn't
+/// continuing from a manifest).
+pub fn missing_barcodes_hint(barcodes_files: &[String]) -> Option<String> {
+    if barcodes_files.iter().any(|path| Path::new(path).exists()) {
+        return None;
+    }
+    Some(format!(
+        "No barcodes file found ({}). `windchime run-all` needs one (`--barcodes-file <file>`, repeatable) \
+         to demultiplex raw reads; use `--continue-from-manifest <manifest>` for reads that are already \
+         demultiplexed, or run `windchime wizard` to be guided through it.",
+        barcodes_files.join(", ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn near_misses_name_the_subcommand_they_stand_for() {
+        let hint = unknown_subcommand_hint("Run");
+        assert!(hint.starts_with("'Run' is not a subcommand; did you mean `windchime run-all`?"), "{}", hint);
+        assert!(hint.contains("`windchime wizard`"));
+        let other = unknown_subcommand_hint("frobnicate");
+        assert!(other.starts_with("Run `windchime --help` for the list of subcommands."), "{}", other);
+    }
+}
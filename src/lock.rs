@@ -0,0 +1,166 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::color_print::{print_error, print_info};
+use crate::output_dir;
+
+/// How often `--wait` checks whether the other run has finished.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether this process holds the output directory lock.
+static HELD: AtomicBool = AtomicBool::new(false);
+
+fn lock_path() -> String {
+    format!("{}/.lock", output_dir())
+}
+
+/// The process recorded in a lock file.
+struct LockOwner {
+    pid: Option<u32>,
+    since: String,
+}
+
+impl LockOwner {
+    fn read(path: &str) -> Self {
+        let contents = fs::read_to_string(path).unwrap_or_default();
+        let mut fields = contents.split_whitespace();
+        LockOwner {
+            pid: fields.next().and_then(|pid| pid.parse().ok()),
+            since: fields.next().unwrap_or("an unknown time").to_string(),
+        }
+    }
+
+    /// `false` only when the owner is known to have exited (its PID is gone
+    /// from `/proc`); without `/proc` the owner is assumed to be running.
+    fn is_running(&self) -> bool {
+        match self.pid {
+            Some(pid) if Path::new("/proc/self").exists() => Path::new(&format!("/proc/{}", pid)).exists(),
+            _ => true,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.pid {
+            Some(pid) => format!("process {} (started {})", pid, self.since),
+            None => format!("another process (started {})", self.since),
+        }
+    }
+}
+
+fn try_create(path: &str) -> io::Result<bool> {
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(mut file) => {
+            writeln!(file, "{}\t{}", std::process::id(), Utc::now().to_rfc3339())?;
+            Ok(true)
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Takes `OUTPUT_DIR/.lock` so two runs can't clobber each other's outputs.
+/// If another run holds it, fails, or with `wait` polls until it's released.
+/// `force_unlock` first removes an existing lock, e.g. one left behind by a
+/// run that was killed.
+pub fn acquire(wait: bool, force_unlock: bool) -> Result<(), String> {
+    let path = lock_path();
+    if force_unlock && Path::new(&path).exists() {
+        let owner = LockOwner::read(&path);
+        print_info(&format!("Removing the output directory lock held by {}.", owner.describe()));
+        fs::remove_file(&path).map_err(|e| format!("Could not remove {}: {}", path, e))?;
+    }
+    let mut announced = false;
+    loop {
+        if try_create(&path).map_err(|e| format!("Could not create {}: {}", path, e))? {
+            HELD.store(true, Ordering::SeqCst);
+            return Ok(());
+        }
+        let owner = LockOwner::read(&path);
+        if !owner.is_running() {
+            return Err(format!(
+                "{} is locked by {}, which is no longer running; rerun with --force-unlock to remove the stale lock",
+                output_dir(),
+                owner.describe()
+            ));
+        }
+        if !wait {
+            return Err(format!(
+                "{} is in use by {}; wait for it to finish, rerun with --wait, or use --force-unlock if it is stale",
+                output_dir(),
+                owner.describe()
+            ));
+        }
+        if !announced {
+            print_info(&format!("Waiting for {} to release {}...", owner.describe(), output_dir()));
+            announced = true;
+        }
+        thread::sleep(WAIT_POLL_INTERVAL);
+    }
+}
+
+/// Removes the lock if this process holds it. Safe to call more than once.
+pub fn release() {
+    if HELD.swap(false, Ordering::SeqCst)
+        && let Err(e) = fs::remove_file(lock_path())
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        print_error(&format!("Could not remove {}: {}", lock_path(), e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn a_lock_file_can_only_be_created_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".lock").to_string_lossy().into_owned();
+        assert!(try_create(&path).unwrap());
+        assert!(!try_create(&path).unwrap());
+        let owner = LockOwner::read(&path);
+        assert_eq!(owner.pid, Some(std::process::id()));
+        assert!(owner.is_running());
+    }
+
+    #[test]
+    fn a_second_run_against_a_locked_dir_is_rejected() {
+        let _state = test_support::global_state();
+        acquire(false, false).unwrap();
+        assert!(Path::new(&lock_path()).exists());
+
+        // This process still holds the lock, so it counts as a live second run
+        let err = acquire(false, false).unwrap_err();
+        assert!(err.contains(&format!("is in use by process {}", std::process::id())), "{}", err);
+        assert!(err.contains("--wait"), "{}", err);
+
+        release();
+        assert!(!Path::new(&lock_path()).exists());
+        acquire(false, false).unwrap();
+        release();
+    }
+
+    #[test]
+    fn a_stale_lock_needs_force_unlock() {
+        let _state = test_support::global_state();
+        if !Path::new("/proc/self").exists() {
+            return;
+        }
+        // PIDs are capped well below u32::MAX, so this process can't exist
+        fs::write(lock_path(), "4294967295\t2026-01-01T00:00:00+00:00\n").unwrap();
+        let err = acquire(false, false).unwrap_err();
+        assert!(err.contains("process 4294967295 (started 2026-01-01T00:00:00+00:00)"), "{}", err);
+        assert!(err.contains("no longer running") && err.contains("--force-unlock"), "{}", err);
+
+        acquire(false, true).unwrap();
+        assert_eq!(LockOwner::read(&lock_path()).pid, Some(std::process::id()));
+        release();
+    }
+}
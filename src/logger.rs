@@ -3,27 +3,123 @@ use std::io::Write;
 use std::sync::Mutex;
 use once_cell::sync::Lazy;
 use chrono::Utc;
-use crate::OUTPUT_DIR;
+use crate::output_dir;
 
 /// A global mutex-guarded log file handle.
 static LOG_FILE: Lazy<Mutex<Option<std::fs::File>>> = Lazy::new(|| Mutex::new(None));
 
-/// Initialize the log file in append mode inside OUTPUT_DIR/windchime.log
-pub fn init_log() {
-    let log_path = format!("{}/windchime.log", OUTPUT_DIR);
+/// The `--json-logs` file handle, if JSON logging is on.
+static JSON_LOG_FILE: Lazy<Mutex<Option<std::fs::File>>> = Lazy::new(|| Mutex::new(None));
+
+/// Severity of a log entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+impl LogLevel {
+    /// The level's name in `windchime.jsonl`.
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Info => "info",
+            LogLevel::Warning => "warning",
+            LogLevel::Error => "error",
+        }
+    }
+}
+
+/// Initialize the log file in append mode inside OUTPUT_DIR/windchime.log,
+/// and with `json_logs` also OUTPUT_DIR/windchime.jsonl (otherwise any JSON
+/// log opened before is closed).
+pub fn init_log(json_logs: bool) {
+    let log_path = format!("{}/windchime.log", output_dir());
     if let Ok(file) = OpenOptions::new().create(true).append(true).open(log_path) {
         let mut guard = LOG_FILE.lock().unwrap();
         *guard = Some(file);
     } else {
         eprintln!("Warning: failed to open windchime.log for logging.");
     }
+    if json_logs {
+        let json_path = format!("{}/windchime.jsonl", output_dir());
+        if let Ok(file) = OpenOptions::new().create(true).append(true).open(json_path) {
+            let mut guard = JSON_LOG_FILE.lock().unwrap();
+            *guard = Some(file);
+        } else {
+            eprintln!("Warning: failed to open windchime.jsonl for logging.");
+        }
+    } else {
+        *JSON_LOG_FILE.lock().unwrap() = None;
+    }
 }
 
-/// Append a line to the log file.
-pub fn log_action(action: &str) {
-    let mut guard = LOG_FILE.lock().unwrap();
+/// Append an entry to the log file and, with `--json-logs`, a JSON object
+/// with `timestamp`, `level`, `message` and `step` (null outside a pipeline
+/// step) to windchime.jsonl. The text log has no level; warnings already
+/// say so in their message.
+pub fn log_event(level: LogLevel, step: Option<&str>, msg: &str) {
+    let timestamp = Utc::now().to_rfc3339();
+    {
+        let mut guard = LOG_FILE.lock().unwrap();
+        if let Some(ref mut file) = *guard {
+            let _ = writeln!(file, "[{}] {}", timestamp, msg);
+        }
+    }
+    let mut guard = JSON_LOG_FILE.lock().unwrap();
     if let Some(ref mut file) = *guard {
-        let timestamp = Utc::now();
-        let _ = writeln!(file, "[{}] {}", timestamp.to_rfc3339(), action);
+        let entry = serde_json::json!({
+            "timestamp": timestamp,
+            "level": level.name(),
+            "message": msg,
+            "step": step,
+        });
+        let _ = writeln!(file, "{}", entry);
+    }
+}
+
+/// Append a line to the log file, at info level and outside any step.
+pub fn log_action(action: &str) {
+    log_event(LogLevel::Info, None, action);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::fs;
+
+    #[test]
+    fn json_logs_mirror_the_text_log_with_level_and_step() {
+        let _state = test_support::global_state();
+        init_log(true);
+        log_action("Starting Windchime");
+        log_event(LogLevel::Warning, Some("Trimming reads with Cutadapt"), "Warning: few reads kept");
+        log_event(LogLevel::Error, None, "Application error: \"quoted\"\nsecond line");
+        init_log(false);
+        log_action("Not in the JSON log");
+
+        let text = fs::read_to_string(test_support::output_dir().join("windchime.log")).unwrap();
+        let text: Vec<&str> = text.lines().collect();
+        assert_eq!(text.len(), 5);
+        assert!(text[0].starts_with('[') && text[0].ends_with("] Starting Windchime"), "{}", text[0]);
+        assert!(text[4].ends_with("] Not in the JSON log"));
+
+        let json = fs::read_to_string(test_support::output_dir().join("windchime.jsonl")).unwrap();
+        let entries: Vec<serde_json::Value> = json.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(entries.len(), 3, "{}", json);
+        let fields = |entry: &serde_json::Value| (entry["level"].clone(), entry["step"].clone(), entry["message"].clone());
+        assert_eq!(fields(&entries[0]), ("info".into(), serde_json::Value::Null, "Starting Windchime".into()));
+        assert_eq!(
+            fields(&entries[1]),
+            ("warning".into(), "Trimming reads with Cutadapt".into(), "Warning: few reads kept".into())
+        );
+        // Messages are escaped, so a multi-line error stays on one JSON line
+        assert_eq!(entries[2]["message"], "Application error: \"quoted\"\nsecond line");
+        for (entry, line) in entries.iter().zip(&text) {
+            let timestamp = entry["timestamp"].as_str().unwrap();
+            assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok());
+            assert!(line.starts_with(&format!("[{}] ", timestamp)), "{}", line);
+        }
     }
 }
@@ -4,21 +4,89 @@ mod wizard;
 mod config;
 mod color_print;
 mod logger;
+mod qiime_compat;
+mod summary;
+mod estimate;
+mod ordered_writer;
+mod bundle;
+mod taxonomy;
+mod memory;
+mod primers;
+mod reference;
+mod qc_report;
+mod biom;
+mod progress;
+mod command;
+mod audit;
+mod updates;
+mod conda;
+mod annotate;
+mod fastq_io;
+mod redo;
+mod cleanup;
+mod lock;
+mod preflight;
+mod fetch;
+mod merge_qc;
+mod archive;
+mod status;
+mod replicates;
+mod peek;
+mod hints;
+mod verify;
+#[cfg(test)]
+mod test_support;
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use once_cell::sync::OnceCell;
 use std::process;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::error::Error;
 use std::fs;
 
+use cleanup::CleanupProfile;
+use conda::CondaSolver;
 use config::WindchimeConfig;
-use logger::{init_log, log_action};
-use color_print::{print_info, print_success, print_error};
+use demultiplex::{DemuxOptions, SampleIdTemplate, Since};
+use fetch::{FetchMethod, FetchOptions};
+use pipeline::{AdapterMode, Database, DbComponent, DenoiseMode, PhredOffset, PipelineOptions};
+use logger::{init_log, log_action, log_event, LogLevel};
+use color_print::{print_info, print_success, print_error, ColorChoice};
 
 /// GLOBAL VERBOSE FLAG: true = print commands verbosely, false = use progress bars.
 static VERBOSE_MODE: AtomicBool = AtomicBool::new(false);
 
-/// OUTPUT DIRECTORY for all generated files.
-pub const OUTPUT_DIR: &str = "windchime_out";
+/// GLOBAL DRY-RUN FLAG: true = print conda commands instead of running them.
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// GLOBAL SKIP-CHECKSUM FLAG: true = don't compare downloads with their known SHA-256.
+static SKIP_CHECKSUM: AtomicBool = AtomicBool::new(false);
+
+/// GLOBAL SINGLE-END FLAG: true = reads are single-end (R1 only), not paired.
+static SINGLE_END: AtomicBool = AtomicBool::new(false);
+
+/// GLOBAL DOWNLOAD RETRIES: how often a failed download is retried.
+static DOWNLOAD_RETRIES: AtomicUsize = AtomicUsize::new(3);
+
+/// OUTPUT DIRECTORY for all generated files (`--output-dir`), set once at startup.
+static OUTPUT_DIR: OnceCell<String> = OnceCell::new();
+
+/// Output directory used when `--output-dir` isn't given.
+const DEFAULT_OUTPUT_DIR: &str = "windchime_out";
+
+/// The directory all generated files go to: `--output-dir`, or `windchime_out`.
+pub fn output_dir() -> &'static str {
+    OUTPUT_DIR.get().map_or(DEFAULT_OUTPUT_DIR, String::as_str)
+}
+
+/// Parses `--output-dir`, dropping trailing slashes so paths join cleanly.
+fn parse_output_dir(arg: &str) -> Result<String, String> {
+    let dir = arg.trim_end_matches('/');
+    if dir.is_empty() {
+        return Err("must name a directory other than '/'".to_string());
+    }
+    Ok(dir.to_string())
+}
 
 /// CLI definition using Clap.
 #[derive(Parser, Debug)]
@@ -28,10 +96,63 @@ struct Cli {
     #[arg(short, long, global = true)]
     verbose: bool,
 
+    /// Print every conda command the run would execute, in order, without running them
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Retry a failed download this many times, with exponential backoff, resuming where it stopped
+    #[arg(long, global = true, default_value_t = 3, value_name = "N")]
+    download_retries: usize,
+
+    /// Don't compare downloaded reference databases with their known SHA-256 digests (e.g. for mirrors)
+    #[arg(long, global = true)]
+    skip_checksum: bool,
+
+    /// Reads are single-end: demultiplex and import R1 only, trim only the forward
+    /// primer and denoise with `dada2 denoise-single`
+    #[arg(long, global = true)]
+    single_end: bool,
+
+    /// Directory all outputs, logs and reference databases are written to; give each
+    /// analysis its own to run several in one working directory
+    #[arg(long, global = true, default_value = DEFAULT_OUTPUT_DIR, value_name = "DIR", value_parser = parse_output_dir)]
+    output_dir: String,
+
+    /// Also log to windchime.jsonl in the output directory, one JSON object per line, for log aggregators
+    #[arg(long, global = true)]
+    json_logs: bool,
+
     /// Optional path to a config file (TOML). If provided, default settings are loaded from there.
     #[arg(long)]
     config: Option<String>,
 
+    /// On failure, write a windchime_failure_<timestamp>.zip with the log, manifest,
+    /// failing step's stderr and effective config for bug reports
+    #[arg(long, global = true)]
+    bundle_on_error: bool,
+
+    /// When to color output: `auto` (only on a terminal, honouring NO_COLOR), `always`, or `never`
+    #[arg(long, global = true, default_value = "auto", value_name = "always|auto|never")]
+    color: ColorChoice,
+
+    /// If another run holds the output directory, wait for it to finish instead of failing
+    #[arg(long, global = true)]
+    wait: bool,
+
+    /// Remove an existing output directory lock first, e.g. one left by a killed run
+    #[arg(long, global = true)]
+    force_unlock: bool,
+
+    /// Conda binary to use. By default `conda` on PATH, else $CONDA_EXE or a
+    /// usual install location (~/miniconda3, ~/anaconda3, ~/miniforge3, /opt/conda)
+    #[arg(long, global = true, value_name = "PATH")]
+    conda_path: Option<String>,
+
+    /// Solver for creating the QIIME2 environment: `auto` (conda's setting, retried
+    /// with libmamba if it fails to solve), `classic` or `libmamba`
+    #[arg(long, global = true, default_value = "auto", value_name = "auto|classic|libmamba")]
+    solver: CondaSolver,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -43,87 +164,749 @@ enum Commands {
         /// Name of the conda environment to install
         #[arg(short, long, default_value = "qiime2-amplicon-2024.10")]
         env_name: String,
+
+        /// Ask the QIIME2 distribution index whether a newer release exists (needs network access).
+        #[arg(long, default_value_t = false)]
+        check_updates: bool,
     },
-    /// Run demultiplexing using a barcodes file.
+    /// Run demultiplexing using one or more barcodes files.
     Demux {
-        /// Path to the barcodes file for demultiplexing.
-        barcodes_file: String,
+        /// Barcodes file(s) for demultiplexing, one per lane, or directories of `.tsv` barcodes files.
+        #[arg(required = true, num_args = 1..)]
+        barcodes_files: Vec<String>,
 
         /// Whether to skip if demultiplexed output already exists
         #[arg(long, default_value_t = false)]
         skip_existing: bool,
+
+        /// Only count how many of the first PAIRS read pairs of each input match each sample, writing nothing.
+        #[arg(long, value_name = "PAIRS", num_args = 0..=1, default_missing_value = "10000")]
+        preview: Option<usize>,
+
+        #[command(flatten)]
+        demux: DemuxArgs,
     },
     /// Execute only Steps 2–7 of the pipeline, optionally skipping existing outputs.
     Pipeline {
+        #[command(flatten)]
+        pipeline: PipelineArgs,
+    },
+    /// Trim primers from an existing demultiplexed artifact with Cutadapt (pipeline Step 3 only).
+    Trim {
         #[arg(short, long, default_value = "qiime2-amplicon-2024.10")]
         env_name: String,
 
-        /// QIIME2 manifest file.
-        #[arg(short, long, default_value = "manifest.tsv")]
-        manifest: String,
+        /// Demultiplexed SampleData[PairedEndSequencesWithQuality] artifact (.qza), or
+        /// SampleData[SequencesWithQuality] with --single-end.
+        #[arg(short, long)]
+        input: String,
+
+        /// Output artifact name within the output directory; the summary is written alongside as .qzv.
+        #[arg(short, long, default_value = "paired-end-demux-trimmed.qza")]
+        output: String,
+
+        /// Target region (16s, 18sv4, 18sv9, or its).
+        #[arg(short, long, default_value = "18sv9")]
+        target: String,
 
-        /// Number of CPU cores to use.
+        /// How primers are passed to Cutadapt: `linked`, `regular`, or `anchored`.
+        #[arg(long, default_value = "linked", value_name = "linked|regular|anchored")]
+        adapter_mode: AdapterMode,
+
+        /// Cutadapt's maximum error rate when matching primers, between 0 and 1.
+        #[arg(long, default_value_t = 0.1, value_name = "RATE", value_parser = parse_error_rate)]
+        cutadapt_error_rate: f64,
+
+        /// Fewest bases of a primer Cutadapt must find before trimming it.
+        #[arg(long, default_value_t = 3, value_name = "BASES")]
+        cutadapt_overlap: usize,
+
+        /// Number of CPU cores to use (0 = all available).
         #[arg(long, default_value_t = 1)]
         cores: usize,
 
-        /// Target region (16s, 18sv4, or 18sv9).
+        /// Skip trimming if the output artifact and summary already exist.
+        #[arg(long, default_value_t = false)]
+        skip_existing: bool,
+
+        /// Reads `demux summarize` subsamples for the quality plots.
+        #[arg(long, default_value_t = 100_000, value_name = "COUNT")]
+        summarize_n: usize,
+    },
+    /// Classify an existing representative sequences artifact (pipeline Step 6 only),
+    /// optionally merging the taxonomy with a feature table.
+    Classify {
+        #[arg(short, long, default_value = "qiime2-amplicon-2024.10")]
+        env_name: String,
+
+        /// Representative sequences artifact (FeatureData[Sequence]), e.g. rep-seqs-dada2.qza.
+        #[arg(long)]
+        rep_seqs: String,
+
+        /// Feature table artifact (FeatureTable[Frequency]) to export and merge with the taxonomy.
+        #[arg(long)]
+        table: Option<String>,
+
+        /// Target region (16s, 18sv4, 18sv9, or its); selects the primers used to train a classifier.
         #[arg(short, long, default_value = "18sv9")]
         target: String,
 
-        /// Skip pipeline steps if expected outputs already exist.
+        /// Use this trained classifier artifact (.qza) instead of downloading or training one.
+        #[arg(long)]
+        classifier: Option<String>,
+
+        /// Use a pre-trained classifier instead of training from the reference database;
+        /// `--use-pretrained-classifier false` trains one.
+        #[arg(long, default_value_t = true, num_args = 0..=1, default_missing_value = "true",
+              action = clap::ArgAction::Set, value_name = "BOOL")]
+        use_pretrained_classifier: bool,
+
+        /// Reference database to download a classifier for or train one on: `pr2`, `silva` or
+        /// `unite` (default: `unite` for `--target its`, `pr2` otherwise).
+        #[arg(long, value_name = "pr2|silva|unite")]
+        database: Option<Database>,
+
+        /// Reads classify-sklearn holds in memory at once (default: derived from available memory).
+        #[arg(long, value_name = "N")]
+        reads_per_batch: Option<usize>,
+
+        /// Number of CPU cores to use (0 = all available).
+        #[arg(long, default_value_t = 1)]
+        cores: usize,
+
+        /// Skip steps whose outputs already exist.
         #[arg(long, default_value_t = false)]
         skip_existing: bool,
 
-        /// Use a pre-trained classifier instead of training from PR2 references.
-        #[arg(long, default_value_t = true)]
-        use_pretrained_classifier: bool,
+        /// Fail the merge step on a malformed ASV or taxonomy table row instead of skipping it.
+        #[arg(long, default_value_t = false)]
+        strict_merge: bool,
+
+        /// Fail instead of warning when the target region and reference database don't match.
+        #[arg(long, default_value_t = false)]
+        strict_reference: bool,
+
+        /// Add a `Confidence_<rank>` column per taxonomic rank to the merged table.
+        #[arg(long, default_value_t = false)]
+        confidence_by_rank: bool,
     },
-    /// Single command: install env if needed, demultiplex, generate manifest, download DBs, pipeline
-    RunAll {
+    /// Import a manifest and run `qiime tools validate` on it, without running the pipeline.
+    ValidateManifest {
         #[arg(short, long, default_value = "qiime2-amplicon-2024.10")]
         env_name: String,
 
-        /// Path to the barcodes file for demultiplexing.
-        #[arg(long, default_value = "barcodes.tsv")]
-        barcodes_file: String,
-
         /// QIIME2 manifest file.
         #[arg(short, long, default_value = "manifest.tsv")]
         manifest: String,
 
-        /// Number of CPU cores to use.
-        #[arg(long, default_value_t = 1)]
-        cores: usize,
-
-        /// Target region (16s, 18sv4, or 18sv9).
-        #[arg(short, long, default_value = "18sv9")]
-        target: String,
+        /// Quality score offset of the FASTQs: 33 or 64.
+        #[arg(long, default_value = "33", value_name = "33|64")]
+        phred: PhredOffset,
+    },
+    /// Single command: install env if needed, demultiplex, generate manifest, download DBs, pipeline
+    RunAll {
+        /// Barcodes file for demultiplexing; repeat for several lanes, or pass a directory of `.tsv` files.
+        #[arg(long = "barcodes-file", default_value = "barcodes.tsv")]
+        barcodes_files: Vec<String>,
 
-        /// Skip pipeline steps if expected outputs already exist.
+        /// Fail if the downloaded reference FASTA and taxonomy don't list the same IDs, instead of warning.
         #[arg(long, default_value_t = false)]
-        skip_existing: bool,
+        strict_db: bool,
 
-        /// Use a pre-trained classifier instead of training from PR2 references.
-        #[arg(long, default_value_t = true)]
-        use_pretrained_classifier: bool,
+        /// Skip demultiplexing and manifest generation and run the pipeline on this existing manifest.
+        #[arg(long, value_name = "PATH")]
+        continue_from_manifest: Option<String>,
+
+        /// Start without asking for confirmation after the preflight summary.
+        #[arg(short = 'y', long, default_value_t = false)]
+        assume_yes: bool,
+
+        #[command(flatten)]
+        pipeline: PipelineArgs,
+
+        #[command(flatten)]
+        demux: DemuxArgs,
     },
     /// Download the database files (and unzip them if needed).
     DownloadDBs {
         /// Force re-download and unzip even if the files already exist.
         #[arg(short, long, default_value_t = false)]
         force: bool,
+
+        /// Fail if the reference FASTA and taxonomy don't list the same IDs, instead of warning.
+        #[arg(long, default_value_t = false)]
+        strict_db: bool,
+
+        /// Download (or with --force, refresh) only this component: `fasta`, `taxonomy`, or `all`.
+        #[arg(long, default_value = "all", value_name = "fasta|taxonomy|all")]
+        only: DbComponent,
+
+        /// Reference database to download: `pr2`, `silva` or `unite`.
+        #[arg(long, default_value = "pr2", value_name = "pr2|silva|unite")]
+        database: Database,
+
+        /// UNITE QIIME release (`.tgz`) to install into db/unite, as a file or an http(s) URL. Needs `--database unite`.
+        #[arg(long, value_name = "PATH|URL")]
+        unite_archive: Option<String>,
+    },
+    /// Download SRA runs by accession as `{name}_R1_001.fastq.gz` / `{name}_R2_001.fastq.gz` pairs.
+    Fetch {
+        /// Run accessions (SRR/ERR/DRR), or files listing one per line with an optional tab-separated name.
+        #[arg(required = true, num_args = 1..)]
+        accessions: Vec<String>,
+
+        /// Directory to write the FASTQ pairs to. The log, lock and `--manifest` still go to --output-dir.
+        #[arg(long, default_value = ".", value_name = "DIR")]
+        fastq_dir: String,
+
+        /// Download with `auto` (sra-tools if installed, otherwise ENA), `ena`, or `sra-tools`.
+        #[arg(long, default_value = "auto", value_name = "auto|ena|sra-tools")]
+        method: FetchMethod,
+
+        /// ENA file report endpoint asked for each run's FASTQ URLs.
+        #[arg(long, default_value = fetch::ENA_PORTAL_URL, value_name = "URL")]
+        ena_portal: String,
+
+        /// Download again even if a run's files already exist.
+        #[arg(short, long, default_value_t = false)]
+        force: bool,
+
+        /// Also write a QIIME2 manifest of the fetched runs under this name in the output directory.
+        #[arg(long, value_name = "NAME")]
+        manifest: Option<String>,
+    },
+    /// List the pipeline's expected outputs, whether each exists and is valid, and the next step to run.
+    Status {
+        #[command(flatten)]
+        pipeline: PipelineArgs,
+    },
+    /// Pack the output directory into a gzipped tarball with a listing of its contents.
+    Archive {
+        /// Path of the tarball to write.
+        #[arg(short, long, default_value = "windchime_out.tar.gz")]
+        output: String,
+
+        /// Leave out `.qza` artifacts, FASTQs, reference databases and parameter records.
+        #[arg(long, default_value_t = false)]
+        exclude_intermediates: bool,
+    },
+    /// Show the type, UUID and entries of a `.qza`/`.qzv` without QIIME, or extract one entry.
+    Peek {
+        /// The `.qza` or `.qzv` file.
+        artifact: String,
+
+        /// Entry to extract, with or without the leading `<uuid>/` (e.g. `data/index.html`).
+        #[arg(long, value_name = "ENTRY")]
+        extract: Option<String>,
+
+        /// Where to write the extracted entry (default: its file name in the current directory).
+        #[arg(short, long, requires = "extract")]
+        output: Option<String>,
+    },
+    /// Run `qiime tools validate` on every `.qza`/`.qzv` in the output directory and report which pass.
+    Verify {
+        #[arg(short, long, default_value = "qiime2-amplicon-2024.10")]
+        env_name: String,
     },
     /// Interactive wizard that guides you through environment setup, demux, etc.
     Wizard,
+    /// Re-run the last command recorded in windchime.log, e.g. after fixing an environment issue.
+    Redo,
     /// Info subcommand: show environment availability, OS details, config, etc.
-    Info,
+    Info {
+        /// Ask the QIIME2 distribution index whether a newer release exists (needs network access).
+        #[arg(long, default_value_t = false)]
+        check_updates: bool,
+    },
+}
+
+impl Commands {
+    /// Path of the QIIME2 manifest this command reads, if any.
+    fn manifest_path(&self) -> Option<String> {
+        match self {
+            Commands::RunAll { continue_from_manifest: Some(manifest), .. } => Some(manifest.clone()),
+            Commands::Pipeline { pipeline } | Commands::RunAll { pipeline, .. } => {
+                Some(pipeline::shared_path(&pipeline.manifest))
+            }
+            Commands::ValidateManifest { manifest, .. } => Some(format!("{}/{}", output_dir(), manifest)),
+            _ => None,
+        }
+    }
+
+    /// Whether this command writes into the output directory, and so must hold
+    /// its lock. Commands that only read it can run alongside another run.
+    fn writes_output_dir(&self) -> bool {
+        !matches!(
+            self,
+            Commands::Status { .. }
+                | Commands::Peek { .. }
+                | Commands::Verify { .. }
+                | Commands::Archive { .. }
+                | Commands::Info { .. }
+        )
+    }
+}
+
+/// Demultiplexing options shared by the `Demux` and `RunAll` subcommands.
+#[derive(Args, Debug)]
+struct DemuxArgs {
+    /// Accept a read when at least this many leading index bases match (default: full index).
+    #[arg(long)]
+    min_index_match: Option<usize>,
+
+    /// Accept a read whose index differs from the expected one in at most this many bases.
+    #[arg(long, default_value_t = 0, value_name = "N")]
+    barcode_mismatches: usize,
+
+    /// Read barcode fields by header name, e.g. `name=Sample,file=FileBase,index=I5`
+    /// (default: positional name, file_name, idx1, seq1, idx2, seq2).
+    #[arg(long, alias = "barcode-column-map")]
+    barcode_columns: Option<String>,
+
+    /// Write read pairs matching no sample to unassigned_L001_R{1,2}_001.fastq.gz.
+    #[arg(long, default_value_t = false)]
+    keep_unassigned: bool,
+
+    /// Stop demultiplexing at the first failed sample.
+    #[arg(long, overrides_with = "no_fail_fast")]
+    fail_fast: bool,
+
+    /// Process every sample even if some fail, then report the failures (default).
+    #[arg(long, overrides_with = "fail_fast")]
+    no_fail_fast: bool,
+
+    /// 16S only: write pairs that match the index but not the forward primer
+    /// to {sample}_suspect_R{1,2}.fastq.gz instead of the sample's output.
+    #[arg(long, default_value_t = false)]
+    flag_suspect_primers: bool,
+
+    /// Only (re)process samples whose input FASTQs changed: `outputs` (newer than
+    /// the sample's existing demux output) or a time (RFC 3339 or `YYYY-MM-DD[ HH:MM:SS]`).
+    #[arg(long, value_name = "TIME|outputs")]
+    since: Option<Since>,
+
+    /// Discard pairs whose R1 is shorter than this many bases after trimming the index.
+    #[arg(long, default_value_t = 1)]
+    min_trimmed_length: usize,
+
+    /// Write per-sample read statistics of the demultiplexed FASTQs to
+    /// windchime_mqc.txt (MultiQC custom-content format).
+    #[arg(long, default_value_t = false)]
+    qc_report: bool,
+
+    /// Copy samples without an inline index straight to the output (gzipped inputs byte for byte)
+    /// instead of parsing and recompressing every read. Ignored when a per-read filter is enabled.
+    #[arg(long, default_value_t = false)]
+    passthrough: bool,
+
+    /// Before demultiplexing, record the path, size and modification time of the
+    /// barcodes files and every input FASTQ in input_audit.tsv and the log.
+    #[arg(long, default_value_t = false)]
+    audit: bool,
+
+    /// Also record a SHA-256 of each audited input (reads every file once more; implies --audit).
+    #[arg(long, default_value_t = false)]
+    audit_hash: bool,
+
+    /// Each sample's input is a single interleaved FASTQ (`<file_name>.fastq[.gz]`)
+    /// with R1 and R2 records alternating.
+    #[arg(long, default_value_t = false)]
+    interleaved: bool,
+
+    /// Also reject blank lines inside FASTQs, `+` lines naming another read, and R1/R2
+    /// files that differ in record count or read IDs, naming the file and record.
+    #[arg(long, default_value_t = false)]
+    strict_fastq: bool,
+
+    /// Build sample IDs from a template such as `{plate}_{name}_{seq2}`, with fields
+    /// name, file, index/seq2, lane, barcodes, tag or any barcodes file column.
+    #[arg(long, value_name = "TEMPLATE")]
+    sample_id_template: Option<String>,
+
+    /// Value of `{tag}` in --sample-id-template; give it once for all barcodes
+    /// files or once per file, in order.
+    #[arg(long = "sample-tag", value_name = "TAG", requires = "sample_id_template")]
+    sample_tags: Vec<String>,
+}
+
+impl DemuxArgs {
+    fn to_options(&self, skip_existing: bool) -> DemuxOptions {
+        DemuxOptions {
+            skip_existing,
+            min_index_match: self.min_index_match,
+            barcode_mismatches: self.barcode_mismatches,
+            barcode_columns: self.barcode_columns.clone(),
+            keep_unassigned: self.keep_unassigned,
+            fail_fast: self.fail_fast && !self.no_fail_fast,
+            // The primer check is only defined for the 16S primers (515F)
+            suspect_primer: if self.flag_suspect_primers {
+                pipeline::region_primers("16s").ok().map(|p| p.primer_f.to_string())
+            } else {
+                None
+            },
+            since: self.since.clone(),
+            min_trimmed_length: self.min_trimmed_length,
+            interleaved: self.interleaved,
+            qc_report: self.qc_report,
+            passthrough: self.passthrough,
+            audit: self.audit || self.audit_hash,
+            audit_hash: self.audit_hash,
+            strict_fastq: self.strict_fastq,
+            sample_id_template: self.sample_id_template.clone().map(|template| SampleIdTemplate {
+                template,
+                tags: self.sample_tags.clone(),
+            }),
+            single_end: pipeline::single_end(),
+        }
+    }
+}
+
+/// Pipeline options shared by the `Pipeline` and `RunAll` subcommands.
+#[derive(Args, Debug)]
+struct PipelineArgs {
+    #[arg(short, long, default_value = "qiime2-amplicon-2024.10")]
+    env_name: String,
+
+    /// QIIME2 manifest file.
+    #[arg(short, long, default_value = "manifest.tsv")]
+    manifest: String,
+
+    /// Number of CPU cores to use (0 = all available).
+    #[arg(long, default_value_t = 1)]
+    cores: usize,
+
+    /// Reduce --cores to the available parallelism instead of only warning.
+    #[arg(long, default_value_t = false)]
+    clamp_cores: bool,
+
+    /// Sample the input reads and skip Cutadapt if the primers were already removed upstream.
+    #[arg(long, default_value_t = false)]
+    detect_primers: bool,
+
+    /// DADA2 forward truncation length; defaults to the target region's (16s 219, 18sv4 262, 18sv9 123).
+    #[arg(long)]
+    trunc_len_f: Option<usize>,
+
+    /// DADA2 reverse truncation length; defaults to the target region's (16s 194, 18sv4 223, 18sv9 91).
+    #[arg(long)]
+    trunc_len_r: Option<usize>,
+
+    /// DADA2 denoising: `paired`, `single` (forward reads only), or `auto` to use
+    /// single-end when the reads are too short to overlap across the amplicon.
+    #[arg(long, default_value = "auto", value_name = "auto|paired|single")]
+    denoise_mode: DenoiseMode,
+
+    /// How primers are passed to Cutadapt: `linked` (anchored linked adapters),
+    /// `regular` (unanchored 5' primers), or `anchored` (5' primers anchored at the read start).
+    #[arg(long, default_value = "linked", value_name = "linked|regular|anchored")]
+    adapter_mode: AdapterMode,
+
+    /// Quality score offset of the demultiplexed FASTQs: 33 (current Illumina) or 64 (older sequencers).
+    #[arg(long, default_value = "33", value_name = "33|64")]
+    phred: PhredOffset,
+
+    /// Cutadapt's maximum error rate when matching primers, between 0 and 1.
+    #[arg(long, default_value_t = 0.1, value_name = "RATE", value_parser = parse_error_rate)]
+    cutadapt_error_rate: f64,
+
+    /// Fewest bases of a primer Cutadapt must find before trimming it.
+    #[arg(long, default_value_t = 3, value_name = "BASES")]
+    cutadapt_overlap: usize,
+
+    /// Fail instead of warning when the target region and the reference database
+    /// (PR2, or the one --classifier is named after) don't match, e.g. 16s with PR2.
+    #[arg(long, default_value_t = false)]
+    strict_reference: bool,
+
+    /// Add a `Confidence_<rank>` column per taxonomic rank to asv_count_tax.tsv, for filtering at a chosen rank.
+    #[arg(long, default_value_t = false)]
+    confidence_by_rank: bool,
+
+    /// Build unrooted and rooted phylogenetic trees from the representative sequences
+    /// (MAFFT + FastTree) for phylogenetic diversity metrics.
+    #[arg(long, default_value_t = false)]
+    build_tree: bool,
+
+    /// TSV mapping `sample_id<TAB>group`; replicates in a group are summed into
+    /// asv_count_tax_grouped.tsv.
+    #[arg(long, value_name = "TSV")]
+    replicate_groups: Option<String>,
+
+    /// Sample metadata TSV passed to `feature-table summarize` (default: summarize without metadata).
+    #[arg(long, value_name = "FILE")]
+    metadata: Option<String>,
+
+    /// Warn when DADA2 merges less than this fraction of input reads in the median sample.
+    #[arg(long, default_value_t = 0.5, value_name = "FRACTION")]
+    min_merge_rate: f64,
+
+    /// Check the reference FASTA for non-IUPAC characters and malformed headers before importing it.
+    #[arg(long, default_value_t = false)]
+    strict_iupac: bool,
+
+    /// Fail the merge step on a malformed ASV or taxonomy table row instead of skipping it.
+    #[arg(long, default_value_t = false)]
+    strict_merge: bool,
+
+    /// Always export the feature table with conda's `biom convert` instead of trying the built-in converter first.
+    #[arg(long, default_value_t = false)]
+    force_conda_biom: bool,
+
+    /// Prepend this to every artifact name written to the output directory, e.g. `run1_`.
+    /// Reference databases and the manifest are shared and not prefixed.
+    #[arg(long, default_value = "")]
+    output_prefix: String,
+
+    /// Memory DADA2 needs, in GB (default: estimated from sample count and reads learned).
+    #[arg(long, value_name = "GB")]
+    min_free_memory: Option<f64>,
+
+    /// Fail before starting instead of warning when available memory is below --min-free-memory.
+    #[arg(long, default_value_t = false)]
+    require_memory: bool,
+
+    /// Target region (16s, 18sv4, 18sv9, or its).
+    #[arg(short, long, default_value = "18sv9")]
+    target: String,
+
+    /// Skip pipeline steps if expected outputs already exist.
+    #[arg(long, default_value_t = false)]
+    skip_existing: bool,
+
+    /// Skip only the steps recorded as complete in pipeline_state.json (with their outputs present);
+    /// re-run the rest, including any a killed run left half-written.
+    #[arg(long, default_value_t = false)]
+    resume: bool,
+
+    /// Use a pre-trained classifier instead of training from the reference database;
+    /// `--use-pretrained-classifier false` trains one.
+    #[arg(long, default_value_t = true, num_args = 0..=1, default_missing_value = "true",
+          action = clap::ArgAction::Set, value_name = "BOOL")]
+    use_pretrained_classifier: bool,
+
+    /// Reference database to download a classifier for or train one on: `pr2`, `silva` or
+    /// `unite` (default: `unite` for `--target its`, `pr2` otherwise).
+    #[arg(long, value_name = "pr2|silva|unite")]
+    database: Option<Database>,
+
+    /// Skip `qiime tools validate` on the imported reads (the summary still runs).
+    #[arg(long, alias = "no-validate", default_value_t = false)]
+    skip_validate: bool,
+
+    /// Use this trained classifier artifact (.qza) instead of downloading or training one.
+    #[arg(long)]
+    classifier: Option<String>,
+
+    /// Reads classify-sklearn holds in memory at once (default: derived from available memory).
+    #[arg(long, value_name = "N")]
+    reads_per_batch: Option<usize>,
+
+    /// Reads both `demux summarize` steps subsample for the quality plots; lower is faster but less precise.
+    #[arg(long, default_value_t = 100_000, value_name = "COUNT")]
+    summarize_n: usize,
+
+    /// After a successful run, remove intermediates: `qzv-only` keeps visualizations and final tables,
+    /// `final-tsv-only` keeps only the final tables, `none` keeps everything.
+    #[arg(long, default_value = "none", value_name = "none|qzv-only|final-tsv-only")]
+    cleanup_on_success: CleanupProfile,
+
+    /// Write the pipeline's commands to this bash script instead of running them.
+    #[arg(long, value_name = "FILE")]
+    dump_commands: Option<String>,
+
+    /// Print a rough runtime and disk-usage estimate for the run, then exit.
+    #[arg(long, default_value_t = false)]
+    estimate: bool,
+
+    /// Extra environment variable for QIIME child processes (repeatable), e.g. `--env TMPDIR=/scratch`.
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    env_vars: Vec<(String, String)>,
+}
+
+/// Labels an error from one `RunAll` phase with the phase's name.
+fn in_phase<T, E: Into<Box<dyn Error>>>(phase: &str, result: Result<T, E>) -> Result<T, Box<dyn Error>> {
+    result.map_err(|e| format!("RunAll failed during {}: {}", phase, e.into()).into())
+}
+
+/// Runs environment setup, demultiplexing, manifest generation, database
+/// download and the pipeline, stopping at the first phase that fails. With
+/// `existing_manifest`, demultiplexing and manifest generation are skipped and
+/// `options.manifest` (which must name that manifest) is checked instead.
+/// Nothing runs until the preflight summary is confirmed (or `assume_yes`).
+fn run_all(
+    barcodes_files: &[String],
+    options: &PipelineOptions,
+    demux_options: &DemuxOptions,
+    strict_db: bool,
+    existing_manifest: bool,
+    assume_yes: bool,
+) -> Result<(), Box<dyn Error>> {
+    if existing_manifest {
+        print_info(&format!("==> Checking the files listed in {}", options.manifest));
+        let samples = in_phase("manifest check", demultiplex::check_manifest_files(&options.manifest))?;
+        log_action(&format!("Continuing from manifest {} ({} samples); skipping demultiplexing.", options.manifest, samples));
+    }
+
+    in_phase("preflight", pipeline::check_metadata_file(options))?;
+    // Otherwise the pipeline warns; a strict mismatch should stop before demultiplexing
+    if options.strict_reference {
+        in_phase("preflight", pipeline::check_reference_region(options))?;
+    }
+    let plan = in_phase("preflight", preflight::build_preflight(barcodes_files, options, demux_options, existing_manifest))?;
+    // A dry run changes nothing, so there is nothing to confirm
+    if !preflight::confirm_run(&plan, assume_yes || pipeline::dry_run_mode())? {
+        print_info("RunAll cancelled; nothing was changed.");
+        log_action("RunAll cancelled at the preflight confirmation.");
+        return Ok(());
+    }
+
+    print_info(&format!("==> Checking conda environment '{}'", options.env_name));
+    in_phase("environment setup", pipeline::install_qiime2_amplicon_2024_10(&options.env_name))?;
+
+    if !existing_manifest && pipeline::dry_run_mode() {
+        println!(
+            "[DRY RUN] demultiplex {} and write the manifest {} (done by Windchime itself)",
+            barcodes_files.join(", "),
+            options.manifest
+        );
+    } else if !existing_manifest {
+        print_info("==> Running demultiplexing step...");
+        in_phase("demultiplexing", demultiplex::run_demultiplex_combined(barcodes_files, demux_options))?;
+
+        print_info("==> Generating QIIME2 manifest file...");
+        in_phase(
+            "manifest generation",
+            demultiplex::generate_qiime_manifest(
+                barcodes_files,
+                &options.manifest,
+                demux_options.barcode_columns.as_deref(),
+                demux_options.passthrough,
+                demux_options.sample_id_template.as_ref(),
+                demux_options.single_end,
+            ),
+        )?;
+    }
+
+    print_info("==> Downloading database files if necessary...");
+    in_phase("database download", pipeline::download_databases(options.reference_database(), false, strict_db, DbComponent::All))?;
+
+    print_info(&format!("==> Running QIIME2 pipeline using manifest file: {}", options.manifest));
+    in_phase("the QIIME2 pipeline", pipeline::run_pipeline(options))?;
+    Ok(())
+}
+
+/// Parses a `KEY=VALUE` command-line argument.
+fn parse_key_value(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => Ok((key.trim().to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", arg)),
+    }
+}
+
+/// Parses `--cutadapt-error-rate`, which Cutadapt takes as a fraction.
+fn parse_error_rate(arg: &str) -> Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        Ok(rate) => Err(format!("must be between 0.0 and 1.0, got {}", rate)),
+        Err(e) => Err(format!("expected a number between 0.0 and 1.0: {}", e)),
+    }
+}
+
+impl PipelineArgs {
+    fn to_options(&self) -> PipelineOptions {
+        PipelineOptions {
+            env_name: self.env_name.clone(),
+            manifest: self.manifest.clone(),
+            cores: self.cores,
+            target: self.target.clone(),
+            skip_existing: self.skip_existing,
+            resume: self.resume,
+            use_pretrained_classifier: self.use_pretrained_classifier,
+            database: self.database,
+            skip_validate: self.skip_validate,
+            classifier: self.classifier.clone(),
+            env_vars: self.env_vars.clone(),
+            clamp_cores: self.clamp_cores,
+            min_free_memory_gb: self.min_free_memory,
+            require_memory: self.require_memory,
+            reads_per_batch: self.reads_per_batch,
+            summarize_n: self.summarize_n,
+            cleanup_on_success: self.cleanup_on_success,
+            dump_commands: self.dump_commands.clone(),
+            detect_primers: self.detect_primers,
+            trunc_len_f: self.trunc_len_f,
+            trunc_len_r: self.trunc_len_r,
+            min_merge_rate: self.min_merge_rate,
+            replicate_groups: self.replicate_groups.clone(),
+            metadata: self.metadata.clone(),
+            strict_reference: self.strict_reference,
+            confidence_by_rank: self.confidence_by_rank,
+            build_tree: self.build_tree,
+            denoise_mode: self.denoise_mode,
+            adapter_mode: self.adapter_mode,
+            phred: self.phred,
+            cutadapt_error_rate: self.cutadapt_error_rate,
+            cutadapt_overlap: self.cutadapt_overlap,
+            strict_merge: self.strict_merge,
+            strict_iupac: self.strict_iupac,
+            force_conda_biom: self.force_conda_biom,
+            single_end: pipeline::single_end(),
+        }
+    }
+}
+
+/// A hint for a `pipeline` or `run-all` that can't start because its
+/// manifest or barcodes files don't exist.
+fn missing_input_hint(cli: &Cli) -> Option<String> {
+    match &cli.command {
+        Commands::Pipeline { pipeline }
+            if !pipeline.skip_existing && !pipeline.resume && pipeline.dump_commands.is_none() && !cli.dry_run =>
+        {
+            hints::missing_manifest_hint(&pipeline::shared_path(&pipeline.manifest))
+        }
+        Commands::RunAll { barcodes_files, continue_from_manifest: None, .. } => {
+            hints::missing_barcodes_hint(barcodes_files)
+        }
+        _ => None,
+    }
 }
 
 fn main() {
-    let cli = Cli::parse();
+    let mut args: Vec<String> = std::env::args().collect();
+    let mut cli = Cli::try_parse_from(&args).unwrap_or_else(|e| {
+        if e.kind() == clap::error::ErrorKind::InvalidSubcommand
+            && let Some(clap::error::ContextValue::String(word)) = e.get(clap::error::ContextKind::InvalidSubcommand)
+        {
+            let _ = e.print();
+            print_info(&hints::unknown_subcommand_hint(word));
+            process::exit(e.exit_code());
+        }
+        e.exit()
+    });
+    if let Commands::Redo = cli.command {
+        match redo::last_run(&format!("{}/windchime.log", cli.output_dir)) {
+            Ok(last) => {
+                print_info(&format!("Redoing: {}", last.join(" ")));
+                cli = Cli::try_parse_from(&last).unwrap_or_else(|e| e.exit());
+                args = last;
+            }
+            Err(e) => {
+                print_error(&e);
+                process::exit(1);
+            }
+        }
+    }
+    // Every path below is resolved against the output directory
+    let _ = OUTPUT_DIR.set(cli.output_dir.clone());
+    color_print::set_color_choice(cli.color);
+    conda::set_conda_path(cli.conda_path.clone());
+    conda::set_solver(cli.solver);
 
-    // Initialize logging to windchime.log
-    init_log();
+    // Point first-time users at the right subcommand before anything is created
+    if let Some(hint) = missing_input_hint(&cli) {
+        print_error(&hint);
+        process::exit(1);
+    }
 
     // Load config file if provided
     let mut config_data = WindchimeConfig::default();
@@ -136,90 +919,237 @@ fn main() {
         }
     }
 
-    // Set the global verbose flag
+    // Set the global verbose, dry-run, checksum, single-end and download-retry flags
     VERBOSE_MODE.store(cli.verbose, Ordering::Relaxed);
+    DRY_RUN.store(cli.dry_run, Ordering::Relaxed);
+    SKIP_CHECKSUM.store(cli.skip_checksum, Ordering::Relaxed);
+    SINGLE_END.store(cli.single_end, Ordering::Relaxed);
+    DOWNLOAD_RETRIES.store(cli.download_retries, Ordering::Relaxed);
+
+    // Namespace pipeline artifacts, so several runs can share the output directory
+    if let Commands::Pipeline { pipeline } | Commands::RunAll { pipeline, .. } | Commands::Status { pipeline } = &cli.command
+        && let Err(e) = pipeline::set_output_prefix(&pipeline.output_prefix)
+    {
+        print_error(&e.to_string());
+        process::exit(1);
+    }
 
     // Ensure the output directory exists
-    if let Err(e) = fs::create_dir_all(OUTPUT_DIR) {
-        print_error(&format!("Error creating output directory {}: {}", OUTPUT_DIR, e));
+    if let Err(e) = fs::create_dir_all(output_dir()) {
+        print_error(&format!("Error creating output directory {}: {}", output_dir(), e));
+        process::exit(1);
+    }
+
+    // Only one run at a time may write to the output directory
+    if cli.command.writes_output_dir()
+        && let Err(e) = lock::acquire(cli.wait, cli.force_unlock)
+    {
+        print_error(&e);
         process::exit(1);
     }
+    if let Err(e) = ctrlc::set_handler(|| {
+        lock::release();
+        process::exit(130);
+    }) {
+        print_error(&format!("Warning: could not install the Ctrl-C handler: {}", e));
+    }
+
+    // Initialize logging to windchime.log (needs the output directory to exist)
+    init_log(cli.json_logs);
 
     // Log the action and parse subcommands
     log_action(&format!("Starting Windchime with command: {:?}", cli.command));
+    log_action(&redo::command_line_entry(&args));
+
+    // Captured up front because `cli.command` is moved into the match below
+    let manifest_path = cli.command.manifest_path();
+    let effective_config = format!(
+        "Windchime version: {}\nOS: {}, ARCH: {}\nVerbose: {}\n\nCommand: {:#?}\n\nConfig: {:#?}\n",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        cli.verbose,
+        cli.command,
+        config_data
+    );
 
     let result = match cli.command {
-        Commands::InstallEnv { env_name } => {
+        Commands::InstallEnv { env_name, check_updates } => {
+            if check_updates {
+                updates::check_for_updates();
+            }
             pipeline::install_qiime2_amplicon_2024_10(&env_name)
         }
         Commands::Demux {
-            barcodes_file,
+            barcodes_files,
+            preview: Some(pairs),
+            demux,
+            ..
+        } => demultiplex::preview_barcodes(&barcodes_files, &demux.to_options(false), pairs).map_err(|e| e.into()),
+        Commands::Demux {
+            barcodes_files,
             skip_existing,
+            demux,
+            ..
         } => {
             print_info("Running demultiplex step...");
-            demultiplex::run_demultiplex_combined(&barcodes_file, skip_existing)
+            let demux_options = demux.to_options(skip_existing);
+            demultiplex::run_demultiplex_combined(&barcodes_files, &demux_options)
                 .map_err(|e| e.into())
         }
-        Commands::Pipeline {
+        Commands::Pipeline { pipeline: args } if args.estimate => {
+            estimate::manifest_inputs(&pipeline::shared_path(&args.manifest))
+                .and_then(|pairs| {
+                    let samples = pairs.len();
+                    estimate::measure_inputs(&pairs, samples)
+                })
+                .map(|size| {
+                    let stages = estimate::estimate_run(&size, args.cores, false, args.to_options().trains_classifier());
+                    estimate::print_estimate(&size, args.cores, &stages);
+                })
+                .map_err(|e| e.into())
+        }
+        Commands::Pipeline { pipeline: args } => {
+            print_info(&format!("Running QIIME2 pipeline with environment: {}", args.env_name));
+            pipeline::run_pipeline(&args.to_options()).map(|_| ())
+        }
+        Commands::Trim {
             env_name,
-            manifest,
-            cores,
+            input,
+            output,
             target,
+            adapter_mode,
+            cutadapt_error_rate,
+            cutadapt_overlap,
+            cores,
             skip_existing,
-            use_pretrained_classifier,
+            summarize_n,
         } => {
-            print_info(&format!("Running QIIME2 pipeline with environment: {}", env_name));
-            pipeline::run_pipeline(
-                &env_name,
-                &manifest,
+            print_info(&format!("Trimming {} for target {}", input, target));
+            let options = PipelineOptions {
+                env_name,
+                target,
+                adapter_mode,
+                cutadapt_error_rate,
+                cutadapt_overlap,
                 cores,
-                &target,
                 skip_existing,
-                use_pretrained_classifier,
-                219,
-                194,
-            )
+                summarize_n,
+                single_end: pipeline::single_end(),
+                ..PipelineOptions::default()
+            };
+            pipeline::trim_demultiplexed(&options, &input, &output)
         }
-        Commands::RunAll {
+        Commands::Classify {
             env_name,
-            barcodes_file,
-            manifest,
-            cores,
+            rep_seqs,
+            table,
             target,
-            skip_existing,
+            classifier,
             use_pretrained_classifier,
+            database,
+            reads_per_batch,
+            cores,
+            skip_existing,
+            strict_merge,
+            strict_reference,
+            confidence_by_rank,
         } => {
-            print_info(&format!("==> Checking conda environment '{}'", env_name));
-            pipeline::install_qiime2_amplicon_2024_10(&env_name).unwrap();
-
-            print_info("==> Running demultiplexing step...");
-            demultiplex::run_demultiplex_combined(&barcodes_file, skip_existing).unwrap();
-
-            print_info("==> Generating QIIME2 manifest file...");
-            demultiplex::generate_qiime_manifest(&barcodes_file, &manifest).unwrap();
-
-            print_info("==> Downloading database files if necessary...");
-            pipeline::download_databases(false).unwrap();
-
-            print_info(&format!("==> Running QIIME2 pipeline using manifest file: {}", manifest));
-            pipeline::run_pipeline(
-                &env_name,
-                &manifest,
+            let options = PipelineOptions {
+                env_name,
+                target,
+                classifier,
+                use_pretrained_classifier,
+                database,
+                reads_per_batch,
                 cores,
-                &target,
                 skip_existing,
-                use_pretrained_classifier,
-                219,
-                194,
+                strict_merge,
+                strict_reference,
+                confidence_by_rank,
+                ..PipelineOptions::default()
+            };
+            print_info(&format!("Classifying {}", rep_seqs));
+            pipeline::classify_rep_seqs(&options, &rep_seqs, table.as_deref())
+        }
+        Commands::ValidateManifest { env_name, manifest, phred } => {
+            pipeline::validate_manifest(&env_name, &manifest, phred, pipeline::single_end())
+        }
+        Commands::RunAll {
+            continue_from_manifest: Some(manifest),
+            pipeline: args,
+            ..
+        } if args.estimate => estimate::manifest_inputs(&manifest)
+            .and_then(|pairs| estimate::measure_inputs(&pairs, pairs.len()))
+            .map(|size| {
+                let stages = estimate::estimate_run(&size, args.cores, false, args.to_options().trains_classifier());
+                estimate::print_estimate(&size, args.cores, &stages);
+            })
+            .map_err(|e| e.into()),
+        Commands::RunAll {
+            barcodes_files,
+            pipeline: args,
+            demux,
+            ..
+        } if args.estimate => {
+            demultiplex::barcode_input_files(
+                &barcodes_files,
+                demux.barcode_columns.as_deref(),
+                demux.passthrough,
+                pipeline::single_end(),
             )
+                .and_then(|(samples, pairs)| estimate::measure_inputs(&pairs, samples))
+                .map(|size| {
+                    let stages = estimate::estimate_run(&size, args.cores, true, args.to_options().trains_classifier());
+                    estimate::print_estimate(&size, args.cores, &stages);
+                })
+                .map_err(|e| e.into())
+        }
+        Commands::RunAll {
+            barcodes_files,
+            strict_db,
+            continue_from_manifest,
+            assume_yes,
+            pipeline: args,
+            demux,
+        } => {
+            let demux_options = demux.to_options(args.skip_existing || args.resume);
+            let mut options = args.to_options();
+            match continue_from_manifest.as_deref().map(std::path::absolute).transpose() {
+                Ok(manifest) => {
+                    let existing_manifest = manifest.is_some();
+                    if let Some(path) = manifest {
+                        options.manifest = path.to_string_lossy().into_owned();
+                    }
+                    run_all(&barcodes_files, &options, &demux_options, strict_db, existing_manifest, assume_yes)
+                }
+                Err(e) => Err(format!("Invalid --continue-from-manifest path: {}", e).into()),
+            }
         }
-        Commands::DownloadDBs { force } => {
-            pipeline::download_databases(force)
+        Commands::DownloadDBs { force, strict_db, only, database, unite_archive } => match unite_archive {
+            Some(_) if database != Database::Unite => {
+                Err(format!("--unite-archive installs UNITE; pass --database unite (got {}).", database.label()).into())
+            }
+            Some(archive) => pipeline::install_unite_archive(&archive, force, strict_db, only),
+            None => pipeline::download_databases(database, force, strict_db, only),
+        },
+        Commands::Fetch { accessions, fastq_dir, method, ena_portal, force, manifest } => {
+            let options = FetchOptions { method, fastq_dir, ena_portal, force, manifest };
+            fetch::fetch_runs(&accessions, &options)
+        }
+        Commands::Status { pipeline: args } => status::print_status(&args.to_options()),
+        Commands::Archive { output, exclude_intermediates } => archive::write_archive(&output, exclude_intermediates),
+        Commands::Peek { artifact, extract: Some(entry), output } => {
+            peek::extract_entry(&artifact, &entry, output.as_deref())
         }
+        Commands::Peek { artifact, .. } => peek::print_artifact(&artifact),
+        Commands::Verify { env_name } => verify::verify_outputs(&env_name),
+        // Replaced by the recorded command above; a recorded `redo` has nothing to re-run
+        Commands::Redo => Err("The last recorded command is itself `redo`; nothing to redo".into()),
         Commands::Wizard => {
             wizard::run_wizard()
         }
-        Commands::Info => {
+        Commands::Info { check_updates } => {
             print_info("Gathering system and environment info...");
             // Show version
             print_success(&format!("Windchime version: {}", env!("CARGO_PKG_VERSION")));
@@ -230,6 +1160,7 @@ fn main() {
             print_success(&format!("OS: {}, ARCH: {}", os, arch));
 
             // Check conda presence
+            print_info(&format!("Conda binary: {}", conda::conda_binary()));
             match pipeline::conda_env_exists("base") {
                 Ok(_) => print_success("Conda appears to be installed and accessible."),
                 Err(e) => print_error(&format!("Conda not found or error: {}", e)),
@@ -239,15 +1170,290 @@ fn main() {
             print_info("Loaded config:");
             print_info(&format!("{:#?}", config_data));
 
+            if check_updates {
+                updates::check_for_updates();
+            }
+
             Ok(())
         }
     };
 
+    if let Err(e) = summary::write_run_summary() {
+        print_error(&format!("Could not write run summary: {}", e));
+    }
+
     if let Err(e) = result {
         print_error(&format!("Application error: {}", e));
+        log_event(LogLevel::Error, None, &format!("Application error: {}", e));
+        if cli.bundle_on_error {
+            match bundle::write_failure_bundle(
+                &e.to_string(),
+                &effective_config,
+                manifest_path.as_deref(),
+                pipeline::last_failure_stderr().as_deref(),
+            ) {
+                Ok(path) => print_info(&format!("Failure bundle for bug reports written to {}", path)),
+                Err(bundle_err) => print_error(&format!("Could not write failure bundle: {}", bundle_err)),
+            }
+        }
+        lock::release();
         process::exit(1);
     }
 
     log_action("Windchime finished successfully.");
+    lock::release();
     print_success("All done!");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, write_fastq_gz, write_file};
+
+    /// A barcodes file in `dir` for sample `s1`, whose reads exist, and `s2`, whose don't.
+    fn barcodes_with_a_missing_input(dir: &std::path::Path) -> String {
+        let plate = dir.join("plate").to_string_lossy().into_owned();
+        write_fastq_gz(dir, "plate_R1_001.fastq.gz", &[("read1", "ACGTAACCGGTTTT")]);
+        write_fastq_gz(dir, "plate_R2_001.fastq.gz", &[("read1", "GGGGCCCC")]);
+        let missing = dir.join("missing").to_string_lossy().into_owned();
+        write_file(
+            dir,
+            "barcodes.tsv",
+            &format!(
+                "name\tfile_name\tidx1\tseq1\tidx2\tseq2\n\
+                 s1\t{plate}\tN701\tTAAGGCGA\tS501\tAACCGG\n\
+                 s2\t{missing}\tN701\tTAAGGCGA\tS502\tTTGGCC\n"
+            ),
+        )
+    }
+
+    fn run_all_with(barcodes: String, options: &PipelineOptions) -> Result<(), Box<dyn Error>> {
+        run_all(&[barcodes], options, &DemuxOptions::default(), false, false, true)
+    }
+
+    #[test]
+    fn run_all_labels_a_failed_environment_setup() {
+        let _state = test_support::global_state();
+        let dir = tempfile::tempdir().unwrap();
+        test_support::conda_fails_on("env list");
+        let options = PipelineOptions { env_name: "qiime-env".to_string(), ..PipelineOptions::default() };
+
+        let err = run_all_with(barcodes_with_a_missing_input(dir.path()), &options).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "RunAll failed during environment setup: Could not retrieve conda environment list."
+        );
+    }
+
+    #[test]
+    fn run_all_stops_at_a_failed_demultiplexing() {
+        let _state = test_support::global_state();
+        let dir = tempfile::tempdir().unwrap();
+        test_support::conda_prints("qiime-env                /opt/conda/envs/qiime-env\n");
+        let options = PipelineOptions { env_name: "qiime-env".to_string(), ..PipelineOptions::default() };
+
+        let err = run_all_with(barcodes_with_a_missing_input(dir.path()), &options).unwrap_err();
+        let msg = err.to_string();
+        let expected = "RunAll failed during demultiplexing: Demultiplexing failed for 1 of 2 samples";
+        assert!(msg.starts_with(expected), "{}", msg);
+        // Nothing after the failed phase ran
+        assert!(!test_support::output_dir().join(&options.manifest).exists());
+        assert_eq!(test_support::conda_calls(), ["env list", "env list"]);
+    }
+
+    #[test]
+    fn run_all_from_a_manifest_skips_demultiplexing() {
+        let _state = test_support::global_state();
+        let dir = tempfile::tempdir().unwrap();
+        test_support::conda_prints("qiime-env                /opt/conda/envs/qiime-env\n");
+        let r1 = write_fastq_gz(dir.path(), "s1_R1.fastq.gz", &[("read1", "ACGTAACCGGTTTT")]);
+        let r2 = write_fastq_gz(dir.path(), "s1_R2.fastq.gz", &[("read1", "GGGGCCCC")]);
+        let manifest = write_file(
+            dir.path(),
+            "manifest.tsv",
+            &format!(
+                "sample-id\tforward-absolute-filepath\treverse-absolute-filepath\ns1\t{}\t{}\n",
+                r1.display(),
+                r2.display()
+            ),
+        );
+        // Already downloaded, so nothing is fetched
+        let database = pipeline::Database::Pr2;
+        std::fs::create_dir_all(database.dir()).unwrap();
+        for (path, contents) in [(database.fasta_path(), ">a\nACGT\n"), (database.taxonomy_path(), "a\tEukaryota\n")] {
+            std::fs::write(format!("{}.gz", path), "").unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        let options = PipelineOptions { env_name: "qiime-env".to_string(), manifest, ..PipelineOptions::default() };
+
+        // The barcodes file doesn't exist: demultiplexing would fail on it
+        let barcodes = dir.path().join("missing_barcodes.tsv").to_string_lossy().into_owned();
+        test_support::conda_fails_on("tools import");
+        let err = run_all(&[barcodes], &options, &DemuxOptions::default(), false, true, true).unwrap_err();
+        assert!(err.to_string().starts_with("RunAll failed during the QIIME2 pipeline: "), "{}", err);
+        // QIIME imported the given manifest; none was generated and nothing demultiplexed
+        let import = test_support::conda_calls().into_iter().find(|call| call.contains("tools import")).unwrap();
+        assert!(import.contains(&format!("--input-path {} ", options.manifest)), "{}", import);
+        assert!(!test_support::output_dir().join(PipelineOptions::default().manifest).exists());
+        assert!(!test_support::output_dir().join("demux_stats.tsv").exists());
+    }
+
+    #[test]
+    fn a_pipeline_without_its_manifest_is_pointed_at_run_all_and_the_wizard() {
+        let _state = test_support::global_state();
+        let hint = |args: &[&str]| missing_input_hint(&Cli::try_parse_from(args).unwrap());
+
+        let missing = hint(&["windchime", "pipeline"]).expect("no hint for a missing manifest");
+        assert!(missing.contains(&format!("No QIIME2 manifest at '{}/manifest.tsv'", output_dir())), "{}", missing);
+        assert!(missing.contains("`windchime run-all --barcodes-file <file>`") && missing.contains("`windchime wizard`"));
+        // Runs that won't read the manifest yet aren't stopped
+        assert_eq!(hint(&["windchime", "--dry-run", "pipeline"]), None);
+        assert_eq!(hint(&["windchime", "pipeline", "--skip-existing"]), None);
+
+        fs::write(format!("{}/manifest.tsv", output_dir()), "sample-id\n").unwrap();
+        assert_eq!(hint(&["windchime", "pipeline"]), None);
+
+        let no_barcodes = hint(&["windchime", "run-all", "--barcodes-file", "missing.tsv"]).unwrap();
+        assert!(no_barcodes.contains("No barcodes file found (missing.tsv)"), "{}", no_barcodes);
+        assert_eq!(hint(&["windchime", "run-all", "--continue-from-manifest", "manifest.tsv"]), None);
+    }
+
+    #[test]
+    fn redo_reconstructs_the_logged_invocation() {
+        let dir = tempfile::tempdir().unwrap();
+        let original: Vec<String> =
+            ["windchime", "pipeline", "--manifest", "my manifest.tsv", "--cores", "4", "--skip-existing"]
+                .iter()
+                .map(|w| w.to_string())
+                .collect();
+        let log = write_file(
+            dir.path(),
+            "windchime.log",
+            &format!("[t] {}\n[t] Starting Windchime\n", redo::command_line_entry(&original)),
+        );
+        let last = redo::last_run(&log).unwrap();
+        assert_eq!(last, original);
+        let Commands::Pipeline { pipeline } = Cli::try_parse_from(&last).unwrap().command else {
+            panic!("redo should reconstruct a pipeline run");
+        };
+        assert_eq!(pipeline.manifest, "my manifest.tsv");
+        assert_eq!(pipeline.cores, 4);
+        assert!(pipeline.skip_existing);
+    }
+
+    #[test]
+    fn only_commands_that_write_outputs_take_the_lock() {
+        let writes = |args: &[&str]| Cli::try_parse_from(args).unwrap().command.writes_output_dir();
+        assert!(writes(&["windchime", "pipeline"]));
+        assert!(writes(&["windchime", "demux", "barcodes.tsv"]));
+        assert!(!writes(&["windchime", "status"]));
+        assert!(!writes(&["windchime", "peek", "table.qza"]));
+        assert!(!writes(&["windchime", "info"]));
+    }
+
+    #[test]
+    fn trunc_lengths_are_unset_unless_given() {
+        let pipeline = |args: &[&str]| match Cli::try_parse_from(args).unwrap().command {
+            Commands::Pipeline { pipeline } => pipeline.to_options(),
+            _ => panic!("expected a pipeline command"),
+        };
+        let defaults = pipeline(&["windchime", "pipeline", "--target", "18sv9"]);
+        assert_eq!((defaults.trunc_len_f, defaults.trunc_len_r), (None, None));
+        let given = pipeline(&["windchime", "pipeline", "--trunc-len-f", "150", "--trunc-len-r", "0"]);
+        assert_eq!((given.trunc_len_f, given.trunc_len_r), (Some(150), Some(0)));
+    }
+
+    #[test]
+    fn use_pretrained_classifier_can_be_turned_off() {
+        let pretrained = |args: &[&str]| match Cli::try_parse_from(args).unwrap().command {
+            Commands::Pipeline { pipeline } => pipeline.to_options().use_pretrained_classifier,
+            Commands::Classify { use_pretrained_classifier, .. } => use_pretrained_classifier,
+            _ => panic!("expected a pipeline or classify command"),
+        };
+        assert!(pretrained(&["windchime", "pipeline"]));
+        assert!(pretrained(&["windchime", "pipeline", "--use-pretrained-classifier"]));
+        assert!(!pretrained(&["windchime", "pipeline", "--use-pretrained-classifier", "false"]));
+        assert!(!pretrained(&["windchime", "classify", "--rep-seqs", "rep-seqs.qza", "--use-pretrained-classifier=false"]));
+    }
+
+    #[test]
+    fn cutadapt_error_rate_and_overlap_default_to_the_old_values_and_are_range_checked() {
+        let options = |args: &[&str]| match Cli::try_parse_from(args).unwrap().command {
+            Commands::Pipeline { pipeline } | Commands::RunAll { pipeline, .. } => pipeline.to_options(),
+            _ => panic!("expected a pipeline or run-all command"),
+        };
+        let defaults = options(&["windchime", "pipeline"]);
+        assert_eq!((defaults.cutadapt_error_rate, defaults.cutadapt_overlap), (0.1, 3));
+        let given = options(&["windchime", "run-all", "--cutadapt-error-rate", "0.15", "--cutadapt-overlap", "8"]);
+        assert_eq!((given.cutadapt_error_rate, given.cutadapt_overlap), (0.15, 8));
+        assert_eq!(options(&["windchime", "pipeline", "--cutadapt-error-rate", "1"]).cutadapt_error_rate, 1.0);
+
+        for (rate, reason) in [("1.5", "must be between 0.0 and 1.0, got 1.5"), ("-0.1", "got -0.1"), ("ten", "expected a number")] {
+            let arg = format!("--cutadapt-error-rate={}", rate);
+            let err = Cli::try_parse_from(["windchime", "pipeline", &arg]).unwrap_err();
+            assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation);
+            assert!(err.to_string().contains(reason), "{}", err);
+        }
+        assert!(Cli::try_parse_from(["windchime", "pipeline", "--cutadapt-overlap=-3"]).is_err());
+
+        // The standalone trim takes the same flags, so it can run the pipeline's command
+        let trim = Cli::try_parse_from([
+            "windchime", "trim", "-i", "demux.qza", "--cutadapt-error-rate", "0.2", "--cutadapt-overlap", "5",
+        ]);
+        let Commands::Trim { cutadapt_error_rate, cutadapt_overlap, .. } = trim.unwrap().command else {
+            panic!("expected a trim command");
+        };
+        assert_eq!((cutadapt_error_rate, cutadapt_overlap), (0.2, 5));
+        assert!(Cli::try_parse_from(["windchime", "trim", "-i", "demux.qza", "--cutadapt-error-rate", "2"]).is_err());
+    }
+
+    #[test]
+    fn json_logs_is_a_global_flag() {
+        let json_logs = |args: &[&str]| Cli::try_parse_from(args).unwrap().json_logs;
+        assert!(!json_logs(&["windchime", "pipeline"]));
+        assert!(json_logs(&["windchime", "--json-logs", "pipeline"]));
+        assert!(json_logs(&["windchime", "demux", "barcodes.tsv", "--json-logs"]));
+    }
+
+    #[test]
+    fn phred_defaults_to_33_and_accepts_only_33_or_64() {
+        let phred = |args: &[&str]| match Cli::try_parse_from(args).unwrap().command {
+            Commands::Pipeline { pipeline } => pipeline.to_options().phred,
+            Commands::ValidateManifest { phred, .. } => phred,
+            _ => panic!("expected a pipeline or validate-manifest command"),
+        };
+        assert_eq!(phred(&["windchime", "pipeline"]), PhredOffset::Phred33);
+        assert_eq!(phred(&["windchime", "pipeline", "--phred", "64"]), PhredOffset::Phred64);
+        assert_eq!(phred(&["windchime", "validate-manifest", "--phred", "64"]), PhredOffset::Phred64);
+        let err = Cli::try_parse_from(["windchime", "pipeline", "--phred", "42"]).unwrap_err();
+        assert!(err.to_string().contains("expected 33 or 64, got '42'"), "{}", err);
+    }
+
+    #[test]
+    fn fetch_writes_fastqs_to_fastq_dir_and_keeps_the_global_output_dir() {
+        let parse = |args: &[&str]| {
+            let cli = Cli::try_parse_from(args).unwrap();
+            match cli.command {
+                Commands::Fetch { fastq_dir, .. } => (cli.output_dir, fastq_dir),
+                _ => panic!("expected a fetch command"),
+            }
+        };
+        // A plain fetch keeps its log and lock in the default output directory, not the working directory
+        assert_eq!(parse(&["windchime", "fetch", "SRR1"]), (DEFAULT_OUTPUT_DIR.to_string(), ".".to_string()));
+        assert_eq!(
+            parse(&["windchime", "fetch", "SRR1", "--fastq-dir", "raw", "--output-dir", "run1/"]),
+            ("run1".to_string(), "raw".to_string())
+        );
+        assert_eq!(parse(&["windchime", "--output-dir", "run2", "fetch", "SRR1"]).0, "run2");
+    }
+
+    #[test]
+    fn output_dir_is_global_and_defaults_to_windchime_out() {
+        let output_dir = |args: &[&str]| Cli::try_parse_from(args).map(|cli| cli.output_dir);
+        assert_eq!(output_dir(&["windchime", "pipeline"]).unwrap(), DEFAULT_OUTPUT_DIR);
+        assert_eq!(output_dir(&["windchime", "--output-dir", "a/b/", "pipeline"]).unwrap(), "a/b");
+        assert_eq!(output_dir(&["windchime", "demux", "barcodes.tsv", "--output-dir", "plate1"]).unwrap(), "plate1");
+        let err = output_dir(&["windchime", "status", "--output-dir", "/"]).unwrap_err();
+        assert!(err.to_string().contains("must name a directory other than '/'"), "{}", err);
+    }
+}
@@ -0,0 +1,103 @@
+use std::fs;
+
+/// Fixed DADA2 overhead (R session, error models, sequence tables).
+const DADA2_BASE_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+/// Memory held per read used for error learning (`--p-n-reads-learn`).
+const DADA2_BYTES_PER_LEARN_READ: u64 = 2_000;
+/// Extra memory per sample for pooled chimera removal.
+const DADA2_BYTES_PER_SAMPLE: u64 = 50 * 1024 * 1024;
+/// Memory each classify-sklearn job needs besides its batch (the loaded classifier).
+const CLASSIFY_BASE_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+/// Memory per read in a batch (per-class probabilities over a PR2-sized label set).
+const CLASSIFY_BYTES_PER_READ: u64 = 400 * 1024;
+/// Bounds for a derived `--p-reads-per-batch`.
+const MIN_READS_PER_BATCH: u64 = 100;
+const MAX_READS_PER_BATCH: u64 = 20_000;
+
+/// Memory the kernel estimates is available for new processes without
+/// swapping (`MemAvailable` in `/proc/meminfo`), or `None` if it can't be read.
+pub fn available_memory_bytes() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    parse_mem_available(&meminfo)
+}
+
+fn parse_mem_available(meminfo: &str) -> Option<u64> {
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Rough peak memory of `dada2 denoise-paired` with pooled chimera removal.
+pub fn dada2_memory_estimate(samples: usize, n_reads_learn: u64) -> u64 {
+    DADA2_BASE_BYTES + n_reads_learn * DADA2_BYTES_PER_LEARN_READ + samples as u64 * DADA2_BYTES_PER_SAMPLE
+}
+
+/// A `--p-reads-per-batch` for classify-sklearn that fits `jobs` parallel jobs
+/// into `available` memory, or `None` (leave it to QIIME) if `available` is unknown.
+pub fn classifier_reads_per_batch(available: Option<u64>, jobs: usize) -> Option<usize> {
+    let available = available?;
+    let per_job = available / jobs.max(1) as u64;
+    let reads = per_job.saturating_sub(CLASSIFY_BASE_BYTES) / CLASSIFY_BYTES_PER_READ;
+    Some(reads.clamp(MIN_READS_PER_BATCH, MAX_READS_PER_BATCH) as usize)
+}
+
+/// Compares `available` memory against `required`. Returns a message when
+/// there is too little, or `None` when there is enough (or `available` is unknown).
+pub fn memory_shortfall(required: u64, available: Option<u64>) -> Option<String> {
+    let available = available?;
+    if available >= required {
+        return None;
+    }
+    Some(format!(
+        "Only {:.1} GB of memory is available but DADA2 is expected to need about {:.1} GB; \
+         it may be killed for running out of memory.",
+        gib(available),
+        gib(required)
+    ))
+}
+
+fn gib(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0 * 1024.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    #[test]
+    fn shortfall_only_when_less_is_available_than_required() {
+        let required = dada2_memory_estimate(96, 1_000_000);
+        let message = memory_shortfall(required, Some(4 * GIB)).unwrap();
+        assert!(message.starts_with("Only 4.0 GB of memory is available"), "{}", message);
+        assert!(message.contains(&format!("about {:.1} GB", required as f64 / GIB as f64)), "{}", message);
+        assert_eq!(memory_shortfall(required, Some(required)), None);
+        assert_eq!(memory_shortfall(required, Some(64 * GIB)), None);
+        assert_eq!(memory_shortfall(required, None), None);
+    }
+
+    #[test]
+    fn dada2_estimate_grows_with_samples_and_reads_learned() {
+        assert!(dada2_memory_estimate(10, 1_000_000) < dada2_memory_estimate(100, 1_000_000));
+        assert!(dada2_memory_estimate(10, 100_000) < dada2_memory_estimate(10, 1_000_000));
+        assert_eq!(dada2_memory_estimate(0, 0), DADA2_BASE_BYTES);
+    }
+
+    #[test]
+    fn mem_available_is_read_in_kib() {
+        let meminfo = "MemTotal:       16303208 kB\nMemFree:         1203352 kB\nMemAvailable:    8388608 kB\n";
+        assert_eq!(parse_mem_available(meminfo), Some(8 * GIB));
+        assert_eq!(parse_mem_available("MemTotal: 16303208 kB\n"), None);
+    }
+
+    #[test]
+    fn reads_per_batch_fits_the_jobs_into_memory() {
+        assert_eq!(classifier_reads_per_batch(None, 4), None);
+        assert_eq!(classifier_reads_per_batch(Some(2 * GIB), 1), Some(MIN_READS_PER_BATCH as usize));
+        assert_eq!(classifier_reads_per_batch(Some(512 * GIB), 1), Some(MAX_READS_PER_BATCH as usize));
+        let one_job = classifier_reads_per_batch(Some(16 * GIB), 1).unwrap();
+        let two_jobs = classifier_reads_per_batch(Some(16 * GIB), 2).unwrap();
+        assert!(two_jobs < one_job);
+    }
+}
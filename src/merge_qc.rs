@@ -0,0 +1,166 @@
+use std::error::Error;
+use std::fs;
+
+use crate::color_print::{print_error, print_info};
+use crate::logger::{log_action, log_event, LogLevel};
+
+/// Fraction of a sample's input reads that DADA2 merged into pairs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeRate {
+    pub sample_id: String,
+    pub input: f64,
+    pub merged: f64,
+}
+
+impl MergeRate {
+    pub fn rate(&self) -> f64 {
+        if self.input > 0.0 { self.merged / self.input } else { 0.0 }
+    }
+}
+
+/// Reads the per-sample `input` and `merged` counts from DADA2's exported
+/// denoising stats (`stats.tsv`). Samples with no input reads are left out.
+pub fn merge_rates(stats_tsv: &str) -> Result<Vec<MergeRate>, Box<dyn Error>> {
+    let text = fs::read_to_string(stats_tsv)?;
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    let header: Vec<&str> = lines.next().ok_or_else(|| format!("'{}' is empty", stats_tsv))?.split('\t').collect();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|&field| field.trim() == name)
+            .ok_or_else(|| format!("'{}' has no '{}' column", stats_tsv, name))
+    };
+    let (input_col, merged_col) = (column("input")?, column("merged")?);
+
+    let mut rates = Vec::new();
+    for (index, line) in lines.enumerate() {
+        // QIIME metadata exports carry a `#q2:types` directive under the header
+        if line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let count = |col: usize| -> Result<f64, Box<dyn Error>> {
+            let value = fields.get(col).map(|field| field.trim()).unwrap_or_default();
+            value
+                .parse::<f64>()
+                .map_err(|_| format!("'{}' line {}: '{}' is not a read count", stats_tsv, index + 2, value).into())
+        };
+        let (input, merged) = (count(input_col)?, count(merged_col)?);
+        if input > 0.0 {
+            rates.push(MergeRate { sample_id: fields[0].trim().to_string(), input, merged });
+        }
+    }
+    Ok(rates)
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) { (values[mid - 1] + values[mid]) / 2.0 } else { values[mid] })
+}
+
+/// Reports the median merge rate across samples and warns, with the
+/// truncation lengths used, when it is below `min_rate`: too little overlap
+/// between truncated mates is the usual cause.
+pub fn check_merge_rate(
+    stats_tsv: &str,
+    min_rate: f64,
+    trunc_len_f: usize,
+    trunc_len_r: usize,
+) -> Result<(), Box<dyn Error>> {
+    let mut rates = merge_rates(stats_tsv)?;
+    let mut values: Vec<f64> = rates.iter().map(MergeRate::rate).collect();
+    let Some(median) = median(&mut values) else {
+        print_info("No samples with input reads in the DADA2 stats; merge rate not checked.");
+        return Ok(());
+    };
+    let msg = format!("Median DADA2 merge rate: {:.1}% of input reads across {} samples.", 100.0 * median, rates.len());
+    log_action(&msg);
+    if median >= min_rate {
+        print_info(&msg);
+        return Ok(());
+    }
+
+    rates.sort_by(|a, b| a.rate().total_cmp(&b.rate()));
+    let lowest: Vec<String> = rates
+        .iter()
+        .take(5)
+        .map(|rate| format!("{} ({:.1}%)", rate.sample_id, 100.0 * rate.rate()))
+        .collect();
+    let length = |len: usize| if len == 0 { "none".to_string() } else { len.to_string() };
+    let warning = format!(
+        "Warning: the median DADA2 merge rate is {:.1}%, below {:.1}% (lowest: {}). \
+         Truncation lengths (forward {}, reverse {}) may leave too little overlap between mates to merge them; \
+         check the quality plots and try longer --trunc-len-f/--trunc-len-r, or --denoise-mode single.",
+        100.0 * median,
+        100.0 * min_rate,
+        lowest.join(", "),
+        length(trunc_len_f),
+        length(trunc_len_r)
+    );
+    print_error(&warning);
+    log_event(LogLevel::Warning, None, &warning);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, write_file};
+
+    const HEADER: &str = "sample-id\tinput\tfiltered\tdenoised\tmerged\tnon-chimeric\n#q2:types\tnumeric\tnumeric\tnumeric\tnumeric\tnumeric\n";
+
+    #[test]
+    fn rates_are_read_per_sample_skipping_empty_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let stats = write_file(dir.path(), "stats.tsv", &format!("{}s1\t1000\t900\t850\t500\t450\ns2\t0\t0\t0\t0\t0\n", HEADER));
+        let rates = merge_rates(&stats).unwrap();
+        assert_eq!(rates, [MergeRate { sample_id: "s1".to_string(), input: 1000.0, merged: 500.0 }]);
+        assert_eq!(rates[0].rate(), 0.5);
+
+        let broken = write_file(dir.path(), "broken.tsv", &format!("{}s1\t1000\t900\t850\tNA\t450\n", HEADER));
+        assert!(merge_rates(&broken).unwrap_err().to_string().ends_with("line 3: 'NA' is not a read count"));
+        assert_eq!(median(&mut [0.4, 0.1, 0.3, 0.2]), Some(0.25));
+    }
+
+    #[test]
+    fn low_merge_rates_warn_with_a_trunc_length_suggestion() {
+        let _state = test_support::global_state();
+        crate::logger::init_log(false);
+        let dir = tempfile::tempdir().unwrap();
+        let stats = write_file(
+            dir.path(),
+            "stats.tsv",
+            &format!("{}s1\t1000\t900\t850\t100\t90\ns2\t1000\t900\t850\t300\t290\ns3\t1000\t900\t850\t900\t890\n", HEADER),
+        );
+
+        check_merge_rate(&stats, 0.5, 200, 0).unwrap();
+
+        let log = fs::read_to_string(test_support::output_dir().join("windchime.log")).unwrap();
+        assert!(log.contains("Median DADA2 merge rate: 30.0% of input reads across 3 samples."), "{}", log);
+        assert!(
+            log.contains("Warning: the median DADA2 merge rate is 30.0%, below 50.0% (lowest: s1 (10.0%), s2 (30.0%), s3 (90.0%))"),
+            "{}",
+            log
+        );
+        assert!(log.contains("Truncation lengths (forward 200, reverse none)"), "{}", log);
+        assert!(log.contains("try longer --trunc-len-f/--trunc-len-r, or --denoise-mode single"), "{}", log);
+    }
+
+    #[test]
+    fn good_merge_rates_do_not_warn() {
+        let _state = test_support::global_state();
+        crate::logger::init_log(false);
+        let dir = tempfile::tempdir().unwrap();
+        let stats = write_file(dir.path(), "stats.tsv", &format!("{}s1\t1000\t900\t850\t800\t790\n", HEADER));
+
+        check_merge_rate(&stats, 0.5, 200, 180).unwrap();
+
+        let log = fs::read_to_string(test_support::output_dir().join("windchime.log")).unwrap();
+        assert!(log.contains("Median DADA2 merge rate: 80.0%"), "{}", log);
+        assert!(!log.contains("Warning"), "{}", log);
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use bio::io::fastq;
+use flate2::{write::GzEncoder, Compression};
+
+/// Number of read pairs a producer buffers before handing them to the writer.
+pub const CHUNK_SIZE: usize = 4096;
+
+/// A batch of read pairs from one source (e.g. one sample), tagged with its
+/// position so the writer can restore a deterministic order.
+struct Chunk {
+    source: usize,
+    sequence: usize,
+    pairs: Vec<(fastq::Record, Option<fastq::Record>)>,
+    last: bool,
+}
+
+/// A gzipped R1/R2 output (R1 only for single-end reads) shared by parallel producers.
+///
+/// Producers send their records through a channel to a single consumer thread
+/// that writes them ordered by source index, then by chunk sequence within a
+/// source. The output is therefore identical across runs no matter how rayon
+/// schedules the producers. Every source index in `0..sources` must eventually
+/// send a chunk with `last = true` (see [`OrderedPairSender::finish`]).
+pub struct OrderedPairWriter {
+    sender: Sender<Chunk>,
+    handle: JoinHandle<io::Result<u64>>,
+}
+
+impl OrderedPairWriter {
+    /// Creates the gzipped output files (no R2 file when `r2_path` is `None`)
+    /// and starts the writer thread.
+    pub fn create(r1_path: &str, r2_path: Option<&str>, level: Compression) -> io::Result<Self> {
+        let out1 = fastq::Writer::new(GzEncoder::new(File::create(r1_path)?, level));
+        let out2 = match r2_path {
+            Some(path) => Some(fastq::Writer::new(GzEncoder::new(File::create(path)?, level))),
+            None => None,
+        };
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || write_in_order(receiver, out1, out2));
+        Ok(OrderedPairWriter { sender, handle })
+    }
+
+    /// Returns a buffered sender for the producer with index `source`.
+    pub fn sender(&self, source: usize) -> OrderedPairSender {
+        OrderedPairSender {
+            sender: self.sender.clone(),
+            source,
+            sequence: 0,
+            pairs: Vec::new(),
+        }
+    }
+
+    /// Waits for all producers' records to be written and flushes the files.
+    /// Returns the number of pairs written.
+    pub fn finish(self) -> io::Result<u64> {
+        drop(self.sender);
+        self.handle
+            .join()
+            .map_err(|_| io::Error::other("ordered writer thread panicked"))?
+    }
+}
+
+/// One producer's handle onto an [`OrderedPairWriter`].
+pub struct OrderedPairSender {
+    sender: Sender<Chunk>,
+    source: usize,
+    sequence: usize,
+    pairs: Vec<(fastq::Record, Option<fastq::Record>)>,
+}
+
+impl OrderedPairSender {
+    /// Queues a pair, sending a chunk to the writer once [`CHUNK_SIZE`] pairs
+    /// are buffered. `r2` is `None` for single-end reads.
+    pub fn push(&mut self, r1: fastq::Record, r2: Option<fastq::Record>) -> io::Result<()> {
+        self.pairs.push((r1, r2));
+        if self.pairs.len() >= CHUNK_SIZE {
+            self.send(false)?;
+        }
+        Ok(())
+    }
+
+    /// Sends any buffered pairs and marks this source as complete.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.send(true)
+    }
+
+    fn send(&mut self, last: bool) -> io::Result<()> {
+        let chunk = Chunk {
+            source: self.source,
+            sequence: self.sequence,
+            pairs: std::mem::take(&mut self.pairs),
+            last,
+        };
+        self.sequence += 1;
+        self.sender
+            .send(chunk)
+            .map_err(|_| io::Error::other("ordered writer thread stopped early"))
+    }
+}
+
+/// Consumer loop: buffers out-of-order chunks and writes each as soon as every
+/// chunk before it has been written. Anything still buffered when all senders
+/// are gone (a source that never finished) is written in key order.
+fn write_in_order<W: Write>(
+    receiver: Receiver<Chunk>,
+    mut out1: fastq::Writer<W>,
+    mut out2: Option<fastq::Writer<W>>,
+) -> io::Result<u64> {
+    let mut pending: BTreeMap<(usize, usize), Chunk> = BTreeMap::new();
+    let mut next = (0, 0);
+    let mut written = 0;
+
+    let mut write_chunk = |chunk: &Chunk| -> io::Result<()> {
+        for (r1, r2) in &chunk.pairs {
+            out1.write_record(r1)?;
+            if let (Some(out2), Some(r2)) = (&mut out2, r2) {
+                out2.write_record(r2)?;
+            }
+            written += 1;
+        }
+        Ok(())
+    };
+
+    for chunk in receiver {
+        pending.insert((chunk.source, chunk.sequence), chunk);
+        while let Some(chunk) = pending.remove(&next) {
+            write_chunk(&chunk)?;
+            next = if chunk.last { (next.0 + 1, 0) } else { (next.0, next.1 + 1) };
+        }
+    }
+    for chunk in pending.values() {
+        write_chunk(chunk)?;
+    }
+
+    out1.flush()?;
+    if let Some(out2) = &mut out2 {
+        out2.flush()?;
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rayon::prelude::*;
+
+    use crate::test_support::read_fastq_gz;
+
+    /// Writes `sources` producers' pairs in parallel, each sending more than a
+    /// chunk, and returns the `(id, sequence)` of the R1 records written.
+    fn write_in_parallel(dir: &std::path::Path, sources: usize, pairs_per_source: usize) -> Vec<(String, String)> {
+        let r1 = dir.join("shared_R1.fastq.gz").to_string_lossy().into_owned();
+        let r2 = dir.join("shared_R2.fastq.gz").to_string_lossy().into_owned();
+        let writer = OrderedPairWriter::create(&r1, Some(&r2), Compression::fast()).unwrap();
+        (0..sources).into_par_iter().for_each(|source| {
+            let mut sender = writer.sender(source);
+            for i in 0..pairs_per_source {
+                let id = format!("s{}r{}", source, i);
+                let record = |seq: &[u8]| fastq::Record::with_attrs(&id, None, seq, &vec![b'I'; seq.len()]);
+                sender.push(record(b"ACGT"), Some(record(b"TTGG"))).unwrap();
+            }
+            sender.finish().unwrap();
+        });
+        assert_eq!(writer.finish().unwrap(), (sources * pairs_per_source) as u64);
+        assert_eq!(read_fastq_gz(&r2).len(), sources * pairs_per_source);
+        read_fastq_gz(&r1)
+    }
+
+    #[test]
+    fn shared_output_is_identical_across_parallel_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (sources, pairs_per_source) = (6, CHUNK_SIZE + 10);
+        let first = write_in_parallel(dir.path(), sources, pairs_per_source);
+        for _ in 0..3 {
+            assert_eq!(write_in_parallel(dir.path(), sources, pairs_per_source), first);
+        }
+        let expected: Vec<String> = (0..sources)
+            .flat_map(|source| (0..pairs_per_source).map(move |i| format!("s{}r{}", source, i)))
+            .collect();
+        let ids: Vec<String> = first.into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn single_end_output_has_no_r2() {
+        let dir = tempfile::tempdir().unwrap();
+        let r1 = dir.path().join("single_R1.fastq.gz").to_string_lossy().into_owned();
+        let writer = OrderedPairWriter::create(&r1, None, Compression::fast()).unwrap();
+        let mut sender = writer.sender(0);
+        sender.push(fastq::Record::with_attrs("read1", None, b"ACGT", b"IIII"), None).unwrap();
+        sender.finish().unwrap();
+        assert_eq!(writer.finish().unwrap(), 1);
+        assert_eq!(read_fastq_gz(&r1), [("read1".to_string(), "ACGT".to_string())]);
+    }
+}
@@ -0,0 +1,188 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read};
+
+use zip::ZipArchive;
+
+use crate::color_print::print_success;
+use crate::estimate::format_bytes;
+
+/// What a `.qza`/`.qzv` says about itself in `<uuid>/metadata.yaml`, and
+/// the entries of the archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArtifactInfo {
+    pub uuid: String,
+    /// Semantic type, e.g. `FeatureTable[Frequency]` (`Visualization` for a `.qzv`).
+    pub artifact_type: String,
+    /// Directory format, absent for visualizations.
+    pub format: Option<String>,
+    /// Entry names and uncompressed sizes, in archive order.
+    pub entries: Vec<(String, u64)>,
+}
+
+fn open_archive(path: &str) -> Result<ZipArchive<File>, Box<dyn Error>> {
+    ZipArchive::new(File::open(path).map_err(|e| format!("Unable to open '{}': {}", path, e))?)
+        .map_err(|e| format!("'{}' is not a QIIME artifact (not a zip archive: {})", path, e).into())
+}
+
+/// Name of the archive's `<uuid>/metadata.yaml` entry.
+fn metadata_entry(archive: &ZipArchive<File>) -> Option<String> {
+    archive
+        .file_names()
+        .find(|name| {
+            let mut parts = name.split('/');
+            parts.next().is_some_and(|uuid| !uuid.is_empty())
+                && parts.next() == Some("metadata.yaml")
+                && parts.next().is_none()
+        })
+        .map(str::to_string)
+}
+
+/// Reads the UUID, type and format from `metadata.yaml` and lists the entries
+/// of the artifact at `path`, without QIIME.
+pub fn read_artifact(path: &str) -> Result<ArtifactInfo, Box<dyn Error>> {
+    let mut archive = open_archive(path)?;
+    let metadata = metadata_entry(&archive).ok_or_else(|| format!("'{}' has no <uuid>/metadata.yaml", path))?;
+    let mut text = String::new();
+    archive.by_name(&metadata)?.read_to_string(&mut text)?;
+
+    // metadata.yaml is three flat `key: value` lines; no YAML parser needed
+    let field = |key: &str| {
+        text.lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(k, _)| k.trim() == key)
+            .map(|(_, value)| value.trim().trim_matches(['\'', '"']).to_string())
+            .filter(|value| !value.is_empty() && value != "null")
+    };
+    let uuid = field("uuid").unwrap_or_else(|| metadata.split('/').next().unwrap_or_default().to_string());
+    let artifact_type = field("type").ok_or_else(|| format!("{} in '{}' has no type", metadata, path))?;
+
+    let mut entries = Vec::with_capacity(archive.len());
+    for index in 0..archive.len() {
+        let entry = archive.by_index(index)?;
+        if !entry.is_dir() {
+            entries.push((entry.name().to_string(), entry.size()));
+        }
+    }
+    Ok(ArtifactInfo { uuid, artifact_type, format: field("format"), entries })
+}
+
+/// Prints the type, UUID and entries of the artifact at `path`.
+pub fn print_artifact(path: &str) -> Result<(), Box<dyn Error>> {
+    let info = read_artifact(path)?;
+    println!("Type:   {}", info.artifact_type);
+    println!("UUID:   {}", info.uuid);
+    if let Some(format) = &info.format {
+        println!("Format: {}", format);
+    }
+    println!();
+    println!("{:>10}  Entry", "Size");
+    for (name, size) in &info.entries {
+        println!("{:>10}  {}", format_bytes(*size as f64), name);
+    }
+    Ok(())
+}
+
+/// Writes the entry `name` of the artifact at `path` to `output`, by default
+/// the entry's file name in the current directory. `name` may leave out the
+/// leading `<uuid>/`, e.g. `data/metadata.tsv`.
+pub fn extract_entry(path: &str, name: &str, output: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let mut archive = open_archive(path)?;
+    let name = name.trim_start_matches('/');
+    let full_name = archive
+        .file_names()
+        .find(|entry| *entry == name || entry.split_once('/').is_some_and(|(_, rest)| rest == name))
+        .map(str::to_string)
+        .ok_or_else(|| format!("'{}' has no entry '{}'; run `windchime peek {}` to list them", path, name, path))?;
+    let output = output.unwrap_or_else(|| full_name.rsplit('/').next().unwrap_or(&full_name));
+    let mut entry = archive.by_name(&full_name)?;
+    let written = io::copy(&mut entry, &mut File::create(output)?)?;
+    print_success(&format!("Extracted '{}' ({}) to '{}'.", full_name, format_bytes(written as f64), output));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use tempfile::TempDir;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    /// Writes a `.qza` as QIIME lays it out: everything under `<uuid>/`.
+    fn write_artifact(path: &Path, entries: &[(&str, &str)]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        for (name, contents) in entries {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    const UUID: &str = "2c6a1b2e-6f0e-4f0a-9d3a-6a0c1c9a7e11";
+
+    #[test]
+    fn peek_reports_the_type_uuid_and_entries() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("table.qza");
+        let metadata = format!("uuid: {}\ntype: FeatureTable[Frequency]\nformat: BIOMV210DirFmt\n", UUID);
+        write_artifact(
+            &path,
+            &[
+                (&format!("{}/metadata.yaml", UUID), &metadata),
+                (&format!("{}/VERSION", UUID), "QIIME 2\narchive: 5\n"),
+                (&format!("{}/data/feature-table.biom", UUID), "biom"),
+            ],
+        );
+
+        let info = read_artifact(path.to_str().unwrap()).unwrap();
+        assert_eq!(info.uuid, UUID);
+        assert_eq!(info.artifact_type, "FeatureTable[Frequency]");
+        assert_eq!(info.format.as_deref(), Some("BIOMV210DirFmt"));
+        assert_eq!(
+            info.entries,
+            [
+                (format!("{}/metadata.yaml", UUID), metadata.len() as u64),
+                (format!("{}/VERSION", UUID), 19),
+                (format!("{}/data/feature-table.biom", UUID), 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_visualization_has_no_format_and_its_entries_can_be_extracted() {
+        let dir = TempDir::new().unwrap();
+        let qzv = dir.path().join("stats.qzv");
+        let path = qzv.to_str().unwrap();
+        write_artifact(
+            &qzv,
+            &[
+                (&format!("{}/metadata.yaml", UUID), &format!("uuid: {}\ntype: Visualization\nformat: null\n", UUID)),
+                (&format!("{}/data/metadata.tsv", UUID), "sample-id\tinput\ns1\t100\n"),
+            ],
+        );
+        let info = read_artifact(path).unwrap();
+        assert_eq!((info.artifact_type.as_str(), info.format), ("Visualization", None));
+
+        let output = dir.path().join("stats.tsv");
+        extract_entry(path, "data/metadata.tsv", Some(output.to_str().unwrap())).unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), "sample-id\tinput\ns1\t100\n");
+        let missing = extract_entry(path, "data/index.html", None).unwrap_err().to_string();
+        assert!(missing.contains("has no entry 'data/index.html'"), "{}", missing);
+    }
+
+    #[test]
+    fn files_that_are_not_artifacts_are_rejected() {
+        let dir = TempDir::new().unwrap();
+        let not_zip = dir.path().join("table.qza");
+        fs::write(&not_zip, "not a zip").unwrap();
+        assert!(read_artifact(not_zip.to_str().unwrap()).unwrap_err().to_string().contains("not a zip archive"));
+
+        let no_metadata = dir.path().join("other.qza");
+        write_artifact(&no_metadata, &[("data/table.tsv", "asv\ts1\n")]);
+        let error = read_artifact(no_metadata.to_str().unwrap()).unwrap_err().to_string();
+        assert!(error.contains("has no <uuid>/metadata.yaml"), "{}", error);
+    }
+}
@@ -1,31 +1,111 @@
-use std::process::{Command, Stdio};
+use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::io::{self, Write};
+use std::io;
 use std::path::Path;
+use std::collections::HashMap;
 use std::error::Error;
+use std::sync::Mutex;
 use std::time::Duration;
-use std::collections::HashMap;
 
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use flate2::read::GzDecoder;
-use reqwest;
 use csv::{ReaderBuilder, WriterBuilder};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::logger::log_action;
+use crate::annotate;
+use crate::biom;
+use crate::cleanup::{self, CleanupProfile};
+use crate::conda::{self, conda_binary};
+use crate::command::{command_line, run_command, shell_quote, CommandOptions, CommandOutput};
+use crate::estimate;
+use crate::logger::{log_action, log_event, LogLevel};
+use crate::memory;
+use crate::merge_qc;
+use crate::replicates;
+use crate::primers::{self, PrimerDetection};
+use crate::progress;
+use crate::qiime_compat;
+use crate::reference;
+use crate::summary;
+use crate::taxonomy;
 use crate::color_print::{print_info, print_error, print_success};
-use crate::{OUTPUT_DIR};
+use crate::{output_dir};
 
 // We'll assume we can get the verbose bool from a function.
-fn verbose_mode() -> bool {
+pub(crate) fn verbose_mode() -> bool {
     // In real code, you'd reference the AtomicBool in main.rs
     // For example:
     // crate::main::VERBOSE_MODE.load(std::sync::atomic::Ordering::Relaxed)
     super::VERBOSE_MODE.load(std::sync::atomic::Ordering::Relaxed)
 }
 
-/// Helper to generate an output file/folder path within OUTPUT_DIR.
+/// Whether `--dry-run` is set: conda commands are printed, not run.
+pub(crate) fn dry_run_mode() -> bool {
+    super::DRY_RUN.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// `--download-retries`: how often a failed download is retried.
+pub(crate) fn download_retries() -> usize {
+    super::DOWNLOAD_RETRIES.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Whether `--single-end` was given: reads are R1 only, not pairs.
+pub(crate) fn single_end() -> bool {
+    super::SINGLE_END.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Whether `--skip-checksum` was given: downloads aren't compared with their known digests.
+pub(crate) fn skip_checksum() -> bool {
+    super::SKIP_CHECKSUM.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// In `--dry-run` mode, prints the command that would run (after its
+/// environment variables, as a shell would take them) and returns true.
+fn print_dry_run(program: &str, args: &[&str], env: &[(String, String)]) -> bool {
+    if !dry_run_mode() {
+        return false;
+    }
+    let mut words: Vec<String> = env.iter().map(|(key, value)| shell_quote(&format!("{}={}", key, value))).collect();
+    words.push(command_line(program, args));
+    let line = words.join(" ");
+    log_action(&format!("Dry run, not running: {}", line));
+    println!("[DRY RUN] {}", line);
+    true
+}
+
+/// Prepended to every artifact name the pipeline writes (`--output-prefix`).
+static OUTPUT_PREFIX: Lazy<Mutex<String>> = Lazy::new(|| Mutex::new(String::new()));
+
+/// Sets the prefix [`out_path`] applies, so several runs can share OUTPUT_DIR.
+pub fn set_output_prefix(prefix: &str) -> Result<(), Box<dyn Error>> {
+    if prefix.contains('/') || prefix.contains('\\') {
+        return Err(format!("--output-prefix must not contain path separators: '{}'", prefix).into());
+    }
+    *OUTPUT_PREFIX.lock().unwrap() = prefix.to_string();
+    Ok(())
+}
+
+/// The current output prefix (empty by default).
+pub fn output_prefix() -> String {
+    OUTPUT_PREFIX.lock().unwrap().clone()
+}
+
+/// Helper to generate an output file/folder path within OUTPUT_DIR. The
+/// output prefix is applied to the top-level name, so `asvs/x.qza` becomes
+/// `<prefix>asvs/x.qza`.
 fn out_path(relative: &str) -> String {
-    format!("{}/{}", OUTPUT_DIR, relative)
+    format!("{}/{}{}", output_dir(), output_prefix(), relative)
+}
+
+/// Path within OUTPUT_DIR of a file shared between runs (reference databases,
+/// user-named manifests); never prefixed. Absolute paths are kept as they are.
+pub(crate) fn shared_path(relative: &str) -> String {
+    if Path::new(relative).is_absolute() {
+        return relative.to_string();
+    }
+    format!("{}/{}", output_dir(), relative)
 }
 
 /// Wraps an operation `f` in a spinner-based progress bar if not in verbose mode.
@@ -33,15 +113,41 @@ fn run_step<F>(description: &str, f: F) -> Result<(), Box<dyn Error>>
 where
     F: FnOnce() -> Result<(), Box<dyn Error>>,
 {
-    log_action(&format!("Starting step: {}", description));
+    log_event(LogLevel::Info, Some(description), &format!("Starting step: {}", description));
 
     // If verbose, just print the step description and run it
     if verbose_mode() {
         print_info(&format!("==> {}", description));
         let result = f();
         match &result {
-            Ok(_) => print_success(&format!("{} ✔", description)),
-            Err(_) => print_error(&format!("{} ✘", description)),
+            Ok(_) => {
+                print_success(&format!("{} ✔", description));
+                log_event(LogLevel::Info, Some(description), &format!("Step succeeded: {}", description));
+            }
+            Err(e) => {
+                print_error(&format!("{} ✘", description));
+                log_event(LogLevel::Error, Some(description), &format!("Step failed: {}: {}", description, e));
+            }
+        }
+        return result;
+    }
+
+    // Without a terminal to draw on, print plain lines and a periodic heartbeat
+    if !progress::interactive() {
+        print_info(&format!("==> {}", description));
+        let result = {
+            let _heartbeat = progress::Heartbeat::start(description);
+            f()
+        };
+        match &result {
+            Ok(_) => {
+                print_success(&format!("{} ✔", description));
+                log_event(LogLevel::Info, Some(description), &format!("Step succeeded: {}", description));
+            }
+            Err(e) => {
+                print_error(&format!("{} ✘", description));
+                log_event(LogLevel::Error, Some(description), &format!("Step failed: {}: {}", description, e));
+            }
         }
         return result;
     }
@@ -61,15 +167,17 @@ where
     pb.enable_steady_tick(Duration::from_millis(100));
     pb.set_message(description.to_owned());
 
+    progress::set_step_spinner(Some(pb.clone()));
     let result = f();
+    progress::set_step_spinner(None);
     match &result {
         Ok(_) => {
             pb.finish_with_message(format!("{} ✔", description));
-            log_action(&format!("Step succeeded: {}", description));
+            log_event(LogLevel::Info, Some(description), &format!("Step succeeded: {}", description));
         },
-        Err(_) => {
+        Err(e) => {
             pb.abandon_with_message(format!("{} ✘", description));
-            log_action(&format!("Step failed: {}", description));
+            log_event(LogLevel::Error, Some(description), &format!("Step failed: {}: {}", description, e));
         }
     }
     result
@@ -77,22 +185,15 @@ where
 
 /// Checks if a specified conda environment already exists.
 pub fn conda_env_exists(env_name: &str) -> Result<bool, Box<dyn Error>> {
-    let output = Command::new("conda")
-        .arg("env")
-        .arg("list")
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output();
-
-    let output = match output {
+    let output = match run_command(conda_binary(), &["env", "list"], &capture_options()) {
         Ok(o) => o,
         Err(e) => {
             print_error(&format!("Failed to run 'conda env list': {}", e));
-            return Err(e.into());
+            return Err(e);
         }
     };
 
-    if !output.status.success() {
+    if !output.success {
         let msg = "Could not retrieve conda environment list.";
         print_error(msg);
         return Err(msg.into());
@@ -108,6 +209,8 @@ pub fn conda_env_exists(env_name: &str) -> Result<bool, Box<dyn Error>> {
 }
 
 /// Installs the specified QIIME2 environment if it doesn't already exist.
+// Linux and Windows are listed separately, though both use the Linux environment file
+#[allow(clippy::if_same_then_else)]
 pub fn install_qiime2_amplicon_2024_10(env_name: &str) -> Result<(), Box<dyn Error>> {
     match conda_env_exists(env_name) {
         Ok(true) => {
@@ -123,118 +226,274 @@ pub fn install_qiime2_amplicon_2024_10(env_name: &str) -> Result<(), Box<dyn Err
         }
     }
 
+    let conda = conda_binary();
+
     // Check current channel priority
-    let output = Command::new("conda")
-        .args(&["config", "--show", "channel_priority"])
-        .output()?;
+    let output = run_command(conda, &["config", "--show", "channel_priority"], &capture_options())?;
     let current_priority = String::from_utf8_lossy(&output.stdout);
     let was_strict = current_priority.contains("strict");
 
     // Only set to flexible if it wasn't already
     if was_strict {
-        run_shell_command("conda config --set channel_priority flexible")?;
+        run_conda(&["config", "--set", "channel_priority", "flexible"], Vec::new())?;
     }
 
-    let commands: Vec<String> = if cfg!(target_os = "macos") && cfg!(target_arch = "aarch64") {
-        vec![
-            format!(
-                "CONDA_SUBDIR=osx-64 conda env create -n {} --file https://data.qiime2.org/distro/amplicon/qiime2-amplicon-2024.10-py310-osx-conda.yml",
-                env_name
-            ),
-            "conda config --env --set subdir osx-64".to_string(),
-        ]
+    let env_file = |platform: &str| {
+        format!("https://data.qiime2.org/distro/amplicon/qiime2-amplicon-2024.10-py310-{}-conda.yml", platform)
+    };
+    if cfg!(target_os = "macos") && cfg!(target_arch = "aarch64") {
+        create_conda_env(
+            env_name,
+            &env_file("osx"),
+            vec![("CONDA_SUBDIR".to_string(), "osx-64".to_string())],
+        )?;
+        run_conda(&["config", "--env", "--set", "subdir", "osx-64"], Vec::new())?;
     } else if cfg!(target_os = "macos") {
-        vec![format!(
-            "conda env create -n {} --file https://data.qiime2.org/distro/amplicon/qiime2-amplicon-2024.10-py310-osx-conda.yml",
-            env_name
-        )]
+        create_conda_env(env_name, &env_file("osx"), Vec::new())?;
     } else if cfg!(target_os = "linux") {
-        vec![format!(
-            "conda env create -n {} --file https://data.qiime2.org/distro/amplicon/qiime2-amplicon-2024.10-py310-linux-conda.yml",
-            env_name
-        )]
+        create_conda_env(env_name, &env_file("linux"), Vec::new())?;
     } else if cfg!(target_os = "windows") {
-        vec![format!(
-            "conda env create -n {} --file https://data.qiime2.org/distro/amplicon/qiime2-amplicon-2024.10-py310-linux-conda.yml",
-            env_name
-        )]
+        create_conda_env(env_name, &env_file("linux"), Vec::new())?;
     } else {
-        vec!["echo 'Unknown or unsupported platform'".to_string()]
-    };
-
-    for cmd in &commands {
-        run_shell_command(cmd)?;
+        print_error("Unknown or unsupported platform");
     }
 
     // Only reset to strict if we changed it
     if was_strict {
-        run_shell_command("conda config --set channel_priority strict")?;
+        run_conda(&["config", "--set", "channel_priority", "strict"], Vec::new())?;
     }
 
-    print_success(&format!(
-        "Installation complete. Environment '{}' is ready to use.",
-        env_name
-    ));
+    if !dry_run_mode() {
+        print_success(&format!(
+            "Installation complete. Environment '{}' is ready to use.",
+            env_name
+        ));
+    }
     Ok(())
 }
 
-/// Executes a shell command (via `bash -c`) in either quiet or verbose mode.
-fn run_shell_command(cmd: &str) -> Result<(), Box<dyn Error>> {
-    log_action(&format!("Running shell command: {}", cmd));
-    if verbose_mode() {
-        println!("[CMD] {}", cmd);
+/// The solver conda uses when none is passed: its `solver` setting, which is
+/// `libmamba` by default since conda 23.10. Older condas without the setting
+/// only have the classic solver.
+fn configured_solver() -> String {
+    run_command(conda_binary(), &["config", "--show", "solver"], &capture_options())
+        .ok()
+        .filter(|output| output.success)
+        .and_then(|output| {
+            let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+            stdout.split_once("solver:").map(|(_, value)| value.trim().to_string())
+        })
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| "classic".to_string())
+}
+
+/// Runs `conda env create` for `env_name` from `file` with the `--solver`
+/// choice. With `auto`, conda's configured solver is tried first and, if it
+/// fails to solve the environment, the partial environment is removed and
+/// creation is retried with `--solver libmamba`.
+fn create_conda_env(env_name: &str, file: &str, env: Vec<(String, String)>) -> Result<(), Box<dyn Error>> {
+    let create = |solver: Option<&str>| {
+        let mut args = vec!["env", "create", "-n", env_name, "--file", file];
+        if let Some(solver) = solver {
+            args.extend(["--solver", solver]);
+        }
+        run_conda(&args, env.clone())
+    };
+
+    let chosen = conda::solver();
+    // A dry run only prints the first attempt
+    if dry_run_mode() {
+        return create(chosen.flag());
+    }
+    if let Some(solver) = chosen.flag() {
+        create(Some(solver))?;
+        log_action(&format!("Created environment '{}' with the {} solver.", env_name, solver));
+        return Ok(());
     }
 
-    let (stdout_setting, stderr_setting) = if verbose_mode() {
-        (Stdio::inherit(), Stdio::inherit())
-    } else {
-        (Stdio::null(), Stdio::null())
+    let default_solver = configured_solver();
+    let Err(e) = create(None) else {
+        log_action(&format!("Created environment '{}' with conda's {} solver.", env_name, default_solver));
+        print_info(&format!("Environment solved with conda's {} solver.", default_solver));
+        return Ok(());
     };
+    // Without captured stderr (verbose mode) the cause is unknown; try anyway
+    let solver_failed = last_failure_stderr().is_none_or(|stderr| conda::is_solver_failure(&stderr));
+    if default_solver == "libmamba" || !solver_failed {
+        return Err(e);
+    }
+
+    let msg = format!(
+        "Warning: conda's {} solver could not create '{}'; retrying with --solver libmamba.",
+        default_solver, env_name
+    );
+    print_error(&msg);
+    log_event(LogLevel::Warning, None, &msg);
+    if conda_env_exists(env_name).unwrap_or(false) {
+        run_conda(&["env", "remove", "-n", env_name, "-y"], Vec::new())?;
+    }
+    create(Some("libmamba")).map_err(|e| {
+        format!(
+            "{} (the libmamba solver also failed; if conda reports it missing, run \
+             `conda install -n base conda-libmamba-solver`)",
+            e
+        )
+    })?;
+    log_action(&format!("Created environment '{}' with the libmamba solver after the {} solver failed.", env_name, default_solver));
+    print_success("Environment solved with the libmamba solver.");
+    Ok(())
+}
 
-    let status = Command::new("bash")
-        .arg("-c")
-        .arg(cmd)
-        .stdin(Stdio::null())
-        .stdout(stdout_setting)
-        .stderr(stderr_setting)
-        .status()?;
+/// Stderr of the most recent failed command, kept for failure bundles.
+static LAST_FAILURE_STDERR: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
 
-    if !status.success() {
-        let msg = format!("Command failed: {}", cmd);
-        print_error(&msg);
-        return Err(msg.into());
+/// Returns the captured stderr of the most recent failed command, if any.
+///
+/// Only available in non-verbose mode; in verbose mode stderr goes straight
+/// to the terminal.
+pub fn last_failure_stderr() -> Option<String> {
+    LAST_FAILURE_STDERR.lock().unwrap().clone()
+}
+
+/// Options for commands whose output we need to inspect.
+fn capture_options() -> CommandOptions {
+    CommandOptions { capture: true, ..Default::default() }
+}
+
+/// Runs `program` via [`run_command`]. In verbose mode its output is streamed
+/// to the terminal; otherwise stdout is discarded and stderr captured so it
+/// can be recorded if the command fails.
+fn run_and_record<S: AsRef<OsStr>>(
+    program: &str,
+    args: &[S],
+    env: Vec<(String, String)>,
+    description: &str,
+) -> Result<(), Box<dyn Error>> {
+    let opts = CommandOptions { env, verbose: verbose_mode(), ..Default::default() };
+    let output = run_command(program, args, &opts)?;
+    if !output.success {
+        *LAST_FAILURE_STDERR.lock().unwrap() =
+            (!opts.verbose).then(|| String::from_utf8_lossy(&output.stderr).into_owned());
+        if let Some(code) = output.code {
+            log_action(&format!("{} (exit code {})", description, code));
+        }
+        print_error(description);
+        return Err(description.into());
     }
     Ok(())
 }
 
-/// Runs a QIIME command in a specified conda environment via `conda run`.
-fn run_conda_qiime_command(env: &str, qiime_args: &str) -> Result<(), Box<dyn Error>> {
-    log_action(&format!("Running QIIME command in {}: qiime {}", env, qiime_args));
+/// Runs `conda <args>` (see [`conda_binary`]) in either quiet or verbose mode.
+fn run_conda(args: &[&str], env: Vec<(String, String)>) -> Result<(), Box<dyn Error>> {
+    let conda = conda_binary();
+    if print_dry_run(conda, args, &env) {
+        return Ok(());
+    }
+    let cmd = command_line(conda, args);
+    log_action(&format!("Running command: {}", cmd));
     if verbose_mode() {
-        println!("[QIIME CMD] qiime {}", qiime_args);
+        println!("[CMD] {}", cmd);
     }
-    let mut args: Vec<&str> = vec!["run", "-n", env, "qiime"];
-    args.extend(qiime_args.split_whitespace());
 
-    let (stdout_setting, stderr_setting) = if verbose_mode() {
-        (Stdio::inherit(), Stdio::inherit())
-    } else {
-        (Stdio::null(), Stdio::null())
-    };
+    run_and_record(conda, args, env, &format!("Command failed: {}", cmd))
+}
 
-    let status = Command::new("conda")
-        .args(&args)
-        .stdin(Stdio::null())
-        .stdout(stdout_setting)
-        .stderr(stderr_setting)
-        .status()?;
+/// Thread-limiting variables honoured by the numeric libraries QIIME uses
+/// (OpenMP, OpenBLAS, MKL, Numba).
+const THREAD_ENV_VARS: [&str; 4] = [
+    "OMP_NUM_THREADS",
+    "OPENBLAS_NUM_THREADS",
+    "MKL_NUM_THREADS",
+    "NUMBA_NUM_THREADS",
+];
 
-    if !status.success() {
-        let msg = format!("QIIME command failed: qiime {}", qiime_args);
-        print_error(&msg);
-        return Err(msg.into());
+/// Environment applied to every QIIME child process of the current run.
+struct ChildEnv {
+    /// Value for [`THREAD_ENV_VARS`] in steps that don't parallelize themselves.
+    default_threads: usize,
+    /// User-supplied `--env KEY=VALUE` pairs; these win over the thread limits.
+    extra: Vec<(String, String)>,
+}
+
+static CHILD_ENV: Lazy<Mutex<ChildEnv>> = Lazy::new(|| {
+    Mutex::new(ChildEnv { default_threads: 1, extra: Vec::new() })
+});
+
+/// Sets the environment used for QIIME child processes for the rest of the run.
+fn configure_child_env(default_threads: usize, extra: &[(String, String)]) {
+    let mut child_env = CHILD_ENV.lock().unwrap();
+    child_env.default_threads = default_threads.max(1);
+    child_env.extra = extra.to_vec();
+}
+
+/// Environment variables for a QIIME child process. `threads` overrides the
+/// run-wide thread limit for one step.
+fn child_env_vars(threads: Option<usize>) -> Vec<(String, String)> {
+    let child_env = CHILD_ENV.lock().unwrap();
+    let threads = threads.unwrap_or(child_env.default_threads).to_string();
+    let mut vars: Vec<(String, String)> = THREAD_ENV_VARS
+        .iter()
+        .map(|key| (key.to_string(), threads.clone()))
+        .collect();
+    for (key, value) in &child_env.extra {
+        vars.retain(|(k, _)| k != key);
+        vars.push((key.clone(), value.clone()));
+    }
+    vars
+}
+
+/// Runs a QIIME command in a specified conda environment via `conda run`.
+fn run_conda_qiime_command(env: &str, qiime_args: &[String]) -> Result<(), Box<dyn Error>> {
+    run_conda_qiime_command_threads(env, qiime_args, None)
+}
+
+/// Like [`run_conda_qiime_command`], but with the child's numeric-library
+/// thread limit set to `threads` instead of the run-wide default. Steps that
+/// already spread work over several processes or threads (Cutadapt, DADA2,
+/// classify-sklearn) pass `Some(1)` so the workers don't oversubscribe cores.
+fn run_conda_qiime_command_threads(
+    env: &str,
+    qiime_args: &[String],
+    threads: Option<usize>,
+) -> Result<(), Box<dyn Error>> {
+    let vars = child_env_vars(threads);
+    let mut args: Vec<&str> = vec!["run", "-n", env, "qiime"];
+    args.extend(qiime_args.iter().map(String::as_str));
+    if print_dry_run(conda_binary(), &args, &vars) {
+        return Ok(());
+    }
+    let cmd = command_line("qiime", qiime_args);
+    log_action(&format!(
+        "Running QIIME command in {}: {} (env: {})",
+        env,
+        cmd,
+        vars.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(" ")
+    ));
+    if verbose_mode() {
+        println!("[QIIME CMD] {}", cmd);
+    }
+    // Each argument is passed as is, so values with spaces or shell
+    // metacharacters can't be split or reinterpreted
+    run_and_record(conda_binary(), &args, vars, &format!("QIIME command failed: {}", cmd))
+}
+
+/// Converts a BIOM table to TSV with the built-in converter, falling back to
+/// conda's `biom convert` if it can't parse the table (e.g. HDF5 BIOM 2.x).
+fn convert_biom_to_tsv(env_name: &str, biom_in: &str, tsv_out: &str, force_conda: bool) -> Result<(), Box<dyn Error>> {
+    if force_conda {
+        return convert_biom_to_tsv_conda(env_name, biom_in, tsv_out);
+    }
+    match biom::convert_biom_to_tsv(biom_in, tsv_out) {
+        Ok(()) => {
+            log_action(&format!("Converted {} to TSV with the built-in converter.", biom_in));
+            Ok(())
+        }
+        Err(builtin) => {
+            log_action(&format!("Built-in BIOM conversion failed ({}); using biom convert.", builtin));
+            convert_biom_to_tsv_conda(env_name, biom_in, tsv_out)
+                .map_err(|e| format!("{} (the built-in converter also failed: {})", e, builtin).into())
+        }
     }
-    Ok(())
 }
 
 /// Converts a BIOM file into TSV format by calling `biom convert` via conda.
@@ -243,43 +502,262 @@ fn convert_biom_to_tsv_conda(
     biom_in: &str,
     tsv_out: &str,
 ) -> Result<(), Box<dyn Error>> {
-    let cmd = format!(
-        "conda run -n {} biom convert -i {} -o {} --to-tsv",
-        env_name, biom_in, tsv_out
-    );
-    run_shell_command(&cmd)
+    let args = ["run", "-n", env_name, "biom", "convert", "-i", biom_in, "-o", tsv_out, "--to-tsv"];
+    run_conda(&args, Vec::new())
+}
+
+/// Whether `path` exists, as every skip-existing check decides. In verbose
+/// mode the path is also reported with its size and modification time (or as
+/// missing), so it's clear which artifact a skipped step reuses.
+fn output_exists(path: &str) -> bool {
+    let meta = fs::metadata(path);
+    if verbose_mode() {
+        let detail = match &meta {
+            Ok(meta) => {
+                let size = if meta.is_dir() { "directory".to_string() } else { estimate::format_bytes(meta.len() as f64) };
+                let modified = meta.modified().map_or_else(
+                    |_| "unknown".to_string(),
+                    |time| chrono::DateTime::<chrono::Local>::from(time).format("%Y-%m-%d %H:%M:%S").to_string(),
+                );
+                format!("{}, modified {}", size, modified)
+            }
+            Err(_) => "missing".to_string(),
+        };
+        let msg = format!("    checked {}: {}", path, detail);
+        log_action(&msg);
+        println!("{}", msg);
+    }
+    meta.is_ok()
 }
 
 /// Downloads a file from a URL to an output path. If `force` is false,
 /// skips download if the file already exists.
-fn download_file(url: &str, output_path: &str, force: bool) -> Result<(), Box<dyn Error>> {
-    if !force && Path::new(output_path).exists() {
+pub(crate) fn download_file(url: &str, output_path: &str, force: bool) -> Result<(), Box<dyn Error>> {
+    if !force && output_exists(output_path) {
         print_info(&format!(
             "File '{}' already exists, skipping download.",
             output_path
         ));
         return Ok(());
     }
+    if dry_run_mode() {
+        println!("[DRY RUN] download {} to {}", url, output_path);
+        return Ok(());
+    }
     print_info(&format!("Downloading '{}' to '{}'...", url, output_path));
-    let mut resp = reqwest::blocking::get(url)?;
-    if !resp.status().is_success() {
-        return Err(format!("Failed to download file: {}", url).into());
+    let client = reqwest::blocking::Client::new();
+    let attempts = download_retries() + 1;
+    for attempt in 1..=attempts {
+        // Retries continue from the bytes already written
+        match download_attempt(&client, url, output_path, attempt > 1) {
+            Ok(()) => {
+                log_action(&format!("Download of {} succeeded on attempt {}/{}", url, attempt, attempts));
+                return verify_download(url, output_path);
+            }
+            Err(DownloadError::Transient(reason)) if attempt < attempts => {
+                let delay = retry_delay(attempt);
+                let msg = format!(
+                    "Warning: download of {} failed (attempt {}/{}): {}; retrying in {:.1}s.",
+                    url, attempt, attempts, reason, delay.as_secs_f64()
+                );
+                print_error(&msg);
+                log_event(LogLevel::Warning, None, &msg);
+                std::thread::sleep(delay);
+            }
+            Err(DownloadError::Transient(reason) | DownloadError::Permanent(reason)) => {
+                // A partial file must not be taken for a finished one by --skip-existing
+                let _ = fs::remove_file(output_path);
+                let msg = format!("Failed to download {} after {} attempt(s): {}", url, attempt, reason);
+                log_event(LogLevel::Error, None, &msg);
+                return Err(msg.into());
+            }
+        }
     }
-    let mut out = File::create(output_path)?;
-    io::copy(&mut resp, &mut out)?;
+    unreachable!("the last attempt always returns")
+}
+
+/// Why one download attempt failed: worth retrying (dropped connection,
+/// server error) or not (e.g. 404).
+enum DownloadError {
+    Transient(String),
+    Permanent(String),
+}
+
+/// One attempt at downloading `url` to `output_path`. With `resume`, asks for
+/// the bytes after those already in `output_path` (an HTTP Range request) and
+/// appends them if the server honours it; otherwise starts over.
+fn download_attempt(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    output_path: &str,
+    resume: bool,
+) -> Result<(), DownloadError> {
+    let offset = if resume { fs::metadata(output_path).map_or(0, |meta| meta.len()) } else { 0 };
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+    }
+    let mut resp = request.send().map_err(|e| DownloadError::Transient(e.to_string()))?;
+    let status = resp.status();
+    if status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        // The partial file doesn't fit what the server has; start over next time
+        let _ = fs::remove_file(output_path);
+        return Err(DownloadError::Transient(format!("server rejected resuming at byte {}", offset)));
+    }
+    if !status.is_success() {
+        let reason = format!("HTTP {}", status);
+        let retryable = status.is_server_error()
+            || status == reqwest::StatusCode::REQUEST_TIMEOUT
+            || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+        return Err(if retryable { DownloadError::Transient(reason) } else { DownloadError::Permanent(reason) });
+    }
+
+    let resumed = offset > 0 && status == reqwest::StatusCode::PARTIAL_CONTENT;
+    if offset > 0 {
+        log_action(&format!(
+            "Download of {}: {}",
+            url,
+            if resumed { format!("resuming at byte {}", offset) } else { "server ignored the range; starting over".to_string() }
+        ));
+    }
+    let start = if resumed { offset } else { 0 };
+    let expected_len = resp.content_length().map(|len| start + len);
+    let file = if resumed {
+        fs::OpenOptions::new().append(true).open(output_path)
+    } else {
+        File::create(output_path)
+    };
+    let out = file.map_err(|e| DownloadError::Permanent(format!("can't write '{}': {}", output_path, e)))?;
+    let bar = if verbose_mode() { None } else { progress::DownloadProgress::new(expected_len, start, output_path) };
+    let copied = match &bar {
+        Some(bar) => io::copy(&mut resp, &mut bar.wrap_write(out)),
+        None => {
+            print_info(&download_size_line(expected_len, start));
+            io::copy(&mut resp, &mut { out })
+        }
+    };
+    let written = fs::metadata(output_path).map_or(0, |meta| meta.len());
+    let result = copied
+        .map_err(|e| DownloadError::Transient(format!("connection lost after {} bytes: {}", written, e)))
+        .and_then(|_| match expected_len.filter(|&len| len != written) {
+            Some(expected_len) => Err(DownloadError::Transient(format!("truncated at {} of {} bytes", written, expected_len))),
+            None => Ok(()),
+        });
+    match (&bar, &result) {
+        (Some(bar), Ok(())) => bar.finish(),
+        (Some(bar), Err(_)) => bar.abandon(),
+        (None, Ok(())) => print_info(&format!("    fetched {}", HumanBytes(written))),
+        (None, Err(_)) => {}
+    }
+    result
+}
+
+/// What is left to download when no bar is drawn: `expected_len` bytes in
+/// all (if the server said), of which `start` are already on disk.
+fn download_size_line(expected_len: Option<u64>, start: u64) -> String {
+    match expected_len {
+        Some(len) if start > 0 => format!("    {} of {} left to fetch", HumanBytes(len - start), HumanBytes(len)),
+        Some(len) => format!("    {} to fetch", HumanBytes(len)),
+        None => "    size not reported by the server".to_string(),
+    }
+}
+
+/// Wait before retry `attempt` (1-based): 1s, 2s, 4s, ... capped at a
+/// minute, plus up to half again as jitter so parallel runs don't retry in step.
+fn retry_delay(attempt: usize) -> Duration {
+    let base = Duration::from_secs(1u64 << (attempt - 1).min(6)).min(Duration::from_secs(60));
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos());
+    base + base.mul_f64(f64::from(nanos % 1000) / 2000.0)
+}
+
+/// SHA-256 digests of the database files as published, by URL. Downloads of
+/// these URLs must match unless `--skip-checksum` is given. Add an entry when a
+/// database file is (re)published. None are recorded yet: the PR2 5.0.0
+/// digests still have to be taken from a known-good download (they're logged
+/// with every download). Until then the digest pinned by the first download
+/// is checked instead (see [`verify_download`]).
+const KNOWN_SHA256: &[(&str, &str)] = &[];
+
+/// Digests of earlier downloads, one `url<TAB>sha256` per line, shared by all
+/// runs in the output directory.
+const PINNED_SHA256_FILE: &str = "db/sha256sums.tsv";
+
+/// Logs the SHA-256 of `path`, just downloaded from `url`, and compares it with
+/// the digest in [`KNOWN_SHA256`], or failing that the digest pinned when `url`
+/// was first downloaded (see [`check_digest`]). A URL downloaded for the first
+/// time has its digest pinned; with `--skip-checksum` the pin is replaced, so a
+/// republished file can be accepted once.
+fn verify_download(url: &str, path: &str) -> Result<(), Box<dyn Error>> {
+    let pins_path = shared_path(PINNED_SHA256_FILE);
+    let mut pins: Vec<(String, String)> = fs::read_to_string(&pins_path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(url, digest)| (url.to_string(), digest.trim().to_string()))
+        .collect();
+    let mut known = KNOWN_SHA256.to_vec();
+    known.extend(pins.iter().map(|(url, digest)| (url.as_str(), digest.as_str())));
+    let digest = check_digest(url, path, &known)?;
+    if KNOWN_SHA256.iter().any(|(known, _)| *known == url) {
+        return Ok(());
+    }
+    match pins.iter_mut().find(|(pinned, _)| pinned == url) {
+        Some((_, pinned)) if *pinned == digest => return Ok(()),
+        Some((_, pinned)) => *pinned = digest.clone(),
+        None => pins.push((url.to_string(), digest.clone())),
+    }
+    if let Some(parent) = Path::new(&pins_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let contents: String = pins.iter().map(|(url, digest)| format!("{}\t{}\n", url, digest)).collect();
+    fs::write(&pins_path, contents)?;
+    log_action(&format!("Pinned SHA-256 {} for {} in {}", digest, url, pins_path));
     Ok(())
 }
 
+/// Logs the SHA-256 of `path`, downloaded from `url`, and compares it with
+/// `url`'s digest in `known`, returning the digest. A mismatching file is
+/// deleted, so a corrupt download can't be picked up by a later
+/// `--skip-existing` run.
+fn check_digest(url: &str, path: &str, known: &[(&str, &str)]) -> Result<String, Box<dyn Error>> {
+    let digest = crate::audit::sha256_file(path)?;
+    log_action(&format!("Downloaded {} to {}, SHA-256 {}", url, path, digest));
+    let Some((_, expected)) = known.iter().find(|(known, _)| *known == url) else {
+        return Ok(digest);
+    };
+    if skip_checksum() {
+        log_action(&format!("Not comparing {} with its known SHA-256 {} (--skip-checksum)", path, expected));
+        return Ok(digest);
+    }
+    if !digest.eq_ignore_ascii_case(expected) {
+        let _ = fs::remove_file(path);
+        let msg = format!(
+            "SHA-256 of {} is {}, expected {}; the download is corrupt or not the published file, and was removed. \
+             Re-run to download it again, or pass --skip-checksum if you use a mirror with different files.",
+            url, digest, expected
+        );
+        log_event(LogLevel::Error, None, &msg);
+        return Err(msg.into());
+    }
+    Ok(digest)
+}
+
 /// Unzips a `.gz` file to `output_path`. If `force` is false,
 /// skips unzip if `output_path` already exists.
 fn unzip_file(input_path: &str, output_path: &str, force: bool) -> Result<(), Box<dyn Error>> {
-    if !force && Path::new(output_path).exists() {
+    if !force && output_exists(output_path) {
         print_info(&format!(
             "File '{}' already exists, skipping unzip.",
             output_path
         ));
         return Ok(());
     }
+    if dry_run_mode() {
+        println!("[DRY RUN] unzip {} to {}", input_path, output_path);
+        return Ok(());
+    }
     print_info(&format!("Unzipping '{}' to '{}'...", input_path, output_path));
     let input_file = File::open(input_path)?;
     let mut gz = GzDecoder::new(input_file);
@@ -288,450 +766,4282 @@ fn unzip_file(input_path: &str, output_path: &str, force: bool) -> Result<(), Bo
     Ok(())
 }
 
-/// Downloads (and unzips) the required database files into `OUTPUT_DIR/db/pr2`.
-pub fn download_databases(force: bool) -> Result<(), Box<dyn Error>> {
-    fs::create_dir_all(out_path("db/pr2"))?;
+/// Which reference database files `download-d-bs` fetches (`--only`).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DbComponent {
+    #[default]
+    All,
+    Fasta,
+    Taxonomy,
+}
 
-    let pr2_fasta_url = "https://windchime.poleshift.cloud/pr2_version_5.0.0_SSU_mothur.fasta.gz";
-    let pr2_tax_url   = "https://windchime.poleshift.cloud/pr2_version_5.0.0_SSU_mothur.tax.gz";
+impl std::str::FromStr for DbComponent {
+    type Err = String;
 
-    download_file(pr2_fasta_url, &out_path("db/pr2/pr2_with_taxonomy_simple.fasta.gz"), force)?;
-    download_file(pr2_tax_url,   &out_path("db/pr2/pr2_taxonomy.tsv.gz"), force)?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "all" => Ok(DbComponent::All),
+            "fasta" => Ok(DbComponent::Fasta),
+            "taxonomy" => Ok(DbComponent::Taxonomy),
+            other => Err(format!("expected fasta, taxonomy or all, got '{}'", other)),
+        }
+    }
+}
 
-    unzip_file(
-        &out_path("db/pr2/pr2_with_taxonomy_simple.fasta.gz"),
-        &out_path("db/pr2/pr2_with_taxonomy_simple.fasta"),
-        force,
-    )?;
-    unzip_file(
-        &out_path("db/pr2/pr2_taxonomy.tsv.gz"),
-        &out_path("db/pr2/pr2_taxonomy.tsv"),
-        force,
-    )?;
+pub(crate) const PR2_FASTA_URL: &str = "https://windchime.poleshift.cloud/pr2_version_5.0.0_SSU_mothur.fasta.gz";
+pub(crate) const PR2_TAXONOMY_URL: &str = "https://windchime.poleshift.cloud/pr2_version_5.0.0_SSU_mothur.tax.gz";
+pub(crate) const PR2_CLASSIFIER_URL: &str = "https://windchime.poleshift.cloud/pr2_classifier.qza.gz";
+// SILVA 138 (99% OTUs) as the QIIME 2 data resources publish it: already imported artifacts
+pub(crate) const SILVA_FASTA_URL: &str = "https://data.qiime2.org/2024.10/common/silva-138-99-seqs.qza";
+pub(crate) const SILVA_TAXONOMY_URL: &str = "https://data.qiime2.org/2024.10/common/silva-138-99-tax.qza";
+// UNITE releases are only published through the download form on its site,
+// so its files are put in place by hand
+pub(crate) const UNITE_DOWNLOAD_PAGE: &str = "https://unite.ut.ee/repository.php";
 
-    print_success("Database download and extraction complete.");
-    Ok(())
+/// Reference database the classifier is downloaded for or trained on (`--database`).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Database {
+    #[default]
+    Pr2,
+    Silva,
+    Unite,
 }
 
-/// Primary pipeline function: runs Steps 2–7 of the QIIME2 workflow.
-pub fn run_pipeline(
-    env_name: &str,
-    manifest: &str,
-    cores: usize,
-    target: &str,
-    skip_existing: bool,
-    use_pretrained_classifier: bool,
-    trunc_len_f: usize,
-    trunc_len_r: usize,
-) -> Result<(), Box<dyn Error>> {
-    fs::create_dir_all(OUTPUT_DIR)?;
+impl std::str::FromStr for Database {
+    type Err = String;
 
-    // Adapter/primer sequences
-    let (adapter_f, adapter_r, primer_f, primer_r) = match target.to_lowercase().as_str() {
-        "18sv9" | "18s" => ( // Keep backward compatibility with "18s"
-            "^TTGTACACACCGCCC...GTAGGTGAACCTGCRGAAGG",
-            "^CCTTCYGCAGGTTCACCTAC...GGGCGGTGTGTACAA",
-            "TTGTACACACCGCCC",
-            "CCTTCYGCAGGTTCACCTAC",
-        ),
-        "18sv4" => (
-            "^CCAGCASCYGCGGTAATTCC...YRATCAAGAACGAAAGT",
-            "^ACTTTCGTTCTTGATYR...GGAATTACCGCRGSTGCTGG",
-            "CCAGCASCYGCGGTAATTCC",
-            "ACTTTCGTTCTTGATYR",
-        ),
-        "16s" => (
-            "^GTGYCAGCMGCCGCGGTAA...AAACTYAAAKRAATTGRCGG",
-            "^CCGYCAATTYMTTTRAGTTT...TTACCGCGGCKGCTGRCAC",
-            "GTGYCAGCMGCCGCGGTAA",
-            "CCGYCAATTYMTTTRAGTTT",
-        ),
-        other => {
-            print_error(&format!("Unsupported target: {}. Use '16s', '18sv4', or '18sv9'.", other));
-            return Err("Unsupported target".into());
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pr2" => Ok(Database::Pr2),
+            "silva" => Ok(Database::Silva),
+            "unite" => Ok(Database::Unite),
+            other => Err(format!("expected pr2, silva or unite, got '{}'", other)),
         }
-    };
-
-    // Step 2: Import Files
-    let pe_demux_qza = out_path("paired-end-demux.qza");
-    if skip_existing && Path::new(&pe_demux_qza).exists() {
-        print_info(&format!("Skipping import ({} exists).", pe_demux_qza));
-    } else {
-        run_step("Importing files with manifest", || {
-            run_conda_qiime_command(env_name, &format!(
-                "tools import --type SampleData[PairedEndSequencesWithQuality] \
-                 --input-path {} \
-                 --output-path {} \
-                 --input-format PairedEndFastqManifestPhred33V2",
-                out_path(manifest),
-                pe_demux_qza
-            ))
-        })?;
-    }
-
-    // Summarize
-    let pe_demux_qzv = out_path("paired-end-demux.qzv");
-    if skip_existing && Path::new(&pe_demux_qzv).exists() {
-        print_info(&format!("Skipping demux summarize ({} exists).", pe_demux_qzv));
-    } else {
-        run_step("Validating imported file", || {
-            run_conda_qiime_command(env_name, &format!("tools validate {}", pe_demux_qza))
-        })?;
-        run_step("Summarizing demultiplexed data", || {
-            run_conda_qiime_command(env_name, &format!(
-                "demux summarize --i-data {} --o-visualization {}",
-                pe_demux_qza, pe_demux_qzv
-            ))
-        })?;
     }
+}
 
-    // Step 3: Trim Reads (Cutadapt)
-    let pe_trimmed_qza = out_path("paired-end-demux-trimmed.qza");
-    let pe_trimmed_qzv = out_path("paired-end-demux-trimmed.qzv");
-    if skip_existing && Path::new(&pe_trimmed_qza).exists() && Path::new(&pe_trimmed_qzv).exists() {
-        print_info(&format!("Skipping Cutadapt ({} exists).", pe_trimmed_qza));
-    } else {
-        run_step("Trimming reads with Cutadapt", || {
-            let cutadapt_cmd = format!(
-                "cutadapt trim-paired --i-demultiplexed-sequences {}  \
-                 --p-cores {} --p-adapter-f {} --p-adapter-r {} \
-                 --p-error-rate 0.1 --p-overlap 3 --verbose \
-                 --o-trimmed-sequences {}",
-                pe_demux_qza, cores, adapter_f, adapter_r, pe_trimmed_qza
-            );
-            run_conda_qiime_command(env_name, &cutadapt_cmd)
-        })?;
-
-        run_step("Summarizing trimmed data", || {
-            run_conda_qiime_command(env_name, &format!(
-                "demux summarize --i-data {} --p-n 100000 --o-visualization {}",
-                pe_trimmed_qza, pe_trimmed_qzv
-            ))
-        })?;
+impl Database {
+    /// The database used for `target` when `--database` isn't given: UNITE
+    /// for fungal ITS, PR2 otherwise.
+    pub fn for_target(target: &str) -> Database {
+        if target.eq_ignore_ascii_case("its") { Database::Unite } else { Database::Pr2 }
     }
 
-    // Step 4: Denoise with DADA2
-    let asvs_dir = out_path("asvs");
-    let table_dada2_qza = out_path("asvs/table-dada2.qza");
-    let rep_seqs_dada2_qza = out_path("asvs/rep-seqs-dada2.qza");
-    let stats_dada2_qza = out_path("asvs/stats-dada2.qza");
-    if skip_existing
-        && Path::new(&table_dada2_qza).exists()
-        && Path::new(&rep_seqs_dada2_qza).exists()
-        && Path::new(&stats_dada2_qza).exists()
-    {
-        print_info("Skipping DADA2 (existing outputs).");
-    } else {
-        run_step("Creating directory for DADA2 output", || {
-            fs::create_dir_all(&asvs_dir).map_err(|e| e.into())
-        })?;
-        run_step("Running DADA2 denoise-paired", || {
-            run_conda_qiime_command(env_name, &format!(
-                "dada2 denoise-paired \
-                 --i-demultiplexed-seqs {} \
-                 --p-n-threads 0 --p-trunc-q 2 --p-trunc-len-f {} --p-trunc-len-r {} \
-                 --p-max-ee-f 2 --p-max-ee-r 4 --p-n-reads-learn 1000000 \
-                 --p-chimera-method pooled \
-                 --o-table {} \
-                 --o-representative-sequences {} \
-                 --o-denoising-stats {}",
-                pe_trimmed_qza, trunc_len_f, trunc_len_r, table_dada2_qza, rep_seqs_dada2_qza, stats_dada2_qza
-            ))
-        })?;
-        run_step("Tabulating DADA2 denoising stats", || {
-            run_conda_qiime_command(env_name, &format!(
-                "metadata tabulate --m-input-file {} --o-visualization {}",
-                stats_dada2_qza,
-                out_path("asvs/stats-dada2.qzv")
-            ))
-        })?;
-        
-        // Add basic feature table summarization without metadata
-        let table_dada2_qzv = out_path("asvs/table-dada2.qzv");
-        run_step("Summarizing feature table", || {
-            run_conda_qiime_command(env_name, &format!(
-                "feature-table summarize \
-                 --i-table {} \
-                 --o-visualization {}",
-                table_dada2_qza, table_dada2_qzv
-            ))
-        })?;
+    /// Lowercase label, used for the `db/<label>` directory, file names and
+    /// taxonomy column prefixes (see [`taxonomy::database_label`]).
+    pub fn label(self) -> &'static str {
+        match self {
+            Database::Pr2 => "pr2",
+            Database::Silva => "silva",
+            Database::Unite => "unite",
+        }
     }
 
-    // Step 5: Export Denoised Data
-    let asv_table_dir = out_path("asv_table");
-    run_step("Exporting ASV table", || {
-        if skip_existing && Path::new(&format!("{}/feature-table.biom", asv_table_dir)).exists() {
-            print_info("Skipping export of ASV table (feature-table.biom exists).");
-            return Ok(());
+    /// Display name, e.g. in step names.
+    pub fn name(self) -> &'static str {
+        match self {
+            Database::Pr2 => "PR2",
+            Database::Silva => "SILVA",
+            Database::Unite => "UNITE",
         }
-        run_conda_qiime_command(env_name, &format!(
-            "tools export --input-path {} --output-path {}",
-            table_dada2_qza, asv_table_dir
-        ))
-    })?;
-    run_step("Converting BIOM to TSV", || {
-        let biom_path = format!("{}/feature-table.biom", asv_table_dir);
-        let tsv_path = format!("{}/asv-table.tsv", asv_table_dir);
-        if skip_existing && Path::new(&tsv_path).exists() {
-            print_info("Skipping BIOM-to-TSV conversion (asv-table.tsv exists).");
-            return Ok(());
+    }
+
+    /// Where the reference sequences are downloaded from; `None` for UNITE,
+    /// which can't be downloaded automatically (see [`UNITE_DOWNLOAD_PAGE`]).
+    pub(crate) fn fasta_url(self) -> Option<&'static str> {
+        match self {
+            Database::Pr2 => Some(PR2_FASTA_URL),
+            Database::Silva => Some(SILVA_FASTA_URL),
+            Database::Unite => None,
         }
-        convert_biom_to_tsv_conda(env_name, &biom_path, &tsv_path)
-    })?;
-    run_step("Exporting representative sequences", || {
-        let rep_seqs_export_dir = out_path("asvs");
-        if skip_existing && Path::new(&format!("{}/dna-sequences.fasta", rep_seqs_export_dir)).exists() {
-            print_info("Skipping export rep-seqs (dna-sequences.fasta exists).");
-            return Ok(());
+    }
+
+    /// Where the reference taxonomy is downloaded from; `None` for UNITE.
+    pub(crate) fn taxonomy_url(self) -> Option<&'static str> {
+        match self {
+            Database::Pr2 => Some(PR2_TAXONOMY_URL),
+            Database::Silva => Some(SILVA_TAXONOMY_URL),
+            Database::Unite => None,
         }
-        run_conda_qiime_command(env_name, &format!(
-            "tools export --input-path {} --output-path {}",
-            rep_seqs_dada2_qza, rep_seqs_export_dir
-        ))
-    })?;
-    let rep_seqs_dada2_qzv = out_path("asvs/rep-seqs-dada2.qzv");
-    if !skip_existing || !Path::new(&rep_seqs_dada2_qzv).exists() {
-        run_step("Tabulating representative sequences", || {
-            run_conda_qiime_command(env_name, &format!(
-                "feature-table tabulate-seqs --i-data {} --o-visualization {}",
-                rep_seqs_dada2_qza, rep_seqs_dada2_qzv
-            ))
-        })?;
     }
-    let table_dada2_qzv = out_path("asvs/table-dada2.qzv");
-    if !skip_existing || !Path::new(&table_dada2_qzv).exists() {
-        run_step("Summarizing feature table", || {
-            run_conda_qiime_command(env_name, &format!(
-                "feature-table summarize --i-table {} --o-visualization {}",
-                table_dada2_qza, table_dada2_qzv
-            ))
-        })?;
-    }
-    let pr2_dir = out_path("db/pr2");
-    fs::create_dir_all(&pr2_dir)?;
-    // 6a) Import PR2 reference sequences
-    let pr2_qza = out_path("db/pr2/pr2.qza");
-    if !skip_existing || !Path::new(&pr2_qza).exists() {
-        run_step("Importing pr2 sequences", || {
-            run_conda_qiime_command(env_name, &format!(
-                "tools import --type FeatureData[Sequence] \
-                 --input-path {} \
-                 --output-path {}",
-                out_path("db/pr2/pr2_with_taxonomy_simple.fasta"),
-                pr2_qza
-            ))
-        })?;
-    }
-
-    // 6b) Import PR2 reference taxonomy
-    let pr2_tax_qza = out_path("db/pr2/pr2_tax.qza");
-    if !skip_existing || !Path::new(&pr2_tax_qza).exists() {
-        run_step("Importing pr2 taxonomy", || {
-            run_conda_qiime_command(env_name, &format!(
-                "tools import --type FeatureData[Taxonomy] \
-                 --input-format HeaderlessTSVTaxonomyFormat \
-                 --input-path {} \
-                 --output-path {}",
-                out_path("db/pr2/pr2_taxonomy.tsv"),
-                pr2_tax_qza
-            ))
-        })?;
-    }
-
-    // 6c) Either download a pre-trained classifier OR extract & train from PR2
-    let pr2_classifier_qza = out_path("db/pr2/pr2_classifier.qza");
-
-    if use_pretrained_classifier {
-        // *** Use a pre-trained classifier ***
-
-        let pr2_classifier_url = "https://windchime.poleshift.cloud/pr2_classifier.qza.gz";
-        let pr2_classifier_gz  = out_path("db/pr2/pr2_classifier.qza.gz");
-
-        if !skip_existing || !Path::new(&pr2_classifier_qza).exists() {
-            run_step("Downloading pre-trained PR2 classifier", || {
-                // Download .gz to db/pr2
-                download_file(pr2_classifier_url, &pr2_classifier_gz, skip_existing)?;
-                // Unzip it so we have pr2_classifier.qza
-                unzip_file(&pr2_classifier_gz, &pr2_classifier_qza, skip_existing)?;
-                Ok(())
-            })?;
+
+    /// The pre-trained classifier to download, if there is one for this
+    /// database. SILVA's and UNITE's are trained from the reference instead.
+    pub(crate) fn classifier_url(self) -> Option<&'static str> {
+        match self {
+            Database::Pr2 => Some(PR2_CLASSIFIER_URL),
+            Database::Silva | Database::Unite => None,
         }
-    } else {
-        // *** Extract reads & train your own classifier ***
-
-        let pr2_extracts_qza = out_path("db/pr2/pr2_extracts.qza");
-        if !skip_existing || !Path::new(&pr2_extracts_qza).exists() {
-            run_step("Extracting pr2 reads", || {
-                // Use specific primers based on target
-                let (primer_f, primer_r) = match target.to_lowercase().as_str() {
-                    "18sv9" | "18s" => ("TTGTACACACCGCCC", "CCTTCYGCAGGTTCACCTAC"),
-                    "18sv4" => ("CCAGCASCYGCGGTAATTCC", "ACTTTCGTTCTTGATYR"),
-                    "16s" => ("GTGYCAGCMGCCGCGGTAA", "CCGYCAATTYMTTTRAGTTT"),
-                    _ => return Err("Invalid target region".into()),
-                };
-                run_conda_qiime_command(env_name, &format!(
-                    "feature-classifier extract-reads \
-                     --i-sequences {} \
-                     --p-f-primer {} \
-                     --p-r-primer {} \
-                     --o-reads {}",
-                    pr2_qza, primer_f, primer_r, pr2_extracts_qza
-                ))
-            })?;
-        }
-
-        if !skip_existing || !Path::new(&pr2_classifier_qza).exists() {
-            run_step("Fitting pr2 classifier", || {
-                run_conda_qiime_command(env_name, &format!(
-                    "feature-classifier fit-classifier-naive-bayes \
-                     --i-reference-reads {} \
-                     --i-reference-taxonomy {} \
-                     --o-classifier {} \
-                     --p-classify--chunk-size 100000",
-                    pr2_extracts_qza, pr2_tax_qza, pr2_classifier_qza
-                ))
-            })?;
-        }
-    }
-
-    // 6d) Classify your representative sequences
-    let rep_seqs_dada2_qza = out_path("asvs/rep-seqs-dada2.qza");
-    let pr2_tax_sklearn_qza = out_path("pr2_tax_sklearn.qza");
-    if !skip_existing || !Path::new(&pr2_tax_sklearn_qza).exists() {
-        run_step("Classifying reads with pr2 classifier", || {
-            run_conda_qiime_command(env_name, &format!(
-                "feature-classifier classify-sklearn \
-                 --p-n-jobs 0 \
-                 --i-classifier {} \
-                 --i-reads {} \
-                 --o-classification {}",
-                pr2_classifier_qza, rep_seqs_dada2_qza, pr2_tax_sklearn_qza
-            ))
-        })?;
-    }
-
-    let pr2_tax_sklearn_qzv = out_path("pr2_tax_sklearn.qzv");
-    if !skip_existing || !Path::new(&pr2_tax_sklearn_qzv).exists() {
-        run_step("Tabulating classified taxonomy", || {
-            run_conda_qiime_command(env_name, &format!(
-                "metadata tabulate --m-input-file {} --o-visualization {}",
-                pr2_tax_sklearn_qza, pr2_tax_sklearn_qzv
-            ))
-        })?;
     }
 
-    // 6e) Export and rename the taxonomy
-    let asv_tax_dir = out_path("asv_tax_dir");
-    if !skip_existing || !Path::new(&format!("{}/taxonomy.tsv", asv_tax_dir)).exists() {
-        run_step("Exporting pr2 taxonomy", || {
-            run_conda_qiime_command(env_name, &format!(
-                "tools export --input-path {} --output-path {}",
-                pr2_tax_sklearn_qza, asv_tax_dir
-            ))
-        })?;
-        run_step("Renaming pr2 taxonomy file", || {
-            let pr2_taxonomy_tsv = format!("{}/pr2_taxonomy.tsv", asv_tax_dir);
-            let old_tsv = format!("{}/taxonomy.tsv", asv_tax_dir);
-            run_shell_command(&format!("mv {} {}", old_tsv, pr2_taxonomy_tsv))
-        })?;
+    /// Whether the reference is published as QIIME artifacts (SILVA), which
+    /// are used as downloaded rather than imported from FASTA and TSV.
+    pub(crate) fn is_imported(self) -> bool {
+        self == Database::Silva
     }
 
-    // Step 7: Merge ASV Table with Taxonomy
-    let merged_output = out_path("asv_count_tax.tsv");
-    if skip_existing && Path::new(&merged_output).exists() {
-        print_info(&format!("Skipping merge ({} exists).", merged_output));
-    } else {
-        run_step("Merging ASV and taxonomy tables", merge_asv_taxonomy)?;
+    /// This database's directory, `OUTPUT_DIR/db/<label>`.
+    pub fn dir(self) -> String {
+        shared_path(&format!("db/{}", self.label()))
     }
 
-    print_success("Pipeline completed successfully!");
-    print_info("Final summary: see 'windchime_out/asv_count_tax.tsv' for merged results.");
+    /// `file_name` in [`Database::dir`].
+    pub fn path(self, file_name: &str) -> String {
+        format!("{}/{}", self.dir(), file_name)
+    }
 
-    if Path::new(&out_path("asvs/stats-dada2.qzv")).exists() {
-        print_info("You can view 'asvs/stats-dada2.qzv' in QIIME2 View for DADA2 stats.");
+    /// The reference sequences, as downloaded (for SILVA, the artifact itself).
+    pub fn fasta_path(self) -> String {
+        match self {
+            Database::Pr2 => self.path("pr2_with_taxonomy_simple.fasta"),
+            Database::Silva => self.sequences_qza(),
+            Database::Unite => self.path("unite_seqs.fasta"),
+        }
     }
 
-    Ok(())
-}
+    /// The reference taxonomy, as downloaded (for SILVA, the artifact itself).
+    pub fn taxonomy_path(self) -> String {
+        if self.is_imported() { self.taxonomy_qza() } else { self.path(&format!("{}_taxonomy.tsv", self.label())) }
+    }
 
-/// Merges the ASV count table with the assigned taxonomy, producing `asv_count_tax.tsv`.
-fn merge_asv_taxonomy() -> Result<(), Box<dyn Error>> {
-    use std::collections::HashMap;
-    use std::io;
+    /// The reference sequences artifact the classifier is trained on.
+    pub fn sequences_qza(self) -> String {
+        self.path(&format!("{}.qza", self.label()))
+    }
 
-    // Read the ASV table
-    let asv_table_path = out_path("asv_table/asv-table.tsv");
-    let mut asv_reader = ReaderBuilder::new()
-        .delimiter(b'\t')
-        .has_headers(true)
-        .comment(Some(b'#'))
-        .from_path(&asv_table_path)?;
-
-    let asv_headers = asv_reader.headers()?.clone();
-    let mut asv_map: HashMap<String, Vec<String>> = HashMap::new();
-    for record in asv_reader.records() {
-        let rec = record?;
-        let feature_id = rec.get(0).unwrap_or("").to_string();
-        asv_map.insert(feature_id, rec.iter().map(|s| s.to_string()).collect());
+    /// The reference taxonomy artifact the classifier is trained on.
+    pub fn taxonomy_qza(self) -> String {
+        self.path(&format!("{}_tax.qza", self.label()))
     }
 
-    // Read the pr2 taxonomy table
-    let pr2_tax_path = out_path("asv_tax_dir/pr2_taxonomy.tsv");
-    let mut pr2_reader = ReaderBuilder::new()
-        .delimiter(b'\t')
-        .has_headers(true)
-        .from_path(&pr2_tax_path)?;
+    /// The pre-trained classifier, as downloaded, or the one trained here.
+    pub fn classifier_path(self) -> String {
+        self.path(&format!("{}_classifier.qza", self.label()))
+    }
+}
 
-    let pr2_headers = pr2_reader.headers()?.clone();
-    let mut pr2_map: HashMap<String, Vec<String>> = HashMap::new();
-    for record in pr2_reader.records() {
-        let rec = record?;
-        let feature_id = rec.get(0).unwrap_or("").to_string();
-        pr2_map.insert(feature_id, rec.iter().map(|s| s.to_string()).collect());
+/// Downloads (and unzips) the required files of `database` into
+/// `OUTPUT_DIR/db/<database>`, or only the `only` component of them, then
+/// checks that the FASTA and taxonomy cover the same IDs. A mismatch is a
+/// warning, or an error with `strict`. Artifacts (SILVA) are kept as
+/// downloaded; QIIME checks them when the classifier is trained.
+pub fn download_databases(database: Database, force: bool, strict: bool, only: DbComponent) -> Result<(), Box<dyn Error>> {
+    match (database.fasta_url(), database.taxonomy_url()) {
+        (Some(fasta_url), Some(taxonomy_url)) => download_database_from(database, (fasta_url, taxonomy_url), force, strict, only),
+        _ => check_manual_database(database, only),
     }
+}
 
-    // Write merged
-    let merged_path = out_path("asv_count_tax.tsv");
-    let mut wtr = WriterBuilder::new()
-        .delimiter(b'\t')
-        .from_path(&merged_path)?;
+/// Checks that the files of a database Windchime can't download (UNITE)
+/// were put in `OUTPUT_DIR/db/<database>` by hand, explaining where to get
+/// them if not.
+fn check_manual_database(database: Database, only: DbComponent) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(database.dir())?;
+    let missing: Vec<String> = [(DbComponent::Fasta, database.fasta_path()), (DbComponent::Taxonomy, database.taxonomy_path())]
+        .into_iter()
+        .filter(|(component, path)| (only == DbComponent::All || only == *component) && !Path::new(path).is_file())
+        .map(|(_, path)| path)
+        .collect();
+    if missing.is_empty() {
+        print_success(&format!("Using the {} reference files in {}.", database.name(), database.dir()));
+        return Ok(());
+    }
+    let msg = format!(
+        "{} can't be downloaded automatically: its releases are only published at {}. Download the QIIME release \
+         there and install it with `windchime download-d-bs --database unite --unite-archive <file or URL>`, or save \
+         its sequences as '{}' and its headerless taxonomy TSV as '{}' (missing: {}).",
+        database.name(),
+        UNITE_DOWNLOAD_PAGE,
+        database.fasta_path(),
+        database.taxonomy_path(),
+        missing.join(", ")
+    );
+    if dry_run_mode() {
+        println!("[DRY RUN] {}", msg);
+        return Ok(());
+    }
+    print_error(&msg);
+    Err(msg.into())
+}
 
-    // Build merged header
-    let mut merged_header = Vec::new();
-    for (i, col) in asv_headers.iter().enumerate() {
-        if i == 0 {
-            merged_header.push("Feature.ID".to_string());
-        } else {
-            merged_header.push(col.to_string());
-        }
+/// Installs a UNITE QIIME release (the `.tgz` from [`UNITE_DOWNLOAD_PAGE`])
+/// into `OUTPUT_DIR/db/unite` (`--unite-archive`). `source` is the archive's
+/// path or an http(s) URL to download it from. The reference sequences and
+/// taxonomy are taken from the release (see [`unite_release_files`]) and
+/// saved where the pipeline imports them, then their IDs are checked as for
+/// a download. Only the `only` component is extracted.
+pub fn install_unite_archive(source: &str, force: bool, strict: bool, only: DbComponent) -> Result<(), Box<dyn Error>> {
+    let database = Database::Unite;
+    fs::create_dir_all(database.dir())?;
+    let archive = if source.starts_with("http://") || source.starts_with("https://") {
+        let path = database.path("unite_release.tgz");
+        download_file(source, &path, force)?;
+        path
+    } else {
+        source.to_string()
+    };
+    let targets: Vec<(DbComponent, String)> = [(DbComponent::Fasta, database.fasta_path()), (DbComponent::Taxonomy, database.taxonomy_path())]
+        .into_iter()
+        .filter(|(component, path)| (only == DbComponent::All || only == *component) && (force || !Path::new(path).is_file()))
+        .collect();
+    if targets.is_empty() {
+        print_info(&format!("The {} reference files in {} already exist, skipping extraction.", database.name(), database.dir()));
+        return check_database_ids(database, strict);
     }
-    for (i, col) in pr2_headers.iter().enumerate() {
-        if i == 0 {
-            continue;
-        }
-        merged_header.push(format!("pr2_{}", col));
+    if dry_run_mode() {
+        println!("[DRY RUN] extract the {} reference files from {} to {}", database.name(), archive, database.dir());
+        return Ok(());
     }
-    wtr.write_record(&merged_header)?;
 
-    // Merge rows
-    for (feature_id, asv_record) in asv_map.iter() {
-        let mut merged_record = asv_record.clone();
-        if let Some(pr2_record) = pr2_map.get(feature_id) {
-            // skip the first column from pr2
-            merged_record.extend(pr2_record.iter().skip(1).cloned());
+    let open = || -> Result<tar::Archive<GzDecoder<File>>, Box<dyn Error>> {
+        let file = File::open(&archive).map_err(|e| format!("Cannot open the UNITE release '{}': {}", archive, e))?;
+        Ok(tar::Archive::new(GzDecoder::new(file)))
+    };
+    let mut names = Vec::new();
+    for entry in open()?.entries()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_file() {
+            names.push(entry.path()?.to_string_lossy().into_owned());
+        }
+    }
+    let (fasta_entry, taxonomy_entry) = unite_release_files(&archive, &names)?;
+
+    let wanted: Vec<(&str, &str)> = targets
+        .iter()
+        .map(|(component, path)| {
+            let entry = if *component == DbComponent::Fasta { &fasta_entry } else { &taxonomy_entry };
+            (entry.as_str(), path.as_str())
+        })
+        .collect();
+    for entry in open()?.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        if let Some((_, path)) = wanted.iter().find(|(wanted, _)| *wanted == name) {
+            print_info(&format!("Extracting '{}' from '{}' to '{}'...", name, archive, path));
+            let partial = format!("{}.part", path);
+            io::copy(&mut entry, &mut File::create(&partial)?)?;
+            fs::rename(&partial, path)?;
+            log_action(&format!("Extracted {} from {} to {}", name, archive, path));
+        }
+    }
+    check_database_ids(database, strict)
+}
+
+/// The reference sequences and taxonomy entries among the files `names` of
+/// the UNITE release `archive`: a `.fasta` and a `*taxonomy*.txt`, outside
+/// the `developer` folder (whose sequences aren't trimmed to the ITS region).
+/// Releases with several clustering thresholds use the 99% files.
+fn unite_release_files(archive: &str, names: &[String]) -> Result<(String, String), Box<dyn Error>> {
+    let pick = |what: &str, matches: &dyn Fn(&str) -> bool| -> Result<String, Box<dyn Error>> {
+        let candidates: Vec<&String> = names
+            .iter()
+            .filter(|name| !name.split('/').any(|part| part.eq_ignore_ascii_case("developer")))
+            .filter(|name| matches(&name.rsplit('/').next().unwrap_or(name).to_lowercase()))
+            .collect();
+        let chosen: Vec<&&String> = if candidates.len() > 1 {
+            candidates.iter().filter(|name| name.contains("_99_")).collect()
         } else {
-            for _ in 1..pr2_headers.len() {
-                merged_record.push(String::new());
-            }
+            candidates.iter().collect()
+        };
+        match chosen.as_slice() {
+            [name] => Ok(name.to_string()),
+            [] if candidates.is_empty() => Err(format!("The UNITE release '{}' has no {}.", archive, what).into()),
+            _ => Err(format!(
+                "The UNITE release '{}' has several {} files and none is unambiguously the 99% one: {}.",
+                archive,
+                what,
+                candidates.iter().map(|name| name.as_str()).collect::<Vec<_>>().join(", ")
+            )
+            .into()),
+        }
+    };
+    let fasta = pick("reference sequences (.fasta)", &|name| name.ends_with(".fasta") || name.ends_with(".fa"))?;
+    let taxonomy = pick("taxonomy (*taxonomy*.txt)", &|name| {
+        name.contains("taxonomy") && (name.ends_with(".txt") || name.ends_with(".tsv"))
+    })?;
+    Ok((fasta, taxonomy))
+}
+
+/// [`download_databases`], fetching the FASTA and taxonomy of `database` from `urls`.
+fn download_database_from(
+    database: Database,
+    (fasta_url, taxonomy_url): (&str, &str),
+    force: bool,
+    strict: bool,
+    only: DbComponent,
+) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(database.dir())?;
+
+    let fasta = database.fasta_path();
+    let taxonomy = database.taxonomy_path();
+
+    for (component, url, path) in [(DbComponent::Fasta, fasta_url, &fasta), (DbComponent::Taxonomy, taxonomy_url, &taxonomy)] {
+        if only != DbComponent::All && only != component {
+            continue;
+        }
+        if database.is_imported() {
+            download_file(url, path, force)?;
+        } else {
+            download_file(url, &format!("{}.gz", path), force)?;
+            unzip_file(&format!("{}.gz", path), path, force)?;
         }
-        wtr.write_record(&merged_record)?;
     }
-    wtr.flush()?;
+
+    if dry_run_mode() || database.is_imported() {
+        if !dry_run_mode() {
+            print_success(&format!("{} database download complete.", database.name()));
+        }
+        return Ok(());
+    }
+    check_database_ids(database, strict)
+}
+
+/// Checks that the FASTA and taxonomy of `database` cover the same IDs, once
+/// both are present: a mismatch is a warning, or an error with `strict`.
+fn check_database_ids(database: Database, strict: bool) -> Result<(), Box<dyn Error>> {
+    let fasta = database.fasta_path();
+    let taxonomy = database.taxonomy_path();
+    // With one component refreshed, the other may not have been downloaded yet
+    if !(Path::new(&fasta).is_file() && Path::new(&taxonomy).is_file()) {
+        print_success(&format!("{} database download and extraction complete.", database.name()));
+        return Ok(());
+    }
+    let mismatch = reference::check_reference_ids(&fasta, &taxonomy)?;
+    if let Some(mismatch) = mismatch {
+        log_event(if strict { LogLevel::Error } else { LogLevel::Warning }, None, &mismatch);
+        if strict {
+            print_error(&mismatch);
+            return Err(mismatch.into());
+        }
+        print_error(&format!(
+            "Warning: {}. The download may be corrupted or from mismatched versions; \
+             re-run with --force (or --strict-db to stop on this).",
+            mismatch
+        ));
+    }
+
+    print_success(&format!("{} database download and extraction complete.", database.name()));
+    Ok(())
+}
+
+/// Runs a QIIME command via `conda run` and returns its captured output
+/// instead of streaming it, for commands whose output we need to inspect.
+pub(crate) fn run_conda_qiime_capture(env: &str, qiime_args: &[&str]) -> Result<CommandOutput, Box<dyn Error>> {
+    log_action(&format!("Running QIIME command in {}: qiime {}", env, qiime_args.join(" ")));
+    if verbose_mode() {
+        println!("[QIIME CMD] qiime {}", qiime_args.join(" "));
+    }
+    let mut args = vec!["run", "-n", env, "qiime"];
+    args.extend_from_slice(qiime_args);
+    run_command(conda_binary(), &args, &CommandOptions { env: child_env_vars(None), ..capture_options() })
+}
+
+/// Runs `qiime tools validate` on an artifact, capturing its output so that a
+/// failure reports QIIME's reason rather than just a failed command.
+fn validate_artifact(env_name: &str, artifact: &str) -> Result<(), Box<dyn Error>> {
+    let output = run_conda_qiime_capture(env_name, &["tools", "validate", artifact])?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.success {
+        *LAST_FAILURE_STDERR.lock().unwrap() = Some(stderr.to_string());
+        let reason = if stderr.trim().is_empty() { stdout.trim() } else { stderr.trim() };
+        let msg = format!("Artifact {} failed QIIME validation: {}", artifact, reason);
+        print_error(&msg);
+        return Err(msg.into());
+    }
+
+    log_action(&format!("Validation passed: {}", stdout.trim()));
+    if verbose_mode() {
+        print!("{}", stdout);
+    }
+    Ok(())
+}
+
+/// Checks that `artifact` is a QIIME artifact of type `expected` (e.g.
+/// `TaxonomicClassifier`) using `qiime tools peek`.
+fn check_artifact_type(env_name: &str, artifact: &str, expected: &str) -> Result<(), Box<dyn Error>> {
+    if !Path::new(artifact).is_file() {
+        return Err(format!("{} artifact not found: {}", expected, artifact).into());
+    }
+    let output = run_conda_qiime_capture(env_name, &["tools", "peek", artifact])?;
+    if !output.success {
+        return Err(format!(
+            "Could not inspect {} artifact {}: {}",
+            expected,
+            artifact,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let artifact_type = stdout
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Type:"))
+        .map(str::trim)
+        .unwrap_or("unknown");
+    if artifact_type != expected {
+        return Err(format!(
+            "{} is a {} artifact, expected {}",
+            artifact, artifact_type, expected
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Quality score encoding of the FASTQs a manifest lists (`--phred`).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PhredOffset {
+    /// Sanger / Illumina 1.8+ (`!` = 0), what current sequencers write.
+    #[default]
+    Phred33,
+    /// Illumina 1.3–1.7 (`@` = 0), from older sequencers.
+    Phred64,
+}
+
+impl std::str::FromStr for PhredOffset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "33" => Ok(PhredOffset::Phred33),
+            "64" => Ok(PhredOffset::Phred64),
+            other => Err(format!("expected 33 or 64, got '{}'", other)),
+        }
+    }
+}
+
+impl PhredOffset {
+    /// The `qiime tools import` input format for a paired-end or single-end manifest.
+    fn manifest_format(self, single_end: bool) -> &'static str {
+        match (self, single_end) {
+            (PhredOffset::Phred33, false) => "PairedEndFastqManifestPhred33V2",
+            (PhredOffset::Phred64, false) => "PairedEndFastqManifestPhred64V2",
+            (PhredOffset::Phred33, true) => "SingleEndFastqManifestPhred33V2",
+            (PhredOffset::Phred64, true) => "SingleEndFastqManifestPhred64V2",
+        }
+    }
+}
+
+/// QIIME arguments that import a manifest, its FASTQs encoded with `phred`,
+/// into `output_qza`: as paired-end reads, or forward reads only with `single_end`.
+fn import_manifest_args(manifest_path: &str, output_qza: &str, phred: PhredOffset, single_end: bool) -> Vec<String> {
+    let semantic_type = if single_end {
+        "SampleData[SequencesWithQuality]"
+    } else {
+        "SampleData[PairedEndSequencesWithQuality]"
+    };
+    qiime_args(&[
+        "tools", "import",
+        "--type", semantic_type,
+        "--input-path", manifest_path,
+        "--output-path", output_qza,
+        "--input-format", phred.manifest_format(single_end),
+    ])
+}
+
+/// Imports `manifest` (relative to the output directory) and validates the result,
+/// without running any other pipeline step. The temporary artifact is removed
+/// afterwards.
+pub fn validate_manifest(
+    env_name: &str,
+    manifest: &str,
+    phred: PhredOffset,
+    single_end: bool,
+) -> Result<(), Box<dyn Error>> {
+    let check_qza = out_path("manifest-validation.qza");
+    let result = run_step("Importing files with manifest", || {
+        run_conda_qiime_command(env_name, &import_manifest_args(&shared_path(manifest), &check_qza, phred, single_end))
+    })
+    .and_then(|_| run_step("Validating imported file", || validate_artifact(env_name, &check_qza)));
+    let _ = fs::remove_file(&check_qza);
+
+    match &result {
+        Ok(_) => print_success(&format!("Manifest {} imports and validates cleanly.", manifest)),
+        Err(e) => print_error(&format!("Manifest {} failed: {}", manifest, e)),
+    }
+    result
+}
+
+/// Cutadapt adapters (linked, anchored) and bare primers for a target region.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionPrimers {
+    pub adapter_f: &'static str,
+    pub adapter_r: &'static str,
+    pub primer_f: &'static str,
+    pub primer_r: &'static str,
+    /// Typical length of the amplified region between the primers.
+    pub amplicon_len: usize,
+    /// DADA2 truncation lengths used when none are given.
+    pub trunc_len_f: usize,
+    pub trunc_len_r: usize,
+}
+
+/// Looks up the primers for `target` (`16s`, `18sv4`, `18sv9`, `its`, or
+/// `18s` as an alias of `18sv9`), case-insensitively.
+pub fn region_primers(target: &str) -> Result<RegionPrimers, Box<dyn Error>> {
+    let primers = match target.to_lowercase().as_str() {
+        "18sv9" | "18s" => RegionPrimers { // Keep backward compatibility with "18s"
+            adapter_f: "^TTGTACACACCGCCC...GTAGGTGAACCTGCRGAAGG",
+            adapter_r: "^CCTTCYGCAGGTTCACCTAC...GGGCGGTGTGTACAA",
+            primer_f: "TTGTACACACCGCCC",
+            primer_r: "CCTTCYGCAGGTTCACCTAC",
+            amplicon_len: 130,
+            trunc_len_f: 123,
+            trunc_len_r: 91,
+        },
+        "18sv4" => RegionPrimers {
+            adapter_f: "^CCAGCASCYGCGGTAATTCC...YRATCAAGAACGAAAGT",
+            adapter_r: "^ACTTTCGTTCTTGATYR...GGAATTACCGCRGSTGCTGG",
+            primer_f: "CCAGCASCYGCGGTAATTCC",
+            primer_r: "ACTTTCGTTCTTGATYR",
+            amplicon_len: 380,
+            trunc_len_f: 262,
+            trunc_len_r: 223,
+        },
+        "16s" => RegionPrimers {
+            adapter_f: "^GTGYCAGCMGCCGCGGTAA...AAACTYAAAKRAATTGRCGG",
+            adapter_r: "^CCGYCAATTYMTTTRAGTTT...TTACCGCGGCKGCTGRCAC",
+            primer_f: "GTGYCAGCMGCCGCGGTAA",
+            primer_r: "CCGYCAATTYMTTTRAGTTT",
+            amplicon_len: 370,
+            trunc_len_f: 219,
+            trunc_len_r: 194,
+        },
+        // ITS1F/ITS2; ITS1 varies too much in length to truncate at a fixed position
+        "its" => RegionPrimers {
+            adapter_f: "^CTTGGTCATTTAGAGGAAGTAA...GCATCGATGAAGAACGCAGC",
+            adapter_r: "^GCTGCGTTCTTCATCGATGC...TTACTTCCTCTAAATGACCAAG",
+            primer_f: "CTTGGTCATTTAGAGGAAGTAA",
+            primer_r: "GCTGCGTTCTTCATCGATGC",
+            amplicon_len: 250,
+            trunc_len_f: 0,
+            trunc_len_r: 0,
+        },
+        other => return Err(format!("Unsupported target: {}", other).into()),
+    };
+    Ok(primers)
+}
+
+/// How the primers are passed to `cutadapt trim-paired` or `trim-single` (`--adapter-mode`).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AdapterMode {
+    /// Anchored linked adapters (`^FWD...revcomp(REV)`): the primer must start
+    /// the read, and the opposite primer is removed if the read runs through the
+    /// amplicon. Best when amplicons are shorter than the reads.
+    #[default]
+    Linked,
+    /// Unanchored 5' primers (`--p-front-*`): found anywhere near the read
+    /// start, e.g. behind variable-length spacers or heterogeneity bases.
+    Regular,
+    /// 5' primers anchored at the read start (`--p-front-* ^PRIMER`), without
+    /// the 3' part; for amplicons longer than the reads.
+    Anchored,
+}
+
+impl std::str::FromStr for AdapterMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "linked" => Ok(AdapterMode::Linked),
+            "regular" => Ok(AdapterMode::Regular),
+            "anchored" => Ok(AdapterMode::Anchored),
+            other => Err(format!("expected linked, regular or anchored, got '{}'", other)),
+        }
+    }
+}
+
+/// Cutadapt parameters that pass the primers of `primers` in `mode`. Single-end
+/// reads (`trim-single`) only get the forward primer or linked adapter.
+fn adapter_args(primers: &RegionPrimers, mode: AdapterMode, single_end: bool) -> Vec<String> {
+    if single_end {
+        return match mode {
+            AdapterMode::Linked => qiime_args(&["--p-adapter", primers.adapter_f]),
+            AdapterMode::Regular => qiime_args(&["--p-front", primers.primer_f]),
+            AdapterMode::Anchored => qiime_args(&["--p-front", &format!("^{}", primers.primer_f)]),
+        };
+    }
+    match mode {
+        AdapterMode::Linked => qiime_args(&["--p-adapter-f", primers.adapter_f, "--p-adapter-r", primers.adapter_r]),
+        AdapterMode::Regular => qiime_args(&["--p-front-f", primers.primer_f, "--p-front-r", primers.primer_r]),
+        AdapterMode::Anchored => qiime_args(&[
+            "--p-front-f",
+            &format!("^{}", primers.primer_f),
+            "--p-front-r",
+            &format!("^{}", primers.primer_r),
+        ]),
+    }
+}
+
+/// How DADA2 denoises the reads (`--denoise-mode`).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DenoiseMode {
+    /// Paired unless the reads are too short to overlap across the amplicon.
+    #[default]
+    Auto,
+    /// `denoise-paired`: merge forward and reverse reads.
+    Paired,
+    /// `denoise-single`: forward reads only.
+    Single,
+}
+
+impl std::str::FromStr for DenoiseMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(DenoiseMode::Auto),
+            "paired" => Ok(DenoiseMode::Paired),
+            "single" => Ok(DenoiseMode::Single),
+            other => Err(format!("expected auto, paired or single, got '{}'", other)),
+        }
+    }
+}
+
+/// Overlap DADA2 needs to merge mates (its `minOverlap` of 12) plus a margin
+/// for length variation within the amplicon.
+const MIN_MERGE_OVERLAP: usize = 20;
+
+/// Expected overlap, in bases, between forward and reverse reads of a
+/// `region` amplicon: reads are cut to the truncation length (or their own
+/// length, if shorter) after the primers are trimmed off. A truncation length
+/// of 0 means no truncation, as it does for DADA2. Negative when they don't
+/// reach each other.
+pub fn expected_overlap(
+    region: &RegionPrimers,
+    read_len_f: usize,
+    read_len_r: usize,
+    trunc_len_f: usize,
+    trunc_len_r: usize,
+) -> i64 {
+    let truncated = |len: usize, trunc_len: usize| if trunc_len == 0 { len } else { len.min(trunc_len) };
+    let forward = truncated(read_len_f.saturating_sub(region.primer_f.len()), trunc_len_f);
+    let reverse = truncated(read_len_r.saturating_sub(region.primer_r.len()), trunc_len_r);
+    forward as i64 + reverse as i64 - region.amplicon_len as i64
+}
+
+/// Whether mates overlapping by `overlap` bases can be merged reliably.
+pub fn overlap_sufficient(overlap: i64) -> bool {
+    overlap >= MIN_MERGE_OVERLAP as i64
+}
+
+/// Inputs and outputs of the Cutadapt trimming step.
+pub struct TrimParams<'a> {
+    pub env_name: &'a str,
+    pub input_qza: &'a str,
+    pub output_qza: &'a str,
+    pub output_qzv: &'a str,
+    pub primers: &'a RegionPrimers,
+    pub adapter_mode: AdapterMode,
+    pub cores: usize,
+    pub skip_existing: bool,
+    /// Reads `demux summarize` subsamples for the quality plots (`--p-n`).
+    pub summarize_n: usize,
+    /// Cutadapt `--p-error-rate`: mismatches allowed per primer base.
+    pub error_rate: f64,
+    /// Cutadapt `--p-overlap`: shortest primer match that is trimmed.
+    pub overlap: usize,
+    /// Trim single-end reads with `trim-single` instead of `trim-paired`.
+    pub single_end: bool,
+}
+
+/// The Cutadapt action for paired or single-end reads.
+fn trim_action(single_end: bool) -> &'static str {
+    if single_end { "trim-single" } else { "trim-paired" }
+}
+
+/// QIIME arguments for `cutadapt trim-paired` (or `trim-single`). `cores_flag`
+/// is the version-specific spelling of the cores parameter.
+fn trim_command(params: &TrimParams, cores_flag: &str) -> Vec<String> {
+    let mut args = qiime_args(&[
+        "cutadapt", trim_action(params.single_end),
+        "--i-demultiplexed-sequences", params.input_qza,
+        cores_flag, &params.cores.to_string(),
+    ]);
+    args.extend(adapter_args(params.primers, params.adapter_mode, params.single_end));
+    args.extend(qiime_args(&[
+        "--p-error-rate", &params.error_rate.to_string(),
+        "--p-overlap", &params.overlap.to_string(),
+        "--verbose",
+        "--o-trimmed-sequences", params.output_qza,
+    ]));
+    args
+}
+
+/// Step 3: Cutadapt trimming followed by a summary of the trimmed reads.
+fn trim_steps(params: &TrimParams, cores_flag: &str) -> Vec<PipelineStep> {
+    vec![
+        PipelineStep {
+            name: "Trimming reads with Cutadapt".to_string(),
+            command: StepAction::Qiime { args: trim_command(params, cores_flag), threads: Some(1) },
+            outputs: vec![params.output_qza.to_string()],
+            is_summary: false,
+            params_sentinel: true,
+        },
+        PipelineStep {
+            name: "Summarizing trimmed data".to_string(),
+            command: qiime(&[
+                "demux", "summarize",
+                "--i-data", params.output_qza,
+                "--p-n", &params.summarize_n.to_string(),
+                "--o-visualization", params.output_qzv,
+            ]),
+            outputs: vec![params.output_qzv.to_string()],
+            is_summary: true,
+            params_sentinel: false,
+        },
+    ]
+}
+
+/// Trims primers with Cutadapt and summarizes the trimmed reads.
+fn run_trim(params: &TrimParams) -> Result<(), Box<dyn Error>> {
+    let cores_flag = qiime_compat::resolve_flag(params.env_name, "cutadapt", trim_action(params.single_end), "cores");
+    execute_steps(params.env_name, &trim_steps(params, &cores_flag), params.skip_existing, false)
+}
+
+/// Standalone `Trim` subcommand: region-aware Cutadapt trimming of an
+/// existing demultiplexed artifact. `output_qza` is relative to the output directory;
+/// the summary is written next to it as `.qzv`. Uses the environment, target,
+/// adapter mode, cores, `skip_existing` and `summarize_n` of `options`.
+pub fn trim_demultiplexed(options: &PipelineOptions, input_qza: &str, output_qza: &str) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(output_dir())?;
+    if !Path::new(input_qza).is_file() {
+        return Err(format!("Input artifact not found: {}", input_qza).into());
+    }
+    let primers = region_primers(&options.target)?;
+    let cores = if options.cores == 0 { summary::available_parallelism() } else { options.cores };
+    configure_child_env(cores, &[]);
+
+    let output_qza = out_path(output_qza);
+    let output_qzv = match output_qza.strip_suffix(".qza") {
+        Some(stem) => format!("{}.qzv", stem),
+        None => format!("{}.qzv", output_qza),
+    };
+    run_trim(&TrimParams {
+        env_name: &options.env_name,
+        input_qza,
+        output_qza: &output_qza,
+        output_qzv: &output_qzv,
+        primers: &primers,
+        adapter_mode: options.adapter_mode,
+        cores,
+        skip_existing: options.skip_existing,
+        summarize_n: options.summarize_n,
+        error_rate: options.cutadapt_error_rate,
+        overlap: options.cutadapt_overlap,
+        single_end: options.single_end,
+    })?;
+    print_success(&format!("Trimmed reads written to {} (summary: {}).", output_qza, output_qzv));
+    Ok(())
+}
+
+/// What a [`PipelineStep`] does when executed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepAction {
+    /// `qiime <args>` in the conda environment, one argument per element.
+    /// `threads` caps BLAS/OpenMP threads for actions that parallelize
+    /// themselves (see `child_env_vars`).
+    Qiime { args: Vec<String>, threads: Option<usize> },
+    /// `qiime tools validate` on an artifact.
+    Validate { artifact: String },
+    /// Check a reference FASTA for non-IUPAC characters and malformed headers.
+    CheckReference { fasta: String },
+    /// Check that an artifact has the semantic type `expected`, e.g. `TaxonomicClassifier`.
+    CheckArtifactType { artifact: String, expected: &'static str },
+    /// Export a BIOM table to TSV with `biom convert`.
+    /// Unless `force_conda`, the built-in JSON converter is tried first.
+    BiomToTsv { biom: String, tsv: String, force_conda: bool },
+    /// Download a gzipped artifact and decompress it.
+    Download { url: String, gz: String, output: String },
+    /// Rename a file.
+    Rename { from: String, to: String },
+    /// Write the taxonomy rank-completeness summary.
+    RankCompleteness { taxonomy: String, table: String, output: String, database: String },
+    /// Merge the ASV table with the taxonomy into `asv_count_tax.tsv`, with the
+    /// taxonomy columns prefixed by `database` (see [`taxonomy::database_label`]).
+    /// With `strict`, a malformed row fails the step instead of being skipped.
+    /// With `confidence_by_rank`, a confidence column per rank is added (see
+    /// [`taxonomy::rank_confidences`]).
+    MergeAsvTaxonomy { strict: bool, database: String, confidence_by_rank: bool },
+    /// Write the representative sequences with their total count and taxonomy
+    /// in the headers (`asvs_annotated.fasta`).
+    AnnotateFasta { fasta: String, taxonomy: String, output: String, strict: bool },
+    /// Sum the count columns of replicates `mapping` groups together into
+    /// `asv_count_tax_grouped.tsv` (see [`replicates::write_grouped_table`]).
+    GroupReplicates { mapping: String, database: String },
+    /// Warn when the median fraction of reads DADA2 merged, from the exported
+    /// denoising stats, is below `min_rate`.
+    CheckMergeRate { stats: String, min_rate: f64, trunc_len_f: usize, trunc_len_r: usize },
+}
+
+/// One step of the pipeline, as planned by [`plan_pipeline`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineStep {
+    /// Description shown in the spinner and the log.
+    pub name: String,
+    pub command: StepAction,
+    /// Files the step produces. With `--skip-existing` the step is skipped if
+    /// all of them exist; steps without outputs always run.
+    pub outputs: Vec<String>,
+    /// The step only produces a visualization (`.qzv`) or report.
+    pub is_summary: bool,
+    /// The outputs depend on parameters that can change between runs (e.g.
+    /// trimming, denoising). Their parameters are recorded next to the first
+    /// output, and `--skip-existing` only reuses outputs made with the same ones.
+    pub params_sentinel: bool,
+}
+
+impl PipelineStep {
+    fn with_params_sentinel(mut self) -> Self {
+        self.params_sentinel = true;
+        self
+    }
+}
+
+/// Version-specific spellings of the QIIME flags that have changed between
+/// releases (see [`qiime_compat::resolve_flag`]).
+#[derive(Debug, Clone, PartialEq)]
+pub struct QiimeFlags {
+    pub cutadapt_cores: String,
+    pub dada2_threads: String,
+    pub classify_chunk_size: String,
+    pub classify_jobs: String,
+}
+
+impl Default for QiimeFlags {
+    /// The spellings used by the QIIME release Windchime installs.
+    fn default() -> Self {
+        QiimeFlags {
+            cutadapt_cores: "--p-cores".to_string(),
+            dada2_threads: "--p-n-threads".to_string(),
+            classify_chunk_size: "--p-classify--chunk-size".to_string(),
+            classify_jobs: "--p-n-jobs".to_string(),
+        }
+    }
+}
+
+impl QiimeFlags {
+    /// Resolves the flags against the actions' `--help` in `env_name`. The
+    /// classifier-training flag is only looked up when `train_classifier` is
+    /// set; the DADA2 flag is looked up for `denoise-single` when `denoise_single` is,
+    /// and the Cutadapt flag for `trim-single` when `single_end` is.
+    pub fn resolve(env_name: &str, train_classifier: bool, denoise_single: bool, single_end: bool) -> Self {
+        let defaults = QiimeFlags::default();
+        QiimeFlags {
+            cutadapt_cores: qiime_compat::resolve_flag(env_name, "cutadapt", trim_action(single_end), "cores"),
+            dada2_threads: qiime_compat::resolve_flag(
+                env_name,
+                "dada2",
+                if denoise_single { "denoise-single" } else { "denoise-paired" },
+                "n_threads",
+            ),
+            classify_chunk_size: if train_classifier {
+                qiime_compat::resolve_flag(
+                    env_name,
+                    "feature-classifier",
+                    "fit-classifier-naive-bayes",
+                    "classify__chunk_size",
+                )
+            } else {
+                defaults.classify_chunk_size
+            },
+            classify_jobs: qiime_compat::resolve_flag(env_name, "feature-classifier", "classify-sklearn", "n_jobs"),
+        }
+    }
+}
+
+/// Everything [`plan_pipeline`] needs, with cores, primers and flags already resolved.
+pub struct PlanParams<'a> {
+    pub options: &'a PipelineOptions,
+    pub cores: usize,
+    pub primers: &'a RegionPrimers,
+    pub flags: &'a QiimeFlags,
+    /// Run Cutadapt. When false, DADA2 denoises the imported reads directly.
+    pub trim: bool,
+    /// Denoise forward reads only (`denoise-single`) instead of merging pairs.
+    pub denoise_single: bool,
+    /// classify-sklearn `--p-reads-per-batch`; `None` leaves QIIME's default.
+    pub reads_per_batch: Option<usize>,
+}
+
+/// Artifacts produced by [`run_pipeline`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineOutputs {
+    /// ASV counts merged with taxonomy (`asv_count_tax.tsv`).
+    pub merged_tsv: String,
+    /// DADA2 feature table artifact.
+    pub feature_table: String,
+    /// Feature table exported to TSV.
+    pub feature_table_tsv: String,
+    /// DADA2 representative sequences artifact.
+    pub rep_seqs: String,
+    /// Representative sequences exported to FASTA.
+    pub rep_seqs_fasta: String,
+    /// classify-sklearn taxonomy artifact.
+    pub taxonomy: String,
+    /// Taxonomy exported to TSV.
+    pub taxonomy_tsv: String,
+    /// Taxonomy rank-completeness summary.
+    pub rank_completeness_tsv: String,
+    /// Representative sequences with total counts and taxonomy in the headers.
+    pub annotated_fasta: String,
+    /// Visualizations (`.qzv`) present once the run finished, in step order.
+    pub visualizations: Vec<String>,
+    /// Rooted phylogenetic tree, when `--build-tree` built one.
+    pub tree: Option<String>,
+}
+
+impl PipelineOutputs {
+    /// Paths of the artifacts `steps` produce, classifying against `database`
+    /// (see [`PipelineOptions::database_label`]). Only visualizations that
+    /// exist on disk are listed.
+    fn from_steps(steps: &[PipelineStep], database: &str) -> Self {
+        let visualizations = steps
+            .iter()
+            .filter(|step| step.is_summary)
+            .flat_map(|step| &step.outputs)
+            .filter(|output| output.ends_with(".qzv") && Path::new(output).exists())
+            .cloned()
+            .collect();
+        let rooted_tree = out_path("rooted-tree.qza");
+        let tree = steps
+            .iter()
+            .flat_map(|step| &step.outputs)
+            .any(|output| *output == rooted_tree && Path::new(output).exists())
+            .then_some(rooted_tree);
+        PipelineOutputs {
+            merged_tsv: out_path("asv_count_tax.tsv"),
+            feature_table: out_path("asvs/table-dada2.qza"),
+            feature_table_tsv: out_path("asv_table/asv-table.tsv"),
+            rep_seqs: out_path("asvs/rep-seqs-dada2.qza"),
+            rep_seqs_fasta: out_path("asvs/dna-sequences.fasta"),
+            taxonomy: taxonomy_qza_path(database),
+            taxonomy_tsv: taxonomy_tsv_path(database),
+            rank_completeness_tsv: out_path("rank_completeness.tsv"),
+            annotated_fasta: out_path("asvs_annotated.fasta"),
+            visualizations,
+            tree,
+        }
+    }
+}
+
+/// Settings for a pipeline run (Steps 2–7).
+#[derive(Debug, Clone)]
+pub struct PipelineOptions {
+    pub env_name: String,
+    pub manifest: String,
+    pub cores: usize,
+    pub target: String,
+    pub skip_existing: bool,
+    /// Skip steps recorded as complete in [`PIPELINE_STATE`] whose outputs
+    /// exist, and re-run the rest.
+    pub resume: bool,
+    pub use_pretrained_classifier: bool,
+    /// Reference database to download a classifier for or train one on;
+    /// `None` uses the target's (see [`Database::for_target`]).
+    pub database: Option<Database>,
+    /// DADA2 truncation lengths; `None` uses the target region's defaults.
+    pub trunc_len_f: Option<usize>,
+    pub trunc_len_r: Option<usize>,
+    /// Skip `qiime tools validate` on the imported reads (the summary still runs).
+    pub skip_validate: bool,
+    /// A user-supplied `TaxonomicClassifier` artifact; skips the reference import, download and training.
+    pub classifier: Option<String>,
+    /// Extra environment variables for every QIIME child process (`--env KEY=VALUE`).
+    pub env_vars: Vec<(String, String)>,
+    /// Reduce `cores` to the available parallelism instead of only warning.
+    pub clamp_cores: bool,
+    /// Memory DADA2 needs, in GB. `None` estimates it from the run size.
+    pub min_free_memory_gb: Option<f64>,
+    /// Fail the memory preflight instead of warning.
+    pub require_memory: bool,
+    /// classify-sklearn `--p-reads-per-batch`; `None` derives it from available memory.
+    pub reads_per_batch: Option<usize>,
+    /// Sample the input reads and skip Cutadapt if the primers were already removed.
+    pub detect_primers: bool,
+    /// Paired or single-end denoising, or pick by expected read overlap.
+    pub denoise_mode: DenoiseMode,
+    /// How Cutadapt is given the primers.
+    pub adapter_mode: AdapterMode,
+    /// Quality encoding of the manifest's FASTQs.
+    pub phred: PhredOffset,
+    /// The manifest lists single-end reads (forward only): they are imported
+    /// as `SampleData[SequencesWithQuality]`, trimmed of the forward primer
+    /// only and denoised with `denoise-single`.
+    pub single_end: bool,
+    /// Cutadapt `--p-error-rate`, between 0 and 1.
+    pub cutadapt_error_rate: f64,
+    /// Cutadapt `--p-overlap`, in bases.
+    pub cutadapt_overlap: usize,
+    /// Fail the merge step on a malformed table row instead of skipping it.
+    pub strict_merge: bool,
+    /// Scan the reference FASTA for invalid characters before importing it.
+    pub strict_iupac: bool,
+    /// Skip the built-in BIOM converter and always use conda's `biom convert`.
+    pub force_conda_biom: bool,
+    /// Reads both `demux summarize` steps subsample for their quality plots (`--p-n`).
+    pub summarize_n: usize,
+    /// Intermediate outputs to remove once the run succeeds.
+    pub cleanup_on_success: CleanupProfile,
+    /// Write the planned commands to this shell script instead of running them.
+    pub dump_commands: Option<String>,
+    /// Median fraction of input reads DADA2 should merge before a warning.
+    pub min_merge_rate: f64,
+    /// Fail instead of warning when the target region and reference database don't match.
+    pub strict_reference: bool,
+    /// Add a confidence column per taxonomic rank to the merged table.
+    pub confidence_by_rank: bool,
+    /// Build a phylogenetic tree from the representative sequences.
+    pub build_tree: bool,
+    /// `sample_id<TAB>group` mapping of technical replicates to sum in
+    /// `asv_count_tax_grouped.tsv`.
+    pub replicate_groups: Option<String>,
+    /// Sample metadata TSV for `feature-table summarize` (`--metadata`).
+    pub metadata: Option<String>,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        PipelineOptions {
+            env_name: "qiime2-amplicon-2024.10".to_string(),
+            manifest: "manifest.tsv".to_string(),
+            cores: 1,
+            target: "18sv9".to_string(),
+            skip_existing: false,
+            resume: false,
+            use_pretrained_classifier: true,
+            database: None,
+            trunc_len_f: None,
+            trunc_len_r: None,
+            skip_validate: false,
+            classifier: None,
+            env_vars: Vec::new(),
+            clamp_cores: false,
+            min_free_memory_gb: None,
+            require_memory: false,
+            reads_per_batch: None,
+            detect_primers: false,
+            denoise_mode: DenoiseMode::Auto,
+            adapter_mode: AdapterMode::Linked,
+            phred: PhredOffset::Phred33,
+            single_end: false,
+            cutadapt_error_rate: 0.1,
+            cutadapt_overlap: 3,
+            strict_merge: false,
+            strict_iupac: false,
+            force_conda_biom: false,
+            summarize_n: 100_000,
+            cleanup_on_success: CleanupProfile::None,
+            dump_commands: None,
+            min_merge_rate: 0.5,
+            replicate_groups: None,
+            metadata: None,
+            strict_reference: false,
+            confidence_by_rank: false,
+            build_tree: false,
+        }
+    }
+}
+
+impl PipelineOptions {
+    /// The forward and reverse DADA2 truncation lengths, falling back to
+    /// `region`'s defaults for any not given.
+    pub fn trunc_lens(&self, region: &RegionPrimers) -> (usize, usize) {
+        (self.trunc_len_f.unwrap_or(region.trunc_len_f), self.trunc_len_r.unwrap_or(region.trunc_len_r))
+    }
+
+    /// The `--database` given, or the target's default.
+    pub fn reference_database(&self) -> Database {
+        self.database.unwrap_or_else(|| Database::for_target(&self.target))
+    }
+
+    /// Label of the database the reads are classified against (see
+    /// [`taxonomy::database_label`]), which names the taxonomy outputs.
+    pub fn database_label(&self) -> String {
+        taxonomy::database_label(self.classifier.as_deref(), self.reference_database())
+    }
+
+    /// Whether the classifier is trained here: without `--classifier`, when
+    /// pre-trained classifiers are turned off or the reference has none.
+    pub fn trains_classifier(&self) -> bool {
+        self.classifier.is_none()
+            && !(self.use_pretrained_classifier && self.reference_database().classifier_url().is_some())
+    }
+}
+
+/// Samples the manifest's R1 reads for the forward primer and reports
+/// whether Cutadapt should run. Trims whenever the answer is unclear.
+fn primers_need_trimming(options: &PipelineOptions, region: &RegionPrimers) -> bool {
+    let r1_files: Vec<String> = match estimate::manifest_inputs(&shared_path(&options.manifest)) {
+        Ok(pairs) => pairs.into_iter().map(|(r1, _)| r1).collect(),
+        Err(e) => {
+            print_error(&format!("Could not read the manifest to detect primers ({}); trimming anyway.", e));
+            return true;
+        }
+    };
+    let detection = match primers::detect_primer(&r1_files, region.primer_f) {
+        Ok(detection) => detection,
+        Err(e) => {
+            print_error(&format!("Could not sample reads to detect primers ({}); trimming anyway.", e));
+            return true;
+        }
+    };
+    let decision = match &detection {
+        PrimerDetection::Present { fraction, reads } => format!(
+            "Forward primer found at the start of {:.1}% of {} sampled reads; trimming with Cutadapt.",
+            fraction * 100.0,
+            reads
+        ),
+        PrimerDetection::Absent { fraction, reads } => format!(
+            "Forward primer found at the start of only {:.1}% of {} sampled reads; primers look already removed, \
+             skipping Cutadapt.",
+            fraction * 100.0,
+            reads
+        ),
+        PrimerDetection::Unknown => "No reads could be sampled to detect primers; trimming with Cutadapt.".to_string(),
+    };
+    print_info(&decision);
+    log_action(&decision);
+    let needs_trimming = detection.needs_trimming();
+    summary::record_setting("cutadapt", "auto", if needs_trimming { "run" } else { "skipped (no primers)" });
+    needs_trimming
+}
+
+/// Decides between paired and single-end denoising. Single-end reads are
+/// always denoised with `denoise-single`. In auto mode, falls back to forward
+/// reads when the mates are not expected to overlap enough to merge; in
+/// paired mode, only warns.
+fn choose_single_end(options: &PipelineOptions, region: &RegionPrimers, trimmed: bool) -> bool {
+    if options.single_end {
+        summary::record_setting("DADA2 mode", "single-end reads", "single");
+        return true;
+    }
+    if options.denoise_mode == DenoiseMode::Single {
+        summary::record_setting("DADA2 mode", "single", "single");
+        return true;
+    }
+    let pairs = estimate::manifest_inputs(&shared_path(&options.manifest)).unwrap_or_default();
+    let lengths = pairs.first().and_then(|(r1, r2)| {
+        Some((estimate::typical_read_length(r1).ok()??, estimate::typical_read_length(r2.as_ref()?).ok()??))
+    });
+    let Some((len_f, len_r)) = lengths else {
+        // Nothing to measure; keep the paired default
+        summary::record_setting("DADA2 mode", format!("{:?}", options.denoise_mode).to_lowercase(), "paired");
+        return false;
+    };
+    // Without Cutadapt the primers are already gone from the reads
+    let (len_f, len_r) = if trimmed { (len_f, len_r) } else { (len_f + region.primer_f.len(), len_r + region.primer_r.len()) };
+    let (trunc_len_f, trunc_len_r) = options.trunc_lens(region);
+    let overlap = expected_overlap(region, len_f, len_r, trunc_len_f, trunc_len_r);
+    if overlap_sufficient(overlap) {
+        summary::record_setting("DADA2 mode", format!("{:?}", options.denoise_mode).to_lowercase(), "paired");
+        return false;
+    }
+    let reason = format!(
+        "Reads of {}/{} bases (truncated to {}/{}) are expected to overlap by only {} bases across the ~{} bp amplicon; \
+         DADA2 needs at least {} to merge pairs.",
+        len_f, len_r, trunc_len_f, trunc_len_r, overlap, region.amplicon_len, MIN_MERGE_OVERLAP
+    );
+    log_action(&reason);
+    if options.denoise_mode == DenoiseMode::Paired {
+        print_error(&format!("Warning: {} Consider --denoise-mode single.", reason));
+        summary::record_setting("DADA2 mode", "paired", "paired");
+        return false;
+    }
+    print_error(&format!("Warning: {} Falling back to single-end denoising of forward reads.", reason));
+    summary::record_setting("DADA2 mode", "auto", "single");
+    true
+}
+
+/// Reads used by DADA2 to learn error rates (`--p-n-reads-learn`).
+const DADA2_N_READS_LEARN: u64 = 1_000_000;
+
+/// Checks available memory before the run starts, so a DADA2 step that will be
+/// OOM-killed fails in seconds rather than after hours of trimming.
+fn memory_preflight(options: &PipelineOptions) -> Result<(), Box<dyn Error>> {
+    if (options.skip_existing || options.resume) && Path::new(&out_path("asvs/table-dada2.qza")).exists() {
+        return Ok(());
+    }
+    let required = match options.min_free_memory_gb {
+        Some(gb) => (gb * 1024.0 * 1024.0 * 1024.0) as u64,
+        None => {
+            let samples = estimate::manifest_inputs(&shared_path(&options.manifest)).map_or(0, |pairs| pairs.len());
+            memory::dada2_memory_estimate(samples, DADA2_N_READS_LEARN)
+        }
+    };
+    let Some(shortfall) = memory::memory_shortfall(required, memory::available_memory_bytes()) else {
+        return Ok(());
+    };
+    log_action(&shortfall);
+    if options.require_memory {
+        print_error(&shortfall);
+        return Err(shortfall.into());
+    }
+    print_error(&format!("Warning: {} Use --require-memory to stop instead.", shortfall));
+    Ok(())
+}
+
+/// Resolves the requested core count against `available` (0 means all).
+/// Returns the cores to use and, if more were requested than are available,
+/// a warning to show the user. With `clamp`, the count is reduced to `available`.
+pub fn effective_cores(requested: usize, available: usize, clamp: bool) -> (usize, Option<String>) {
+    if requested == 0 {
+        return (available, None);
+    }
+    if requested <= available {
+        return (requested, None);
+    }
+    if clamp {
+        let warning = format!(
+            "Requested {} cores but only {} are available; clamping to {}.",
+            requested, available, available
+        );
+        (available, Some(warning))
+    } else {
+        let warning = format!(
+            "Requested {} cores but only {} are available; cutadapt and DADA2 will oversubscribe the CPU. \
+             Consider --cores {} (or pass --clamp-cores).",
+            requested, available, available
+        );
+        (requested, Some(warning))
+    }
+}
+
+/// The classify-sklearn batch size: `--reads-per-batch`, or derived from the
+/// available memory and `jobs`. Recorded in the run summary.
+fn resolve_reads_per_batch(options: &PipelineOptions, jobs: usize) -> Option<usize> {
+    let reads_per_batch = options
+        .reads_per_batch
+        .or_else(|| memory::classifier_reads_per_batch(memory::available_memory_bytes(), jobs));
+    summary::record_setting(
+        "classify-sklearn reads per batch",
+        options.reads_per_batch.map_or("auto".to_string(), |n| n.to_string()),
+        reads_per_batch.map_or("QIIME default".to_string(), |n| n.to_string()),
+    );
+    reads_per_batch
+}
+
+/// Fails unless the `--metadata` file, if given, is a readable file, so a typo
+/// stops the run before any step rather than at the feature table summary.
+pub fn check_metadata_file(options: &PipelineOptions) -> Result<(), Box<dyn Error>> {
+    let Some(metadata) = &options.metadata else {
+        return Ok(());
+    };
+    match fs::metadata(metadata) {
+        Ok(meta) if meta.is_file() => Ok(()),
+        Ok(_) => Err(format!("--metadata '{}' is not a file", metadata).into()),
+        Err(e) => Err(format!("--metadata '{}' can't be read: {}", metadata, e).into()),
+    }
+}
+
+/// Says so when a pre-trained classifier was asked for but the reference
+/// database has none, so the (much longer) training isn't a surprise.
+fn note_classifier_training(options: &PipelineOptions) {
+    let reference = options.reference_database();
+    if options.classifier.is_none() && options.use_pretrained_classifier && reference.classifier_url().is_none() {
+        let msg = format!(
+            "No pre-trained {} classifier is available; one is trained from the reference instead.",
+            reference.name()
+        );
+        log_action(&msg);
+        print_info(&msg);
+    }
+}
+
+/// Warns, or with `--strict-reference` fails, when the reference database
+/// (`--database`, or the one `--classifier` is named after) doesn't suit the
+/// target region. Fungal ITS with a `--database` other than UNITE always fails.
+pub fn check_reference_region(options: &PipelineOptions) -> Result<(), Box<dyn Error>> {
+    if let Some(database) = options.database
+        && options.classifier.is_none()
+        && options.target.eq_ignore_ascii_case("its")
+        && database != Database::Unite
+    {
+        return Err(format!(
+            "--target its classifies fungal ITS reads, which {} has no references for; \
+             use --database unite (the default for its) or leave --database out",
+            database.name()
+        )
+        .into());
+    }
+    let database = options.database_label();
+    let Some(mismatch) = taxonomy::reference_mismatch(&options.target, &database) else {
+        return Ok(());
+    };
+    if options.strict_reference {
+        return Err(format!("Reference mismatch: {} (--strict-reference)", mismatch).into());
+    }
+    let msg = format!("Warning: {}. Use --strict-reference to make this an error.", mismatch);
+    print_error(&msg);
+    log_event(LogLevel::Warning, None, &msg);
+    Ok(())
+}
+
+/// Runs only the taxonomy branch (Step 6) on an existing representative
+/// sequences artifact. With `table_qza`, the feature table is exported too and
+/// merged with the taxonomy (Step 7).
+pub fn classify_rep_seqs(
+    options: &PipelineOptions,
+    rep_seqs_qza: &str,
+    table_qza: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let env_name = options.env_name.as_str();
+    let available_cores = summary::available_parallelism();
+    let (cores, cores_warning) = effective_cores(options.cores, available_cores, options.clamp_cores);
+    if let Some(warning) = cores_warning {
+        print_error(&format!("Warning: {}", warning));
+        log_event(LogLevel::Warning, None, &warning);
+    }
+    let primers = region_primers(&options.target)?;
+    check_reference_region(options)?;
+    note_classifier_training(options);
+    configure_child_env(cores, &options.env_vars);
+
+    fs::create_dir_all(output_dir())?;
+    let external_classifier = options.classifier.as_deref();
+    if external_classifier.is_none() {
+        download_databases(options.reference_database(), false, false, DbComponent::All)?;
+    }
+
+    let train_classifier = options.trains_classifier();
+    let flags = QiimeFlags::resolve(env_name, train_classifier, false, false);
+    let params = PlanParams {
+        options,
+        cores,
+        primers: &primers,
+        flags: &flags,
+        trim: false,
+        denoise_single: false,
+        reads_per_batch: resolve_reads_per_batch(options, available_cores),
+    };
+
+    let steps = classify_steps(&params, rep_seqs_qza, table_qza);
+    execute_steps(env_name, &steps, options.skip_existing, options.resume)?;
 
     print_success(&format!(
-        "Merged ASV count and taxonomy table written to {}",
-        merged_path
+        "Taxonomy written to {}.",
+        taxonomy_tsv_path(&options.database_label())
     ));
+    if table_qza.is_some() {
+        print_info(&format!("Merged results: {}", out_path("asv_count_tax.tsv")));
+    }
     Ok(())
 }
+
+/// The steps of [`classify_rep_seqs`]: check the input artifacts, then Step 6
+/// and, with `table_qza`, the table export and Step 7.
+fn classify_steps(params: &PlanParams, rep_seqs_qza: &str, table_qza: Option<&str>) -> Vec<PipelineStep> {
+    let mut steps = vec![step(
+        "Checking representative sequences artifact",
+        StepAction::CheckArtifactType { artifact: rep_seqs_qza.to_string(), expected: "FeatureData[Sequence]" },
+        &[],
+        false,
+    )];
+    if let Some(table_qza) = table_qza {
+        steps.push(step(
+            "Checking feature table artifact",
+            StepAction::CheckArtifactType { artifact: table_qza.to_string(), expected: "FeatureTable[Frequency]" },
+            &[],
+            false,
+        ));
+        steps.extend(export_table_steps(table_qza, params.options.force_conda_biom));
+    }
+    steps.extend(taxonomy_steps(params, rep_seqs_qza));
+    if table_qza.is_some() {
+        steps.extend(merge_steps(params.options));
+    }
+    steps
+}
+
+/// Removes the outputs of a finished run's `steps` that `profile` doesn't
+/// keep. The final tables are always kept, and reference databases are
+/// shared between runs and never removed. Returns the bytes reclaimed.
+fn cleanup_run_outputs(steps: &[PipelineStep], profile: CleanupProfile) -> io::Result<u64> {
+    let shared_db = shared_path("db/");
+    let run_outputs: Vec<String> = steps
+        .iter()
+        .flat_map(|step| step.outputs.iter().cloned().chain(params_sentinel_path(step)))
+        .filter(|output| !output.starts_with(&shared_db))
+        .collect();
+    let deliverables = [
+        out_path("asv_count_tax.tsv"),
+        out_path("asv_count_tax_grouped.tsv"),
+        out_path("rank_completeness.tsv"),
+        out_path("asvs_annotated.fasta"),
+        out_path("rooted-tree.qza"),
+    ];
+    let deliverables: Vec<&str> = deliverables.iter().map(String::as_str).collect();
+    cleanup::cleanup_outputs(&run_outputs, &deliverables, profile)
+}
+
+/// Primary pipeline function: runs Steps 2–7 of the QIIME2 workflow and
+/// returns the paths of what it produced.
+pub fn run_pipeline(options: &PipelineOptions) -> Result<PipelineOutputs, Box<dyn Error>> {
+    let env_name = options.env_name.as_str();
+    // 0 means "use every core we're allowed to" (cgroup/affinity aware)
+    let available_cores = summary::available_parallelism();
+    let (cores, cores_warning) = effective_cores(options.cores, available_cores, options.clamp_cores);
+    if let Some(warning) = cores_warning {
+        print_error(&format!("Warning: {}", warning));
+        log_event(LogLevel::Warning, None, &warning);
+    }
+    let target = options.target.as_str();
+
+    fs::create_dir_all(output_dir())?;
+
+    // Adapter/primer sequences
+    let primers = match region_primers(target) {
+        Ok(primers) => primers,
+        Err(e) => {
+            print_error(&format!("{}. Use '16s', '18sv4', '18sv9', or 'its'.", e));
+            return Err(e);
+        }
+    };
+
+    check_reference_region(options)?;
+    note_classifier_training(options);
+    if options.single_end && options.denoise_mode == DenoiseMode::Paired {
+        let msg = "--denoise-mode paired needs paired-end reads; single-end reads (--single-end) are always denoised with denoise-single";
+        print_error(msg);
+        return Err(msg.into());
+    }
+
+    let requested_cores = if options.cores == 0 { "0 (auto)".to_string() } else { options.cores.to_string() };
+    summary::record_setting("cores", &requested_cores, cores);
+    configure_child_env(cores, &options.env_vars);
+    let qiime_version = qiime_compat::detect_qiime_version(env_name);
+    log_action(&format!("QIIME version in {}: {}", env_name, qiime_version.as_deref().unwrap_or("unknown")));
+    summary::record_setting("QIIME version", env_name, qiime_version.as_deref().unwrap_or("unknown"));
+    summary::record_setting("BLAS/OpenMP threads (other steps)", &requested_cores, cores);
+    summary::record_setting("BLAS/OpenMP threads (parallel steps)", 1, 1);
+    summary::record_setting("cutadapt threads", &requested_cores, cores);
+    summary::record_setting("DADA2 threads", "0 (all)", available_cores);
+    summary::record_setting("classify-sklearn jobs", "0 (all)", available_cores);
+    summary::record_setting(
+        "frontend",
+        if verbose_mode() { "verbose" } else { "default" },
+        if verbose_mode() {
+            "verbose"
+        } else if progress::interactive() {
+            "spinner"
+        } else {
+            "plain"
+        },
+    );
+
+    let external_classifier = options.classifier.as_deref();
+    if let Some(classifier) = external_classifier {
+        print_info(&format!(
+            "Using external classifier {}. Make sure it was trained for the '{}' region and primers; \
+             a mismatch silently degrades classification.",
+            classifier, target
+        ));
+    }
+
+    // Nothing runs here when only writing the script or printing the commands
+    if options.dump_commands.is_none() && !dry_run_mode() {
+        memory_preflight(options)?;
+    }
+    // A broken mapping should fail now, not after hours of denoising
+    if let Some(mapping) = &options.replicate_groups {
+        replicates::read_groups(mapping)?;
+    }
+    check_metadata_file(options)?;
+
+    fs::create_dir_all(out_path("asvs"))?;
+    fs::create_dir_all(options.reference_database().dir())?;
+
+    let (trunc_len_f, trunc_len_r) = options.trunc_lens(&primers);
+    let requested_trunc = |len: Option<usize>| len.map_or("region default".to_string(), |len| len.to_string());
+    summary::record_setting(
+        "DADA2 truncation",
+        format!("{}/{}", requested_trunc(options.trunc_len_f), requested_trunc(options.trunc_len_r)),
+        format!("{}/{}", trunc_len_f, trunc_len_r),
+    );
+
+    let trim = !options.detect_primers || primers_need_trimming(options, &primers);
+
+    let denoise_single = choose_single_end(options, &primers, trim);
+
+    let train_classifier = options.trains_classifier();
+    let flags = QiimeFlags::resolve(env_name, train_classifier, denoise_single, options.single_end);
+    let reads_per_batch = resolve_reads_per_batch(options, available_cores);
+    let steps = plan_pipeline(&PlanParams {
+        options,
+        cores,
+        primers: &primers,
+        flags: &flags,
+        trim,
+        denoise_single,
+        reads_per_batch,
+    });
+    if let Some(script) = &options.dump_commands {
+        write_shell_script(env_name, &steps, options.skip_existing, script)?;
+        print_success(&format!("Wrote the {} planned steps to {}; nothing was run.", steps.len(), script));
+        return Ok(PipelineOutputs::from_steps(&steps, &options.database_label()));
+    }
+    if dry_run_mode() {
+        execute_steps(env_name, &steps, options.skip_existing, options.resume)?;
+        print_success(&format!("Dry run: printed the {} planned steps; nothing was run.", steps.len()));
+        return Ok(PipelineOutputs::from_steps(&steps, &options.database_label()));
+    }
+    if !options.skip_existing && !options.resume {
+        reset_pipeline_state()?;
+    }
+    execute_steps(env_name, &steps, options.skip_existing, options.resume)?;
+
+    if options.cleanup_on_success != CleanupProfile::None {
+        let reclaimed = cleanup_run_outputs(&steps, options.cleanup_on_success)?;
+        summary::record_setting(
+            "cleanup on success",
+            options.cleanup_on_success.name(),
+            format!("{} bytes reclaimed", reclaimed),
+        );
+    }
+
+    let outputs = PipelineOutputs::from_steps(&steps, &options.database_label());
+
+    print_success("Pipeline completed successfully!");
+    print_info(&format!("Final summary: see '{}' for merged results.", outputs.merged_tsv));
+
+    if Path::new(&out_path("asvs/stats-dada2.qzv")).exists() {
+        print_info(&format!("You can view '{}' in QIIME2 View for DADA2 stats.", out_path("asvs/stats-dada2.qzv")));
+    }
+
+    Ok(outputs)
+}
+
+/// Owned QIIME arguments, one per element.
+fn qiime_args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// `qiime <args>` with the run-wide thread limit.
+fn qiime(args: &[&str]) -> StepAction {
+    StepAction::Qiime { args: qiime_args(args), threads: None }
+}
+
+fn step(name: &str, command: StepAction, outputs: &[&str], is_summary: bool) -> PipelineStep {
+    PipelineStep {
+        name: name.to_string(),
+        command,
+        outputs: outputs.iter().map(|o| o.to_string()).collect(),
+        is_summary,
+        params_sentinel: false,
+    }
+}
+
+/// Plans Steps 2–7 without running anything. Skipping of existing outputs is
+/// left to [`execute_steps`].
+pub fn plan_pipeline(params: &PlanParams) -> Vec<PipelineStep> {
+    let options = params.options;
+    let flags = params.flags;
+    let mut steps = Vec::new();
+
+    // Step 2: Import Files
+    let demux_name = if options.single_end { "single-end-demux" } else { "paired-end-demux" };
+    let pe_demux_qza = out_path(&format!("{}.qza", demux_name));
+    let pe_demux_qzv = out_path(&format!("{}.qzv", demux_name));
+    steps.push(step(
+        "Importing files with manifest",
+        StepAction::Qiime {
+            args: import_manifest_args(&shared_path(&options.manifest), &pe_demux_qza, options.phred, options.single_end),
+            threads: None,
+        },
+        &[&pe_demux_qza],
+        false,
+    ));
+    // Validation is skipped together with the summary when the summary exists
+    if !options.skip_validate {
+        steps.push(step(
+            "Validating imported file",
+            StepAction::Validate { artifact: pe_demux_qza.clone() },
+            &[&pe_demux_qzv],
+            false,
+        ));
+    }
+    steps.push(step(
+        "Summarizing demultiplexed data",
+        qiime(&[
+            "demux", "summarize",
+            "--i-data", &pe_demux_qza,
+            "--p-n", &options.summarize_n.to_string(),
+            "--o-visualization", &pe_demux_qzv,
+        ]),
+        &[&pe_demux_qzv],
+        true,
+    ));
+
+    // Step 3: Trim Reads (Cutadapt)
+    let pe_trimmed_qza = if params.trim { out_path(&format!("{}-trimmed.qza", demux_name)) } else { pe_demux_qza.clone() };
+    let pe_trimmed_qzv = out_path(&format!("{}-trimmed.qzv", demux_name));
+    if params.trim {
+        steps.extend(trim_steps(
+        &TrimParams {
+            env_name: &options.env_name,
+            input_qza: &pe_demux_qza,
+            output_qza: &pe_trimmed_qza,
+            output_qzv: &pe_trimmed_qzv,
+            primers: params.primers,
+            adapter_mode: options.adapter_mode,
+            cores: params.cores,
+            skip_existing: options.skip_existing,
+            summarize_n: options.summarize_n,
+            error_rate: options.cutadapt_error_rate,
+            overlap: options.cutadapt_overlap,
+            single_end: options.single_end,
+        },
+        &flags.cutadapt_cores,
+        ));
+    }
+
+    // Step 4: Denoise with DADA2
+    let table_dada2_qza = out_path("asvs/table-dada2.qza");
+    let rep_seqs_dada2_qza = out_path("asvs/rep-seqs-dada2.qza");
+    let stats_dada2_qza = out_path("asvs/stats-dada2.qza");
+    let stats_dada2_qzv = out_path("asvs/stats-dada2.qzv");
+    let table_dada2_qzv = out_path("asvs/table-dada2.qzv");
+    let dada2_outputs = [table_dada2_qza.as_str(), rep_seqs_dada2_qza.as_str(), stats_dada2_qza.as_str()];
+    let (trunc_len_f, trunc_len_r) = options.trunc_lens(params.primers);
+    if params.denoise_single {
+        steps.push(step(
+            "Running DADA2 denoise-single (forward reads)",
+            StepAction::Qiime {
+                args: qiime_args(&[
+                    "dada2", "denoise-single",
+                    "--i-demultiplexed-seqs", &pe_trimmed_qza,
+                    &flags.dada2_threads, "0",
+                    "--p-trunc-q", "2",
+                    "--p-trunc-len", &trunc_len_f.to_string(),
+                    "--p-max-ee", "2",
+                    "--p-n-reads-learn", &DADA2_N_READS_LEARN.to_string(),
+                    "--p-chimera-method", "pooled",
+                    "--o-table", &table_dada2_qza,
+                    "--o-representative-sequences", &rep_seqs_dada2_qza,
+                    "--o-denoising-stats", &stats_dada2_qza,
+                ]),
+                threads: Some(1),
+            },
+            &dada2_outputs,
+            false,
+        ).with_params_sentinel());
+    } else {
+        steps.push(step(
+            "Running DADA2 denoise-paired",
+            StepAction::Qiime {
+                args: qiime_args(&[
+                    "dada2", "denoise-paired",
+                    "--i-demultiplexed-seqs", &pe_trimmed_qza,
+                    &flags.dada2_threads, "0",
+                    "--p-trunc-q", "2",
+                    "--p-trunc-len-f", &trunc_len_f.to_string(),
+                    "--p-trunc-len-r", &trunc_len_r.to_string(),
+                    "--p-max-ee-f", "2",
+                    "--p-max-ee-r", "4",
+                    "--p-n-reads-learn", &DADA2_N_READS_LEARN.to_string(),
+                    "--p-chimera-method", "pooled",
+                    "--o-table", &table_dada2_qza,
+                    "--o-representative-sequences", &rep_seqs_dada2_qza,
+                    "--o-denoising-stats", &stats_dada2_qza,
+                ]),
+                threads: Some(1),
+            },
+            &dada2_outputs,
+            false,
+        ).with_params_sentinel());
+    }
+    steps.push(step(
+        "Tabulating DADA2 denoising stats",
+        qiime(&["metadata", "tabulate", "--m-input-file", &stats_dada2_qza, "--o-visualization", &stats_dada2_qzv]),
+        &[&stats_dada2_qzv],
+        true,
+    ));
+    if !params.denoise_single {
+        let stats_export_dir = out_path("asvs/denoising-stats");
+        let stats_tsv = format!("{}/stats.tsv", stats_export_dir);
+        steps.push(step(
+            "Exporting DADA2 denoising stats",
+            qiime(&["tools", "export", "--input-path", &stats_dada2_qza, "--output-path", &stats_export_dir]),
+            &[&stats_tsv],
+            false,
+        ));
+        steps.push(step(
+            "Checking DADA2 merge rate",
+            StepAction::CheckMergeRate { stats: stats_tsv, min_rate: options.min_merge_rate, trunc_len_f, trunc_len_r },
+            &[],
+            true,
+        ));
+    }
+    let mut summarize = vec!["feature-table", "summarize", "--i-table", &table_dada2_qza, "--o-visualization", &table_dada2_qzv];
+    if let Some(metadata) = &options.metadata {
+        summarize.extend(["--m-sample-metadata-file", metadata.as_str()]);
+    }
+    steps.push(step("Summarizing feature table", qiime(&summarize), &[&table_dada2_qzv], true));
+
+    // Step 5: Export Denoised Data
+    steps.extend(export_table_steps(&table_dada2_qza, options.force_conda_biom));
+    let rep_seqs_export_dir = out_path("asvs");
+    let rep_seqs_dada2_qzv = out_path("asvs/rep-seqs-dada2.qzv");
+    steps.push(step(
+        "Exporting representative sequences",
+        qiime(&["tools", "export", "--input-path", &rep_seqs_dada2_qza, "--output-path", &rep_seqs_export_dir]),
+        &[&format!("{}/dna-sequences.fasta", rep_seqs_export_dir)],
+        false,
+    ));
+    steps.push(step(
+        "Tabulating representative sequences",
+        qiime(&["feature-table", "tabulate-seqs", "--i-data", &rep_seqs_dada2_qza, "--o-visualization", &rep_seqs_dada2_qzv]),
+        &[&rep_seqs_dada2_qzv],
+        true,
+    ));
+
+    // Step 6: Taxonomy
+    steps.extend(taxonomy_steps(params, &rep_seqs_dada2_qza));
+
+    // Step 7: Merge ASV Table with Taxonomy
+    steps.extend(merge_steps(options));
+    let annotated_fasta = out_path("asvs_annotated.fasta");
+    steps.push(step(
+        "Annotating representative sequences",
+        StepAction::AnnotateFasta {
+            fasta: format!("{}/dna-sequences.fasta", rep_seqs_export_dir),
+            taxonomy: taxonomy_tsv_path(&options.database_label()),
+            output: annotated_fasta.clone(),
+            strict: options.strict_merge,
+        },
+        &[&annotated_fasta],
+        false,
+    ));
+
+    // Step 8: Phylogeny
+    if options.build_tree {
+        steps.push(tree_step(&rep_seqs_dada2_qza, params.cores));
+    }
+
+    steps
+}
+
+/// Step 8: align `rep_seqs_qza` with MAFFT and build FastTree unrooted and
+/// midpoint-rooted trees for phylogenetic diversity metrics.
+fn tree_step(rep_seqs_qza: &str, cores: usize) -> PipelineStep {
+    let aligned_qza = out_path("aligned-rep-seqs.qza");
+    let masked_qza = out_path("masked-aligned-rep-seqs.qza");
+    let unrooted_tree_qza = out_path("unrooted-tree.qza");
+    let rooted_tree_qza = out_path("rooted-tree.qza");
+    step(
+        "Building phylogenetic tree (MAFFT + FastTree)",
+        qiime(&[
+            "phylogeny", "align-to-tree-mafft-fasttree",
+            "--i-sequences", rep_seqs_qza,
+            "--p-n-threads", &cores.to_string(),
+            "--o-alignment", &aligned_qza,
+            "--o-masked-alignment", &masked_qza,
+            "--o-tree", &unrooted_tree_qza,
+            "--o-rooted-tree", &rooted_tree_qza,
+        ]),
+        &[&aligned_qza, &masked_qza, &unrooted_tree_qza, &rooted_tree_qza],
+        false,
+    )
+}
+
+/// Step 5 (ASV table part): export the feature table and convert it to TSV.
+fn export_table_steps(table_qza: &str, force_conda_biom: bool) -> Vec<PipelineStep> {
+    let asv_table_dir = out_path("asv_table");
+    let biom_path = format!("{}/feature-table.biom", asv_table_dir);
+    let asv_table_tsv = format!("{}/asv-table.tsv", asv_table_dir);
+    vec![
+        step(
+            "Exporting ASV table",
+            qiime(&["tools", "export", "--input-path", table_qza, "--output-path", &asv_table_dir]),
+            &[&biom_path],
+            false,
+        ),
+        step(
+            "Converting BIOM to TSV",
+            StepAction::BiomToTsv { biom: biom_path.clone(), tsv: asv_table_tsv.clone(), force_conda: force_conda_biom },
+            &[&asv_table_tsv],
+            false,
+        ),
+    ]
+}
+
+/// Step 6: obtain a classifier (external, pre-trained, or trained on the
+/// `--database` reference), classify `rep_seqs_qza`, export the taxonomy and
+/// summarize rank completeness.
+fn taxonomy_steps(params: &PlanParams, rep_seqs_qza: &str) -> Vec<PipelineStep> {
+    let options = params.options;
+    let flags = params.flags;
+    let asv_table_tsv = out_path("asv_table/asv-table.tsv");
+    let mut steps = Vec::new();
+
+    // 6a/6b) Import the reference sequences and taxonomy (not needed with an external classifier,
+    // nor for references published as artifacts)
+    let reference = options.reference_database();
+    let label = reference.label();
+    let reference_qza = reference.sequences_qza();
+    let reference_tax_qza = reference.taxonomy_qza();
+    let reference_classifier_qza = reference.classifier_path();
+    let external_classifier = options.classifier.as_deref();
+    let database = options.database_label();
+    if external_classifier.is_none() && !reference.is_imported() {
+        let reference_fasta = reference.fasta_path();
+        if options.strict_iupac {
+            steps.push(step(
+                "Validating reference sequences",
+                StepAction::CheckReference { fasta: reference_fasta.clone() },
+                &[&reference_qza],
+                false,
+            ));
+        }
+        steps.push(step(
+            "Importing reference sequences",
+            qiime(&[
+                "tools", "import",
+                "--type", "FeatureData[Sequence]",
+                "--input-path", &reference_fasta,
+                "--output-path", &reference_qza,
+            ]),
+            &[&reference_qza],
+            false,
+        ));
+        steps.push(step(
+            "Importing reference taxonomy",
+            qiime(&[
+                "tools", "import",
+                "--type", "FeatureData[Taxonomy]",
+                "--input-format", "HeaderlessTSVTaxonomyFormat",
+                "--input-path", &reference.taxonomy_path(),
+                "--output-path", &reference_tax_qza,
+            ]),
+            &[&reference_tax_qza],
+            false,
+        ));
+    }
+
+    // 6c) Use the supplied classifier, download a pre-trained one, OR extract & train from the reference
+    if let Some(classifier) = external_classifier {
+        steps.push(step(
+            "Checking external classifier artifact",
+            StepAction::CheckArtifactType { artifact: classifier.to_string(), expected: "TaxonomicClassifier" },
+            &[],
+            false,
+        ));
+    } else if let Some(url) = reference.classifier_url().filter(|_| options.use_pretrained_classifier) {
+        steps.push(step(
+            "Downloading pre-trained classifier",
+            StepAction::Download {
+                url: url.to_string(),
+                gz: format!("{}.gz", reference_classifier_qza),
+                output: reference_classifier_qza.clone(),
+            },
+            &[&reference_classifier_qza],
+            false,
+        ));
+    } else {
+        let reference_extracts_qza = reference.path(&format!("{}_extracts.qza", label));
+        steps.push(step(
+            "Extracting reference reads",
+            qiime(&[
+                "feature-classifier", "extract-reads",
+                "--i-sequences", &reference_qza,
+                "--p-f-primer", params.primers.primer_f,
+                "--p-r-primer", params.primers.primer_r,
+                "--o-reads", &reference_extracts_qza,
+            ]),
+            &[&reference_extracts_qza],
+            false,
+        ));
+        steps.push(step(
+            "Fitting reference classifier",
+            qiime(&[
+                "feature-classifier", "fit-classifier-naive-bayes",
+                "--i-reference-reads", &reference_extracts_qza,
+                "--i-reference-taxonomy", &reference_tax_qza,
+                "--o-classifier", &reference_classifier_qza,
+                &flags.classify_chunk_size, "100000",
+            ]),
+            &[&reference_classifier_qza],
+            false,
+        ));
+    }
+
+    // 6d) Classify the representative sequences
+    let classifier_qza = external_classifier.unwrap_or(&reference_classifier_qza);
+    let tax_sklearn_qza = taxonomy_qza_path(&database);
+    let tax_sklearn_qzv = out_path(&format!("{}_tax_sklearn.qzv", database));
+    // Bounding the batch keeps classify-sklearn within memory on small nodes
+    let mut classify_args = qiime_args(&["feature-classifier", "classify-sklearn", &flags.classify_jobs, "0"]);
+    if let Some(n) = params.reads_per_batch {
+        classify_args.extend(qiime_args(&["--p-reads-per-batch", &n.to_string()]));
+    }
+    classify_args.extend(qiime_args(&[
+        "--i-classifier", classifier_qza,
+        "--i-reads", rep_seqs_qza,
+        "--o-classification", &tax_sklearn_qza,
+    ]));
+    steps.push(step(
+        "Classifying reads",
+        StepAction::Qiime { args: classify_args, threads: Some(1) },
+        &[&tax_sklearn_qza],
+        false,
+    ).with_params_sentinel());
+    steps.push(step(
+        "Tabulating classified taxonomy",
+        qiime(&["metadata", "tabulate", "--m-input-file", &tax_sklearn_qza, "--o-visualization", &tax_sklearn_qzv]),
+        &[&tax_sklearn_qzv],
+        true,
+    ));
+
+    // 6e) Export and rename the taxonomy
+    let asv_tax_dir = out_path("asv_tax_dir");
+    let taxonomy_tsv = taxonomy_tsv_path(&database);
+    steps.push(step(
+        &format!("Exporting {} taxonomy", database),
+        qiime(&["tools", "export", "--input-path", &tax_sklearn_qza, "--output-path", &asv_tax_dir]),
+        &[&taxonomy_tsv],
+        false,
+    ));
+    steps.push(step(
+        &format!("Renaming {} taxonomy file", database),
+        StepAction::Rename { from: format!("{}/taxonomy.tsv", asv_tax_dir), to: taxonomy_tsv.clone() },
+        &[&taxonomy_tsv],
+        false,
+    ));
+
+    // 6f) Summarize how deep the classifications go
+    let rank_completeness_tsv = out_path("rank_completeness.tsv");
+    steps.push(step(
+        "Summarizing taxonomy rank completeness",
+        StepAction::RankCompleteness {
+            taxonomy: taxonomy_tsv,
+            table: asv_table_tsv,
+            output: rank_completeness_tsv.clone(),
+            database,
+        },
+        &[&rank_completeness_tsv],
+        true,
+    ));
+
+    steps
+}
+
+/// The exported taxonomy of the reads classified against `database` (a
+/// [`taxonomy::database_label`]), e.g. `asv_tax_dir/silva_taxonomy.tsv`.
+fn taxonomy_tsv_path(database: &str) -> String {
+    out_path(&format!("asv_tax_dir/{}_taxonomy.tsv", database))
+}
+
+/// The `classify-sklearn` result against `database`, e.g. `silva_tax_sklearn.qza`.
+fn taxonomy_qza_path(database: &str) -> String {
+    out_path(&format!("{}_tax_sklearn.qza", database))
+}
+
+/// Step 7: merge the ASV table with the taxonomy, then sum technical
+/// replicates if `--replicate-groups` was given.
+fn merge_steps(options: &PipelineOptions) -> Vec<PipelineStep> {
+    let database = options.database_label();
+    let merged_output = out_path("asv_count_tax.tsv");
+    let mut steps = vec![step(
+        "Merging ASV and taxonomy tables",
+        StepAction::MergeAsvTaxonomy {
+            strict: options.strict_merge,
+            database: database.clone(),
+            confidence_by_rank: options.confidence_by_rank,
+        },
+        &[&merged_output],
+        false,
+    )];
+    if let Some(mapping) = &options.replicate_groups {
+        let grouped_output = out_path("asv_count_tax_grouped.tsv");
+        steps.push(step(
+            "Summing technical replicates",
+            StepAction::GroupReplicates { mapping: mapping.clone(), database },
+            &[&grouped_output],
+            false,
+        ));
+    }
+    steps
+}
+
+/// Path of the parameter record written next to a step's first output.
+fn params_sentinel_path(step: &PipelineStep) -> Option<String> {
+    (step.params_sentinel && !step.outputs.is_empty()).then(|| format!("{}.params", step.outputs[0]))
+}
+
+/// The parameters that determine a step's outputs. Core, thread and job
+/// counts and classify-sklearn's batch size don't change results, so they're
+/// left out.
+fn step_params(action: &StepAction) -> String {
+    match action {
+        StepAction::Qiime { args, .. } => {
+            let mut params = Vec::new();
+            let mut tokens = args.iter();
+            while let Some(token) = tokens.next() {
+                if token.starts_with("--") && ["cores", "threads", "jobs", "batch"].iter().any(|word| token.contains(word)) {
+                    tokens.next();
+                    continue;
+                }
+                params.push(shell_quote(token));
+            }
+            params.join(" ")
+        }
+        other => format!("{:?}", other),
+    }
+}
+
+/// The sentinel's contents: a SHA-256 of the parameters, then the parameters.
+fn params_record(step: &PipelineStep) -> String {
+    let params = step_params(&step.command);
+    let hash: String = Sha256::digest(params.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}\n{}\n", hash, params)
+}
+
+/// Why a step whose outputs all exist has to run again anyway: its recorded
+/// parameters differ, or it reads an output regenerated earlier in this run.
+pub(crate) fn stale_reason(step: &PipelineStep, regenerated: &[&str]) -> Option<String> {
+    if let Some(sentinel) = params_sentinel_path(step) {
+        let recorded = fs::read_to_string(&sentinel).ok();
+        let current = params_record(step);
+        match recorded {
+            None => return Some(format!("no parameter record ({}) for the existing output", sentinel)),
+            Some(recorded) if recorded.lines().next() != current.lines().next() => {
+                return Some("its parameters changed since the existing output was made".to_string());
+            }
+            Some(_) => {}
+        }
+    }
+    let command = format!("{:?}", step.command);
+    regenerated
+        .iter()
+        .find(|output| !step.outputs.iter().any(|o| o == *output) && command.contains(*output))
+        .map(|output| format!("its input {} was regenerated", output))
+}
+
+/// File in the output directory listing the steps that completed (`--resume`).
+pub const PIPELINE_STATE: &str = "pipeline_state.json";
+
+/// Contents of [`PIPELINE_STATE`]: the completed steps, in the order they completed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PipelineState {
+    completed: Vec<CompletedStep>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CompletedStep {
+    step: String,
+    /// When the step returned, in RFC 3339.
+    completed_at: String,
+}
+
+/// Reads [`PIPELINE_STATE`]; a missing or unreadable file counts as no step completed.
+fn read_pipeline_state() -> PipelineState {
+    let path = out_path(PIPELINE_STATE);
+    let Ok(text) = fs::read_to_string(&path) else {
+        return PipelineState::default();
+    };
+    serde_json::from_str(&text).unwrap_or_else(|e| {
+        log_action(&format!("Ignoring unreadable {}: {}", path, e));
+        PipelineState::default()
+    })
+}
+
+/// Replaces [`PIPELINE_STATE`] through a temporary file, so a kill mid-write
+/// can't leave a truncated record behind.
+fn write_pipeline_state(state: &PipelineState) -> Result<(), Box<dyn Error>> {
+    let path = out_path(PIPELINE_STATE);
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, serde_json::to_string_pretty(state)?)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// Records in [`PIPELINE_STATE`] that `step` completed just now.
+pub fn mark_step_complete(step: &str) -> Result<(), Box<dyn Error>> {
+    let mut state = read_pipeline_state();
+    state.completed.retain(|done| done.step != step);
+    state.completed.push(CompletedStep { step: step.to_string(), completed_at: chrono::Local::now().to_rfc3339() });
+    write_pipeline_state(&state)
+}
+
+/// Whether [`PIPELINE_STATE`] records `step` as completed.
+pub fn is_step_complete(step: &str) -> bool {
+    read_pipeline_state().completed.iter().any(|done| done.step == step)
+}
+
+/// Drops `step` from [`PIPELINE_STATE`] before it runs, so that if it is
+/// killed, its partial outputs aren't taken as complete.
+fn unmark_step(step: &str) -> Result<(), Box<dyn Error>> {
+    let mut state = read_pipeline_state();
+    let before = state.completed.len();
+    state.completed.retain(|done| done.step != step);
+    if state.completed.len() == before {
+        return Ok(());
+    }
+    write_pipeline_state(&state)
+}
+
+/// Forgets every completed step, at the start of a run that redoes them all.
+fn reset_pipeline_state() -> Result<(), Box<dyn Error>> {
+    match fs::remove_file(out_path(PIPELINE_STATE)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Runs planned steps in order. With `skip_existing`, steps whose outputs all
+/// exist are skipped, unless they are stale (see [`stale_reason`]). With
+/// `resume`, they also have to be recorded as complete in [`PIPELINE_STATE`].
+/// Each step is recorded there once it returns Ok.
+fn execute_steps(env_name: &str, steps: &[PipelineStep], skip_existing: bool, resume: bool) -> Result<(), Box<dyn Error>> {
+    let skip_existing = skip_existing || resume;
+    let mut regenerated: Vec<&str> = Vec::new();
+    for step in steps {
+        // Every output is checked (not just up to the first missing one) so verbose mode reports them all
+        let outputs_exist = skip_existing
+            && !step.outputs.is_empty()
+            && step.outputs.iter().filter(|o| !output_exists(o)).count() == 0;
+        if outputs_exist {
+            let reason = if resume && !is_step_complete(&step.name) {
+                Some(format!("it isn't recorded as complete in {}, so its outputs may be partial", PIPELINE_STATE))
+            } else {
+                stale_reason(step, &regenerated)
+            };
+            match reason {
+                None => {
+                    print_info(&format!("Skipping '{}' ({} exists).", step.name, step.outputs.join(", ")));
+                    continue;
+                }
+                Some(reason) => {
+                    let msg = format!("Regenerating '{}': {}.", step.name, reason);
+                    log_action(&msg);
+                    print_info(&msg);
+                }
+            }
+        }
+        if dry_run_mode() {
+            print_dry_run_step(env_name, step);
+            regenerated.extend(step.outputs.iter().map(String::as_str));
+            continue;
+        }
+        unmark_step(&step.name)?;
+        run_step(&step.name, || execute_action(env_name, &step.command, skip_existing))?;
+        if let Some(sentinel) = params_sentinel_path(step) {
+            fs::write(&sentinel, params_record(step))?;
+        }
+        mark_step_complete(&step.name)?;
+        regenerated.extend(step.outputs.iter().map(String::as_str));
+    }
+    Ok(())
+}
+
+/// Prints and logs what `step` would run in `--dry-run` mode (see
+/// [`dry_run_lines`]). Nothing is run or written.
+fn print_dry_run_step(env_name: &str, step: &PipelineStep) {
+    for line in dry_run_lines(env_name, step) {
+        log_action(&format!("Dry run, not running: {}", line.trim_start_matches("[DRY RUN] ")));
+        println!("{}", line);
+    }
+}
+
+/// The lines `--dry-run` prints for `step`: its name, then its shell
+/// commands, or that Windchime does it itself.
+fn dry_run_lines(env_name: &str, step: &PipelineStep) -> Vec<String> {
+    let mut lines = vec![format!("[DRY RUN] ==> {}", step.name)];
+    match shell_commands(env_name, &step.command) {
+        Some(commands) => lines.extend(commands.iter().map(|command| format!("[DRY RUN] {}", command))),
+        None => lines.push(format!("[DRY RUN] (done by Windchime itself, writing {})", describe_outputs(&step.outputs))),
+    }
+    lines
+}
+
+/// `outputs` joined for a message, or "no files" for a check.
+fn describe_outputs(outputs: &[String]) -> String {
+    if outputs.is_empty() { "no files".to_string() } else { outputs.join(", ") }
+}
+
+fn execute_action(env_name: &str, action: &StepAction, skip_existing: bool) -> Result<(), Box<dyn Error>> {
+    match action {
+        StepAction::Qiime { args, threads } => run_conda_qiime_command_threads(env_name, args, *threads),
+        StepAction::Validate { artifact } => validate_artifact(env_name, artifact),
+        StepAction::CheckReference { fasta } => reference::check_reference_fasta(fasta),
+        StepAction::CheckArtifactType { artifact, expected } => check_artifact_type(env_name, artifact, expected),
+        StepAction::BiomToTsv { biom, tsv, force_conda } => convert_biom_to_tsv(env_name, biom, tsv, *force_conda),
+        StepAction::Download { url, gz, output } => {
+            download_file(url, gz, skip_existing)?;
+            unzip_file(gz, output, skip_existing)
+        }
+        StepAction::Rename { from, to } => fs::rename(from, to).map_err(|e| e.into()),
+        StepAction::RankCompleteness { taxonomy, table, output, database } => {
+            taxonomy::write_rank_completeness(taxonomy, table, output, database)
+        }
+        StepAction::MergeAsvTaxonomy { strict, database, confidence_by_rank } => {
+            merge_asv_taxonomy(*strict, database, *confidence_by_rank)
+        }
+        StepAction::AnnotateFasta { fasta, taxonomy, output, strict } => {
+            annotate_rep_seqs(fasta, taxonomy, output, *strict)
+        }
+        StepAction::GroupReplicates { mapping, database } => replicates::write_grouped_table(
+            &out_path("asv_count_tax.tsv"),
+            mapping,
+            database,
+            &out_path("asv_count_tax_grouped.tsv"),
+        ),
+        StepAction::CheckMergeRate { stats, min_rate, trunc_len_f, trunc_len_r } => {
+            merge_qc::check_merge_rate(stats, *min_rate, *trunc_len_f, *trunc_len_r)
+        }
+    }
+}
+
+/// Shell commands equivalent to `action`, or `None` for steps Windchime
+/// performs itself (checks, merging, reports).
+fn shell_commands(env_name: &str, action: &StepAction) -> Option<Vec<String>> {
+    let conda = |args: &[&str], threads: Option<usize>| {
+        let mut words: Vec<String> = child_env_vars(threads).into_iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        words.push(conda_binary().to_string());
+        words.extend(["run", "-n", env_name].iter().chain(args).map(|arg| arg.to_string()));
+        words.iter().map(|word| shell_quote(word)).collect::<Vec<_>>().join(" ")
+    };
+    let commands = match action {
+        StepAction::Qiime { args, threads } => {
+            let mut words = vec!["qiime"];
+            words.extend(args.iter().map(String::as_str));
+            vec![conda(&words, *threads)]
+        }
+        StepAction::Validate { artifact } => vec![conda(&["qiime", "tools", "validate", artifact], None)],
+        StepAction::CheckArtifactType { artifact, expected } => vec![format!(
+            "{} | grep -Eq {}",
+            conda(&["qiime", "tools", "peek", artifact], None),
+            shell_quote(&format!("^Type:[[:space:]]+{}[[:space:]]*$", expected))
+        )],
+        StepAction::BiomToTsv { biom, tsv, .. } => {
+            vec![conda(&["biom", "convert", "-i", biom, "-o", tsv, "--to-tsv"], None)]
+        }
+        StepAction::Download { url, gz, output } => vec![
+            format!("curl -fL -o {} {}", shell_quote(gz), shell_quote(url)),
+            format!("gunzip -c {} > {}", shell_quote(gz), shell_quote(output)),
+        ],
+        StepAction::Rename { from, to } => vec![format!("mv -f {} {}", shell_quote(from), shell_quote(to))],
+        StepAction::CheckReference { .. }
+        | StepAction::RankCompleteness { .. }
+        | StepAction::MergeAsvTaxonomy { .. }
+        | StepAction::AnnotateFasta { .. }
+        | StepAction::GroupReplicates { .. }
+        | StepAction::CheckMergeRate { .. } => return None,
+    };
+    Some(commands)
+}
+
+/// Writes `steps` as a standalone bash script (`--dump-commands`) running the
+/// same `conda run` commands in order. With `skip_existing`, each step is
+/// wrapped in a check for its outputs. Steps Windchime performs itself are
+/// echoed as skipped.
+fn write_shell_script(env_name: &str, steps: &[PipelineStep], skip_existing: bool, path: &str) -> Result<(), Box<dyn Error>> {
+    let mut script = String::from("#!/usr/bin/env bash\n");
+    script.push_str(&format!(
+        "# QIIME2 pipeline planned by Windchime {} on {}.\n",
+        env!("CARGO_PKG_VERSION"),
+        chrono::Local::now().format("%Y-%m-%d %H:%M")
+    ));
+    script.push_str("# Run it from the directory Windchime was run in.\nset -euo pipefail\n\n");
+
+    let mut dirs: Vec<&str> = steps
+        .iter()
+        .flat_map(|step| &step.outputs)
+        .filter_map(|output| Path::new(output).parent()?.to_str())
+        .filter(|dir| !dir.is_empty())
+        .collect();
+    dirs.sort_unstable();
+    dirs.dedup();
+    let dirs: Vec<String> = dirs.into_iter().map(shell_quote).collect();
+    script.push_str(&format!("mkdir -p {}\n", dirs.join(" ")));
+
+    for (i, step) in steps.iter().enumerate() {
+        script.push_str(&format!("\n# Step {}/{}: {}\n", i + 1, steps.len(), step.name));
+        let Some(commands) = shell_commands(env_name, &step.command) else {
+            let note = format!("Skipping {}: done by Windchime itself, no shell equivalent.", step.name);
+            script.push_str(&format!("echo {}\n", shell_quote(&note)));
+            continue;
+        };
+        let guard = skip_existing && !step.outputs.is_empty();
+        let indent = if guard { "  " } else { "" };
+        if guard {
+            let tests: Vec<String> = step.outputs.iter().map(|output| format!("-e {}", shell_quote(output))).collect();
+            script.push_str(&format!("if [[ {} ]]; then\n", tests.join(" && ")));
+            script.push_str(&format!("  echo {}\n", shell_quote(&format!("Skipping {} (outputs exist).", step.name))));
+            script.push_str("else\n");
+        }
+        script.push_str(&format!("{}echo {}\n", indent, shell_quote(&format!("==> {}", step.name))));
+        for command in commands {
+            script.push_str(&format!("{}{}\n", indent, command));
+        }
+        if guard {
+            script.push_str("fi\n");
+        }
+    }
+
+    fs::write(path, script)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    }
+    log_action(&format!("Wrote {} planned steps to {}", steps.len(), path));
+    Ok(())
+}
+
+/// Describes a CSV error with the file name and line it occurred on, e.g.
+/// `asv-table.tsv line 4213: wrong number of fields (got 5, expected 97)`.
+/// `skipped` lines before the ones the CSV reader saw are added to its line number.
+fn csv_error_context(path: &str, error: &csv::Error, skipped: u64) -> String {
+    let file = Path::new(path).file_name().map_or(path.into(), |name| name.to_string_lossy());
+    let location = match error.position() {
+        Some(pos) => format!("{} line {}", file, pos.line() + skipped),
+        None => file.into_owned(),
+    };
+    match error.kind() {
+        csv::ErrorKind::UnequalLengths { expected_len, len, .. } => format!(
+            "{}: wrong number of fields (got {}, expected {})",
+            location, len, expected_len
+        ),
+        _ => format!("{}: {}", location, error),
+    }
+}
+
+/// Table rows keyed by feature ID (the first column).
+type FeatureRows = HashMap<String, Vec<String>>;
+
+/// Lines before the header of a `biom convert --to-tsv` table: every leading
+/// `#` line except the last, which is the `#OTU ID` header itself.
+fn biom_preamble_lines(path: &str) -> io::Result<u64> {
+    use std::io::BufRead;
+    let mut comments = 0u64;
+    for line in io::BufReader::new(File::open(path)?).lines() {
+        if !line?.starts_with('#') {
+            break;
+        }
+        comments += 1;
+    }
+    Ok(comments.saturating_sub(1))
+}
+
+/// Number of newline-terminated lines in `path`, without parsing them.
+fn count_lines(path: &str) -> io::Result<u64> {
+    use std::io::BufRead;
+    let mut reader = io::BufReader::with_capacity(1 << 16, File::open(path)?);
+    let mut lines = 0;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(lines);
+        }
+        lines += buf.iter().filter(|&&b| b == b'\n').count() as u64;
+        let len = buf.len();
+        reader.consume(len);
+    }
+}
+
+/// Reads a tab-separated table keyed by its first column, after the
+/// `biom convert` preamble if `skip_comments` is set. Malformed rows are
+/// skipped with a warning, or fail the read when `strict` is set; either way
+/// the error names the file and line.
+fn read_feature_rows(
+    path: &str,
+    skip_comments: bool,
+    strict: bool,
+    bar: Option<&ProgressBar>,
+) -> Result<(csv::StringRecord, FeatureRows), Box<dyn Error>> {
+    use std::io::BufRead;
+    let preamble = if skip_comments { biom_preamble_lines(path).map_err(|e| format!("{}: {}", path, e))? } else { 0 };
+    let mut input = io::BufReader::new(File::open(path).map_err(|e| format!("{}: {}", path, e))?);
+    for _ in 0..preamble {
+        input.read_line(&mut String::new())?;
+    }
+    let context = |e: &csv::Error| csv_error_context(path, e, preamble);
+    let mut reader = ReaderBuilder::new().delimiter(b'\t').has_headers(true).from_reader(input);
+
+    let headers = reader.headers().map_err(|e| context(&e))?.clone();
+    let mut rows = HashMap::new();
+    let mut skipped = 0;
+    for record in reader.records() {
+        if let Some(pb) = bar {
+            pb.inc(1);
+        }
+        let rec = match record {
+            Ok(rec) => rec,
+            Err(e) if !strict && matches!(e.kind(), csv::ErrorKind::UnequalLengths { .. } | csv::ErrorKind::Utf8 { .. }) => {
+                let msg = context(&e);
+                print_error(&format!("Warning: skipping malformed row: {}", msg));
+                log_action(&format!("Skipped malformed row: {}", msg));
+                skipped += 1;
+                continue;
+            }
+            Err(e) => return Err(context(&e).into()),
+        };
+        let feature_id = rec.get(0).unwrap_or("").to_string();
+        rows.insert(feature_id, rec.iter().map(|s| s.to_string()).collect());
+    }
+    if skipped > 0 {
+        print_error(&format!(
+            "Warning: skipped {} malformed row(s) in {}; use --strict-merge to fail instead.",
+            skipped, path
+        ));
+    }
+    Ok((headers, rows))
+}
+
+/// Writes `output` from the exported representative sequences, with each
+/// ASV's total count (summed over samples) and taxonomy in its header.
+fn annotate_rep_seqs(fasta: &str, taxonomy: &str, output: &str, strict: bool) -> Result<(), Box<dyn Error>> {
+    let (_, asv_map) = read_feature_rows(&out_path("asv_table/asv-table.tsv"), true, strict, None)?;
+    let totals: HashMap<String, f64> = asv_map
+        .into_iter()
+        .map(|(feature_id, record)| {
+            let total = record.iter().skip(1).filter_map(|count| count.parse::<f64>().ok()).sum();
+            (feature_id, total)
+        })
+        .collect();
+
+    let (tax_headers, tax_map) = read_feature_rows(taxonomy, false, strict, None)?;
+    let taxon_column = tax_headers.iter().position(|col| col == "Taxon").unwrap_or(1);
+    let taxa: HashMap<String, String> = tax_map
+        .into_iter()
+        .filter_map(|(feature_id, record)| Some((feature_id, record.get(taxon_column)?.clone())))
+        .collect();
+
+    let written = annotate::write_annotated_fasta(fasta, output, &totals, &taxa)?;
+    print_success(&format!("Wrote {} annotated sequences to {}", written, output));
+    Ok(())
+}
+
+/// Merges the ASV count table with the assigned taxonomy, producing `asv_count_tax.tsv`.
+fn merge_asv_taxonomy(strict: bool, database: &str, confidence_by_rank: bool) -> Result<(), Box<dyn Error>> {
+    let asv_table_path = out_path("asv_table/asv-table.tsv");
+    let tax_path = taxonomy_tsv_path(database);
+    // On a terminal, show progress over reading both tables and writing the
+    // merged rows; a line count is a cheap upper bound on the rows
+    let bar = progress::step_bar(|| {
+        let lines = |path: &str| count_lines(path).unwrap_or(0);
+        2 * lines(&asv_table_path) + lines(&tax_path)
+    });
+
+    // Read the ASV table
+    let (asv_headers, asv_map) = read_feature_rows(&asv_table_path, true, strict, bar.as_ref())?;
+
+    // Read the taxonomy table
+    let (tax_headers, tax_map) = read_feature_rows(&tax_path, false, strict, bar.as_ref())?;
+
+    // QIIME's export has one `;`-delimited `Taxon` column; it's also split into
+    // a column per rank. Tables that already have rank columns are kept as-is.
+    let taxon_column = tax_headers.iter().position(|col| col == "Taxon").filter(|&i| i > 0);
+    let rank_names = match taxon_column {
+        Some(col) => taxonomy::lineage_rank_names(
+            tax_map.values().filter_map(|rec| rec.get(col)).map(String::as_str),
+            database == "pr2",
+        ),
+        None => Vec::new(),
+    };
+    let confidence_column = tax_headers.iter().position(|col| col == "Confidence").filter(|&i| i > 0);
+    let rank_confidence = match (confidence_by_rank, taxon_column, confidence_column) {
+        (true, Some(taxon), Some(confidence)) => Some((taxon, confidence)),
+        (true, _, _) => {
+            let msg = format!("Warning: {} has no Taxon and Confidence columns; no per-rank confidences added.", tax_path);
+            print_error(&msg);
+            log_action(&msg);
+            None
+        }
+        (false, _, _) => None,
+    };
+    let confidence_names: Vec<String> = match rank_confidence {
+        Some(_) => rank_names.iter().map(|rank| format!("Confidence_{}", rank)).collect(),
+        None => Vec::new(),
+    };
+
+    // Write merged
+    let merged_path = out_path("asv_count_tax.tsv");
+    let mut wtr = WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_path(&merged_path)?;
+
+    // Build merged header
+    let mut merged_header = Vec::new();
+    for (i, col) in asv_headers.iter().enumerate() {
+        if i == 0 {
+            merged_header.push("Feature.ID".to_string());
+        } else {
+            merged_header.push(col.to_string());
+        }
+    }
+    let extra_columns = rank_names.iter().chain(&confidence_names).map(String::as_str);
+    for col in tax_headers.iter().skip(1).chain(extra_columns) {
+        merged_header.push(format!("{}_{}", database, col));
+    }
+    wtr.write_record(&merged_header)?;
+
+    // Merge rows
+    let tax_columns = tax_headers.len().saturating_sub(1) + rank_names.len() + confidence_names.len();
+    for (feature_id, asv_record) in asv_map.iter() {
+        let mut merged_record = asv_record.clone();
+        if let Some(tax_record) = tax_map.get(feature_id) {
+            // skip the feature ID column of the taxonomy
+            merged_record.extend(tax_record.iter().skip(1).cloned());
+            if let Some(col) = taxon_column {
+                let ranks: Vec<&str> = tax_record.get(col).map_or(Vec::new(), |t| t.split(';').map(str::trim).collect());
+                merged_record.extend((0..rank_names.len()).map(|i| ranks.get(i).unwrap_or(&"").to_string()));
+            }
+            if let Some((taxon, confidence)) = rank_confidence {
+                let field = |col: usize| tax_record.get(col).map_or("", String::as_str);
+                merged_record.extend(taxonomy::rank_confidences(field(taxon), field(confidence), rank_names.len()));
+            }
+        } else {
+            merged_record.extend(std::iter::repeat_n(String::new(), tax_columns));
+        }
+        wtr.write_record(&merged_record)?;
+        if let Some(pb) = &bar {
+            pb.inc(1);
+        }
+    }
+    wtr.flush()?;
+
+    print_success(&format!(
+        "Merged ASV count and taxonomy table written to {}",
+        merged_path
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    /// The steps planned for `options` on the 18S V9 region, trimmed and paired.
+    fn plan(options: &PipelineOptions) -> Vec<PipelineStep> {
+        let primers = region_primers(&options.target).unwrap();
+        plan_pipeline(&PlanParams {
+            options,
+            cores: 1,
+            primers: &primers,
+            flags: &QiimeFlags::default(),
+            trim: true,
+            denoise_single: false,
+            reads_per_batch: None,
+        })
+    }
+
+    fn step_named<'a>(steps: &'a [PipelineStep], name: &str) -> Option<&'a PipelineStep> {
+        steps.iter().find(|step| step.name == name)
+    }
+
+    #[test]
+    fn imported_reads_are_validated_by_default() {
+        let steps = plan(&PipelineOptions::default());
+        let validate = step_named(&steps, "Validating imported file").unwrap();
+        assert!(matches!(&validate.command, StepAction::Validate { artifact } if artifact.ends_with("paired-end-demux.qza")));
+    }
+
+    #[test]
+    fn skip_validate_drops_only_the_validation() {
+        let steps = plan(&PipelineOptions { skip_validate: true, ..PipelineOptions::default() });
+        assert!(!steps.iter().any(|step| matches!(step.command, StepAction::Validate { .. })));
+        assert!(step_named(&steps, "Importing files with manifest").is_some());
+        assert!(step_named(&steps, "Summarizing demultiplexed data").is_some());
+    }
+
+    #[test]
+    fn the_import_step_uses_the_manifest_format_for_the_phred_offset() {
+        let _state = test_support::global_state();
+        let import_format = |phred| {
+            let steps = plan(&PipelineOptions { phred, ..PipelineOptions::default() });
+            let args = planned_args(&steps, "Importing files with manifest");
+            args.rsplit(' ').next().unwrap().to_string()
+        };
+        assert_eq!(import_format(PhredOffset::default()), "PairedEndFastqManifestPhred33V2");
+        assert_eq!(import_format(PhredOffset::Phred64), "PairedEndFastqManifestPhred64V2");
+        assert_eq!("64".parse::<PhredOffset>(), Ok(PhredOffset::Phred64));
+        assert_eq!(" 33 ".parse::<PhredOffset>(), Ok(PhredOffset::Phred33));
+        assert_eq!("42".parse::<PhredOffset>(), Err("expected 33 or 64, got '42'".to_string()));
+    }
+
+    #[test]
+    fn external_classifier_is_classified_with_and_skips_training() {
+        let options = PipelineOptions { classifier: Some("/lab/classifier.qza".to_string()), ..PipelineOptions::default() };
+        let steps = plan(&options);
+        let check = step_named(&steps, "Checking external classifier artifact").unwrap();
+        assert_eq!(
+            check.command,
+            StepAction::CheckArtifactType { artifact: "/lab/classifier.qza".to_string(), expected: "TaxonomicClassifier" }
+        );
+        let StepAction::Qiime { args, .. } = &step_named(&steps, "Classifying reads").unwrap().command else {
+            panic!("classification isn't a QIIME step");
+        };
+        let classifier = args.iter().position(|arg| arg == "--i-classifier").unwrap();
+        assert_eq!(args[classifier + 1], "/lab/classifier.qza");
+        for skipped in [
+            "Importing reference sequences",
+            "Importing reference taxonomy",
+            "Downloading pre-trained classifier",
+            "Extracting reference reads",
+            "Fitting reference classifier",
+        ] {
+            assert!(step_named(&steps, skipped).is_none(), "{} was planned", skipped);
+        }
+    }
+
+    #[test]
+    fn a_pretrained_classifier_replaces_training_and_is_kept_when_present() {
+        let _state = test_support::global_state();
+        let mut steps = plan(&PipelineOptions::default());
+        let names = names(&steps);
+        assert!(!names.contains(&"Extracting reference reads") && !names.contains(&"Fitting reference classifier"));
+        let classifier = Database::Pr2.classifier_path();
+        assert!(classifier.ends_with("db/pr2/pr2_classifier.qza"), "{}", classifier);
+        assert!(planned_args(&steps, "Classifying reads").contains(&format!("--i-classifier {} ", classifier)));
+
+        let server = test_support::MockServer::start(|_| {
+            let body = gzipped("classifier");
+            test_support::http_response("200 OK", &[("Content-Length", &body.len().to_string())], &body)
+        });
+        steps.retain(|step| step.name == "Downloading pre-trained classifier");
+        let StepAction::Download { url, output, .. } = &mut steps[0].command else { panic!("not a download") };
+        assert_eq!(*output, classifier);
+        *url = format!("{}/pr2_classifier.qza.gz", server.url);
+        fs::create_dir_all(Path::new(&classifier).parent().unwrap()).unwrap();
+
+        execute_steps("qiime-env", &steps, false, false).unwrap();
+        assert_eq!(fs::read_to_string(&classifier).unwrap(), "classifier");
+        // With --skip-existing the downloaded classifier is reused, not fetched again
+        execute_steps("qiime-env", &steps, true, false).unwrap();
+        assert_eq!(server.request_lines(), ["GET /pr2_classifier.qza.gz HTTP/1.1"]);
+    }
+
+    #[test]
+    fn validate_manifest_imports_and_validates_only() {
+        let _state = test_support::global_state();
+        validate_manifest("qiime-env", "manifest.tsv", PhredOffset::Phred33, false).unwrap();
+
+        let calls = test_support::conda_calls();
+        let manifest = shared_path("manifest.tsv");
+        let check_qza = out_path("manifest-validation.qza");
+        assert_eq!(
+            calls,
+            [
+                format!(
+                    "run -n qiime-env qiime tools import --type SampleData[PairedEndSequencesWithQuality] \
+                     --input-path {} --output-path {} --input-format PairedEndFastqManifestPhred33V2",
+                    manifest, check_qza
+                ),
+                format!("run -n qiime-env qiime tools validate {}", check_qza),
+            ]
+        );
+        assert!(!Path::new(&check_qza).exists());
+    }
+
+    #[test]
+    fn validate_manifest_reports_a_failed_import_without_validating() {
+        let _state = test_support::global_state();
+        test_support::conda_fails_on("tools import");
+        assert!(validate_manifest("qiime-env", "manifest.tsv", PhredOffset::Phred64, true).is_err());
+        let calls = test_support::conda_calls();
+        assert_eq!(calls.len(), 1);
+        assert!(calls[0].contains("--type SampleData[SequencesWithQuality]"), "{}", calls[0]);
+        assert!(calls[0].ends_with("--input-format SingleEndFastqManifestPhred64V2"), "{}", calls[0]);
+    }
+
+    #[test]
+    fn denoise_step_runs_single_threaded_with_the_extra_env() {
+        let _state = test_support::global_state();
+        let extra = [("OMP_NUM_THREADS", "2"), ("TMPDIR", "/scratch")].map(|(k, v)| (k.to_string(), v.to_string()));
+        configure_child_env(8, &extra);
+        let steps = plan(&PipelineOptions::default());
+        let denoise = step_named(&steps, "Running DADA2 denoise-paired").unwrap();
+        let summarize = step_named(&steps, "Summarizing feature table").unwrap();
+        let result = execute_action("qiime-env", &denoise.command, false)
+            .and_then(|_| execute_action("qiime-env", &summarize.command, false));
+        configure_child_env(1, &[]);
+        result.unwrap();
+
+        let envs = test_support::conda_envs();
+        assert_eq!(envs.len(), 2);
+        let has = |env: &[String], var: &str| env.iter().any(|line| line == var);
+        // DADA2 parallelizes itself, so the libraries it uses get one thread each
+        for var in ["OPENBLAS_NUM_THREADS=1", "MKL_NUM_THREADS=1", "NUMBA_NUM_THREADS=1", "OMP_NUM_THREADS=2", "TMPDIR=/scratch"] {
+            assert!(has(&envs[0], var), "denoise env lacks {}", var);
+        }
+        for var in ["OPENBLAS_NUM_THREADS=8", "MKL_NUM_THREADS=8", "NUMBA_NUM_THREADS=8", "OMP_NUM_THREADS=2", "TMPDIR=/scratch"] {
+            assert!(has(&envs[1], var), "summarize env lacks {}", var);
+        }
+    }
+
+    #[test]
+    fn standalone_trim_runs_the_pipelines_trim_commands() {
+        let _state = test_support::global_state();
+        // An environment of its own, so the cores flag's --help isn't cached yet
+        let options = PipelineOptions {
+            env_name: "trim-standalone".to_string(),
+            target: "16s".to_string(),
+            adapter_mode: AdapterMode::Anchored,
+            cutadapt_error_rate: 0.2,
+            cutadapt_overlap: 5,
+            ..PipelineOptions::default()
+        };
+        let input_qza = out_path("paired-end-demux.qza");
+        fs::write(&input_qza, "").unwrap();
+        let result = trim_demultiplexed(&options, &input_qza, "paired-end-demux-trimmed.qza");
+        configure_child_env(1, &[]);
+        result.unwrap();
+
+        let planned: Vec<String> = plan(&options)
+            .into_iter()
+            .filter(|step| ["Trimming reads with Cutadapt", "Summarizing trimmed data"].contains(&step.name.as_str()))
+            .map(|step| match step.command {
+                StepAction::Qiime { args, .. } => format!("run -n {} qiime {}", options.env_name, args.join(" ")),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(planned.len(), 2);
+        assert!(planned[0].contains(&format!("--p-front-f ^{}", region_primers("16s").unwrap().primer_f)));
+        assert!(planned[0].contains(" --p-error-rate 0.2 --p-overlap 5 "), "{}", planned[0]);
+        let calls = test_support::conda_calls();
+        assert!(calls[0].ends_with("cutadapt trim-paired --help"), "{}", calls[0]);
+        assert_eq!(calls[1..], planned);
+    }
+
+    #[test]
+    fn cores_above_the_available_count_warn_and_clamp() {
+        let (cores, warning) = effective_cores(64, 8, false);
+        assert_eq!(cores, 64);
+        let warning = warning.unwrap();
+        assert!(warning.contains("Requested 64 cores but only 8 are available"), "{}", warning);
+        assert!(warning.contains("--cores 8"), "{}", warning);
+
+        let (cores, warning) = effective_cores(64, 8, true);
+        assert_eq!(cores, 8);
+        assert!(warning.unwrap().contains("clamping to 8"));
+
+        assert_eq!(effective_cores(8, 8, false), (8, None));
+        assert_eq!(effective_cores(0, 8, false), (8, None));
+    }
+
+    fn names(steps: &[PipelineStep]) -> Vec<&str> {
+        steps.iter().map(|step| step.name.as_str()).collect()
+    }
+
+    #[test]
+    fn default_plan_runs_every_step_in_order() {
+        let _state = test_support::global_state();
+        let options = PipelineOptions { trunc_len_f: Some(120), trunc_len_r: Some(110), ..PipelineOptions::default() };
+        let steps = plan(&options);
+        assert_eq!(
+            names(&steps),
+            [
+                "Importing files with manifest",
+                "Validating imported file",
+                "Summarizing demultiplexed data",
+                "Trimming reads with Cutadapt",
+                "Summarizing trimmed data",
+                "Running DADA2 denoise-paired",
+                "Tabulating DADA2 denoising stats",
+                "Exporting DADA2 denoising stats",
+                "Checking DADA2 merge rate",
+                "Summarizing feature table",
+                "Exporting ASV table",
+                "Converting BIOM to TSV",
+                "Exporting representative sequences",
+                "Tabulating representative sequences",
+                "Importing reference sequences",
+                "Importing reference taxonomy",
+                "Downloading pre-trained classifier",
+                "Classifying reads",
+                "Tabulating classified taxonomy",
+                "Exporting pr2 taxonomy",
+                "Renaming pr2 taxonomy file",
+                "Summarizing taxonomy rank completeness",
+                "Merging ASV and taxonomy tables",
+                "Annotating representative sequences",
+            ]
+        );
+
+        let out = |name: &str| out_path(name);
+        let denoise = step_named(&steps, "Running DADA2 denoise-paired").unwrap();
+        assert_eq!(
+            denoise.command,
+            StepAction::Qiime {
+                args: qiime_args(&[
+                    "dada2", "denoise-paired",
+                    "--i-demultiplexed-seqs", &out("paired-end-demux-trimmed.qza"),
+                    "--p-n-threads", "0",
+                    "--p-trunc-q", "2",
+                    "--p-trunc-len-f", "120",
+                    "--p-trunc-len-r", "110",
+                    "--p-max-ee-f", "2",
+                    "--p-max-ee-r", "4",
+                    "--p-n-reads-learn", &DADA2_N_READS_LEARN.to_string(),
+                    "--p-chimera-method", "pooled",
+                    "--o-table", &out("asvs/table-dada2.qza"),
+                    "--o-representative-sequences", &out("asvs/rep-seqs-dada2.qza"),
+                    "--o-denoising-stats", &out("asvs/stats-dada2.qza"),
+                ]),
+                threads: Some(1),
+            }
+        );
+        assert!(denoise.params_sentinel);
+        assert_eq!(
+            denoise.outputs,
+            [out("asvs/table-dada2.qza"), out("asvs/rep-seqs-dada2.qza"), out("asvs/stats-dada2.qza")]
+        );
+        // Summaries only produce visualizations and reports
+        let mut summary_outputs = steps.iter().filter(|step| step.is_summary).flat_map(|step| &step.outputs);
+        assert!(summary_outputs.all(|output| !output.ends_with(".qza")));
+    }
+
+    #[test]
+    fn build_tree_adds_a_tree_step_on_the_dada2_rep_seqs() {
+        let _state = test_support::global_state();
+        let tree = "Building phylogenetic tree (MAFFT + FastTree)";
+        assert!(step_named(&plan(&PipelineOptions::default()), tree).is_none());
+
+        let mut steps = plan(&PipelineOptions { build_tree: true, ..PipelineOptions::default() });
+        assert_eq!(names(&steps).last(), Some(&tree));
+        let rep_seqs = out_path("asvs/rep-seqs-dada2.qza");
+        let rooted = out_path("rooted-tree.qza");
+        let args = planned_args(&steps, tree);
+        assert!(args.starts_with(&format!("phylogeny align-to-tree-mafft-fasttree --i-sequences {} ", rep_seqs)), "{}", args);
+        assert!(args.ends_with(&format!("--o-tree {} --o-rooted-tree {}", out_path("unrooted-tree.qza"), rooted)), "{}", args);
+        let denoise = "Running DADA2 denoise-paired";
+        assert!(step_named(&steps, denoise).unwrap().outputs.contains(&rep_seqs));
+
+        // Like the other steps, the tree is kept with --skip-existing unless the rep-seqs are remade
+        steps.retain(|step| step.name == denoise || step.name == tree);
+        fs::create_dir_all(out_path("asvs")).unwrap();
+        for output in steps.iter().flat_map(|step| &step.outputs) {
+            fs::write(output, "made").unwrap();
+        }
+        for step in &steps {
+            if let Some(sentinel) = params_sentinel_path(step) {
+                fs::write(sentinel, params_record(step)).unwrap();
+            }
+        }
+        execute_steps("qiime-env", &steps, true, false).unwrap();
+        assert!(test_support::conda_calls().is_empty());
+        fs::remove_file(&steps[0].outputs[0]).unwrap();
+        execute_steps("qiime-env", &steps, true, false).unwrap();
+        let calls = test_support::conda_calls();
+        assert_eq!(calls.len(), 2, "{:?}", calls);
+        assert!(calls[0].contains("dada2 denoise-paired") && calls[1].contains("align-to-tree-mafft-fasttree"));
+        assert_eq!(PipelineOutputs::from_steps(&steps, "pr2").tree, Some(rooted));
+    }
+
+    #[test]
+    fn untrimmed_single_end_plan_skips_cutadapt_and_merging() {
+        let _state = test_support::global_state();
+        let options = PipelineOptions {
+            single_end: true,
+            build_tree: true,
+            use_pretrained_classifier: false,
+            ..PipelineOptions::default()
+        };
+        let primers = region_primers(&options.target).unwrap();
+        let steps = plan_pipeline(&PlanParams {
+            options: &options,
+            cores: 4,
+            primers: &primers,
+            flags: &QiimeFlags::default(),
+            trim: false,
+            denoise_single: true,
+            reads_per_batch: Some(5000),
+        });
+        let names = names(&steps);
+        assert_eq!(
+            names[..4],
+            [
+                "Importing files with manifest",
+                "Validating imported file",
+                "Summarizing demultiplexed data",
+                "Running DADA2 denoise-single (forward reads)",
+            ]
+        );
+        for absent in [
+            "Trimming reads with Cutadapt",
+            "Exporting DADA2 denoising stats",
+            "Checking DADA2 merge rate",
+            "Downloading pre-trained classifier",
+        ] {
+            assert!(!names.contains(&absent), "{} was planned", absent);
+        }
+        assert_eq!(names.last(), Some(&"Building phylogenetic tree (MAFFT + FastTree)"));
+        let fit = names.iter().position(|name| *name == "Fitting reference classifier").unwrap();
+        let classify = names.iter().position(|name| *name == "Classifying reads").unwrap();
+        assert_eq!(names[fit - 1], "Extracting reference reads");
+        assert!(fit < classify);
+
+        let StepAction::Qiime { args, .. } = &steps[3].command else { panic!("denoising isn't a QIIME step") };
+        let demux = out_path("single-end-demux.qza");
+        assert_eq!(args[..4], qiime_args(&["dada2", "denoise-single", "--i-demultiplexed-seqs", &demux]));
+        let StepAction::Qiime { args, .. } = &steps[classify].command else { panic!("classification isn't a QIIME step") };
+        assert!(args.windows(2).any(|pair| pair == ["--p-reads-per-batch", "5000"]));
+        let StepAction::Qiime { args, .. } = &steps[0].command else { panic!("import isn't a QIIME step") };
+        assert!(args.windows(2).any(|pair| pair == ["--type", "SampleData[SequencesWithQuality]"]));
+    }
+
+    #[test]
+    fn single_end_plan_imports_trims_and_denoises_forward_reads_only() {
+        let _state = test_support::global_state();
+        let options = PipelineOptions { single_end: true, ..PipelineOptions::default() };
+        let primers = region_primers(&options.target).unwrap();
+        let steps = plan_pipeline(&PlanParams {
+            options: &options,
+            cores: 1,
+            primers: &primers,
+            flags: &QiimeFlags::default(),
+            trim: true,
+            denoise_single: true,
+            reads_per_batch: None,
+        });
+        let import = planned_args(&steps, "Importing files with manifest");
+        assert!(import.contains("--type SampleData[SequencesWithQuality] "), "{}", import);
+        assert!(import.ends_with("--input-format SingleEndFastqManifestPhred33V2"), "{}", import);
+
+        let trim = planned_args(&steps, "Trimming reads with Cutadapt");
+        assert!(trim.starts_with("cutadapt trim-single "), "{}", trim);
+        assert!(trim.contains(&format!("--p-adapter {} ", primers.adapter_f)), "{}", trim);
+        assert!(!trim.contains(primers.adapter_r) && !trim.contains("-f ") && !trim.contains("-r "), "{}", trim);
+        for mode in [AdapterMode::Regular, AdapterMode::Anchored] {
+            let args = adapter_args(&primers, mode, true).join(" ");
+            assert!(args.starts_with("--p-front ") && args.ends_with(primers.primer_f), "{}", args);
+        }
+
+        let names = names(&steps);
+        assert!(names.contains(&"Running DADA2 denoise-single (forward reads)"), "{:?}", names);
+        assert!(!names.iter().any(|name| name.contains("denoise-paired") || name.contains("merge rate")), "{:?}", names);
+    }
+
+    #[test]
+    fn output_prefix_applies_to_every_artifact() {
+        let _state = test_support::global_state();
+        set_output_prefix("run1_").unwrap();
+        let options = PipelineOptions {
+            build_tree: true,
+            replicate_groups: Some("groups.tsv".to_string()),
+            ..PipelineOptions::default()
+        };
+        let steps = plan(&options);
+        let outputs = PipelineOutputs::from_steps(&steps, &options.database_label());
+        let reference_dir = options.reference_database().dir();
+
+        // Downstream steps read the prefixed files too
+        fs::create_dir_all(out_path("asv_table")).unwrap();
+        fs::create_dir_all(out_path("asv_tax_dir")).unwrap();
+        fs::write(out_path("asv_table/asv-table.tsv"), "#OTU ID\ts1\nasv1\t5\n").unwrap();
+        fs::write(taxonomy_tsv_path("pr2"), "Feature ID\tTaxon\tConfidence\nasv1\tEukaryota\t0.99\n").unwrap();
+        let merge = step_named(&steps, "Merging ASV and taxonomy tables").unwrap();
+        let merged = execute_action(&options.env_name, &merge.command, false);
+        set_output_prefix("").unwrap();
+        merged.unwrap();
+
+        let prefixed = format!("{}/run1_", output_dir());
+        for output in steps.iter().flat_map(|step| &step.outputs) {
+            assert!(
+                output.starts_with(&prefixed) || output.starts_with(&reference_dir),
+                "{} isn't prefixed",
+                output
+            );
+        }
+        assert!(steps.iter().flat_map(|step| &step.outputs).any(|output| output.starts_with(&reference_dir)));
+        for path in [&outputs.merged_tsv, &outputs.feature_table, &outputs.rep_seqs, &outputs.taxonomy_tsv] {
+            assert!(path.starts_with(&prefixed), "{} isn't prefixed", path);
+        }
+        assert!(test_support::output_dir().join("run1_asv_count_tax.tsv").is_file());
+        assert!(!test_support::output_dir().join("asv_count_tax.tsv").exists());
+    }
+
+    #[test]
+    fn output_prefix_cant_leave_the_output_directory() {
+        assert!(set_output_prefix("../run1_").is_err());
+        assert!(set_output_prefix("runs\\run1_").is_err());
+    }
+
+    #[test]
+    fn outputs_point_at_the_planned_artifacts() {
+        let _state = test_support::global_state();
+        let steps = plan(&PipelineOptions { build_tree: true, ..PipelineOptions::default() });
+        let dir = test_support::output_dir();
+        fs::create_dir_all(dir.join("asvs")).unwrap();
+        for made in ["paired-end-demux.qzv", "asvs/stats-dada2.qzv", "pr2_tax_sklearn.qzv", "rooted-tree.qza"] {
+            fs::write(dir.join(made), "").unwrap();
+        }
+
+        let outputs = PipelineOutputs::from_steps(&steps, "pr2");
+        assert_eq!(
+            outputs,
+            PipelineOutputs {
+                merged_tsv: out_path("asv_count_tax.tsv"),
+                feature_table: out_path("asvs/table-dada2.qza"),
+                feature_table_tsv: out_path("asv_table/asv-table.tsv"),
+                rep_seqs: out_path("asvs/rep-seqs-dada2.qza"),
+                rep_seqs_fasta: out_path("asvs/dna-sequences.fasta"),
+                taxonomy: taxonomy_qza_path("pr2"),
+                taxonomy_tsv: taxonomy_tsv_path("pr2"),
+                rank_completeness_tsv: out_path("rank_completeness.tsv"),
+                annotated_fasta: out_path("asvs_annotated.fasta"),
+                visualizations: vec![
+                    out_path("paired-end-demux.qzv"),
+                    out_path("asvs/stats-dada2.qzv"),
+                    out_path("pr2_tax_sklearn.qzv"),
+                ],
+                tree: Some(out_path("rooted-tree.qza")),
+            }
+        );
+        // Every path is one a planned step writes
+        let planned: Vec<&String> = steps.iter().flat_map(|step| &step.outputs).collect();
+        for path in [&outputs.merged_tsv, &outputs.feature_table, &outputs.feature_table_tsv, &outputs.rep_seqs]
+            .into_iter()
+            .chain([&outputs.rep_seqs_fasta, &outputs.taxonomy, &outputs.taxonomy_tsv, &outputs.annotated_fasta])
+        {
+            assert!(planned.contains(&path), "{} isn't planned", path);
+        }
+
+        fs::remove_file(dir.join("rooted-tree.qza")).unwrap();
+        assert_eq!(PipelineOutputs::from_steps(&steps, "pr2").tree, None);
+    }
+
+    #[test]
+    fn the_taxonomy_outputs_are_named_after_the_database() {
+        let _state = test_support::global_state();
+        let options = PipelineOptions {
+            database: Some(Database::Silva),
+            target: "16s".to_string(),
+            ..PipelineOptions::default()
+        };
+        let steps = plan(&options);
+        let taxonomy_tsv = out_path("asv_tax_dir/silva_taxonomy.tsv");
+        assert_eq!(taxonomy_tsv_path(&options.database_label()), taxonomy_tsv);
+
+        assert_eq!(step_named(&steps, "Exporting silva taxonomy").unwrap().outputs, vec![taxonomy_tsv.clone()]);
+        let rename = &step_named(&steps, "Renaming silva taxonomy file").unwrap().command;
+        assert!(matches!(rename, StepAction::Rename { to, .. } if *to == taxonomy_tsv), "{:?}", rename);
+        let completeness = &step_named(&steps, "Summarizing taxonomy rank completeness").unwrap().command;
+        assert!(matches!(completeness, StepAction::RankCompleteness { taxonomy, .. } if *taxonomy == taxonomy_tsv));
+        let annotate = &step_named(&steps, "Annotating representative sequences").unwrap().command;
+        assert!(matches!(annotate, StepAction::AnnotateFasta { taxonomy, .. } if *taxonomy == taxonomy_tsv));
+        assert!(!steps.iter().any(|step| step.name.contains("pr2")), "{:?}", names(&steps));
+        let outputs = PipelineOutputs::from_steps(&steps, &options.database_label());
+        assert_eq!((outputs.taxonomy, outputs.taxonomy_tsv), (out_path("silva_tax_sklearn.qza"), taxonomy_tsv));
+        assert_eq!(step_named(&steps, "Classifying reads").unwrap().outputs, vec![out_path("silva_tax_sklearn.qza")]);
+        let tabulate = step_named(&steps, "Tabulating classified taxonomy").unwrap();
+        assert_eq!(tabulate.outputs, vec![out_path("silva_tax_sklearn.qzv")]);
+        let planned: Vec<&String> = steps.iter().flat_map(|step| &step.outputs).collect();
+        assert!(!planned.iter().any(|output| output.contains("pr2")), "{:?}", planned);
+
+        // The merge reads the same file
+        let merged = merge("Feature ID\tTaxon\tConfidence\nasv1\td__Bacteria\t0.99\n", "silva", false);
+        assert!(merged[1].starts_with("asv1\t5.0\t0.0\td__Bacteria\t"), "{:?}", merged);
+        assert!(!Path::new(&taxonomy_tsv_path("pr2")).exists());
+    }
+
+    /// Writes a one-sample manifest in the output directory whose reads are
+    /// `len_f` and `len_r` bases long.
+    fn manifest_with_read_lengths(len_f: usize, len_r: usize) {
+        let dir = test_support::output_dir();
+        let r1 = test_support::write_fastq_gz(dir, "s1_R1.fastq.gz", &[("read1", &"A".repeat(len_f))]);
+        let r2 = test_support::write_fastq_gz(dir, "s1_R2.fastq.gz", &[("read1", &"C".repeat(len_r))]);
+        let manifest = format!(
+            "sample-id\tforward-absolute-filepath\treverse-absolute-filepath\ns1\t{}\t{}\n",
+            r1.display(),
+            r2.display()
+        );
+        test_support::write_file(dir, "manifest.tsv", &manifest);
+    }
+
+    #[test]
+    fn short_reads_on_a_long_amplicon_are_denoised_single_end() {
+        let _state = test_support::global_state();
+        let region = region_primers("18sv4").unwrap();
+        let auto = PipelineOptions { target: "18sv4".to_string(), ..PipelineOptions::default() };
+        manifest_with_read_lengths(150, 150);
+        assert!(!overlap_sufficient(expected_overlap(&region, 150, 150, 262, 223)));
+        assert!(choose_single_end(&auto, &region, true));
+        // Asking for paired denoising only warns
+        let paired = PipelineOptions { denoise_mode: DenoiseMode::Paired, ..auto.clone() };
+        assert!(!choose_single_end(&paired, &region, true));
+
+        manifest_with_read_lengths(300, 300);
+        assert_eq!(expected_overlap(&region, 300, 300, 262, 223), 262 + 223 - 380);
+        assert!(!choose_single_end(&auto, &region, true));
+        let single = PipelineOptions { denoise_mode: DenoiseMode::Single, ..auto };
+        assert!(choose_single_end(&single, &region, true));
+    }
+
+    #[test]
+    fn untruncated_overlap_is_limited_by_read_length() {
+        let region = region_primers("18sv9").unwrap();
+        let forward = 100 - region.primer_f.len();
+        let reverse = 100 - region.primer_r.len();
+        assert_eq!(expected_overlap(&region, 100, 100, 0, 0), (forward + reverse) as i64 - 130);
+        assert_eq!(expected_overlap(&region, 100, 100, 50, 0), (50 + reverse) as i64 - 130);
+    }
+
+    #[test]
+    fn broken_row_names_the_file_and_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = test_support::write_file(
+            dir.path(),
+            "taxonomy.tsv",
+            "Feature ID\tTaxon\tConfidence\nasv1\tEukaryota;Alveolata\t0.99\nasv2\tEukaryota\nasv3\tEukaryota\t0.8\n",
+        );
+
+        let err = read_feature_rows(&path, false, true, None).unwrap_err();
+        assert_eq!(err.to_string(), "taxonomy.tsv line 3: wrong number of fields (got 2, expected 3)");
+
+        let (headers, rows) = read_feature_rows(&path, false, false, None).unwrap();
+        assert_eq!(headers.iter().collect::<Vec<_>>(), ["Feature ID", "Taxon", "Confidence"]);
+        let mut ids: Vec<&str> = rows.keys().map(String::as_str).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, ["asv1", "asv3"]);
+    }
+
+    #[test]
+    fn json_biom_is_converted_without_conda() {
+        let _state = test_support::global_state();
+        let dir = tempfile::tempdir().unwrap();
+        let json = test_support::write_file(
+            dir.path(),
+            "table.biom",
+            r#"{"rows": [{"id": "asv1"}], "columns": [{"id": "s1"}, {"id": "s2"}],
+                "matrix_type": "sparse", "shape": [1, 2], "data": [[0, 1, 3.0]]}"#,
+        );
+        let tsv = dir.path().join("table.tsv").to_string_lossy().into_owned();
+
+        convert_biom_to_tsv("biom-env", &json, &tsv, false).unwrap();
+        assert_eq!(fs::read_to_string(&tsv).unwrap(), "# Constructed from biom file\n#OTU ID\ts1\ts2\nasv1\t0.0\t3.0\n");
+        assert!(test_support::conda_calls().is_empty());
+
+        // Only a table the built-in converter can't read, or --force-conda-biom, goes to `biom convert`
+        let unreadable = test_support::write_file(dir.path(), "unreadable.biom", "not JSON");
+        convert_biom_to_tsv("biom-env", &unreadable, &tsv, false).unwrap();
+        convert_biom_to_tsv("biom-env", &json, &tsv, true).unwrap();
+        assert_eq!(
+            test_support::conda_calls(),
+            [
+                format!("run -n biom-env biom convert -i {} -o {} --to-tsv", unreadable, tsv),
+                format!("run -n biom-env biom convert -i {} -o {} --to-tsv", json, tsv),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_per_batch_is_forwarded_to_classify_sklearn() {
+        let classify_args = |reads_per_batch| {
+            let options = PipelineOptions::default();
+            let primers = region_primers(&options.target).unwrap();
+            let params = PlanParams {
+                options: &options,
+                cores: 1,
+                primers: &primers,
+                flags: &QiimeFlags::default(),
+                trim: true,
+                denoise_single: false,
+                reads_per_batch,
+            };
+            match &step_named(&plan_pipeline(&params), "Classifying reads").unwrap().command {
+                StepAction::Qiime { args, .. } => args.join(" "),
+                other => panic!("unexpected action {:?}", other),
+            }
+        };
+        assert!(classify_args(Some(500)).contains(" --p-reads-per-batch 500 --i-classifier "));
+        assert!(!classify_args(None).contains("--p-reads-per-batch"));
+    }
+
+    #[test]
+    fn reads_per_batch_is_derived_from_memory_unless_set() {
+        let _state = test_support::global_state();
+        let options = PipelineOptions { reads_per_batch: Some(750), ..Default::default() };
+        assert_eq!(resolve_reads_per_batch(&options, 4), Some(750));
+        assert_eq!(
+            resolve_reads_per_batch(&PipelineOptions::default(), 4),
+            memory::classifier_reads_per_batch(memory::available_memory_bytes(), 4)
+        );
+    }
+
+    /// Writes the exported ASV table (as `biom convert` writes it) and the
+    /// taxonomy the merge step reads, and returns the merged table's lines.
+    fn merge(taxonomy: &str, database: &str, confidence_by_rank: bool) -> Vec<String> {
+        fs::create_dir_all(out_path("asv_table")).unwrap();
+        fs::create_dir_all(out_path("asv_tax_dir")).unwrap();
+        fs::write(
+            out_path("asv_table/asv-table.tsv"),
+            "# Constructed from biom file\n#OTU ID\ts1\ts2\nasv1\t5.0\t0.0\nasv2\t1.0\t2.0\n",
+        )
+        .unwrap();
+        fs::write(taxonomy_tsv_path(database), taxonomy).unwrap();
+        merge_asv_taxonomy(true, database, confidence_by_rank).unwrap();
+        let merged = fs::read_to_string(out_path("asv_count_tax.tsv")).unwrap();
+        let mut lines: Vec<String> = merged.lines().map(String::from).collect();
+        lines[1..].sort_unstable();
+        lines
+    }
+
+    #[test]
+    fn merge_splits_a_pr2_taxonomy_into_pr2_ranks() {
+        let _state = test_support::global_state();
+        let lines = merge(
+            "Feature ID\tTaxon\tConfidence\nasv1\tEukaryota;TSAR;Alveolata\t0.99\nasv2\tEukaryota\t0.8\n",
+            "pr2",
+            false,
+        );
+        assert_eq!(
+            lines,
+            [
+                "Feature.ID\ts1\ts2\tpr2_Taxon\tpr2_Confidence\tpr2_Domain\tpr2_Supergroup\tpr2_Division",
+                "asv1\t5.0\t0.0\tEukaryota;TSAR;Alveolata\t0.99\tEukaryota\tTSAR\tAlveolata",
+                "asv2\t1.0\t2.0\tEukaryota\t0.8\tEukaryota\t\t",
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_names_silva_ranks_from_their_prefixes() {
+        let _state = test_support::global_state();
+        let lines = merge(
+            "Feature ID\tTaxon\tConfidence\n\
+             asv1\td__Bacteria; p__Firmicutes; c__Bacilli; o__Bacillales\t0.95\n\
+             asv2\td__Bacteria; p__Proteobacteria\t0.9\n",
+            "silva",
+            false,
+        );
+        assert_eq!(
+            lines,
+            [
+                "Feature.ID\ts1\ts2\tsilva_Taxon\tsilva_Confidence\tsilva_Domain\tsilva_Phylum\tsilva_Class\tsilva_Order",
+                "asv1\t5.0\t0.0\td__Bacteria; p__Firmicutes; c__Bacilli; o__Bacillales\t0.95\t\
+                 d__Bacteria\tp__Firmicutes\tc__Bacilli\to__Bacillales",
+                "asv2\t1.0\t2.0\td__Bacteria; p__Proteobacteria\t0.9\td__Bacteria\tp__Proteobacteria\t\t",
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_keeps_a_taxonomy_that_already_has_rank_columns() {
+        let _state = test_support::global_state();
+        let lines = merge("Feature ID\tKingdom\tPhylum\nasv1\tFungi\tAscomycota\n", "unite", false);
+        assert_eq!(
+            lines,
+            ["Feature.ID\ts1\ts2\tunite_Kingdom\tunite_Phylum", "asv1\t5.0\t0.0\tFungi\tAscomycota", "asv2\t1.0\t2.0\t\t"]
+        );
+    }
+
+    #[test]
+    fn biom_preamble_is_skipped_but_counted_in_line_numbers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = test_support::write_file(
+            dir.path(),
+            "asv-table.tsv",
+            "# Constructed from biom file\n#OTU ID\ts1\ts2\nasv1\t5.0\t0.0\nasv2\t7.0\n",
+        );
+        let err = read_feature_rows(&path, true, true, None).unwrap_err();
+        assert_eq!(err.to_string(), "asv-table.tsv line 4: wrong number of fields (got 2, expected 3)");
+
+        let (headers, rows) = read_feature_rows(&path, true, false, None).unwrap();
+        assert_eq!(headers.iter().collect::<Vec<_>>(), ["#OTU ID", "s1", "s2"]);
+        assert_eq!(rows.keys().collect::<Vec<_>>(), ["asv1"]);
+    }
+
+    #[test]
+    fn the_merge_bar_advances_once_per_row_read_and_merged() {
+        let _state = test_support::global_state();
+        fs::create_dir_all(out_path("asv_table")).unwrap();
+        fs::create_dir_all(out_path("asv_tax_dir")).unwrap();
+        let mut asvs = String::from("# Constructed from biom file\n#OTU ID\ts1\n");
+        let mut taxa = String::from("Feature ID\tTaxon\tConfidence\n");
+        for i in 0..40 {
+            asvs.push_str(&format!("asv{}\t{}.0\n", i, i));
+            taxa.push_str(&format!("asv{}\tEukaryota\t0.9\n", i));
+        }
+        fs::write(out_path("asv_table/asv-table.tsv"), asvs).unwrap();
+        fs::write(taxonomy_tsv_path("pr2"), taxa).unwrap();
+
+        let spinner = ProgressBar::hidden();
+        progress::set_step_spinner(Some(spinner.clone()));
+        let result = merge_asv_taxonomy(true, "pr2", false);
+        progress::set_step_spinner(None);
+        result.unwrap();
+
+        // 40 rows read from each table, then 40 merged rows written
+        assert_eq!(spinner.position(), 3 * 40);
+        // The total is estimated from line counts: the ASV table twice, plus the taxonomy
+        assert_eq!(spinner.length(), Some(2 * 42 + 41));
+        assert_eq!(fs::read_to_string(out_path("asv_count_tax.tsv")).unwrap().lines().count(), 41);
+    }
+
+    #[test]
+    fn each_adapter_mode_gives_its_cutadapt_arguments() {
+        let primers = region_primers("18sv9").unwrap();
+        let args = |mode, single_end| adapter_args(&primers, mode, single_end).join(" ");
+        assert_eq!(
+            args(AdapterMode::Linked, false),
+            "--p-adapter-f ^TTGTACACACCGCCC...GTAGGTGAACCTGCRGAAGG --p-adapter-r ^CCTTCYGCAGGTTCACCTAC...GGGCGGTGTGTACAA"
+        );
+        assert_eq!(args(AdapterMode::Regular, false), "--p-front-f TTGTACACACCGCCC --p-front-r CCTTCYGCAGGTTCACCTAC");
+        assert_eq!(args(AdapterMode::Anchored, false), "--p-front-f ^TTGTACACACCGCCC --p-front-r ^CCTTCYGCAGGTTCACCTAC");
+        assert_eq!(args(AdapterMode::Linked, true), "--p-adapter ^TTGTACACACCGCCC...GTAGGTGAACCTGCRGAAGG");
+        assert_eq!(args(AdapterMode::Regular, true), "--p-front TTGTACACACCGCCC");
+        assert_eq!(args(AdapterMode::Anchored, true), "--p-front ^TTGTACACACCGCCC");
+
+        // Linked stays the default
+        let linked = args(AdapterMode::Linked, false);
+        let steps = plan(&PipelineOptions::default());
+        let trim = &step_named(&steps, "Trimming reads with Cutadapt").unwrap().command;
+        assert!(matches!(trim, StepAction::Qiime { args: trim_args, .. } if trim_args.join(" ").contains(&linked)));
+    }
+
+    #[test]
+    fn the_trim_command_uses_the_given_cutadapt_error_rate_and_overlap() {
+        let _state = test_support::global_state();
+        let trim_args = |options: &PipelineOptions| planned_args(&plan(options), "Trimming reads with Cutadapt");
+        let defaults = trim_args(&PipelineOptions::default());
+        assert!(defaults.contains(" --p-error-rate 0.1 --p-overlap 3 "), "{}", defaults);
+        let options = PipelineOptions { cutadapt_error_rate: 0.25, cutadapt_overlap: 10, ..PipelineOptions::default() };
+        let given = trim_args(&options);
+        assert!(given.contains(" --p-error-rate 0.25 --p-overlap 10 "), "{}", given);
+    }
+
+    /// Gzipped `contents`, as the database files are served.
+    fn gzipped(contents: &str) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        io::Write::write_all(&mut encoder, contents.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn only_the_selected_database_component_is_downloaded() {
+        let _state = test_support::global_state();
+        let server = test_support::MockServer::start(|head| {
+            let body = match test_support::request_path(head) {
+                "/pr2.fasta.gz" => gzipped(">a\nACGT\n"),
+                _ => gzipped("a\tEukaryota\n"),
+            };
+            test_support::http_response("200 OK", &[("Content-Length", &body.len().to_string())], &body)
+        });
+        let urls = (format!("{}/pr2.fasta.gz", server.url), format!("{}/pr2_tax.tsv.gz", server.url));
+        let urls = (urls.0.as_str(), urls.1.as_str());
+        let database = Database::Pr2;
+
+        download_database_from(database, urls, false, true, DbComponent::Taxonomy).unwrap();
+        assert_eq!(server.request_lines(), ["GET /pr2_tax.tsv.gz HTTP/1.1"]);
+        assert_eq!(fs::read_to_string(database.taxonomy_path()).unwrap(), "a\tEukaryota\n");
+        assert!(!Path::new(&database.fasta_path()).exists());
+
+        // A forced refresh of the FASTA leaves the taxonomy alone
+        download_database_from(database, urls, true, true, DbComponent::Fasta).unwrap();
+        assert_eq!(server.request_lines()[1..], ["GET /pr2.fasta.gz HTTP/1.1"]);
+        assert_eq!(fs::read_to_string(database.fasta_path()).unwrap(), ">a\nACGT\n");
+    }
+
+    #[test]
+    fn a_download_that_doesnt_match_its_known_digest_is_rejected_and_removed() {
+        let _state = test_support::global_state();
+        crate::logger::init_log(false);
+        let url = "https://example.org/pr2_version_5.0.0_SSU_mothur.fasta.gz";
+        let known = [(url, "64969475B404C5EE2D66F229936B4F579D2C23EEC8DDAFCF47C92A70CAA7ED4F")];
+        let path = out_path("pr2.fasta.gz");
+
+        fs::write(&path, ">AY505519.1\nACGT\n").unwrap();
+        check_digest(url, &path, &known).unwrap();
+        assert!(Path::new(&path).exists());
+
+        // One changed base is enough to reject the file
+        fs::write(&path, ">AY505519.1\nACGA\n").unwrap();
+        let err = check_digest(url, &path, &known).unwrap_err().to_string();
+        assert!(err.starts_with(&format!("SHA-256 of {} is ", url)), "{}", err);
+        assert!(err.contains(", expected 64969475B404") && err.contains("--skip-checksum"), "{}", err);
+        assert!(!Path::new(&path).exists());
+
+        fs::write(&path, ">AY505519.1\nACGA\n").unwrap();
+        crate::SKIP_CHECKSUM.store(true, std::sync::atomic::Ordering::Relaxed);
+        let skipped = check_digest(url, &path, &known);
+        crate::SKIP_CHECKSUM.store(false, std::sync::atomic::Ordering::Relaxed);
+        skipped.unwrap();
+        assert!(Path::new(&path).exists());
+        // Files without a known digest are only logged
+        check_digest("https://example.org/other.fasta.gz", &path, &known).unwrap();
+
+        let log = fs::read_to_string(out_path("windchime.log")).unwrap();
+        let tampered = crate::audit::sha256_file(&path).unwrap();
+        assert!(log.contains(&format!("Downloaded {} to {}, SHA-256 64969475b404c5ee", url, path)), "{}", log);
+        assert_eq!(log.matches(&format!(", SHA-256 {}", tampered)).count(), 3, "{}", log);
+        assert!(log.contains(&format!("Not comparing {} with its known SHA-256", path)));
+    }
+
+    #[test]
+    fn the_first_download_of_a_url_pins_its_digest_for_later_downloads() {
+        let _state = test_support::global_state();
+        crate::logger::init_log(false);
+        let path = out_path("pr2.fasta.gz");
+        let pins = shared_path(PINNED_SHA256_FILE);
+        assert!(!KNOWN_SHA256.iter().any(|(url, _)| *url == PR2_FASTA_URL));
+
+        fs::write(&path, ">AY505519.1\nACGT\n").unwrap();
+        verify_download(PR2_FASTA_URL, &path).unwrap();
+        let pinned = format!("{}\t{}\n", PR2_FASTA_URL, crate::audit::sha256_file(&path).unwrap());
+        assert_eq!(fs::read_to_string(&pins).unwrap(), pinned);
+        // The same file again matches its pin
+        verify_download(PR2_FASTA_URL, &path).unwrap();
+        verify_download(PR2_TAXONOMY_URL, &path).unwrap();
+        assert_eq!(fs::read_to_string(&pins).unwrap().lines().count(), 2);
+
+        fs::write(&path, ">AY505519.1\nACGA\n").unwrap();
+        let err = verify_download(PR2_FASTA_URL, &path).unwrap_err().to_string();
+        assert!(err.contains("the download is corrupt or not the published file"), "{}", err);
+        assert!(!Path::new(&path).exists());
+        assert!(fs::read_to_string(&pins).unwrap().starts_with(&pinned));
+
+        // --skip-checksum accepts a republished file and pins it instead
+        fs::write(&path, ">AY505519.1\nACGA\n").unwrap();
+        crate::SKIP_CHECKSUM.store(true, std::sync::atomic::Ordering::Relaxed);
+        let skipped = verify_download(PR2_FASTA_URL, &path);
+        crate::SKIP_CHECKSUM.store(false, std::sync::atomic::Ordering::Relaxed);
+        skipped.unwrap();
+        verify_download(PR2_FASTA_URL, &path).unwrap();
+        let republished = crate::audit::sha256_file(&path).unwrap();
+        assert!(fs::read_to_string(&pins).unwrap().starts_with(&format!("{}\t{}\n", PR2_FASTA_URL, republished)));
+    }
+
+    /// The `Range` header of an HTTP request head, if it has one.
+    fn range_header(head: &str) -> Option<&str> {
+        head.lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("range"))
+            .map(|(_, value)| value.trim())
+    }
+
+    #[test]
+    fn a_dropped_download_is_retried_from_the_bytes_already_written() {
+        let _state = test_support::global_state();
+        crate::logger::init_log(false);
+        let server = test_support::MockServer::start(|head| match range_header(head) {
+            // The connection drops after 4 of the 10 announced bytes
+            None => test_support::http_response("200 OK", &[("Content-Length", "10")], b"ACGT"),
+            Some(range) => {
+                assert_eq!(range, "bytes=4-");
+                let headers = [("Content-Length", "6"), ("Content-Range", "bytes 4-9/10")];
+                test_support::http_response("206 Partial Content", &headers, b"TTGGCC")
+            }
+        });
+        let url = format!("{}/pr2.fasta.gz", server.url);
+        let path = out_path("pr2.fasta.gz");
+
+        download_file(&url, &path, true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "ACGTTTGGCC");
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests.iter().map(|head| range_header(head)).collect::<Vec<_>>(), [None, Some("bytes=4-")]);
+        let log = fs::read_to_string(out_path("windchime.log")).unwrap();
+        assert!(log.contains(&format!("Warning: download of {} failed (attempt 1/4): connection lost after 4 bytes", url)), "{}", log);
+        assert!(log.contains(&format!("Download of {}: resuming at byte 4", url)));
+        assert!(log.contains(&format!("Download of {} succeeded on attempt 2/4", url)));
+    }
+
+    #[test]
+    fn downloads_give_up_at_once_on_a_missing_file_and_after_the_last_retry_otherwise() {
+        let _state = test_support::global_state();
+        let server = test_support::MockServer::start(|head| match test_support::request_path(head) {
+            "/missing.gz" => test_support::http_response("404 Not Found", &[("Content-Length", "0")], b""),
+            _ => test_support::http_response("503 Service Unavailable", &[("Content-Length", "0")], b""),
+        });
+        let path = out_path("download.gz");
+        crate::DOWNLOAD_RETRIES.store(1, std::sync::atomic::Ordering::Relaxed);
+        let missing = download_file(&format!("{}/missing.gz", server.url), &path, true);
+        let busy = download_file(&format!("{}/busy.gz", server.url), &path, true);
+        crate::DOWNLOAD_RETRIES.store(3, std::sync::atomic::Ordering::Relaxed);
+
+        assert_eq!(
+            missing.unwrap_err().to_string(),
+            format!("Failed to download {}/missing.gz after 1 attempt(s): HTTP 404 Not Found", server.url)
+        );
+        assert_eq!(
+            busy.unwrap_err().to_string(),
+            format!("Failed to download {}/busy.gz after 2 attempt(s): HTTP 503 Service Unavailable", server.url)
+        );
+        assert_eq!(server.request_lines(), ["GET /missing.gz HTTP/1.1", "GET /busy.gz HTTP/1.1", "GET /busy.gz HTTP/1.1"]);
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn retry_delays_double_up_to_a_minute_with_jitter() {
+        for (attempt, base) in [(1, 1), (2, 2), (3, 4), (6, 32), (7, 60), (20, 60)] {
+            let delay = retry_delay(attempt);
+            let base = Duration::from_secs(base);
+            assert!(delay >= base && delay <= base.mul_f64(1.5), "attempt {}: {:?}", attempt, delay);
+        }
+    }
+
+    #[test]
+    fn a_download_without_a_content_length_is_read_to_the_end() {
+        let _state = test_support::global_state();
+        let body = gzipped(">AY505519.1\nACGT\n");
+        let served = body.clone();
+        let server = test_support::MockServer::start(move |_| test_support::http_response("200 OK", &[], &served));
+        let gz = out_path("pr2.fasta.gz");
+
+        download_file(&format!("{}/pr2.fasta.gz", server.url), &gz, true).unwrap();
+        assert_eq!(fs::read(&gz).unwrap(), body);
+        assert_eq!(server.request_lines().len(), 1);
+        assert_eq!(download_size_line(None, 0), "    size not reported by the server");
+        assert_eq!(download_size_line(Some(2048), 0), "    2.00 KiB to fetch");
+        assert_eq!(download_size_line(Some(2048), 1024), "    1.00 KiB of 2.00 KiB left to fetch");
+    }
+
+    #[test]
+    fn a_partial_download_resumes_with_a_range_request_when_the_server_allows() {
+        let _state = test_support::global_state();
+        crate::logger::init_log(false);
+        let server = test_support::MockServer::start(|head| match (test_support::request_path(head), range_header(head)) {
+            ("/ranged.gz", Some("bytes=4-")) => {
+                let headers = [("Content-Length", "6"), ("Content-Range", "bytes 4-9/10")];
+                test_support::http_response("206 Partial Content", &headers, b"TTGGCC")
+            }
+            _ => test_support::http_response("200 OK", &[("Content-Length", "10")], b"ACGTTTGGCC"),
+        });
+        let client = reqwest::blocking::Client::new();
+        let path = out_path("download.gz");
+
+        fs::write(&path, "ACGT").unwrap();
+        let resumed = download_attempt(&client, &format!("{}/ranged.gz", server.url), &path, true);
+        assert!(resumed.is_ok());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "ACGTTTGGCC");
+
+        // A server that ignores the range sends the whole file, which replaces the partial one
+        fs::write(&path, "ACGT").unwrap();
+        let restarted = download_attempt(&client, &format!("{}/whole.gz", server.url), &path, true);
+        assert!(restarted.is_ok());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "ACGTTTGGCC");
+
+        let ranges: Vec<Option<String>> =
+            server.requests().iter().map(|head| range_header(head).map(str::to_string)).collect();
+        assert_eq!(ranges, [Some("bytes=4-".to_string()), Some("bytes=4-".to_string())]);
+        let log = fs::read_to_string(out_path("windchime.log")).unwrap();
+        assert!(log.contains(&format!("Download of {}/ranged.gz: resuming at byte 4", server.url)), "{}", log);
+        assert!(log.contains(&format!("Download of {}/whole.gz: server ignored the range; starting over", server.url)));
+    }
+
+    #[test]
+    fn silva_artifacts_are_used_as_downloaded_and_a_classifier_is_trained_on_them() {
+        let _state = test_support::global_state();
+        let server = test_support::MockServer::start(|head| {
+            let body = format!("artifact {}", test_support::request_path(head)).into_bytes();
+            test_support::http_response("200 OK", &[("Content-Length", &body.len().to_string())], &body)
+        });
+        let urls = (format!("{}/silva-138-99-seqs.qza", server.url), format!("{}/silva-138-99-tax.qza", server.url));
+        let silva = Database::Silva;
+
+        download_database_from(silva, (&urls.0, &urls.1), false, true, DbComponent::All).unwrap();
+        assert_eq!(fs::read_to_string(silva.sequences_qza()).unwrap(), "artifact /silva-138-99-seqs.qza");
+        assert_eq!(fs::read_to_string(silva.taxonomy_qza()).unwrap(), "artifact /silva-138-99-tax.qza");
+        assert!(!Path::new(&format!("{}.gz", silva.sequences_qza())).exists());
+
+        let options = PipelineOptions { target: "16s".to_string(), database: Some(silva), ..PipelineOptions::default() };
+        assert!(options.use_pretrained_classifier && options.trains_classifier());
+        let steps = plan(&options);
+        let names = names(&steps);
+        for absent in ["Importing reference sequences", "Importing reference taxonomy", "Downloading pre-trained classifier"] {
+            assert!(!names.contains(&absent), "{} was planned", absent);
+        }
+        assert!(planned_args(&steps, "Extracting reference reads")
+            .contains(&format!("--i-sequences {} ", silva.sequences_qza())));
+        assert!(planned_args(&steps, "Fitting reference classifier")
+            .contains(&format!("--i-reference-taxonomy {} ", silva.taxonomy_qza())));
+        assert!(!PipelineOptions::default().trains_classifier());
+    }
+
+    #[test]
+    fn unite_files_must_be_put_in_place_by_hand() {
+        let _state = test_support::global_state();
+        let unite = Database::Unite;
+        let error = download_databases(unite, false, true, DbComponent::All).unwrap_err().to_string();
+        assert!(error.starts_with("UNITE can't be downloaded automatically: "), "{}", error);
+        assert!(error.contains(UNITE_DOWNLOAD_PAGE) && error.contains("--unite-archive <file or URL>"), "{}", error);
+        assert!(error.ends_with(&format!("(missing: {}, {}).", unite.fasta_path(), unite.taxonomy_path())), "{}", error);
+
+        fs::write(unite.fasta_path(), ">a\nACGT\n").unwrap();
+        assert!(download_databases(unite, false, true, DbComponent::Taxonomy).is_err());
+        download_databases(unite, false, true, DbComponent::Fasta).unwrap();
+        fs::write(unite.taxonomy_path(), "a\tk__Fungi\n").unwrap();
+        download_databases(unite, true, true, DbComponent::All).unwrap();
+
+        // There is no pre-trained UNITE classifier: it's trained on the imported files
+        let options = PipelineOptions { target: "its".to_string(), ..PipelineOptions::default() };
+        assert!(options.trains_classifier());
+        let steps = plan(&options);
+        assert!(step_named(&steps, "Downloading pre-trained classifier").is_none());
+        let import = planned_args(&steps, "Importing reference sequences");
+        assert!(import.contains(&format!("--input-path {} ", unite.fasta_path())), "{}", import);
+        assert!(step_named(&steps, "Fitting reference classifier").is_some());
+    }
+
+    /// A gzipped tarball of `files`, like a UNITE QIIME release.
+    fn tarball(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast()));
+        for (name, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, contents.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn unite_archive_installs_the_99_percent_release_files_from_a_file_or_url() {
+        let _state = test_support::global_state();
+        let unite = Database::Unite;
+        let release = tarball(&[
+            ("sh_qiime_release_04.04.2024/sh_refs_qiime_ver10_97_04.04.2024.fasta", ">SH97\nACGT\n"),
+            ("sh_qiime_release_04.04.2024/sh_taxonomy_qiime_ver10_97_04.04.2024.txt", "SH97\tk__Fungi\n"),
+            ("sh_qiime_release_04.04.2024/sh_refs_qiime_ver10_99_04.04.2024.fasta", ">SH99\nACGT\n"),
+            ("sh_qiime_release_04.04.2024/sh_taxonomy_qiime_ver10_99_04.04.2024.txt", "SH99\tk__Fungi;p__Ascomycota\n"),
+            ("sh_qiime_release_04.04.2024/developer/sh_refs_qiime_ver10_99_04.04.2024_dev.fasta", ">SH99\nTTACGTAA\n"),
+        ]);
+        let archive = out_path("sh_qiime_release_04.04.2024.tgz");
+        fs::write(&archive, &release).unwrap();
+
+        install_unite_archive(&archive, false, true, DbComponent::All).unwrap();
+        assert_eq!(fs::read_to_string(unite.fasta_path()).unwrap(), ">SH99\nACGT\n");
+        assert_eq!(fs::read_to_string(unite.taxonomy_path()).unwrap(), "SH99\tk__Fungi;p__Ascomycota\n");
+        download_databases(unite, false, true, DbComponent::All).unwrap();
+
+        // Installed files are kept unless forced; --only refreshes one of them
+        fs::write(unite.taxonomy_path(), "SH99\tk__Fungi\n").unwrap();
+        install_unite_archive(&archive, false, true, DbComponent::All).unwrap();
+        assert_eq!(fs::read_to_string(unite.taxonomy_path()).unwrap(), "SH99\tk__Fungi\n");
+        fs::write(unite.fasta_path(), "stale").unwrap();
+        install_unite_archive(&archive, true, false, DbComponent::Taxonomy).unwrap();
+        assert_eq!(fs::read_to_string(unite.fasta_path()).unwrap(), "stale");
+        assert_eq!(fs::read_to_string(unite.taxonomy_path()).unwrap(), "SH99\tk__Fungi;p__Ascomycota\n");
+
+        fs::remove_dir_all(unite.dir()).unwrap();
+        let server = test_support::MockServer::start(move |_| {
+            test_support::http_response("200 OK", &[("Content-Length", &release.len().to_string())], &release)
+        });
+        install_unite_archive(&format!("{}/unite.tgz", server.url), false, true, DbComponent::All).unwrap();
+        assert_eq!(server.request_lines(), ["GET /unite.tgz HTTP/1.1"]);
+        assert!(Path::new(&unite.path("unite_release.tgz")).is_file());
+        assert_eq!(fs::read_to_string(unite.fasta_path()).unwrap(), ">SH99\nACGT\n");
+
+        let missing = install_unite_archive(&out_path("missing.tgz"), true, true, DbComponent::All).unwrap_err();
+        assert!(missing.to_string().starts_with("Cannot open the UNITE release "), "{}", missing);
+    }
+
+    #[test]
+    fn unite_release_files_skip_the_developer_folder_and_need_an_unambiguous_pair() {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let release = names(&["r/developer/refs_99.fasta", "r/refs.fasta", "r/taxonomy.txt", "r/README.txt"]);
+        assert_eq!(unite_release_files("r.tgz", &release).unwrap(), ("r/refs.fasta".to_string(), "r/taxonomy.txt".to_string()));
+
+        let dynamic = names(&["r/refs_dynamic.fasta", "r/refs_97_.fasta", "r/taxonomy.txt"]);
+        let error = unite_release_files("r.tgz", &dynamic).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "The UNITE release 'r.tgz' has several reference sequences (.fasta) files and none is unambiguously the 99% one: \
+             r/refs_dynamic.fasta, r/refs_97_.fasta."
+        );
+        let error = unite_release_files("r.tgz", &names(&["r/refs.fasta"])).unwrap_err().to_string();
+        assert_eq!(error, "The UNITE release 'r.tgz' has no taxonomy (*taxonomy*.txt).");
+    }
+
+    #[test]
+    fn classify_only_runs_the_taxonomy_branch() {
+        let options = PipelineOptions { classifier: Some("silva-classifier.qza".to_string()), ..PipelineOptions::default() };
+        let primers = region_primers(&options.target).unwrap();
+        let params = PlanParams {
+            options: &options,
+            cores: 1,
+            primers: &primers,
+            flags: &QiimeFlags::default(),
+            trim: false,
+            denoise_single: false,
+            reads_per_batch: None,
+        };
+        let steps = classify_steps(&params, "rep-seqs.qza", Some("table.qza"));
+        assert_eq!(
+            names(&steps),
+            [
+                "Checking representative sequences artifact",
+                "Checking feature table artifact",
+                "Exporting ASV table",
+                "Converting BIOM to TSV",
+                "Checking external classifier artifact",
+                "Classifying reads",
+                "Tabulating classified taxonomy",
+                "Exporting silva taxonomy",
+                "Renaming silva taxonomy file",
+                "Summarizing taxonomy rank completeness",
+                "Merging ASV and taxonomy tables",
+            ]
+        );
+        let classify = &step_named(&steps, "Classifying reads").unwrap().command;
+        assert!(matches!(classify, StepAction::Qiime { args, .. } if args.contains(&"rep-seqs.qza".to_string())));
+
+        // Training a classifier adds only reference steps; nothing touches reads, primers or DADA2
+        let options = PipelineOptions::default();
+        let steps = classify_steps(&PlanParams { options: &options, ..params }, "rep-seqs.qza", None);
+        assert!(step_named(&steps, "Merging ASV and taxonomy tables").is_none());
+        for step in &steps {
+            if let StepAction::Qiime { args, .. } = &step.command {
+                assert!(["feature-classifier", "metadata", "tools"].contains(&args[0].as_str()), "{}: {:?}", step.name, args);
+                assert!(!args.iter().any(|arg| arg.contains("demux") || arg.contains("dada2")), "{}: {:?}", step.name, args);
+            }
+        }
+    }
+
+    #[test]
+    fn rep_seqs_are_annotated_with_summed_counts_and_taxonomy() {
+        let _state = test_support::global_state();
+        merge("Feature ID\tTaxon\tConfidence\nasv1\tEukaryota; TSAR\t0.99\n", "pr2", false);
+        let fasta = test_support::write_file(test_support::output_dir(), "dna-sequences.fasta", ">asv1\nACGT\n>asv2\nGGCC\n");
+        let annotated = out_path("asvs_annotated.fasta");
+
+        annotate_rep_seqs(&fasta, &taxonomy_tsv_path("pr2"), &annotated, true).unwrap();
+        assert_eq!(
+            fs::read_to_string(&annotated).unwrap(),
+            ">asv1 total=5 taxonomy=Eukaryota;TSAR\nACGT\n>asv2 total=3 taxonomy=Unassigned\nGGCC\n"
+        );
+    }
+
+    #[test]
+    fn both_demux_summaries_use_summarize_n() {
+        let steps = plan(&PipelineOptions { summarize_n: 5000, ..PipelineOptions::default() });
+        let summaries: Vec<String> = steps
+            .iter()
+            .filter_map(|step| match &step.command {
+                StepAction::Qiime { args, .. } if args[..2] == ["demux", "summarize"] => Some(args.join(" ")),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(summaries.len(), 2, "{:?}", names(&steps));
+        for summary in &summaries {
+            assert!(summary.contains(" --p-n 5000 "), "{}", summary);
+        }
+        assert!(summaries[1].contains("paired-end-demux-trimmed.qza"));
+    }
+
+    #[test]
+    fn metadata_is_passed_to_the_table_summary_only_when_given() {
+        let _state = test_support::global_state();
+        let summarize = |metadata: Option<&str>| {
+            let options = PipelineOptions { metadata: metadata.map(String::from), ..PipelineOptions::default() };
+            planned_args(&plan(&options), "Summarizing feature table")
+        };
+        let table = out_path("asvs/table-dada2.qza");
+        let summary = out_path("asvs/table-dada2.qzv");
+        let without = format!("feature-table summarize --i-table {} --o-visualization {}", table, summary);
+        assert_eq!(summarize(Some("samples.tsv")), format!("{} --m-sample-metadata-file samples.tsv", without));
+        assert_eq!(summarize(None), without);
+    }
+
+    #[test]
+    fn a_missing_metadata_file_stops_the_run_before_any_step() {
+        let _state = test_support::global_state();
+        let metadata = out_path("samples.tsv");
+        let options = PipelineOptions { metadata: Some(metadata.clone()), ..PipelineOptions::default() };
+
+        let error = run_pipeline(&options).unwrap_err().to_string();
+        assert!(error.starts_with(&format!("--metadata '{}' can't be read: ", metadata)), "{}", error);
+        assert!(!test_support::conda_calls().iter().any(|call| call.contains("qiime tools import")));
+
+        fs::create_dir_all(&metadata).unwrap();
+        assert_eq!(check_metadata_file(&options).unwrap_err().to_string(), format!("--metadata '{}' is not a file", metadata));
+        fs::remove_dir(&metadata).unwrap();
+        fs::write(&metadata, "sample-id\tsite\ns1\tA\n").unwrap();
+        check_metadata_file(&options).unwrap();
+    }
+
+    /// Plans a default run and writes a small file at each of its outputs.
+    fn write_planned_outputs() -> Vec<PipelineStep> {
+        let steps = plan(&PipelineOptions::default());
+        for output in steps.iter().flat_map(|step| &step.outputs) {
+            fs::create_dir_all(Path::new(output).parent().unwrap()).unwrap();
+            fs::write(output, "output").unwrap();
+        }
+        steps
+    }
+
+    #[test]
+    fn final_tsv_only_cleanup_keeps_only_the_final_tables() {
+        let _state = test_support::global_state();
+        let steps = write_planned_outputs();
+
+        let reclaimed = cleanup_run_outputs(&steps, CleanupProfile::FinalTsvOnly).unwrap();
+
+        for kept in ["asv_count_tax.tsv", "rank_completeness.tsv", "asvs_annotated.fasta"] {
+            assert!(Path::new(&out_path(kept)).exists(), "{} should be kept", kept);
+        }
+        for removed in ["paired-end-demux.qza", "paired-end-demux.qzv", "asvs/table-dada2.qza", "pr2_tax_sklearn.qza"] {
+            assert!(!Path::new(&out_path(removed)).exists(), "{} should be removed", removed);
+        }
+        // Directories emptied by the cleanup go too; the shared reference database stays
+        assert!(!Path::new(&out_path("asv_table")).exists());
+        assert!(Path::new(&shared_path("db/pr2/pr2.qza")).exists());
+        let outputs: std::collections::BTreeSet<&String> = steps.iter().flat_map(|step| &step.outputs).collect();
+        let removed = outputs.iter().filter(|output| !Path::new(output).exists()).count();
+        assert_eq!(reclaimed, removed as u64 * "output".len() as u64);
+    }
+
+    #[test]
+    fn qzv_only_cleanup_keeps_the_visualizations() {
+        let _state = test_support::global_state();
+        let steps = write_planned_outputs();
+
+        cleanup_run_outputs(&steps, CleanupProfile::QzvOnly).unwrap();
+
+        assert!(Path::new(&out_path("asvs/stats-dada2.qzv")).exists());
+        assert!(Path::new(&out_path("asv_count_tax.tsv")).exists());
+        assert!(!Path::new(&out_path("asvs/stats-dada2.qza")).exists());
+        assert!(!Path::new(&out_path("asvs/denoising-stats/stats.tsv")).exists());
+    }
+
+    /// The arguments of the planned QIIME step `name`, joined with spaces.
+    fn planned_args(steps: &[PipelineStep], name: &str) -> String {
+        match &step_named(steps, name).unwrap().command {
+            StepAction::Qiime { args, .. } => args.join(" "),
+            other => panic!("unexpected action {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unset_trunc_lengths_resolve_to_the_region_defaults() {
+        let _state = test_support::global_state();
+        let v9 = PipelineOptions { target: "18sv9".to_string(), ..PipelineOptions::default() };
+        assert_eq!(v9.trunc_lens(&region_primers("18sv9").unwrap()), (123, 91));
+        let denoise = planned_args(&plan(&v9), "Running DADA2 denoise-paired");
+        assert!(denoise.contains("--p-trunc-len-f 123 --p-trunc-len-r 91"), "{}", denoise);
+
+        let v4 = PipelineOptions { target: "18sv4".to_string(), ..PipelineOptions::default() };
+        assert!(planned_args(&plan(&v4), "Running DADA2 denoise-paired").contains("--p-trunc-len-f 262 --p-trunc-len-r 223"));
+
+        // An explicit length overrides only its own direction
+        let overridden = PipelineOptions { trunc_len_f: Some(100), ..v9 };
+        assert_eq!(overridden.trunc_lens(&region_primers("18sv9").unwrap()), (100, 91));
+    }
+
+    #[test]
+    fn its_uses_the_its_primers_and_no_truncation() {
+        let _state = test_support::global_state();
+        let steps = plan(&PipelineOptions { target: "its".to_string(), ..PipelineOptions::default() });
+        let trim = planned_args(&steps, "Trimming reads with Cutadapt");
+        assert!(trim.contains("CTTGGTCATTTAGAGGAAGTAA") && trim.contains("GCTGCGTTCTTCATCGATGC"), "{}", trim);
+        let denoise = planned_args(&steps, "Running DADA2 denoise-paired");
+        assert!(denoise.contains("--p-trunc-len-f 0 --p-trunc-len-r 0 "), "{}", denoise);
+        let extract = planned_args(&steps, "Extracting reference reads");
+        assert!(extract.contains("--p-f-primer CTTGGTCATTTAGAGGAAGTAA --p-r-primer GCTGCGTTCTTCATCGATGC "), "{}", extract);
+        let merge = &step_named(&steps, "Merging ASV and taxonomy tables").unwrap().command;
+        assert!(matches!(merge, StepAction::MergeAsvTaxonomy { database, .. } if database == "unite"), "{:?}", merge);
+    }
+
+    #[test]
+    fn trunc_length_zero_is_passed_through_as_no_truncation() {
+        let _state = test_support::global_state();
+        let options = PipelineOptions { trunc_len_f: Some(0), trunc_len_r: Some(0), ..PipelineOptions::default() };
+        let denoise = planned_args(&plan(&options), "Running DADA2 denoise-paired");
+        assert!(denoise.contains("--p-trunc-len-f 0 --p-trunc-len-r 0"), "{}", denoise);
+
+        let primers = region_primers(&options.target).unwrap();
+        let single = plan_pipeline(&PlanParams {
+            options: &options,
+            cores: 1,
+            primers: &primers,
+            flags: &QiimeFlags::default(),
+            trim: true,
+            denoise_single: true,
+            reads_per_batch: None,
+        });
+        let denoise = planned_args(&single, "Running DADA2 denoise-single (forward reads)");
+        assert!(denoise.contains("--p-trunc-len 0"), "{}", denoise);
+
+        // 0 keeps the reads at full length when estimating the merge overlap
+        let region = region_primers("18sv4").unwrap();
+        assert_eq!(expected_overlap(&region, 300, 300, 0, 0), expected_overlap(&region, 300, 300, 1000, 1000));
+        assert!(expected_overlap(&region, 300, 300, 0, 0) > expected_overlap(&region, 300, 300, 150, 150));
+    }
+
+    #[test]
+    fn shell_commands_render_the_dada2_trunc_lengths() {
+        let _state = test_support::global_state();
+        let trimmed = out_path("paired-end-demux-trimmed.qza");
+        let options = PipelineOptions { trunc_len_f: Some(150), trunc_len_r: Some(0), ..PipelineOptions::default() };
+        let steps = plan(&options);
+        let denoise = step_named(&steps, "Running DADA2 denoise-paired").unwrap();
+
+        let commands = shell_commands("qiime-env", &denoise.command).unwrap();
+        assert_eq!(commands.len(), 1);
+        let command = &commands[0];
+        // DADA2 is pinned to one thread per process; it parallelizes itself with --p-n-threads
+        assert!(command.starts_with(&format!("{}=1 ", THREAD_ENV_VARS[0])), "{}", command);
+        let expected = format!(
+            " run -n qiime-env qiime dada2 denoise-paired --i-demultiplexed-seqs {} --p-n-threads 0 --p-trunc-q 2 \
+             --p-trunc-len-f 150 --p-trunc-len-r 0 --p-max-ee-f 2 --p-max-ee-r 4 ",
+            trimmed
+        );
+        assert!(command.contains(&expected), "{}", command);
+
+        // The dumped script runs exactly that command
+        let dir = tempfile::tempdir().unwrap();
+        let script_path = dir.path().join("run.sh").to_string_lossy().into_owned();
+        write_shell_script("qiime-env", &steps, false, &script_path).unwrap();
+        let script = fs::read_to_string(&script_path).unwrap();
+        assert!(script.contains(&format!("echo '==> Running DADA2 denoise-paired'\n{}\n", command)), "{}", script);
+
+        let single = PipelineOptions { trunc_len_f: Some(140), ..PipelineOptions::default() };
+        let primers = region_primers(&single.target).unwrap();
+        let steps = plan_pipeline(&PlanParams {
+            options: &single,
+            cores: 1,
+            primers: &primers,
+            flags: &QiimeFlags::default(),
+            trim: true,
+            denoise_single: true,
+            reads_per_batch: None,
+        });
+        let denoise = step_named(&steps, "Running DADA2 denoise-single (forward reads)").unwrap();
+        let command = &shell_commands("qiime-env", &denoise.command).unwrap()[0];
+        assert!(command.contains(" --p-trunc-len 140 "), "{}", command);
+        assert!(!command.contains("--p-trunc-len-r"), "{}", command);
+    }
+
+    #[test]
+    fn dumped_script_runs_the_planned_commands_in_order() {
+        let _state = test_support::global_state();
+        let dir = tempfile::tempdir().unwrap();
+        let script_path = dir.path().join("run.sh").to_string_lossy().into_owned();
+        let steps = plan(&PipelineOptions::default());
+
+        write_shell_script("qiime-env", &steps, true, &script_path).unwrap();
+
+        let script = fs::read_to_string(&script_path).unwrap();
+        assert!(script.starts_with("#!/usr/bin/env bash\n"));
+        assert!(script.contains("\nset -euo pipefail\n"));
+        let mut from = 0;
+        for (i, step) in steps.iter().enumerate() {
+            let header = format!("# Step {}/{}: {}\n", i + 1, steps.len(), step.name);
+            from += script[from..].find(&header).unwrap_or_else(|| panic!("{} is missing or out of order", step.name));
+        }
+        let import = out_path("paired-end-demux.qza");
+        assert!(script.contains(&format!("if [[ -e {} ]]; then\n", import)), "{}", script);
+        assert!(script.contains(" run -n qiime-env qiime tools import "), "{}", script);
+        assert!(script.contains(" run -n qiime-env qiime dada2 denoise-paired "), "{}", script);
+        assert!(script.contains("echo 'Skipping Merging ASV and taxonomy tables: done by Windchime itself"));
+
+        let syntax = std::process::Command::new("bash").arg("-n").arg(&script_path).output().unwrap();
+        assert!(syntax.status.success(), "{}", String::from_utf8_lossy(&syntax.stderr));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&script_path).unwrap().permissions().mode() & 0o777, 0o755);
+        }
+    }
+
+    #[test]
+    fn dry_run_prints_the_steps_a_real_run_would_take_without_running_them() {
+        let _state = test_support::global_state();
+        crate::logger::init_log(false);
+        let steps = plan(&PipelineOptions::default());
+        let denoise = step_named(&steps, "Running DADA2 denoise-paired").unwrap();
+        let merge = step_named(&steps, "Merging ASV and taxonomy tables").unwrap();
+        assert_eq!(
+            dry_run_lines("qiime-env", merge),
+            [
+                "[DRY RUN] ==> Merging ASV and taxonomy tables".to_string(),
+                format!("[DRY RUN] (done by Windchime itself, writing {})", merge.outputs.join(", ")),
+            ]
+        );
+        let denoise_lines = dry_run_lines("qiime-env", denoise);
+        let commands = shell_commands("qiime-env", &denoise.command).unwrap();
+        assert_eq!(denoise_lines[1..], commands.iter().map(|c| format!("[DRY RUN] {}", c)).collect::<Vec<_>>());
+        // Existing outputs are still skipped, so the plan matches what a real run would do
+        let import = &steps[0];
+        for output in &import.outputs {
+            fs::write(output, "imported").unwrap();
+        }
+
+        crate::DRY_RUN.store(true, std::sync::atomic::Ordering::Relaxed);
+        let result = execute_steps("qiime-env", &steps, true, false);
+        let direct = run_conda_qiime_command("qiime-env", &qiime_args(&["tools", "peek", "x.qza"]));
+        crate::DRY_RUN.store(false, std::sync::atomic::Ordering::Relaxed);
+        result.unwrap();
+        direct.unwrap();
+
+        assert!(test_support::conda_calls().is_empty());
+        assert!(!Path::new(&merge.outputs[0]).exists());
+        let log = fs::read_to_string(out_path("windchime.log")).unwrap();
+        let planned: Vec<&str> = log.lines().filter_map(|line| line.split("Dry run, not running: ==> ").nth(1)).collect();
+        let expected: Vec<&str> = names(&steps).into_iter().filter(|name| *name != import.name).collect();
+        assert_eq!(planned, expected);
+        assert!(log.contains("Dry run, not running: (done by Windchime itself, writing"));
+        let threads = "OMP_NUM_THREADS=1 OPENBLAS_NUM_THREADS=1 MKL_NUM_THREADS=1 NUMBA_NUM_THREADS=1";
+        let peek = format!("{} {} run -n qiime-env qiime tools peek x.qza", threads, conda_binary());
+        assert!(log.contains(&format!("Dry run, not running: {}", peek)), "{}", log);
+    }
+
+    #[test]
+    fn changed_trimming_parameters_regenerate_an_existing_trimmed_artifact() {
+        let _state = test_support::global_state();
+        let trim_step = |error_rate| {
+            let options = PipelineOptions { cutadapt_error_rate: error_rate, ..PipelineOptions::default() };
+            plan(&options).into_iter().find(|step| step.name == "Trimming reads with Cutadapt").unwrap()
+        };
+        let trim_calls = || test_support::conda_calls().iter().filter(|call| call.contains("cutadapt trim-paired")).count();
+
+        // The first run writes the trimmed artifact and records its parameters
+        let original = trim_step(0.1);
+        fs::write(&original.outputs[0], "trimmed").unwrap();
+        execute_steps("qiime-env", std::slice::from_ref(&original), false, false).unwrap();
+        assert_eq!(trim_calls(), 1);
+        let sentinel = params_sentinel_path(&original).unwrap();
+        assert!(fs::read_to_string(&sentinel).unwrap().contains("--p-error-rate 0.1"));
+
+        // Same parameters: the existing artifact is reused
+        execute_steps("qiime-env", std::slice::from_ref(&original), true, false).unwrap();
+        assert_eq!(trim_calls(), 1);
+
+        // Different parameters: regenerated even though the artifact exists
+        let changed = trim_step(0.2);
+        assert_eq!(
+            stale_reason(&changed, &[]).as_deref(),
+            Some("its parameters changed since the existing output was made")
+        );
+        execute_steps("qiime-env", std::slice::from_ref(&changed), true, false).unwrap();
+        assert_eq!(trim_calls(), 2);
+        assert!(fs::read_to_string(&sentinel).unwrap().contains("--p-error-rate 0.2"));
+    }
+
+    #[test]
+    fn verbose_skip_checks_report_each_output_with_its_size() {
+        let _state = test_support::global_state();
+        crate::logger::init_log(false);
+        let mut steps = plan(&PipelineOptions::default());
+        steps.retain(|step| step.name == "Running DADA2 denoise-paired");
+        let [table, rep_seqs, stats] = [0, 1, 2].map(|i| steps[0].outputs[i].clone());
+        fs::create_dir_all(out_path("asvs")).unwrap();
+        fs::write(&table, vec![b'x'; 2048]).unwrap();
+        fs::write(&rep_seqs, "rep-seqs").unwrap();
+
+        crate::VERBOSE_MODE.store(true, std::sync::atomic::Ordering::Relaxed);
+        let first = execute_steps("qiime-env", &steps, true, false);
+        fs::write(&stats, "stats").unwrap();
+        let second = execute_steps("qiime-env", &steps, true, false);
+        crate::VERBOSE_MODE.store(false, std::sync::atomic::Ordering::Relaxed);
+        first.unwrap();
+        second.unwrap();
+
+        let log = fs::read_to_string(out_path("windchime.log")).unwrap();
+        let checked = |path: &str, detail: &str| log.contains(&format!("    checked {}: {}", path, detail));
+        // Every output is reported, including those after the first missing one
+        assert!(checked(&stats, "missing"), "{}", log);
+        assert_eq!(log.matches(&format!("    checked {}: 2.0 KB, modified ", table)).count(), 2, "{}", log);
+        assert!(checked(&rep_seqs, "8.0 B, modified ") && checked(&stats, "5.0 B, modified "), "{}", log);
+        // The first run had to denoise; the second reused all three outputs
+        assert_eq!(test_support::conda_calls().iter().filter(|call| call.contains("dada2 denoise-paired")).count(), 1);
+    }
+
+    #[test]
+    fn completed_steps_are_recorded_in_the_pipeline_state() {
+        let _state = test_support::global_state();
+        assert!(!is_step_complete("Importing reads"));
+        mark_step_complete("Importing reads").unwrap();
+        mark_step_complete("Running DADA2 denoise-paired").unwrap();
+        // Marking a step again moves it to the end rather than listing it twice
+        mark_step_complete("Importing reads").unwrap();
+        let state: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(out_path(PIPELINE_STATE)).unwrap()).unwrap();
+        let steps: Vec<&str> = state["completed"].as_array().unwrap().iter().map(|s| s["step"].as_str().unwrap()).collect();
+        assert_eq!(steps, ["Running DADA2 denoise-paired", "Importing reads"]);
+        assert!(chrono::DateTime::parse_from_rfc3339(state["completed"][0]["completed_at"].as_str().unwrap()).is_ok());
+
+        unmark_step("Importing reads").unwrap();
+        assert!(!is_step_complete("Importing reads") && is_step_complete("Running DADA2 denoise-paired"));
+        // A corrupt record counts as nothing completed, so every step is redone
+        fs::write(out_path(PIPELINE_STATE), "{\"completed\": [").unwrap();
+        assert!(!is_step_complete("Running DADA2 denoise-paired"));
+        reset_pipeline_state().unwrap();
+        reset_pipeline_state().unwrap();
+        assert!(!Path::new(&out_path(PIPELINE_STATE)).exists());
+    }
+
+    #[test]
+    fn resume_reruns_steps_not_recorded_as_complete() {
+        let _state = test_support::global_state();
+        crate::logger::init_log(false);
+        let first_qza = out_path("first.qza");
+        let second_qza = out_path("second.qza");
+        let steps = [
+            step("First step", qiime(&["tools", "first", "--o-result", &first_qza]), &[&first_qza], false),
+            step("Second step", qiime(&["tools", "second", "--o-result", &second_qza]), &[&second_qza], false),
+        ];
+        // Both outputs exist, but only the first step finished; the second was killed part-way
+        fs::write(&first_qza, "complete").unwrap();
+        fs::write(&second_qza, "partial").unwrap();
+        mark_step_complete("First step").unwrap();
+
+        // --skip-existing alone trusts the partial file
+        execute_steps("qiime-env", &steps, true, false).unwrap();
+        assert!(test_support::conda_calls().is_empty());
+        execute_steps("qiime-env", &steps, false, true).unwrap();
+        let calls = test_support::conda_calls();
+        assert_eq!(calls.len(), 1, "{:?}", calls);
+        assert!(calls[0].contains("tools second"), "{}", calls[0]);
+        assert!(is_step_complete("Second step"));
+        let log = fs::read_to_string(out_path("windchime.log")).unwrap();
+        assert!(log.contains(&format!("Regenerating 'Second step': it isn't recorded as complete in {}", PIPELINE_STATE)));
+
+        // Now both are recorded, so resuming again runs nothing
+        execute_steps("qiime-env", &steps, false, true).unwrap();
+        assert_eq!(test_support::conda_calls().len(), 1);
+    }
+
+    #[test]
+    fn a_failed_step_is_not_recorded_as_complete() {
+        let _state = test_support::global_state();
+        let output = out_path("second.qza");
+        let steps = [step("Second step", qiime(&["tools", "second", "--o-result", &output]), &[&output], false)];
+        mark_step_complete("Second step").unwrap();
+        fs::write(&output, "partial").unwrap();
+        test_support::conda_fails_on("tools second");
+
+        // Its earlier record is dropped before it reruns, so a failure leaves it unrecorded
+        assert!(execute_steps("qiime-env", &steps, false, false).is_err());
+        assert!(!is_step_complete("Second step"));
+        assert!(execute_steps("qiime-env", &steps, false, true).is_err());
+        assert!(!is_step_complete("Second step"));
+        assert_eq!(test_support::conda_calls().len(), 2);
+    }
+
+    #[test]
+    fn qiime_arguments_with_spaces_are_passed_whole() {
+        let _state = test_support::global_state();
+        let manifest = "my run/manifest; rm -rf x.tsv";
+        let args: Vec<String> =
+            ["tools", "import", "--input-path", manifest, "--p-front-f", "^ACGT NNN"].iter().map(|a| a.to_string()).collect();
+
+        run_conda_qiime_command_threads("qiime-env", &args, None).unwrap();
+
+        let mut expected = vec!["run", "-n", "qiime-env", "qiime"];
+        expected.extend(["tools", "import", "--input-path", manifest, "--p-front-f", "^ACGT NNN"]);
+        assert_eq!(test_support::conda_last_args(), expected);
+    }
+
+    #[test]
+    fn json_log_entries_name_the_step_they_come_from() {
+        let _state = test_support::global_state();
+        crate::logger::init_log(true);
+        let output = out_path("first.qza");
+        let steps = [step("First step", qiime(&["tools", "first", "--o-result", &output]), &[&output], false)];
+        test_support::conda_fails_on("tools first");
+        let result = execute_steps("qiime-env", &steps, false, false);
+        crate::logger::init_log(false);
+        assert!(result.is_err());
+
+        let json = fs::read_to_string(out_path("windchime.jsonl")).unwrap();
+        let entries: Vec<serde_json::Value> = json.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let in_step: Vec<(&str, &str)> = entries
+            .iter()
+            .filter(|entry| entry["step"] == "First step")
+            .map(|entry| (entry["level"].as_str().unwrap(), entry["message"].as_str().unwrap()))
+            .collect();
+        assert_eq!(in_step.first(), Some(&("info", "Starting step: First step")));
+        let (level, message) = in_step.last().unwrap();
+        assert_eq!(*level, "error");
+        assert!(message.starts_with("Step failed: First step: "), "{}", message);
+        // The conda command itself is logged outside any step
+        assert!(entries.iter().any(|entry| entry["step"].is_null() && entry["message"].as_str().unwrap().contains("tools first")));
+    }
+
+    #[test]
+    fn a_region_reference_mismatch_warns_or_fails_when_strict() {
+        let _state = test_support::global_state();
+        crate::logger::init_log(false);
+        let log = || fs::read_to_string(test_support::output_dir().join("windchime.log")).unwrap_or_default();
+        let mismatched = PipelineOptions { target: "16s".to_string(), database: Some(Database::Pr2), ..PipelineOptions::default() };
+
+        check_reference_region(&mismatched).unwrap();
+        assert!(log().contains("Warning: target region '16s' is classified against pr2"), "{}", log());
+
+        let strict = PipelineOptions { strict_reference: true, ..mismatched };
+        let err = check_reference_region(&strict).unwrap_err();
+        assert!(err.to_string().starts_with("Reference mismatch: target region '16s'"), "{}", err);
+
+        let its_on_pr2 = PipelineOptions { target: "its".to_string(), database: Some(Database::Pr2), ..PipelineOptions::default() };
+        assert!(check_reference_region(&its_on_pr2).unwrap_err().to_string().starts_with("--target its classifies fungal ITS reads"));
+    }
+
+    #[test]
+    fn a_matching_region_and_reference_stay_silent() {
+        let _state = test_support::global_state();
+        crate::logger::init_log(false);
+        for (target, database) in [("16s", Some(Database::Silva)), ("18sv9", None), ("18sv4", Some(Database::Pr2)), ("its", None)] {
+            let options = PipelineOptions { target: target.to_string(), database, strict_reference: true, ..PipelineOptions::default() };
+            check_reference_region(&options).unwrap();
+        }
+        let log = fs::read_to_string(test_support::output_dir().join("windchime.log")).unwrap_or_default();
+        assert!(!log.contains("Warning"), "{}", log);
+    }
+
+    #[test]
+    fn rank_confidences_flow_into_the_merged_table() {
+        let _state = test_support::global_state();
+        let lines = merge(
+            "Feature ID\tTaxon\tConfidence\nasv1\tEukaryota;TSAR;Alveolata\t0.99;0.95;0.7\nasv2\tEukaryota;TSAR\t0.8\n",
+            "pr2",
+            true,
+        );
+        let header: Vec<&str> = lines[0].split('\t').collect();
+        let column = |name: &str| header.iter().position(|col| *col == name).unwrap_or_else(|| panic!("no {} in {:?}", name, header));
+        let (domain, division) = (column("pr2_Confidence_Domain"), column("pr2_Confidence_Division"));
+        assert_eq!(header[column("pr2_Confidence")], "pr2_Confidence");
+        let row = |line: &str| line.split('\t').map(String::from).collect::<Vec<_>>();
+        let (asv1, asv2) = (row(&lines[1]), row(&lines[2]));
+        assert_eq!([&asv1[domain], &asv1[domain + 1], &asv1[division]], ["0.99", "0.95", "0.7"]);
+        // A single confidence covers every assigned rank; deeper ranks stay empty
+        assert_eq!([&asv2[domain], &asv2[domain + 1], &asv2[division]], ["0.8", "0.8", ""]);
+        assert_eq!(asv1.len(), header.len());
+    }
+
+    /// `conda env create` calls made so far.
+    fn env_create_calls() -> Vec<String> {
+        test_support::conda_calls().into_iter().filter(|call| call.starts_with("env create")).collect()
+    }
+
+    #[test]
+    fn a_classic_solver_failure_is_retried_with_libmamba() {
+        let _state = test_support::global_state();
+        test_support::conda_prints("solver: classic\n");
+        test_support::conda_fails_until("env create", "--solver libmamba", "UnsatisfiableError: conflicting pins\n");
+
+        create_conda_env("qiime-env", "qiime.yml", Vec::new()).unwrap();
+
+        assert_eq!(
+            env_create_calls(),
+            ["env create -n qiime-env --file qiime.yml", "env create -n qiime-env --file qiime.yml --solver libmamba"]
+        );
+    }
+
+    #[test]
+    fn other_env_create_failures_are_not_retried() {
+        let _state = test_support::global_state();
+        test_support::conda_prints("solver: classic\n");
+        test_support::conda_fails_until("env create", "--solver libmamba", "CondaHTTPError: HTTP 000 CONNECTION FAILED\n");
+        assert!(create_conda_env("qiime-env", "qiime.yml", Vec::new()).is_err());
+        assert_eq!(env_create_calls(), ["env create -n qiime-env --file qiime.yml"]);
+    }
+
+    #[test]
+    fn an_explicit_solver_has_no_fallback() {
+        let _state = test_support::global_state();
+        test_support::conda_fails_until("env create", "--solver libmamba", "UnsatisfiableError: conflicting pins\n");
+        conda::set_solver(conda::CondaSolver::Classic);
+        let result = create_conda_env("qiime-env", "qiime.yml", Vec::new());
+        conda::set_solver(conda::CondaSolver::Auto);
+        assert!(result.is_err());
+        assert_eq!(env_create_calls(), ["env create -n qiime-env --file qiime.yml --solver classic"]);
+    }
+}
@@ -0,0 +1,300 @@
+use std::error::Error;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::time::Duration;
+
+use dialoguer::{theme::ColorfulTheme, Confirm};
+
+use crate::color_print::{print_info, print_success};
+use crate::demultiplex::{self, DemuxOptions};
+use crate::estimate::{self, format_bytes};
+use crate::logger::log_action;
+use crate::pipeline::{self, Database, PipelineOptions, PlanParams, QiimeFlags};
+use crate::summary;
+
+/// A reference database file a run needs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseFile {
+    pub label: &'static str,
+    pub path: String,
+    /// Where it comes from when missing; `None` for user-supplied files.
+    pub url: Option<&'static str>,
+    /// Size on disk, when already present.
+    pub present_bytes: Option<u64>,
+    /// Download size reported by the server, when it has to be fetched.
+    pub download_bytes: Option<u64>,
+}
+
+/// What a `RunAll` is about to do, gathered before any long step starts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preflight {
+    pub env_name: String,
+    /// `None` when conda couldn't be asked.
+    pub env_exists: Option<bool>,
+    /// Barcodes files, or the manifest with `--continue-from-manifest`.
+    pub inputs: Vec<String>,
+    pub demultiplex: bool,
+    pub samples: usize,
+    pub target: String,
+    pub primer_f: &'static str,
+    pub primer_r: &'static str,
+    pub databases: Vec<DatabaseFile>,
+    /// Pipeline steps planned after demultiplexing.
+    pub total_steps: usize,
+    /// Cutadapt may still be skipped by `--detect-primers`.
+    pub detect_primers: bool,
+    pub estimated_disk_bytes: f64,
+}
+
+/// Asks the server for a download's size without fetching it.
+fn remote_size(url: &str) -> Option<u64> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .ok()?;
+    let resp = client.head(url).send().ok()?;
+    if !resp.status().is_success() {
+        return None;
+    }
+    resp.content_length().filter(|&len| len > 0)
+}
+
+fn database_file(label: &'static str, path: String, url: Option<&'static str>) -> DatabaseFile {
+    let present_bytes = fs::metadata(&path).ok().map(|meta| meta.len());
+    let download_bytes = match (present_bytes, url) {
+        (None, Some(url)) => remote_size(url),
+        _ => None,
+    };
+    DatabaseFile { label, path, url, present_bytes, download_bytes }
+}
+
+/// The reference files `options` needs: the user's classifier, or the
+/// `--database` reference plus the pre-trained classifier when one is used.
+fn required_databases(options: &PipelineOptions) -> Vec<DatabaseFile> {
+    if let Some(classifier) = &options.classifier {
+        return vec![database_file("External classifier", classifier.clone(), None)];
+    }
+    let reference = options.reference_database();
+    let (sequences, taxonomy) = match reference {
+        Database::Pr2 => ("PR2 sequences", "PR2 taxonomy"),
+        Database::Silva => ("SILVA sequences", "SILVA taxonomy"),
+        Database::Unite => ("UNITE sequences", "UNITE taxonomy"),
+    };
+    let mut databases = vec![
+        database_file(sequences, reference.fasta_path(), reference.fasta_url()),
+        database_file(taxonomy, reference.taxonomy_path(), reference.taxonomy_url()),
+    ];
+    if let Some(url) = reference.classifier_url().filter(|_| options.use_pretrained_classifier) {
+        databases.push(database_file("Pre-trained PR2 classifier", reference.classifier_path(), Some(url)));
+    }
+    databases
+}
+
+/// Scans the inputs and plans the run described by `options`. With
+/// `existing_manifest`, `options.manifest` is read instead of the barcodes files.
+pub fn build_preflight(
+    barcodes_files: &[String],
+    options: &PipelineOptions,
+    demux_options: &DemuxOptions,
+    existing_manifest: bool,
+) -> Result<Preflight, Box<dyn Error>> {
+    let (inputs, samples, pairs) = if existing_manifest {
+        let pairs = estimate::manifest_inputs(&options.manifest)?;
+        (vec![options.manifest.clone()], pairs.len(), pairs)
+    } else {
+        let (samples, pairs) = demultiplex::barcode_input_files(
+            barcodes_files,
+            demux_options.barcode_columns.as_deref(),
+            demux_options.passthrough,
+            demux_options.single_end,
+        )?;
+        (barcodes_files.to_vec(), samples, pairs)
+    };
+
+    let primers = pipeline::region_primers(&options.target)?;
+    let (cores, _) = pipeline::effective_cores(options.cores, summary::available_parallelism(), options.clamp_cores);
+    let steps = pipeline::plan_pipeline(&PlanParams {
+        options,
+        cores,
+        primers: &primers,
+        flags: &QiimeFlags::default(),
+        trim: true,
+        denoise_single: options.single_end || options.denoise_mode == pipeline::DenoiseMode::Single,
+        reads_per_batch: options.reads_per_batch,
+    });
+
+    let size = estimate::measure_inputs(&pairs, samples)?;
+    let train_classifier = options.trains_classifier();
+    let stages = estimate::estimate_run(&size, cores, !existing_manifest, train_classifier);
+
+    Ok(Preflight {
+        env_name: options.env_name.clone(),
+        env_exists: pipeline::conda_env_exists(&options.env_name).ok(),
+        inputs,
+        demultiplex: !existing_manifest,
+        samples,
+        target: options.target.clone(),
+        primer_f: primers.primer_f,
+        primer_r: primers.primer_r,
+        databases: required_databases(options),
+        total_steps: steps.len(),
+        detect_primers: options.detect_primers,
+        estimated_disk_bytes: stages.iter().map(|stage| stage.disk_bytes).sum(),
+    })
+}
+
+impl Preflight {
+    /// The summary as printed, one line per entry.
+    pub fn lines(&self) -> Vec<String> {
+        let env_state = match self.env_exists {
+            Some(true) => "exists",
+            Some(false) => "will be created",
+            None => "unknown (conda not reachable)",
+        };
+        let mut lines = vec![
+            format!("Environment:    {} ({})", self.env_name, env_state),
+            format!(
+                "Input:          {} ({})",
+                self.inputs.join(", "),
+                if self.demultiplex { "demultiplex, then generate the manifest" } else { "existing manifest" }
+            ),
+            format!("Samples:        {}", self.samples),
+            format!("Target:         {} (forward {}, reverse {})", self.target, self.primer_f, self.primer_r),
+            "Databases:".to_string(),
+        ];
+        for db in &self.databases {
+            let state = match (db.present_bytes, db.url, db.download_bytes) {
+                (Some(bytes), _, _) => format!("present, {}", format_bytes(bytes as f64)),
+                (None, Some(_), Some(bytes)) => format!("to download, {}", format_bytes(bytes as f64)),
+                (None, Some(_), None) => "to download, size unknown".to_string(),
+                (None, None, _) => "MISSING".to_string(),
+            };
+            lines.push(format!("  {:<28} {} ({})", db.label, db.path, state));
+        }
+        lines.push(format!(
+            "Pipeline steps: {}{}",
+            self.total_steps,
+            if self.detect_primers { " (one fewer if --detect-primers finds the primers already removed)" } else { "" }
+        ));
+        lines.push(format!("Estimated disk: {} (rough, expect ±2x)", format_bytes(self.estimated_disk_bytes)));
+        lines
+    }
+
+    pub fn print(&self) {
+        print_info("RunAll preflight:");
+        for line in self.lines() {
+            println!("  {}", line);
+        }
+    }
+}
+
+/// Prints the preflight summary and asks whether to go ahead; `assume_yes`
+/// skips the question. Without a terminal to ask on, `--assume-yes` is required.
+pub fn confirm_run(preflight: &Preflight, assume_yes: bool) -> Result<bool, Box<dyn Error>> {
+    preflight.print();
+    log_action(&format!("RunAll preflight:\n{}", preflight.lines().join("\n")));
+    if assume_yes {
+        return Ok(true);
+    }
+    if !io::stdin().is_terminal() {
+        return Err("RunAll needs confirmation but stdin is not a terminal; rerun with --assume-yes".into());
+    }
+    let proceed = Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Start the run?")
+        .default(true)
+        .interact()?;
+    if proceed {
+        print_success("Starting the run.");
+    }
+    Ok(proceed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, write_fastq_gz, write_file};
+
+    #[test]
+    fn preflight_reflects_the_inputs_and_parameters() {
+        let _state = test_support::global_state();
+        let dir = tempfile::tempdir().unwrap();
+        test_support::conda_prints("qiime-env                /opt/conda/envs/qiime-env\n");
+        let plate = dir.path().join("plate").to_string_lossy().into_owned();
+        write_fastq_gz(dir.path(), "plate_R1_001.fastq.gz", &[("read1", "ACGTAACCGGTTTT")]);
+        write_fastq_gz(dir.path(), "plate_R2_001.fastq.gz", &[("read1", "GGGGCCCC")]);
+        let barcodes = write_file(
+            dir.path(),
+            "barcodes.tsv",
+            &format!(
+                "name\tfile_name\tidx1\tseq1\tidx2\tseq2\n\
+                 s1\t{plate}\tN701\tTAAGGCGA\tS501\tAACCGG\n\
+                 s2\t{plate}\tN701\tTAAGGCGA\tS502\tTTGGCC\n"
+            ),
+        );
+        let database = Database::Pr2;
+        fs::create_dir_all(database.dir()).unwrap();
+        fs::write(database.fasta_path(), ">a\nACGT\n").unwrap();
+        fs::write(database.taxonomy_path(), "a\tEukaryota\n").unwrap();
+        let options = PipelineOptions {
+            env_name: "qiime-env".to_string(),
+            target: "18sv9".to_string(),
+            use_pretrained_classifier: false,
+            ..PipelineOptions::default()
+        };
+
+        let preflight = build_preflight(std::slice::from_ref(&barcodes), &options, &DemuxOptions::default(), false).unwrap();
+
+        let primers = pipeline::region_primers("18sv9").unwrap();
+        assert_eq!(preflight.env_exists, Some(true));
+        assert_eq!(preflight.inputs, std::slice::from_ref(&barcodes));
+        assert!(preflight.demultiplex);
+        assert_eq!(preflight.samples, 2);
+        assert_eq!((preflight.primer_f, preflight.primer_r), (primers.primer_f, primers.primer_r));
+        let labels: Vec<&str> = preflight.databases.iter().map(|db| db.label).collect();
+        assert_eq!(labels, ["PR2 sequences", "PR2 taxonomy"]);
+        assert_eq!(preflight.databases[0].present_bytes, Some(">a\nACGT\n".len() as u64));
+        assert!(preflight.estimated_disk_bytes > 0.0);
+
+        let lines = preflight.lines();
+        assert_eq!(lines[0], "Environment:    qiime-env (exists)");
+        assert_eq!(lines[1], format!("Input:          {} (demultiplex, then generate the manifest)", barcodes));
+        assert_eq!(lines[2], "Samples:        2");
+        assert_eq!(
+            lines[3],
+            format!("Target:         18sv9 (forward {}, reverse {})", primers.primer_f, primers.primer_r)
+        );
+        assert!(lines[5].contains("(present, "), "{}", lines[5]);
+        assert_eq!(lines[7], format!("Pipeline steps: {}", preflight.total_steps));
+    }
+
+    #[test]
+    fn preflight_flags_a_missing_environment_and_classifier() {
+        let _state = test_support::global_state();
+        let dir = tempfile::tempdir().unwrap();
+        test_support::conda_prints("base                /opt/conda\n");
+        let r1 = write_fastq_gz(dir.path(), "s1_R1.fastq.gz", &[("read1", "ACGT")]);
+        let r2 = write_fastq_gz(dir.path(), "s1_R2.fastq.gz", &[("read1", "GGCC")]);
+        let manifest = write_file(
+            dir.path(),
+            "manifest.tsv",
+            &format!("sample-id\tforward-absolute-filepath\treverse-absolute-filepath\ns1\t{}\t{}\n", r1.display(), r2.display()),
+        );
+        let classifier = dir.path().join("classifier.qza").to_string_lossy().into_owned();
+        let options = PipelineOptions {
+            env_name: "qiime-env".to_string(),
+            manifest: manifest.clone(),
+            classifier: Some(classifier.clone()),
+            ..PipelineOptions::default()
+        };
+
+        let preflight = build_preflight(&[], &options, &DemuxOptions::default(), true).unwrap();
+
+        assert_eq!(preflight.env_exists, Some(false));
+        assert!(!preflight.demultiplex);
+        assert_eq!(preflight.samples, 1);
+        let lines = preflight.lines();
+        assert_eq!(lines[0], "Environment:    qiime-env (will be created)");
+        assert_eq!(lines[1], format!("Input:          {} (existing manifest)", manifest));
+        assert_eq!(lines[5], format!("  {:<28} {} (MISSING)", "External classifier", classifier));
+    }
+}
@@ -0,0 +1,152 @@
+use std::io;
+
+use crate::fastq_io::FastqReader;
+
+/// Mismatches tolerated when looking for a primer at the start of a read,
+/// to allow for sequencing errors.
+pub const PRIMER_MAX_MISMATCHES: usize = 2;
+
+/// Reads examined per R1 file when detecting primers.
+const DETECT_READS_PER_FILE: usize = 2_000;
+/// R1 files examined when detecting primers.
+const DETECT_MAX_FILES: usize = 5;
+/// Below this fraction of primer-led reads, primers are taken to be absent.
+const PRIMERS_PRESENT_FRACTION: f64 = 0.2;
+
+/// Whether read base `base` is allowed by IUPAC code `code`.
+fn iupac_matches(base: u8, code: u8) -> bool {
+    let allowed: &[u8] = match code.to_ascii_uppercase() {
+        b'R' => b"AG",
+        b'Y' => b"CT",
+        b'S' => b"CG",
+        b'W' => b"AT",
+        b'K' => b"GT",
+        b'M' => b"AC",
+        b'B' => b"CGT",
+        b'D' => b"AGT",
+        b'H' => b"ACT",
+        b'V' => b"ACG",
+        b'N' => return true,
+        _ => return base.eq_ignore_ascii_case(&code),
+    };
+    allowed.contains(&base.to_ascii_uppercase())
+}
+
+/// Whether `observed` starts with `primer` (IUPAC codes allowed), with up to
+/// [`PRIMER_MAX_MISMATCHES`] mismatches.
+pub fn primer_matches(observed: &[u8], primer: &[u8]) -> bool {
+    observed.len() >= primer.len()
+        && observed
+            .iter()
+            .zip(primer)
+            .filter(|&(&base, &code)| !iupac_matches(base, code))
+            .count()
+            <= PRIMER_MAX_MISMATCHES
+}
+
+/// Outcome of looking for the forward primer at the start of R1 reads.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrimerDetection {
+    /// At least [`PRIMERS_PRESENT_FRACTION`] of the sampled reads start with the primer.
+    Present { fraction: f64, reads: usize },
+    /// Too few reads start with the primer: it was probably removed upstream.
+    Absent { fraction: f64, reads: usize },
+    /// No reads could be sampled.
+    Unknown,
+}
+
+impl PrimerDetection {
+    /// Whether Cutadapt trimming should run. Only a confident "absent" skips it.
+    pub fn needs_trimming(&self) -> bool {
+        !matches!(self, PrimerDetection::Absent { .. })
+    }
+}
+
+/// Classifies the sampled `reads` by how many of them start with the primer.
+pub fn classify_primer_presence(matching: usize, reads: usize) -> PrimerDetection {
+    if reads == 0 {
+        return PrimerDetection::Unknown;
+    }
+    let fraction = matching as f64 / reads as f64;
+    if fraction >= PRIMERS_PRESENT_FRACTION {
+        PrimerDetection::Present { fraction, reads }
+    } else {
+        PrimerDetection::Absent { fraction, reads }
+    }
+}
+
+/// Samples the first reads of up to [`DETECT_MAX_FILES`] R1 FASTQs and checks
+/// how many start with `primer`.
+pub fn detect_primer(r1_files: &[String], primer: &str) -> io::Result<PrimerDetection> {
+    let mut reads = 0;
+    let mut matching = 0;
+    for r1_file in r1_files.iter().take(DETECT_MAX_FILES) {
+        for record in FastqReader::open(r1_file)?.take(DETECT_READS_PER_FILE) {
+            let record = record?;
+            reads += 1;
+            if primer_matches(record.seq(), primer.as_bytes()) {
+                matching += 1;
+            }
+        }
+    }
+    Ok(classify_primer_presence(matching, reads))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::write_fastq_gz;
+
+    const PRIMER: &str = "GTGYCAGCMGCCGCGGTAA";
+
+    /// Writes `reads` to an R1 FASTQ in `dir` and returns its path.
+    fn r1_file(dir: &std::path::Path, name: &str, reads: &[String]) -> String {
+        let ids: Vec<String> = (0..reads.len()).map(|i| format!("read{}", i)).collect();
+        let records: Vec<(&str, &str)> = ids.iter().map(String::as_str).zip(reads.iter().map(String::as_str)).collect();
+        write_fastq_gz(dir, name, &records).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn already_trimmed_reads_skip_cutadapt() {
+        let dir = tempfile::tempdir().unwrap();
+        let trimmed: Vec<String> = (0..50).map(|i| format!("TACGGAGGGTGCAAGCGTTA{:04}", i)).collect();
+        let r1 = r1_file(dir.path(), "trimmed_R1.fastq.gz", &trimmed);
+
+        let detection = detect_primer(&[r1], PRIMER).unwrap();
+        assert_eq!(detection, PrimerDetection::Absent { fraction: 0.0, reads: 50 });
+        assert!(!detection.needs_trimming());
+    }
+
+    #[test]
+    fn reads_starting_with_the_primer_are_trimmed() {
+        let dir = tempfile::tempdir().unwrap();
+        // One in four reads carries the primer, its IUPAC codes resolved
+        let reads: Vec<String> = (0..40)
+            .map(|i| match i % 4 {
+                0 => "GTGCCAGCAGCCGCGGTAATACGGAG".to_string(),
+                _ => "TACGGAGGGTGCAAGCGTTAATCGGA".to_string(),
+            })
+            .collect();
+        let r1 = r1_file(dir.path(), "raw_R1.fastq.gz", &reads);
+
+        let detection = detect_primer(&[r1], PRIMER).unwrap();
+        assert_eq!(detection, PrimerDetection::Present { fraction: 0.25, reads: 40 });
+        assert!(detection.needs_trimming());
+    }
+
+    #[test]
+    fn no_reads_means_trimming() {
+        assert_eq!(classify_primer_presence(0, 0), PrimerDetection::Unknown);
+        assert!(PrimerDetection::Unknown.needs_trimming());
+        assert!(!classify_primer_presence(19, 100).needs_trimming());
+        assert!(classify_primer_presence(20, 100).needs_trimming());
+    }
+
+    #[test]
+    fn primer_match_allows_iupac_codes_and_two_mismatches() {
+        assert!(primer_matches(b"GTGTCAGCAGCCGCGGTAA", PRIMER.as_bytes()));
+        assert!(primer_matches(b"GTGTCAGCAGCCGCGGTTT", PRIMER.as_bytes()));
+        assert!(!primer_matches(b"GTGTCAGCAGCCGCGGCCC", PRIMER.as_bytes()));
+        assert!(!primer_matches(b"GTGTCAGCAG", PRIMER.as_bytes()));
+    }
+}
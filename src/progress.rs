@@ -0,0 +1,265 @@
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::color_print::print_info;
+
+/// How often a long-running step reports that it's still going when progress
+/// is printed as plain text.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether progress bars can be drawn: indicatif draws to stderr, and when
+/// that's piped to a file or a CI log we print plain lines instead.
+pub fn interactive() -> bool {
+    io::stderr().is_terminal()
+}
+
+/// Spinner of the pipeline step currently running, if one is drawn.
+static STEP_SPINNER: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Records the spinner drawn for the running step (`None` once it's done).
+pub fn set_step_spinner(spinner: Option<ProgressBar>) {
+    *STEP_SPINNER.lock().unwrap() = spinner;
+}
+
+/// Turns the running step's spinner into a bar of `total()` units, for
+/// built-in steps that know how much work they have. Returns `None`, without
+/// calling `total`, when no spinner is drawn (verbose mode or no terminal).
+pub fn step_bar(total: impl FnOnce() -> u64) -> Option<ProgressBar> {
+    let pb = STEP_SPINNER.lock().unwrap().clone()?;
+    pb.disable_steady_tick();
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {percent:>3}% {msg}")
+            .unwrap(),
+    );
+    pb.set_length(total().max(1));
+    pb.set_position(0);
+    Some(pb)
+}
+
+/// Counts the bytes of a download on a terminal: a bar with transfer rate
+/// and ETA when the server reports the length, a spinner otherwise. Reuses
+/// the running step's spinner if there is one (the step then finishes it).
+pub struct DownloadProgress {
+    bar: ProgressBar,
+    own: bool,
+}
+
+impl DownloadProgress {
+    /// Starts counting at `start` bytes (for a resumed download) of `len` in
+    /// all. Returns `None` without a terminal; callers print a plain line.
+    pub fn new(len: Option<u64>, start: u64, label: &str) -> Option<Self> {
+        Self::with_terminal(len, start, label, interactive())
+    }
+
+    /// Like [`DownloadProgress::new`], drawing a bar only if `terminal`.
+    fn with_terminal(len: Option<u64>, start: u64, label: &str, terminal: bool) -> Option<Self> {
+        if !terminal {
+            return None;
+        }
+        let step = STEP_SPINNER.lock().unwrap().clone();
+        let own = step.is_none();
+        let bar = step.unwrap_or_else(|| ProgressBar::new_spinner().with_message(label.to_string()));
+        bar.disable_steady_tick();
+        match len {
+            Some(len) => {
+                bar.set_style(
+                    ProgressStyle::default_bar()
+                        .template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} {bytes_per_sec} ETA {eta} {msg}")
+                        .unwrap(),
+                );
+                bar.set_length(len);
+            }
+            None => {
+                bar.set_style(
+                    ProgressStyle::default_spinner()
+                        .template("[{elapsed_precise}] {spinner:.cyan} {bytes} {bytes_per_sec} {msg}")
+                        .unwrap(),
+                );
+                bar.enable_steady_tick(Duration::from_millis(100));
+            }
+        }
+        bar.set_position(start);
+        // Bytes from an earlier attempt don't count towards the rate
+        bar.reset_eta();
+        Some(DownloadProgress { bar, own })
+    }
+
+    /// Wraps `out` so that bytes written to it advance the bar.
+    pub fn wrap_write<W: io::Write>(&self, out: W) -> indicatif::ProgressBarIter<W> {
+        self.bar.wrap_write(out)
+    }
+
+    pub fn finish(&self) {
+        if self.own {
+            self.bar.finish_and_clear();
+        }
+    }
+
+    pub fn abandon(&self) {
+        if self.own {
+            self.bar.abandon();
+        }
+    }
+}
+
+/// Prints "still running" lines for a step until dropped.
+pub struct Heartbeat {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    pub fn start(description: &str) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let description = description.to_string();
+        let handle = thread::spawn(move || {
+            let started = Instant::now();
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(HEARTBEAT_INTERVAL) {
+                let elapsed = started.elapsed().as_secs();
+                print_info(&format!(
+                    "    ... {} still running ({}m {:02}s)",
+                    description,
+                    elapsed / 60,
+                    elapsed % 60
+                ));
+            }
+        });
+        Heartbeat { stop: Some(stop), handle: Some(handle) }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread up immediately
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Counts finished items, drawing a progress bar on a terminal and printing
+/// a line every 10% otherwise.
+pub struct CountProgress {
+    bar: Option<ProgressBar>,
+    label: String,
+    total: u64,
+    done: AtomicU64,
+}
+
+impl CountProgress {
+    pub fn new(total: u64, label: &str) -> Self {
+        Self::with_terminal(total, label, interactive())
+    }
+
+    /// Like [`CountProgress::new`], drawing a bar only if `terminal`.
+    fn with_terminal(total: u64, label: &str, terminal: bool) -> Self {
+        let bar = terminal.then(|| {
+            let pb = ProgressBar::new(total).with_message(format!("{}...", label));
+            pb.set_style(
+                ProgressStyle::default_bar()
+                    .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>3}/{len:3} {msg}")
+                    .unwrap(),
+            );
+            pb
+        });
+        CountProgress { bar, label: label.to_string(), total, done: AtomicU64::new(0) }
+    }
+
+    pub fn inc(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        match &self.bar {
+            Some(pb) => pb.inc(1),
+            None => {
+                if let Some(line) = self.plain_line(done) {
+                    print_info(&line);
+                }
+            }
+        }
+    }
+
+    /// The line printed without a terminal once `done` items are finished:
+    /// one when crossing each tenth of the total, and at the end.
+    fn plain_line(&self, done: u64) -> Option<String> {
+        let total = self.total.max(1);
+        (done * 10 / total != (done - 1) * 10 / total || done == total)
+            .then(|| format!("{}: {}/{}", self.label, done, self.total))
+    }
+
+    pub fn finish(&self, msg: &str) {
+        match &self.bar {
+            Some(pb) => pb.finish_with_message(msg.to_string()),
+            None => print_info(msg),
+        }
+    }
+
+    pub fn abandon(&self, msg: &str) {
+        match &self.bar {
+            Some(pb) => pb.abandon_with_message(msg.to_string()),
+            None => print_info(msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn without_a_terminal_progress_is_plain_lines() {
+        let progress = CountProgress::with_terminal(25, "Demultiplexing", false);
+        assert!(progress.bar.is_none());
+        let lines: Vec<String> = (1..=25).filter_map(|done| progress.plain_line(done)).collect();
+        let expected: Vec<String> =
+            [3, 5, 8, 10, 13, 15, 18, 20, 23, 25].iter().map(|done| format!("Demultiplexing: {}/25", done)).collect();
+        assert_eq!(lines, expected);
+        // No carriage returns or escape sequences, as a spinner redraw would emit
+        assert!(lines.iter().all(|line| !line.contains(['\r', '\x1b'])));
+    }
+
+    #[test]
+    fn steps_without_a_spinner_get_no_bar() {
+        let _state = test_support::global_state();
+        set_step_spinner(None);
+        assert!(step_bar(|| unreachable!("the total is only counted for a drawn bar")).is_none());
+    }
+
+    #[test]
+    fn download_bars_count_bytes_from_where_a_resumed_download_starts() {
+        let _state = test_support::global_state();
+        set_step_spinner(None);
+        assert!(DownloadProgress::with_terminal(Some(10), 0, "pr2.fasta.gz", false).is_none());
+
+        let progress = DownloadProgress::with_terminal(Some(10), 4, "pr2.fasta.gz", true).unwrap();
+        assert_eq!((progress.bar.length(), progress.bar.position()), (Some(10), 4));
+        let mut out = progress.wrap_write(Vec::new());
+        io::Write::write_all(&mut out, b"TTGGCC").unwrap();
+        assert_eq!(progress.bar.position(), 10);
+        progress.finish();
+        assert!(progress.bar.is_finished());
+
+        // Without a length the bytes are still counted, on a spinner
+        let spinner = DownloadProgress::with_terminal(None, 0, "pr2.fasta.gz", true).unwrap();
+        assert_eq!(spinner.bar.length(), None);
+        io::Write::write_all(&mut spinner.wrap_write(io::sink()), &[0; 2048]).unwrap();
+        assert_eq!(spinner.bar.position(), 2048);
+        spinner.abandon();
+
+        // A running step's spinner is reused, and left for the step to finish
+        let step = ProgressBar::hidden();
+        set_step_spinner(Some(step.clone()));
+        let reused = DownloadProgress::with_terminal(Some(100), 0, "pr2.fasta.gz", true).unwrap();
+        set_step_spinner(None);
+        assert_eq!(step.length(), Some(100));
+        reused.finish();
+        assert!(!step.is_finished());
+    }
+}
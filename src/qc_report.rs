@@ -0,0 +1,170 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use rayon::prelude::*;
+
+use crate::fastq_io::FastqReader;
+use crate::logger::log_action;
+use crate::output_dir;
+
+/// Phred quality encoding offset (Sanger / Illumina 1.8+).
+const PHRED_OFFSET: u8 = 33;
+
+/// FastQC-style summary of one FASTQ file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReadStats {
+    pub reads: u64,
+    pub bases: u64,
+    pub gc_bases: u64,
+    pub quality_sum: u64,
+    pub bases_q20: u64,
+    pub bases_q30: u64,
+}
+
+impl ReadStats {
+    /// Adds one read.
+    pub fn add(&mut self, seq: &[u8], qual: &[u8]) {
+        self.reads += 1;
+        self.bases += seq.len() as u64;
+        self.gc_bases += seq.iter().filter(|b| matches!(b.to_ascii_uppercase(), b'G' | b'C')).count() as u64;
+        for &q in qual {
+            let q = q.saturating_sub(PHRED_OFFSET);
+            self.quality_sum += q as u64;
+            if q >= 20 {
+                self.bases_q20 += 1;
+            }
+            if q >= 30 {
+                self.bases_q30 += 1;
+            }
+        }
+    }
+
+    pub fn mean_length(&self) -> f64 {
+        ratio(self.bases, self.reads)
+    }
+
+    pub fn gc_percent(&self) -> f64 {
+        100.0 * ratio(self.gc_bases, self.bases)
+    }
+
+    pub fn mean_quality(&self) -> f64 {
+        ratio(self.quality_sum, self.bases)
+    }
+
+    pub fn percent_q20(&self) -> f64 {
+        100.0 * ratio(self.bases_q20, self.bases)
+    }
+
+    pub fn percent_q30(&self) -> f64 {
+        100.0 * ratio(self.bases_q30, self.bases)
+    }
+}
+
+fn ratio(numerator: u64, denominator: u64) -> f64 {
+    if denominator == 0 { 0.0 } else { numerator as f64 / denominator as f64 }
+}
+
+/// Streams a FASTQ file (plain or compressed) and summarizes it.
+pub fn fastq_stats(path: &str) -> io::Result<ReadStats> {
+    let mut stats = ReadStats::default();
+    for record in FastqReader::open(path)? {
+        let record = record?;
+        stats.add(record.seq(), record.qual());
+    }
+    Ok(stats)
+}
+
+/// Computes R1/R2 statistics for each `(sample_id, files)` whose files all
+/// exist (`files` is `[r1, r2]`, or just `[r1]` for single-end reads) and
+/// writes them to `OUTPUT_DIR/windchime_mqc.txt` in MultiQC's custom-content
+/// table format. Returns the path of the report.
+pub fn write_qc_report(samples: &[(String, Vec<String>)]) -> io::Result<String> {
+    let rows: Vec<(String, Vec<ReadStats>)> = samples
+        .par_iter()
+        .filter(|(_, files)| files.iter().all(|file| Path::new(file).is_file()))
+        .map(|(sample, files)| Ok((sample.clone(), files.iter().map(|file| fastq_stats(file)).collect::<io::Result<_>>()?)))
+        .collect::<io::Result<_>>()?;
+    let reads = samples.iter().map(|(_, files)| files.len()).max().unwrap_or(0);
+
+    let report_path = format!("{}/windchime_mqc.txt", output_dir());
+    let mut out = File::create(&report_path)?;
+    writeln!(out, "# id: 'windchime_demux'")?;
+    writeln!(out, "# section_name: 'Windchime demultiplexing'")?;
+    writeln!(out, "# description: 'Per-sample read statistics of the demultiplexed FASTQs.'")?;
+    writeln!(out, "# format: 'tsv'")?;
+    writeln!(out, "# plot_type: 'table'")?;
+    write!(out, "Sample")?;
+    for read in ["R1", "R2"].iter().take(reads) {
+        write!(
+            out,
+            "\t{0} reads\t{0} mean length\t{0} %GC\t{0} mean Q\t{0} %>=Q20\t{0} %>=Q30",
+            read
+        )?;
+    }
+    writeln!(out)?;
+    for (sample, stats) in &rows {
+        write!(out, "{}", sample)?;
+        for stats in stats {
+            write!(
+                out,
+                "\t{}\t{:.1}\t{:.2}\t{:.2}\t{:.2}\t{:.2}",
+                stats.reads,
+                stats.mean_length(),
+                stats.gc_percent(),
+                stats.mean_quality(),
+                stats.percent_q20(),
+                stats.percent_q30()
+            )?;
+        }
+        writeln!(out)?;
+    }
+    log_action(&format!("Wrote QC report for {} samples to {}", rows.len(), report_path));
+    Ok(report_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, write_file, write_gz};
+    use std::fs;
+    use tempfile::TempDir;
+
+    // Qualities Q40, Q40, Q20, Q10 and then six Q40 bases
+    const FASTQ: &str = "@r1\nACGT\n+\nII5+\n@r2\nGGCCAA\n+\nIIIIII\n";
+
+    #[test]
+    fn stats_of_a_small_fastq() {
+        let dir = TempDir::new().unwrap();
+        let stats = fastq_stats(&write_file(dir.path(), "reads.fastq", FASTQ)).unwrap();
+        let expected = ReadStats { reads: 2, bases: 10, gc_bases: 6, quality_sum: 350, bases_q20: 9, bases_q30: 8 };
+        assert_eq!(stats, expected);
+        assert_eq!(stats.mean_length(), 5.0);
+        assert_eq!(stats.gc_percent(), 60.0);
+        assert_eq!(stats.mean_quality(), 35.0);
+        assert_eq!(stats.percent_q20(), 90.0);
+        assert_eq!(stats.percent_q30(), 80.0);
+        assert_eq!(fastq_stats(&write_gz(dir.path(), "reads.fastq.gz", FASTQ).to_string_lossy()).unwrap(), expected);
+    }
+
+    #[test]
+    fn report_is_multiqc_custom_content() {
+        let _guard = test_support::global_state();
+        let dir = TempDir::new().unwrap();
+        let r1 = write_file(dir.path(), "s1_R1.fastq", FASTQ);
+        let r2 = write_file(dir.path(), "s1_R2.fastq", "@r1\nAAAA\n+\n++++\n");
+        let samples = vec![
+            ("s1".to_string(), vec![r1, r2]),
+            // Not demultiplexed, so left out
+            ("s2".to_string(), vec![dir.path().join("missing_R1.fastq").to_string_lossy().into_owned()]),
+        ];
+
+        let report = fs::read_to_string(write_qc_report(&samples).unwrap()).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "# id: 'windchime_demux'");
+        assert_eq!(lines[4], "# plot_type: 'table'");
+        assert!(lines[5].starts_with("Sample\tR1 reads\tR1 mean length\tR1 %GC\tR1 mean Q"));
+        assert!(lines[5].ends_with("\tR2 %>=Q20\tR2 %>=Q30"));
+        assert_eq!(lines[6..], ["s1\t2\t5.0\t60.00\t35.00\t90.00\t80.00\t1\t4.0\t0.00\t10.00\t0.00\t0.00"]);
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use crate::color_print::print_error;
+use crate::logger::log_action;
+use crate::pipeline::run_conda_qiime_capture;
+
+/// Parameters whose flag spelling has changed, or is likely to change, between
+/// QIIME releases: `(plugin, action, parameter, candidates)`. The first
+/// candidate is the spelling used by the release Windchime installs.
+///
+/// Note that `--p-classify--chunk-size` really does have a double dash: QIIME
+/// derives flags from parameter names and `classify__chunk_size` is passed
+/// through to scikit-learn's pipeline.
+const FLAG_CANDIDATES: &[(&str, &str, &str, &[&str])] = &[
+    ("cutadapt", "trim-paired", "cores", &["--p-cores", "--p-num-cores"]),
+    ("cutadapt", "trim-single", "cores", &["--p-cores", "--p-num-cores"]),
+    ("dada2", "denoise-paired", "n_threads", &["--p-n-threads", "--p-num-threads"]),
+    ("dada2", "denoise-single", "n_threads", &["--p-n-threads", "--p-num-threads"]),
+    (
+        "feature-classifier",
+        "fit-classifier-naive-bayes",
+        "classify__chunk_size",
+        &["--p-classify--chunk-size", "--p-classify-chunk-size"],
+    ),
+    ("feature-classifier", "classify-sklearn", "n_jobs", &["--p-n-jobs", "--p-num-jobs"]),
+];
+
+/// `(environment, plugin, action)`.
+type ActionKey = (String, String, String);
+
+/// `qiime <plugin> <action> --help` output per environment, or `None` when it
+/// couldn't be retrieved.
+static HELP_CACHE: Lazy<Mutex<HashMap<ActionKey, Option<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Returns the installed QIIME version (e.g. `2024.10.1`), if `qiime --version` works.
+pub fn detect_qiime_version(env_name: &str) -> Option<String> {
+    let output = run_conda_qiime_capture(env_name, &["--version"]).ok()?;
+    if !output.success {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .find(|word| word.chars().next().is_some_and(|c| c.is_ascii_digit()) && word.contains('.'))
+        .map(|version| version.trim_end_matches('.').to_string())
+}
+
+fn action_help(env_name: &str, plugin: &str, action: &str) -> Option<String> {
+    let key = (env_name.to_string(), plugin.to_string(), action.to_string());
+    if let Some(cached) = HELP_CACHE.lock().unwrap().get(&key) {
+        return cached.clone();
+    }
+    let help = run_conda_qiime_capture(env_name, &[plugin, action, "--help"])
+        .ok()
+        .filter(|output| output.success)
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned());
+    HELP_CACHE.lock().unwrap().insert(key, help.clone());
+    help
+}
+
+/// Picks the first candidate flag that appears in the action's `--help`.
+/// Falls back to the first candidate when none match or help is unavailable.
+pub fn pick_flag(help: Option<&str>, candidates: &[&str]) -> String {
+    let appears = |flag: &str| {
+        help.is_some_and(|help| {
+            help.split(|c: char| c.is_whitespace() || c == ',' || c == '[' || c == ']')
+                .any(|word| word == flag)
+        })
+    };
+    candidates
+        .iter()
+        .find(|flag| appears(flag))
+        .or_else(|| candidates.first())
+        .map(|flag| flag.to_string())
+        .unwrap_or_default()
+}
+
+/// Returns the concrete flag for `parameter` of `qiime <plugin> <action>` in
+/// this environment, checking the action's `--help` output when possible.
+///
+/// Parameters not listed in [`FLAG_CANDIDATES`] map to QIIME's usual
+/// `--p-<name>` spelling.
+pub fn resolve_flag(env_name: &str, plugin: &str, action: &str, parameter: &str) -> String {
+    let Some((_, _, _, candidates)) = FLAG_CANDIDATES
+        .iter()
+        .find(|(p, a, name, _)| *p == plugin && *a == action && *name == parameter)
+    else {
+        return format!("--p-{}", parameter.replace('_', "-"));
+    };
+
+    let help = action_help(env_name, plugin, action);
+    let flag = pick_flag(help.as_deref(), candidates);
+    match &help {
+        Some(help) if !help.contains(flag.as_str()) => {
+            print_error(&format!(
+                "Warning: none of {:?} found in `qiime {} {} --help`; using {}",
+                candidates, plugin, action, flag
+            ));
+        }
+        _ => {}
+    }
+    if flag != candidates[0] {
+        log_action(&format!("Using {} for {} {} {} in {}", flag, plugin, action, parameter, env_name));
+    }
+    flag
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{plan_pipeline, region_primers, PipelineOptions, PlanParams, QiimeFlags, StepAction};
+    use crate::test_support;
+
+    /// The arguments of the planned `fit-classifier-naive-bayes` command.
+    fn fit_classifier_args(flags: &QiimeFlags) -> Vec<String> {
+        let options = PipelineOptions { use_pretrained_classifier: false, ..PipelineOptions::default() };
+        let primers = region_primers(&options.target).unwrap();
+        let steps = plan_pipeline(&PlanParams {
+            options: &options,
+            cores: 1,
+            primers: &primers,
+            flags,
+            trim: true,
+            denoise_single: false,
+            reads_per_batch: None,
+        });
+        steps
+            .into_iter()
+            .find_map(|step| match step.command {
+                StepAction::Qiime { args, .. } if args.iter().any(|arg| arg == "fit-classifier-naive-bayes") => Some(args),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    /// The word following `flag` in `args`.
+    fn value_of<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+        args.iter().position(|arg| arg == flag).map(|i| args[i + 1].as_str())
+    }
+
+    #[test]
+    fn chunk_size_flag_follows_the_installed_help() {
+        let _state = test_support::global_state();
+        test_support::conda_prints("  --p-classify--chunk-size INTEGER\n                        [default: 20000]\n");
+        let flag = resolve_flag("compat-double", "feature-classifier", "fit-classifier-naive-bayes", "classify__chunk_size");
+        assert_eq!(flag, "--p-classify--chunk-size");
+
+        test_support::conda_prints("  --p-classify-chunk-size INTEGER\n                        [default: 20000]\n");
+        let flag = resolve_flag("compat-single", "feature-classifier", "fit-classifier-naive-bayes", "classify__chunk_size");
+        assert_eq!(flag, "--p-classify-chunk-size");
+        let args = fit_classifier_args(&QiimeFlags { classify_chunk_size: flag, ..QiimeFlags::default() });
+        assert_eq!(value_of(&args, "--p-classify-chunk-size"), Some("100000"));
+        assert_eq!(value_of(&args, "--p-classify--chunk-size"), None);
+        // Each environment's help is read once
+        let calls = test_support::conda_calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[1].ends_with("feature-classifier fit-classifier-naive-bayes --help"), "{}", calls[1]);
+    }
+
+    #[test]
+    fn default_chunk_size_flag_is_the_one_qiime_derives() {
+        let args = fit_classifier_args(&QiimeFlags::default());
+        assert_eq!(value_of(&args, "--p-classify--chunk-size"), Some("100000"));
+    }
+
+    #[test]
+    fn first_candidate_wins_without_a_match() {
+        let candidates = ["--p-n-threads", "--p-num-threads"];
+        assert_eq!(pick_flag(Some("  --p-num-threads INTEGER"), &candidates), "--p-num-threads");
+        // A longer flag that merely starts with a candidate doesn't count
+        assert_eq!(pick_flag(Some("  --p-n-threads-max INTEGER"), &candidates), "--p-n-threads");
+        assert_eq!(pick_flag(None, &candidates), "--p-n-threads");
+        assert_eq!(resolve_flag("compat-any", "demux", "summarize", "n"), "--p-n");
+    }
+}
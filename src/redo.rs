@@ -0,0 +1,73 @@
+use std::fs;
+
+/// Log entry written by every run, holding its arguments as a JSON array.
+const COMMAND_LINE_ENTRY: &str = "] Command line: ";
+
+/// The log line recording `args` (the program name and its arguments).
+pub fn command_line_entry(args: &[String]) -> String {
+    format!("Command line: {}", serde_json::to_string(args).unwrap_or_default())
+}
+
+/// Finds the most recent command line recorded in `log`'s contents. Lines
+/// that can't be parsed (a corrupt or truncated log) are skipped.
+pub fn last_command_line(log: &str) -> Option<Vec<String>> {
+    log.lines().rev().find_map(|line| {
+        let (_, json) = line.split_once(COMMAND_LINE_ENTRY)?;
+        serde_json::from_str::<Vec<String>>(json).ok().filter(|args| args.len() > 1)
+    })
+}
+
+/// Reads `log_path` and returns the arguments of the last recorded run, for
+/// the `Redo` subcommand.
+pub fn last_run(log_path: &str) -> Result<Vec<String>, String> {
+    let bytes = fs::read(log_path).map_err(|e| format!("Cannot read {} ({}); nothing to redo", log_path, e))?;
+    last_command_line(&String::from_utf8_lossy(&bytes)).ok_or_else(|| {
+        format!(
+            "No command line recorded in {}; nothing to redo (older logs only describe the command)",
+            log_path
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn the_most_recent_command_line_is_returned() {
+        let first = args(&["windchime", "demultiplex", "--barcodes", "plate 1.tsv"]);
+        let last = args(&["windchime", "pipeline", "--manifest", "manifest.tsv", "--cores", "4"]);
+        let log = format!(
+            "[2026-01-01T10:00:00+00:00] {}\n[2026-01-01T10:00:01+00:00] Step started\n\
+             [2026-01-01T11:00:00+00:00] {}\n[2026-01-01T11:00:05+00:00] Step failed\n",
+            command_line_entry(&first),
+            command_line_entry(&last)
+        );
+        assert_eq!(last_command_line(&log), Some(last));
+    }
+
+    #[test]
+    fn corrupt_and_truncated_entries_are_skipped() {
+        let good = args(&["windchime", "pipeline", "--cores", "2"]);
+        let log = format!(
+            "[t] {}\n[t] Command line: [\"windchime\", \"pipel\n[t] Command line: [\"windchime\"]\n",
+            command_line_entry(&good)
+        );
+        assert_eq!(last_command_line(&log), Some(good));
+        assert_eq!(last_command_line("[t] Starting Windchime with command: Pipeline\n"), None);
+    }
+
+    #[test]
+    fn an_empty_or_missing_log_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("windchime.log");
+        fs::write(&empty, "").unwrap();
+        assert!(last_run(&empty.to_string_lossy()).unwrap_err().contains("nothing to redo"));
+        let missing = dir.path().join("missing.log");
+        assert!(last_run(&missing.to_string_lossy()).unwrap_err().contains("Cannot read"));
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::BufRead;
+
+use crate::fastq_io::open_bufread;
+use crate::logger::log_action;
+
+/// Nucleotide codes QIIME's DNA FASTA format accepts (case-insensitive).
+const IUPAC_NUCLEOTIDES: &[u8] = b"ACGTRYKMSWBDHVN";
+
+/// Scans a reference FASTA (gzipped or not) before it's imported, failing on
+/// the first problem with its line number: sequence data before any header,
+/// a header without an ID, a record without sequence, or a character that
+/// isn't an IUPAC nucleotide code.
+pub fn check_reference_fasta(path: &str) -> Result<(), Box<dyn Error>> {
+    let reader = open_bufread(path).map_err(|e| format!("{}: {}", path, e))?;
+    // Line number of the current record's header
+    let mut header: Option<usize> = None;
+    let mut has_sequence = false;
+    let mut records = 0usize;
+
+    for (index, line) in reader.lines().enumerate() {
+        let line_no = index + 1;
+        let line = line.map_err(|e| format!("{} line {}: {}", path, line_no, e))?;
+        let line = line.trim_end_matches('\r');
+        if let Some(id) = line.strip_prefix('>') {
+            if let Some(header_line) = header
+                && !has_sequence
+            {
+                return Err(format!("{} line {}: record has no sequence", path, header_line).into());
+            }
+            if id.trim().is_empty() {
+                return Err(format!("{} line {}: header has no sequence ID", path, line_no).into());
+            }
+            header = Some(line_no);
+            has_sequence = false;
+            records += 1;
+        } else if line.trim().is_empty() {
+            continue;
+        } else {
+            if header.is_none() {
+                return Err(format!("{} line {}: sequence data before the first '>' header", path, line_no).into());
+            }
+            if let Some((column, c)) = line
+                .char_indices()
+                .find(|(_, c)| !c.is_ascii() || !IUPAC_NUCLEOTIDES.contains(&(*c as u8).to_ascii_uppercase()))
+            {
+                return Err(format!(
+                    "{} line {}, column {}: '{}' is not an IUPAC nucleotide code",
+                    path,
+                    line_no,
+                    column + 1,
+                    c.escape_default()
+                )
+                .into());
+            }
+            has_sequence = true;
+        }
+    }
+
+    match header {
+        None => Err(format!("{}: no FASTA records found", path).into()),
+        Some(header_line) if !has_sequence => {
+            Err(format!("{} line {}: record has no sequence", path, header_line).into())
+        }
+        Some(_) => {
+            log_action(&format!("Reference {} passed IUPAC validation ({} records).", path, records));
+            Ok(())
+        }
+    }
+}
+
+/// Offending IDs listed per side in a consistency report.
+const SAMPLE_IDS: usize = 5;
+
+/// Sequence IDs (up to the first whitespace) of every record in a FASTA.
+fn fasta_ids(path: &str) -> Result<HashSet<String>, Box<dyn Error>> {
+    let mut ids = HashSet::new();
+    for line in open_bufread(path).map_err(|e| format!("{}: {}", path, e))?.lines() {
+        if let Some(header) = line?.strip_prefix('>')
+            && let Some(id) = header.split_whitespace().next()
+        {
+            ids.insert(id.to_string());
+        }
+    }
+    Ok(ids)
+}
+
+/// IDs in the first column of a headerless taxonomy TSV.
+fn taxonomy_ids(path: &str) -> Result<HashSet<String>, Box<dyn Error>> {
+    let mut ids = HashSet::new();
+    for (index, line) in open_bufread(path).map_err(|e| format!("{}: {}", path, e))?.lines().enumerate() {
+        let line = line?;
+        let id = line.split('\t').next().unwrap_or("").trim();
+        // A `Feature ID<TAB>Taxon` header (as QIIME exports write) isn't an ID
+        if !id.is_empty() && (index > 0 || !id.eq_ignore_ascii_case("feature id")) {
+            ids.insert(id.to_string());
+        }
+    }
+    Ok(ids)
+}
+
+/// Describes up to [`SAMPLE_IDS`] of `ids`, sorted, e.g. "3 (A, B, C)".
+fn describe_ids(ids: &[&String]) -> String {
+    let mut sample: Vec<&str> = ids.iter().map(|id| id.as_str()).collect();
+    sample.sort_unstable();
+    let more = if sample.len() > SAMPLE_IDS { ", ..." } else { "" };
+    sample.truncate(SAMPLE_IDS);
+    format!("{} ({}{})", ids.len(), sample.join(", "), more)
+}
+
+/// Checks that every sequence in the reference FASTA has a taxonomy entry and
+/// vice versa. Returns a description of the mismatch, or `None` if the IDs agree.
+pub fn check_reference_ids(fasta: &str, taxonomy: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let sequences = fasta_ids(fasta)?;
+    let taxa = taxonomy_ids(taxonomy)?;
+    let no_taxonomy: Vec<&String> = sequences.difference(&taxa).collect();
+    let no_sequence: Vec<&String> = taxa.difference(&sequences).collect();
+    log_action(&format!(
+        "Reference ID check: {} sequences, {} taxonomy entries, {} without taxonomy, {} without sequence.",
+        sequences.len(),
+        taxa.len(),
+        no_taxonomy.len(),
+        no_sequence.len()
+    ));
+    if no_taxonomy.is_empty() && no_sequence.is_empty() {
+        return Ok(None);
+    }
+    let mut problems = Vec::new();
+    if !no_taxonomy.is_empty() {
+        problems.push(format!("sequences in {} without taxonomy: {}", fasta, describe_ids(&no_taxonomy)));
+    }
+    if !no_sequence.is_empty() {
+        problems.push(format!("taxonomy entries in {} without sequence: {}", taxonomy, describe_ids(&no_sequence)));
+    }
+    Ok(Some(format!("Reference FASTA and taxonomy don't match; {}", problems.join("; "))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{write_file, write_gz};
+    use tempfile::TempDir;
+
+    #[test]
+    fn invalid_character_is_reported_with_its_location() {
+        let dir = TempDir::new().unwrap();
+        let path = write_file(dir.path(), "ref.fasta", ">seq1 Eukaryota\nACGTRYN\n>seq2\nacgt\nACGUAC\n");
+
+        let err = check_reference_fasta(&path).unwrap_err();
+        assert_eq!(err.to_string(), format!("{} line 5, column 4: 'U' is not an IUPAC nucleotide code", path));
+    }
+
+    #[test]
+    fn gzipped_reference_is_scanned() {
+        let dir = TempDir::new().unwrap();
+        let valid = write_gz(dir.path(), "valid.fasta.gz", ">seq1\nACGT\nKMSWBDHV\n>seq2\nacgtn\n");
+        check_reference_fasta(&valid.to_string_lossy()).unwrap();
+
+        let broken = write_gz(dir.path(), "broken.fasta.gz", ">seq1\nACGT\n>\nACGT\n");
+        let broken = broken.to_string_lossy();
+        let err = check_reference_fasta(&broken).unwrap_err();
+        assert_eq!(err.to_string(), format!("{} line 3: header has no sequence ID", broken));
+    }
+
+    #[test]
+    fn malformed_records_are_rejected() {
+        let dir = TempDir::new().unwrap();
+        for (contents, problem) in [
+            ("ACGT\n>seq1\nACGT\n", "line 1: sequence data before the first '>' header"),
+            (">seq1\n>seq2\nACGT\n", "line 1: record has no sequence"),
+            (">seq1\nACGT\n>seq2\n", "line 3: record has no sequence"),
+        ] {
+            let path = write_file(dir.path(), "ref.fasta", contents);
+            assert_eq!(check_reference_fasta(&path).unwrap_err().to_string(), format!("{} {}", path, problem));
+        }
+    }
+
+    #[test]
+    fn mismatched_fasta_and_taxonomy_ids_are_reported() {
+        let dir = TempDir::new().unwrap();
+        let fasta = write_file(dir.path(), "ref.fasta", ">a desc\nACGT\n>b\nACGT\n>c\nACGT\n>d\nACGT\n");
+        let taxonomy = write_file(dir.path(), "ref_tax.tsv", "a\tEukaryota\nb\tEukaryota\nx\tBacteria\n");
+        let report = check_reference_ids(&fasta, &taxonomy).unwrap().unwrap();
+        assert_eq!(
+            report,
+            format!(
+                "Reference FASTA and taxonomy don't match; sequences in {} without taxonomy: 2 (c, d); \
+                 taxonomy entries in {} without sequence: 1 (x)",
+                fasta, taxonomy
+            )
+        );
+    }
+
+    #[test]
+    fn matching_ids_pass_and_long_lists_are_sampled() {
+        let dir = TempDir::new().unwrap();
+        let fasta = write_file(dir.path(), "ref.fasta", ">a\nACGT\n>b\nACGT\n");
+        // SILVA's header line isn't an ID
+        let taxonomy = write_file(dir.path(), "ref_tax.tsv", "Feature ID\tTaxon\na\tx\nb\ty\n");
+        assert_eq!(check_reference_ids(&fasta, &taxonomy).unwrap(), None);
+
+        let ids: Vec<String> = ["g", "b", "f", "a", "e", "c", "d"].iter().map(|id| id.to_string()).collect();
+        assert_eq!(describe_ids(&ids.iter().collect::<Vec<_>>()), "7 (a, b, c, d, e, ...)");
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use csv::{ReaderBuilder, WriterBuilder};
+
+use crate::color_print::print_success;
+use crate::demultiplex::read_text_file;
+use crate::logger::log_action;
+
+/// Reads a `sample_id<TAB>group` mapping. Blank lines, `#` comments and a
+/// header row (first field `sample_id`, `sample-id` or `sample`) are skipped.
+pub fn read_groups(path: &str) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut groups: Vec<(String, String)> = Vec::new();
+    for (index, line) in read_text_file(path)?.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        if groups.is_empty() && ["sample_id", "sample-id", "sample"].contains(&fields[0].to_lowercase().as_str()) {
+            continue;
+        }
+        let (sample, group) = match fields.as_slice() {
+            [sample, group, ..] if !sample.is_empty() && !group.is_empty() => (*sample, *group),
+            _ => return Err(format!("{} line {}: expected a sample ID and a group separated by a tab", path, index + 1).into()),
+        };
+        if let Some((_, earlier)) = groups.iter().find(|(s, _)| s == sample) {
+            return Err(format!("{} line {}: sample '{}' is already in group '{}'", path, index + 1, sample, earlier).into());
+        }
+        groups.push((sample.to_string(), group.to_string()));
+    }
+    if groups.is_empty() {
+        return Err(format!("{} lists no sample groups", path).into());
+    }
+    Ok(groups)
+}
+
+/// Writes `output` from the merged ASV table `merged`, with the count columns
+/// of the samples `groups` maps to the same group summed into one column
+/// named after the group. Samples in no group keep their own column, and
+/// taxonomy columns (prefixed with `database`) are copied unchanged. Fails
+/// if the mapping names a sample that isn't in the table, or a group that
+/// collides with an ungrouped sample's column.
+pub fn write_grouped_table(
+    merged: &str,
+    mapping: &str,
+    database: &str,
+    output: &str,
+) -> Result<(), Box<dyn Error>> {
+    let groups = read_groups(mapping)?;
+    let mut reader = ReaderBuilder::new().delimiter(b'\t').from_path(merged)?;
+    let headers = reader.headers()?.clone();
+    let taxonomy_prefix = format!("{}_", database);
+    let sample_columns: Vec<usize> = (1..headers.len())
+        .filter(|&i| !headers[i].starts_with(&taxonomy_prefix))
+        .collect();
+
+    let unknown: Vec<&str> = groups
+        .iter()
+        .map(|(sample, _)| sample.as_str())
+        .filter(|sample| !sample_columns.iter().any(|&i| &headers[i] == *sample))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!(
+            "{} maps sample IDs that are not in {}: {}",
+            mapping,
+            merged,
+            unknown.join(", ")
+        )
+        .into());
+    }
+
+    // Output columns in order of first appearance, each summing its samples
+    let group_of: HashMap<&str, &str> = groups.iter().map(|(s, g)| (s.as_str(), g.as_str())).collect();
+    let mut columns: Vec<(String, Vec<usize>)> = Vec::new();
+    for &i in &sample_columns {
+        let name = group_of.get(&headers[i]).copied().unwrap_or(&headers[i]);
+        match columns.iter_mut().find(|(column, _)| column == name) {
+            Some((_, members)) => members.push(i),
+            None => columns.push((name.to_string(), vec![i])),
+        }
+    }
+    let collisions: Vec<&str> = columns
+        .iter()
+        .filter(|(name, members)| {
+            members.len() > 1 && members.iter().any(|&i| !group_of.contains_key(&headers[i]) && &headers[i] == name)
+        })
+        .map(|(name, _)| name.as_str())
+        .collect();
+    if !collisions.is_empty() {
+        return Err(format!(
+            "{}: group names are also ungrouped sample IDs: {}; rename the groups",
+            mapping,
+            collisions.join(", ")
+        )
+        .into());
+    }
+    let taxonomy_columns: Vec<usize> = (1..headers.len()).filter(|i| !sample_columns.contains(i)).collect();
+
+    let mut wtr = WriterBuilder::new().delimiter(b'\t').from_path(output)?;
+    let mut header = vec![headers[0].to_string()];
+    header.extend(columns.iter().map(|(name, _)| name.clone()));
+    header.extend(taxonomy_columns.iter().map(|&i| headers[i].to_string()));
+    wtr.write_record(&header)?;
+    for (line, record) in reader.records().enumerate() {
+        let record = record?;
+        let mut row = vec![record[0].to_string()];
+        for (name, members) in &columns {
+            let mut total = 0.0;
+            for &i in members {
+                let value = record.get(i).unwrap_or("").trim();
+                total += if value.is_empty() {
+                    0.0
+                } else {
+                    value.parse::<f64>().map_err(|_| {
+                        format!("{} line {}: count '{}' for {} is not a number", merged, line + 2, value, name)
+                    })?
+                };
+            }
+            row.push(total.to_string());
+        }
+        row.extend(taxonomy_columns.iter().map(|&i| record.get(i).unwrap_or("").to_string()));
+        wtr.write_record(&row)?;
+    }
+    wtr.flush()?;
+
+    let grouped = columns.iter().filter(|(_, members)| members.len() > 1).count();
+    log_action(&format!(
+        "Grouped {} sample columns into {} ({} summed groups) in {}",
+        sample_columns.len(),
+        columns.len(),
+        grouped,
+        output
+    ));
+    print_success(&format!(
+        "Replicate-grouped table ({} columns from {} samples) written to {}",
+        columns.len(),
+        sample_columns.len(),
+        output
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::write_file;
+    use std::fs;
+
+    const MERGED: &str = "\
+ASV\trep1\trep2\tsolo\tpr2_Domain\tpr2_Supergroup
+asv1\t3\t4\t1\tEukaryota\tTSAR
+asv2\t0\t2.5\t\tEukaryota\t
+";
+
+    #[test]
+    fn replicate_columns_are_summed_into_their_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let merged = write_file(dir.path(), "asv_count_tax.tsv", MERGED);
+        let mapping = write_file(dir.path(), "groups.tsv", "sample_id\tgroup\nrep1\tsiteA\nrep2\tsiteA\n");
+        let output = dir.path().join("grouped.tsv").to_string_lossy().into_owned();
+
+        write_grouped_table(&merged, &mapping, "pr2", &output).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "ASV\tsiteA\tsolo\tpr2_Domain\tpr2_Supergroup\n\
+             asv1\t7\t1\tEukaryota\tTSAR\n\
+             asv2\t2.5\t0\tEukaryota\t\n"
+        );
+    }
+
+    #[test]
+    fn the_mapping_must_name_samples_in_the_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let merged = write_file(dir.path(), "asv_count_tax.tsv", MERGED);
+        let output = dir.path().join("grouped.tsv").to_string_lossy().into_owned();
+
+        let unknown = write_file(dir.path(), "unknown.tsv", "rep1\tsiteA\nrep9\tsiteA\n");
+        let err = write_grouped_table(&merged, &unknown, "pr2", &output).unwrap_err();
+        assert_eq!(err.to_string(), format!("{} maps sample IDs that are not in {}: rep9", unknown, merged));
+
+        let colliding = write_file(dir.path(), "colliding.tsv", "rep1\tsolo\n");
+        let err = write_grouped_table(&merged, &colliding, "pr2", &output).unwrap_err();
+        assert!(err.to_string().ends_with("group names are also ungrouped sample IDs: solo; rename the groups"), "{}", err);
+
+        let repeated = write_file(dir.path(), "repeated.tsv", "rep1\tsiteA\nrep1\tsiteB\n");
+        let err = read_groups(&repeated).unwrap_err();
+        assert_eq!(err.to_string(), format!("{} line 2: sample 'rep1' is already in group 'siteA'", repeated));
+    }
+}
@@ -0,0 +1,235 @@
+use std::error::Error;
+use std::fs::{self, File};
+
+use zip::ZipArchive;
+
+use crate::color_print::{print_error, print_info, print_success};
+use crate::pipeline::{self, PipelineOptions, PipelineStep, PlanParams, QiimeFlags};
+use crate::summary;
+
+/// What was found at one of the pipeline's expected output paths.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArtifactState {
+    Present,
+    Absent,
+    /// Exists but can't be used, e.g. a truncated `.qza`.
+    Invalid(String),
+    /// Exists but `--skip-existing` would regenerate it.
+    Stale(String),
+}
+
+impl ArtifactState {
+    fn label(&self) -> &'static str {
+        match self {
+            ArtifactState::Present => "present",
+            ArtifactState::Absent => "absent",
+            ArtifactState::Invalid(_) => "INVALID",
+            ArtifactState::Stale(_) => "stale",
+        }
+    }
+}
+
+/// One expected output and the step that produces it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArtifactStatus {
+    /// 1-based position of the producing step in the plan.
+    pub step: usize,
+    pub path: String,
+    pub state: ArtifactState,
+}
+
+/// Checks that a `.qza`/`.qzv` is a readable QIIME archive: a zip holding
+/// `<uuid>/metadata.yaml`. Cheaper than `qiime tools validate` and needs no
+/// conda environment, so it catches truncated or partially written artifacts.
+fn check_qiime_archive(path: &str) -> Result<(), String> {
+    let mut archive = ZipArchive::new(File::open(path).map_err(|e| e.to_string())?)
+        .map_err(|e| format!("not a readable QIIME archive ({})", e))?;
+    let has_metadata = archive.file_names().any(|name| {
+        let mut parts = name.split('/');
+        parts.next().is_some_and(|uuid| !uuid.is_empty()) && parts.next() == Some("metadata.yaml") && parts.next().is_none()
+    });
+    if !has_metadata {
+        return Err("archive has no <uuid>/metadata.yaml".to_string());
+    }
+    // Reading every entry verifies the CRCs of a partially written file
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).map_err(|e| e.to_string())?;
+        std::io::copy(&mut entry, &mut std::io::sink()).map_err(|e| format!("corrupt entry ({})", e))?;
+    }
+    Ok(())
+}
+
+/// Whether `path` exists and, for files Windchime can check itself, looks usable.
+pub fn artifact_state(path: &str) -> ArtifactState {
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return ArtifactState::Absent,
+    };
+    if meta.is_file() && meta.len() == 0 {
+        return ArtifactState::Invalid("empty file".to_string());
+    }
+    if (path.ends_with(".qza") || path.ends_with(".qzv"))
+        && let Err(reason) = check_qiime_archive(path)
+    {
+        return ArtifactState::Invalid(reason);
+    }
+    ArtifactState::Present
+}
+
+/// The state of every output `steps` produce, and the index of the first
+/// step `--skip-existing` would run: one with a missing output, or a stale
+/// one under the same rules as during a run (see [`pipeline::stale_reason`]).
+/// Steps without outputs (checks) always run and are not counted.
+pub fn pipeline_status(steps: &[PipelineStep]) -> (Vec<ArtifactStatus>, Option<usize>) {
+    let mut statuses = Vec::new();
+    let mut next_step = None;
+    let mut regenerated: Vec<&str> = Vec::new();
+    for (index, step) in steps.iter().enumerate().filter(|(_, step)| !step.outputs.is_empty()) {
+        let mut states: Vec<ArtifactState> = step.outputs.iter().map(|output| artifact_state(output)).collect();
+        // Like a run, only existence decides; invalid outputs are reported but kept
+        let exist = states.iter().all(|state| *state != ArtifactState::Absent);
+        let stale = if exist { pipeline::stale_reason(step, &regenerated) } else { None };
+        if let Some(reason) = &stale {
+            states = vec![ArtifactState::Stale(reason.clone()); states.len()];
+        }
+        if !exist || stale.is_some() {
+            next_step.get_or_insert(index);
+            regenerated.extend(step.outputs.iter().map(String::as_str));
+        }
+        for (output, state) in step.outputs.iter().zip(states) {
+            // A check step can use another step's output as its marker; list each path once
+            if statuses.iter().any(|status: &ArtifactStatus| status.path == *output) {
+                continue;
+            }
+            statuses.push(ArtifactStatus { step: index + 1, path: output.clone(), state });
+        }
+    }
+    (statuses, next_step)
+}
+
+/// Plans the run `options` describes, without touching conda or the inputs:
+/// Cutadapt is assumed to run, and single-end denoising only with
+/// `--denoise-mode single`.
+fn plan_for_status(options: &PipelineOptions) -> Result<Vec<PipelineStep>, Box<dyn Error>> {
+    let primers = pipeline::region_primers(&options.target)?;
+    let (cores, _) = pipeline::effective_cores(options.cores, summary::available_parallelism(), options.clamp_cores);
+    Ok(pipeline::plan_pipeline(&PlanParams {
+        options,
+        cores,
+        primers: &primers,
+        flags: &QiimeFlags::default(),
+        trim: true,
+        denoise_single: options.single_end || options.denoise_mode == pipeline::DenoiseMode::Single,
+        reads_per_batch: options.reads_per_batch,
+    }))
+}
+
+/// Prints which of the pipeline's outputs exist, which are missing, invalid
+/// or stale, and the step a `--skip-existing` run would start from.
+pub fn print_status(options: &PipelineOptions) -> Result<(), Box<dyn Error>> {
+    let steps = plan_for_status(options)?;
+    let (statuses, next_step) = pipeline_status(&steps);
+
+    println!("{:<7} {:<8} Output", "Step", "Status");
+    for status in &statuses {
+        let detail = match &status.state {
+            ArtifactState::Invalid(reason) | ArtifactState::Stale(reason) => format!(" ({})", reason),
+            _ => String::new(),
+        };
+        println!(
+            "{:<7} {:<8} {}{}",
+            format!("{}/{}", status.step, steps.len()),
+            status.state.label(),
+            status.path,
+            detail
+        );
+    }
+
+    let count = |label: &str| statuses.iter().filter(|status| status.state.label() == label).count();
+    print_info(&format!(
+        "{} outputs: {} present, {} absent, {} invalid, {} stale.",
+        statuses.len(),
+        count("present"),
+        count("absent"),
+        count("INVALID"),
+        count("stale")
+    ));
+    if count("INVALID") > 0 {
+        print_error("Invalid outputs are not regenerated by --skip-existing while they exist; remove them first.");
+    }
+    match next_step {
+        Some(index) => print_info(&format!(
+            "Next step with --skip-existing: {}/{} '{}'.",
+            index + 1,
+            steps.len(),
+            steps[index].name
+        )),
+        None => print_success("All outputs exist; a --skip-existing run would only repeat the checks."),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    /// Writes a minimal QIIME archive (`<uuid>/metadata.yaml` and a data file) to `path`.
+    fn write_qiime_archive(path: &str) {
+        fs::create_dir_all(std::path::Path::new(path).parent().unwrap()).unwrap();
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        for (name, contents) in [("0a1b/metadata.yaml", "uuid: 0a1b\n"), ("0a1b/data/table.tsv", "asv\ts1\n")] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn state_of<'a>(statuses: &'a [ArtifactStatus], path: &str) -> &'a ArtifactState {
+        &statuses.iter().find(|status| status.path == path).unwrap().state
+    }
+
+    #[test]
+    fn partial_outputs_are_reported_with_the_next_step() {
+        let _state = test_support::global_state();
+        let steps = plan_for_status(&PipelineOptions::default()).unwrap();
+        let output = |name: &str| steps.iter().flat_map(|step| &step.outputs).find(|o| o.ends_with(name)).unwrap().clone();
+        let (demux_qza, demux_qzv, trimmed) =
+            (output("/paired-end-demux.qza"), output("/paired-end-demux.qzv"), output("/paired-end-demux-trimmed.qza"));
+        write_qiime_archive(&demux_qza);
+        fs::write(&demux_qzv, "truncated").unwrap();
+
+        let (statuses, next_step) = pipeline_status(&steps);
+
+        assert_eq!(*state_of(&statuses, &demux_qza), ArtifactState::Present);
+        assert!(matches!(state_of(&statuses, &demux_qzv), ArtifactState::Invalid(reason) if reason.starts_with("not a readable QIIME archive")));
+        assert_eq!(*state_of(&statuses, &trimmed), ArtifactState::Absent);
+        assert_eq!(steps[next_step.unwrap()].name, "Trimming reads with Cutadapt");
+        // Each path is listed once, under the step that produces it
+        assert_eq!(statuses.iter().filter(|status| status.path == demux_qzv).count(), 1);
+        assert!(statuses.iter().skip_while(|status| status.path != trimmed).all(|status| status.state == ArtifactState::Absent));
+    }
+
+    #[test]
+    fn an_output_without_its_parameter_record_is_stale() {
+        let _state = test_support::global_state();
+        let steps = plan_for_status(&PipelineOptions::default()).unwrap();
+        let trim = steps.iter().position(|step| step.name == "Trimming reads with Cutadapt").unwrap();
+        for step in &steps[..=trim] {
+            for output in &step.outputs {
+                write_qiime_archive(output);
+            }
+        }
+
+        let (statuses, next_step) = pipeline_status(&steps);
+
+        assert_eq!(next_step, Some(trim));
+        let trimmed = &steps[trim].outputs[0];
+        assert!(matches!(state_of(&statuses, trimmed), ArtifactState::Stale(reason) if reason.starts_with("no parameter record")));
+        assert!(statuses.iter().filter(|status| status.step <= trim).all(|status| status.state == ArtifactState::Present));
+        assert_eq!(artifact_state(&test_support::output_dir().join("missing.qza").to_string_lossy()), ArtifactState::Absent);
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use crate::color_print::print_info;
+use crate::pipeline::output_prefix;
+use crate::output_dir;
+
+/// A setting as the user asked for it and as it was actually applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectiveSetting {
+    pub name: String,
+    pub requested: String,
+    pub effective: String,
+}
+
+/// Settings recorded during this run, in the order they were resolved.
+static SETTINGS: Lazy<Mutex<Vec<EffectiveSetting>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Records the requested and effective value of a setting. Recording the same
+/// name again replaces the earlier entry.
+pub fn record_setting(name: &str, requested: impl ToString, effective: impl ToString) {
+    let setting = EffectiveSetting {
+        name: name.to_string(),
+        requested: requested.to_string(),
+        effective: effective.to_string(),
+    };
+    let mut settings = SETTINGS.lock().unwrap();
+    match settings.iter_mut().find(|s| s.name == setting.name) {
+        Some(existing) => *existing = setting,
+        None => settings.push(setting),
+    }
+}
+
+/// Returns the settings recorded so far.
+pub fn recorded_settings() -> Vec<EffectiveSetting> {
+    SETTINGS.lock().unwrap().clone()
+}
+
+/// Number of threads the OS will actually give us (cgroup/affinity aware).
+pub fn available_parallelism() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// Prints the effective settings and writes them to `OUTPUT_DIR/<prefix>run_summary.tsv`.
+/// Does nothing if no settings were recorded.
+pub fn write_run_summary() -> io::Result<()> {
+    let settings = recorded_settings();
+    if settings.is_empty() {
+        return Ok(());
+    }
+
+    print_info("Effective settings:");
+    for s in &settings {
+        if s.requested == s.effective {
+            println!("  {:<28} {}", s.name, s.effective);
+        } else {
+            println!("  {:<28} {} (requested: {})", s.name, s.effective, s.requested);
+        }
+    }
+
+    let mut file = File::create(format!("{}/{}run_summary.tsv", output_dir(), output_prefix()))?;
+    writeln!(file, "setting\trequested\teffective")?;
+    for s in &settings {
+        writeln!(file, "{}\t{}\t{}", s.name, s.requested, s.effective)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::effective_cores;
+    use crate::test_support;
+
+    #[test]
+    fn resolved_settings_are_reported_next_to_the_requested_ones() {
+        let _state = test_support::global_state();
+        let (auto, _) = effective_cores(0, 6, false);
+        record_setting("cores", "0 (auto)", auto);
+        let (clamped, _) = effective_cores(32, 6, true);
+        record_setting("cutadapt threads", 32, clamped);
+        record_setting("demux gzip level", "best", 9);
+        // A later resolution replaces the earlier record
+        record_setting("demux gzip level", "best", 6);
+
+        let settings = recorded_settings();
+        let setting = |name: &str| settings.iter().find(|s| s.name == name).unwrap().clone();
+        assert_eq!(setting("cores").effective, "6");
+        assert_eq!(setting("cutadapt threads"), EffectiveSetting {
+            name: "cutadapt threads".to_string(),
+            requested: "32".to_string(),
+            effective: "6".to_string(),
+        });
+        assert_eq!(settings.iter().filter(|s| s.name == "demux gzip level").count(), 1);
+
+        write_run_summary().unwrap();
+        let tsv = std::fs::read_to_string(test_support::output_dir().join("run_summary.tsv")).unwrap();
+        let mut lines = tsv.lines();
+        assert_eq!(lines.next(), Some("setting\trequested\teffective"));
+        let rows: Vec<&str> = lines.collect();
+        assert!(rows.contains(&"cores\t0 (auto)\t6"), "{}", tsv);
+        assert!(rows.contains(&"cutadapt threads\t32\t6"), "{}", tsv);
+        assert!(rows.contains(&"demux gzip level\tbest\t6"), "{}", tsv);
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use csv::{ReaderBuilder, WriterBuilder};
+
+use crate::color_print::{print_info, print_success};
+use crate::logger::log_action;
+use crate::pipeline::Database;
+
+/// Rank names of the PR2 (v5) lineage, in order. Lineages deeper than this
+/// list get generic `rank_N` labels.
+const PR2_RANKS: [&str; 9] = [
+    "Domain",
+    "Supergroup",
+    "Division",
+    "Subdivision",
+    "Class",
+    "Order",
+    "Family",
+    "Genus",
+    "Species",
+];
+
+/// How many ASVs (and reads) were resolved at least down to a given rank.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RankCompleteness {
+    pub rank: String,
+    pub asvs: usize,
+    pub reads: f64,
+}
+
+/// Reference databases recognised in a classifier's file name, with the label
+/// used for their columns. Checked in order.
+const KNOWN_DATABASES: [(&str, &str); 6] = [
+    ("pr2", "pr2"),
+    ("silva", "silva"),
+    ("unite", "unite"),
+    ("gtdb", "gtdb"),
+    ("greengenes", "gg"),
+    ("gg2", "gg"),
+];
+
+/// Label for the reference database, used to prefix taxonomy columns: that
+/// of the built-in `reference` (`pr2`, `silva` or `unite`), otherwise guessed from the
+/// classifier's file name (`silva`, `unite`, ...), falling back to `ref`.
+pub fn database_label(classifier: Option<&str>, reference: Database) -> String {
+    let Some(classifier) = classifier else {
+        return reference.label().to_string();
+    };
+    let file_name = std::path::Path::new(classifier)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    KNOWN_DATABASES
+        .iter()
+        .find(|(pattern, _)| file_name.contains(pattern))
+        .map_or("ref", |(_, label)| label)
+        .to_string()
+}
+
+/// Database labels suited to each target region: PR2 and SILVA cover
+/// eukaryotic 18S, SILVA, GTDB and Greengenes prokaryotic 16S, and UNITE
+/// fungal ITS.
+const REGION_DATABASES: [(&str, &[&str]); 4] = [
+    ("16s", &["silva", "gtdb", "gg"]),
+    ("18sv4", &["pr2", "silva"]),
+    ("18sv9", &["pr2", "silva"]),
+    ("its", &["unite"]),
+];
+
+/// Explains why classifying `target` amplicons against `database` (a
+/// [`database_label`]) gives meaningless results, or `None` if the pairing is
+/// sensible or the database can't be identified (`ref`).
+pub fn reference_mismatch(target: &str, database: &str) -> Option<String> {
+    let target = target.to_lowercase();
+    let (_, compatible) = REGION_DATABASES.iter().find(|(region, _)| *region == target)?;
+    if database == "ref" || compatible.contains(&database) {
+        return None;
+    }
+    Some(format!(
+        "target region '{}' is classified against {}, which is not a reference for it (use {}); \
+         most ASVs would come out unassigned or misassigned",
+        target,
+        database,
+        compatible.join(", ")
+    ))
+}
+
+/// Rank named by a SILVA/UNITE/GTDB-style prefix such as `g__Bacillus`.
+fn prefixed_rank(rank: &str) -> Option<&'static str> {
+    let (prefix, _) = rank.trim().split_once("__")?;
+    Some(match prefix {
+        "k" => "Kingdom",
+        "d" => "Domain",
+        "p" => "Phylum",
+        "c" => "Class",
+        "o" => "Order",
+        "f" => "Family",
+        "g" => "Genus",
+        "s" => "Species",
+        _ => return None,
+    })
+}
+
+/// Names the ranks of a set of `;`-delimited lineages, as deep as the deepest
+/// one. A rank is named from its `x__` prefix where any lineage has one;
+/// otherwise PR2's rank names are used if `pr2` is set, and `rank_N` if not.
+pub fn lineage_rank_names<'a>(lineages: impl IntoIterator<Item = &'a str>, pr2: bool) -> Vec<String> {
+    let mut names: Vec<Option<&'static str>> = Vec::new();
+    for lineage in lineages {
+        for (index, rank) in lineage.split(';').enumerate() {
+            if names.len() <= index {
+                names.push(None);
+            }
+            if names[index].is_none() {
+                names[index] = prefixed_rank(rank);
+            }
+        }
+    }
+    names
+        .iter()
+        .enumerate()
+        .map(|(index, name)| match name {
+            Some(name) => name.to_string(),
+            None if pr2 => PR2_RANKS
+                .get(index)
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| format!("rank_{}", index + 1)),
+            None => format!("rank_{}", index + 1),
+        })
+        .collect()
+}
+
+/// Counts the leading, non-empty ranks of a `;`-delimited lineage.
+///
+/// Ranks that are blank or only carry a prefix such as `g__` end the
+/// lineage, as does an `Unassigned` label.
+pub fn resolved_depth(lineage: &str) -> usize {
+    lineage
+        .split(';')
+        .map(str::trim)
+        .take_while(|rank| {
+            !rank.is_empty() && !rank.ends_with("__") && !rank.eq_ignore_ascii_case("unassigned")
+        })
+        .count()
+}
+
+/// Confidence of each of the first `ranks` ranks of a classification, for
+/// filtering at a chosen rank. A `;`-delimited `confidence` gives one value
+/// per rank. A single value (what `classify-sklearn` reports) is the
+/// confidence of the deepest assigned rank; shallower ranks are at least as
+/// confident, so it is repeated down to that rank as a lower bound. Ranks
+/// below the assignment are left empty.
+pub fn rank_confidences(lineage: &str, confidence: &str, ranks: usize) -> Vec<String> {
+    let mut values: Vec<String> = if confidence.contains(';') {
+        confidence.split(';').map(|value| value.trim().to_string()).take(ranks).collect()
+    } else if confidence.trim().parse::<f64>().is_ok() {
+        vec![confidence.trim().to_string(); resolved_depth(lineage).min(ranks)]
+    } else {
+        Vec::new()
+    };
+    values.resize(ranks, String::new());
+    values
+}
+
+/// Sums the per-sample counts of each feature in a `biom convert --to-tsv` table.
+fn read_feature_counts(asv_table_tsv: &str) -> Result<HashMap<String, f64>, Box<dyn Error>> {
+    let reader = BufReader::new(File::open(asv_table_tsv)?);
+    let mut counts = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        // Both the "# Constructed from biom file" line and the "#OTU ID" header start with '#'
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let feature_id = fields.next().unwrap_or("").to_string();
+        let total: f64 = fields.filter_map(|v| v.trim().parse::<f64>().ok()).sum();
+        counts.insert(feature_id, total);
+    }
+    Ok(counts)
+}
+
+/// Tallies, for every rank, how many features were resolved at least that deep.
+///
+/// `taxonomy_tsv` is the exported QIIME taxonomy (`Feature ID`, `Taxon`, ...).
+/// When `read_counts` is given, each feature also contributes its read count.
+/// Ranks are named as by [`lineage_rank_names`].
+pub fn rank_completeness(
+    taxonomy_tsv: &str,
+    read_counts: Option<&HashMap<String, f64>>,
+    pr2: bool,
+) -> Result<(usize, f64, Vec<RankCompleteness>), Box<dyn Error>> {
+    let mut reader = ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(true)
+        .from_path(taxonomy_tsv)?;
+
+    let mut total_asvs = 0;
+    let mut total_reads = 0.0;
+    let mut ranks: Vec<RankCompleteness> = Vec::new();
+    let mut lineages: Vec<String> = Vec::new();
+    for record in reader.records() {
+        let rec = record?;
+        let feature_id = rec.get(0).unwrap_or("");
+        // Skip QIIME's "#q2:types" directive row
+        if feature_id.starts_with('#') {
+            continue;
+        }
+        let lineage = rec.get(1).unwrap_or("");
+        let depth = resolved_depth(lineage);
+        lineages.push(lineage.to_string());
+        let reads = read_counts
+            .and_then(|counts| counts.get(feature_id).copied())
+            .unwrap_or(0.0);
+
+        total_asvs += 1;
+        total_reads += reads;
+        while ranks.len() < depth {
+            ranks.push(RankCompleteness { rank: String::new(), asvs: 0, reads: 0.0 });
+        }
+        for entry in ranks.iter_mut().take(depth) {
+            entry.asvs += 1;
+            entry.reads += reads;
+        }
+    }
+    let names = lineage_rank_names(lineages.iter().map(String::as_str), pr2);
+    for (entry, name) in ranks.iter_mut().zip(names) {
+        entry.rank = name;
+    }
+    Ok((total_asvs, total_reads, ranks))
+}
+
+/// Writes `rank_completeness.tsv` from the classified taxonomy and prints a
+/// one-line summary such as "62.0% to Genus, 31.0% to Species".
+///
+/// If `asv_table_tsv` exists, read-weighted fractions are included as well.
+/// `database` is the label from [`database_label`].
+pub fn write_rank_completeness(
+    taxonomy_tsv: &str,
+    asv_table_tsv: &str,
+    output_tsv: &str,
+    database: &str,
+) -> Result<(), Box<dyn Error>> {
+    log_action(&format!("Summarizing rank completeness of {}", taxonomy_tsv));
+    let read_counts = if std::path::Path::new(asv_table_tsv).exists() {
+        Some(read_feature_counts(asv_table_tsv)?)
+    } else {
+        None
+    };
+    let (total_asvs, total_reads, ranks) = rank_completeness(taxonomy_tsv, read_counts.as_ref(), database == "pr2")?;
+
+    let fraction = |part: f64, whole: f64| if whole > 0.0 { part / whole } else { 0.0 };
+
+    let mut wtr = WriterBuilder::new().delimiter(b'\t').from_path(output_tsv)?;
+    wtr.write_record(["rank", "asvs", "asv_fraction", "reads", "read_fraction"])?;
+    for entry in &ranks {
+        wtr.write_record([
+            entry.rank.clone(),
+            entry.asvs.to_string(),
+            format!("{:.4}", fraction(entry.asvs as f64, total_asvs as f64)),
+            format!("{}", entry.reads),
+            format!("{:.4}", fraction(entry.reads, total_reads)),
+        ])?;
+    }
+    wtr.flush()?;
+
+    let summary: Vec<String> = ranks
+        .iter()
+        .map(|entry| {
+            format!(
+                "{:.1}% to {}",
+                100.0 * fraction(entry.asvs as f64, total_asvs as f64),
+                entry.rank
+            )
+        })
+        .collect();
+    print_info(&format!("Rank completeness ({} ASVs): {}", total_asvs, summary.join(", ")));
+    if read_counts.is_some() {
+        let weighted: Vec<String> = ranks
+            .iter()
+            .map(|entry| format!("{:.1}% to {}", 100.0 * fraction(entry.reads, total_reads), entry.rank))
+            .collect();
+        print_info(&format!("Rank completeness (read-weighted): {}", weighted.join(", ")));
+    }
+    print_success(&format!("Rank completeness table written to {}", output_tsv));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::write_file;
+    use tempfile::TempDir;
+
+    const TAXONOMY: &str = "Feature ID\tTaxon\tConfidence\n\
+                            #q2:types\tcategorical\tnumeric\n\
+                            asv1\tEukaryota;Alveolata;Dinoflagellata\t0.91\n\
+                            asv2\tEukaryota;Alveolata\t0.85\n\
+                            asv3\tEukaryota\t0.99\n\
+                            asv4\tUnassigned\t0.52\n";
+
+    const ASV_TABLE: &str = "# Constructed from biom file\n\
+                             #OTU ID\tS1\tS2\n\
+                             asv1\t10\t5\n\
+                             asv2\t3\t0\n\
+                             asv3\t1\t1\n\
+                             asv4\t0\t2\n";
+
+    #[test]
+    fn completeness_counts_asvs_and_reads_resolved_to_each_rank() {
+        let dir = TempDir::new().unwrap();
+        let taxonomy = write_file(dir.path(), "taxonomy.tsv", TAXONOMY);
+        let table = write_file(dir.path(), "asv-table.tsv", ASV_TABLE);
+        let output = dir.path().join("rank_completeness.tsv").to_string_lossy().into_owned();
+
+        write_rank_completeness(&taxonomy, &table, &output, "pr2").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "rank\tasvs\tasv_fraction\treads\tread_fraction\n\
+             Domain\t3\t0.7500\t20\t0.9091\n\
+             Supergroup\t2\t0.5000\t18\t0.8182\n\
+             Division\t1\t0.2500\t15\t0.6818\n"
+        );
+    }
+
+    #[test]
+    fn completeness_without_a_table_counts_only_asvs() {
+        let dir = TempDir::new().unwrap();
+        let taxonomy = write_file(dir.path(), "taxonomy.tsv", TAXONOMY);
+        let missing_table = dir.path().join("missing.tsv").to_string_lossy().into_owned();
+
+        let (asvs, reads, ranks) = rank_completeness(&taxonomy, None, false).unwrap();
+        assert_eq!((asvs, reads), (4, 0.0));
+        let counts: Vec<(&str, usize)> = ranks.iter().map(|entry| (entry.rank.as_str(), entry.asvs)).collect();
+        assert_eq!(counts, vec![("rank_1", 3), ("rank_2", 2), ("rank_3", 1)]);
+
+        let output = dir.path().join("rank_completeness.tsv").to_string_lossy().into_owned();
+        write_rank_completeness(&taxonomy, &missing_table, &output, "ref").unwrap();
+        assert!(std::fs::read_to_string(&output).unwrap().contains("rank_1\t3\t0.7500\t0\t0.0000\n"));
+    }
+
+    #[test]
+    fn mismatched_regions_and_references_are_explained() {
+        let mismatch = reference_mismatch("16S", "pr2").unwrap();
+        assert!(mismatch.starts_with("target region '16s' is classified against pr2, which is not a reference for it (use silva, gtdb, gg)"));
+        assert!(reference_mismatch("18sv4", "unite").unwrap().contains("(use pr2, silva)"));
+        assert!(reference_mismatch("its", "silva").is_some());
+
+        for (target, database) in [("16s", "silva"), ("18sv9", "pr2"), ("18sv4", "silva"), ("its", "unite"), ("16s", "ref")] {
+            assert_eq!(reference_mismatch(target, database), None, "{} with {}", target, database);
+        }
+    }
+
+    #[test]
+    fn rank_confidences_repeat_a_single_value_down_to_the_assigned_rank() {
+        assert_eq!(rank_confidences("Eukaryota;TSAR;Alveolata", "0.9", 4), ["0.9", "0.9", "0.9", ""]);
+        assert_eq!(rank_confidences("Eukaryota;TSAR", "0.99; 0.8", 3), ["0.99", "0.8", ""]);
+        assert_eq!(rank_confidences("Eukaryota", "NA", 2), ["", ""]);
+    }
+}
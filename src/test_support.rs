@@ -0,0 +1,231 @@
+//! Fixtures shared by the unit tests.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use once_cell::sync::Lazy;
+use tempfile::TempDir;
+
+/// The output directory of every test, as `output_dir()` is set once per process.
+static OUTPUT_DIR: Lazy<TempDir> = Lazy::new(|| {
+    let dir = tempfile::tempdir().unwrap();
+    crate::OUTPUT_DIR.set(dir.path().to_string_lossy().into_owned()).unwrap();
+    dir
+});
+
+/// Held by tests that write to the output directory or change global flags.
+static GLOBAL_STATE: Mutex<()> = Mutex::new(());
+
+/// A stand-in `conda` that records its arguments (one call per line in
+/// `calls`, and the last call's one argument per line in `last_args`) and
+/// environment (`env`, one block per call), prints `stdout` if that file
+/// exists, and fails calls containing the text of `fail` (but not that of
+/// `pass`), printing `stderr` if that file exists.
+const FAKE_CONDA: &str = r#"#!/bin/sh
+dir=$(dirname "$0")
+printf '%s\n' "$*" >> "$dir/calls"
+printf '%s\n' "$@" > "$dir/last_args"
+{ env | sort; echo; } >> "$dir/env"
+if [ -f "$dir/pass" ]; then
+  case "$*" in *"$(cat "$dir/pass")"*) rm -f "$dir/fail";; esac
+fi
+if [ -f "$dir/fail" ]; then
+  case "$*" in *"$(cat "$dir/fail")"*)
+    if [ -f "$dir/stderr" ]; then cat "$dir/stderr" >&2; else echo "fake conda failed: $*" >&2; fi
+    exit 1;;
+  esac
+fi
+if [ -f "$dir/stdout" ]; then cat "$dir/stdout"; fi
+exit 0
+"#;
+
+/// Directory of the fake `conda`, installed as the conda binary of every test.
+static FAKE_CONDA_DIR: Lazy<TempDir> = Lazy::new(|| {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("conda");
+    fs::write(&path, FAKE_CONDA).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    crate::conda::set_conda_path(Some(path.to_string_lossy().into_owned()));
+    assert_eq!(crate::conda::conda_binary(), path.to_string_lossy(), "conda was resolved before the fake was installed");
+    dir
+});
+
+/// Serializes tests that use global state, empties the output directory and
+/// resets the fake `conda` (see [`conda_calls`]). Hold the guard for the
+/// whole test; flags changed under it must be reset.
+pub fn global_state() -> MutexGuard<'static, ()> {
+    let guard = GLOBAL_STATE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    for name in ["calls", "last_args", "env", "fail", "pass", "stderr", "stdout"] {
+        let _ = fs::remove_file(FAKE_CONDA_DIR.path().join(name));
+    }
+    for entry in fs::read_dir(output_dir()).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            fs::remove_dir_all(path).unwrap();
+        } else {
+            fs::remove_file(path).unwrap();
+        }
+    }
+    guard
+}
+
+/// The shared output directory (see [`global_state`]).
+pub fn output_dir() -> &'static Path {
+    OUTPUT_DIR.path()
+}
+
+/// Writes `contents` to `name` in `dir` and returns the path as a string.
+pub fn write_file(dir: &Path, name: &str, contents: &str) -> String {
+    let path = dir.join(name);
+    fs::write(&path, contents).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+/// FASTQ text for `(id, sequence)` records, all bases at quality `I`.
+pub fn fastq(records: &[(&str, &str)]) -> String {
+    records
+        .iter()
+        .map(|(id, seq)| format!("@{}\n{}\n+\n{}\n", id, seq, "I".repeat(seq.len())))
+        .collect()
+}
+
+/// Writes `contents` gzipped to `name` in `dir`.
+pub fn write_gz(dir: &Path, name: &str, contents: &str) -> PathBuf {
+    let path = dir.join(name);
+    let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::fast());
+    encoder.write_all(contents.as_bytes()).unwrap();
+    encoder.finish().unwrap();
+    path
+}
+
+/// Writes `records` (see [`fastq`]) gzipped to `name` in `dir`.
+pub fn write_fastq_gz(dir: &Path, name: &str, records: &[(&str, &str)]) -> PathBuf {
+    write_gz(dir, name, &fastq(records))
+}
+
+/// `(id, sequence)` of each record of a gzipped FASTQ.
+pub fn read_fastq_gz(path: &str) -> Vec<(String, String)> {
+    let lines: Vec<String> = BufReader::new(MultiGzDecoder::new(File::open(path).unwrap()))
+        .lines()
+        .map(Result::unwrap)
+        .collect();
+    lines
+        .chunks(4)
+        .map(|record| (record[0].trim_start_matches('@').to_string(), record[1].clone()))
+        .collect()
+}
+
+/// Arguments of each call to the fake `conda` since [`global_state`] was taken.
+pub fn conda_calls() -> Vec<String> {
+    fs::read_to_string(FAKE_CONDA_DIR.path().join("calls"))
+        .map(|calls| calls.lines().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// The separate arguments of the last call to the fake `conda`.
+pub fn conda_last_args() -> Vec<String> {
+    fs::read_to_string(FAKE_CONDA_DIR.path().join("last_args"))
+        .map(|args| args.lines().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// `KEY=VALUE` environment of each call to the fake `conda`, in call order.
+pub fn conda_envs() -> Vec<Vec<String>> {
+    fs::read_to_string(FAKE_CONDA_DIR.path().join("env"))
+        .unwrap_or_default()
+        .split("\n\n")
+        .filter(|block| !block.trim().is_empty())
+        .map(|block| block.lines().map(str::to_string).collect())
+        .collect()
+}
+
+/// Makes calls to the fake `conda` whose arguments contain `text` fail.
+pub fn conda_fails_on(text: &str) {
+    fs::write(FAKE_CONDA_DIR.path().join("fail"), text).unwrap();
+}
+
+/// Makes the fake `conda` fail calls containing `text`, printing `stderr`,
+/// until a call containing `pass` succeeds.
+pub fn conda_fails_until(text: &str, pass: &str, stderr: &str) {
+    conda_fails_on(text);
+    fs::write(FAKE_CONDA_DIR.path().join("pass"), pass).unwrap();
+    fs::write(FAKE_CONDA_DIR.path().join("stderr"), stderr).unwrap();
+}
+
+/// Makes the fake `conda` print `text` on every call.
+pub fn conda_prints(text: &str) {
+    fs::write(FAKE_CONDA_DIR.path().join("stdout"), text).unwrap();
+}
+
+/// A local HTTP server answering each request with the raw response
+/// `respond` builds from the request head, then closing the connection.
+pub struct MockServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockServer {
+    pub fn start(respond: impl Fn(&str) -> Vec<u8> + Send + 'static) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let response = respond(&head);
+                recorded.lock().unwrap().push(head);
+                let _ = stream.write_all(&response);
+            }
+        });
+        MockServer { url, requests }
+    }
+
+    /// Head (request line and headers) of each request so far.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Request line (`GET /path HTTP/1.1`) of each request so far.
+    pub fn request_lines(&self) -> Vec<String> {
+        self.requests.lock().unwrap().iter().map(|head| head.lines().next().unwrap_or("").to_string()).collect()
+    }
+}
+
+/// The path of an HTTP request head.
+pub fn request_path(head: &str) -> &str {
+    head.split_whitespace().nth(1).unwrap_or("")
+}
+
+/// A complete HTTP response with `status` (e.g. `200 OK`), the extra
+/// `headers` and `body`.
+pub fn http_response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    response
+}
@@ -0,0 +1,135 @@
+use std::error::Error;
+use std::time::Duration;
+
+use crate::color_print::{print_error, print_info, print_success};
+use crate::logger::log_action;
+
+/// The QIIME2 amplicon distribution `install-env` installs.
+const BUILTIN_DISTRO: &str = "2024.10";
+/// Directory listing of the published amplicon distribution environment files.
+const DISTRO_INDEX_URL: &str = "https://data.qiime2.org/distro/amplicon/";
+const DISTRO_PREFIX: &str = "qiime2-amplicon-";
+
+/// Parses a `YYYY.M` release into a comparable pair.
+fn parse_release(version: &str) -> Option<(u32, u32)> {
+    let (year, month) = version.split_once('.')?;
+    Some((year.parse().ok()?, month.parse().ok()?))
+}
+
+/// Finds the newest `qiime2-amplicon-<release>-...-conda.yml` named in a
+/// distribution index (an HTML listing or any text containing the file
+/// names). Returns the release and, if there is one for `platform`
+/// (`linux` or `osx`), its environment file name.
+fn newest_release(index: &str, platform: &str) -> Option<(String, Option<String>)> {
+    let mut newest: Option<((u32, u32), String, Option<String>)> = None;
+    for (start, _) in index.match_indices(DISTRO_PREFIX) {
+        let name: String = index[start..]
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            .collect();
+        let Some(version) = name[DISTRO_PREFIX.len()..].split('-').next() else {
+            continue;
+        };
+        let Some(release) = parse_release(version) else {
+            continue;
+        };
+        let file = (name.ends_with("-conda.yml") && name.contains(platform)).then(|| name.clone());
+        match &mut newest {
+            Some((best, _, best_file)) if *best == release => {
+                if best_file.is_none() {
+                    *best_file = file;
+                }
+            }
+            Some((best, _, _)) if *best > release => {}
+            _ => newest = Some((release, version.to_string(), file)),
+        }
+    }
+    newest.map(|(_, version, file)| (version, file))
+}
+
+/// Returns the release in `index` if it is newer than [`BUILTIN_DISTRO`].
+fn newer_release(index: &str, platform: &str) -> Option<(String, Option<String>)> {
+    let builtin = parse_release(BUILTIN_DISTRO)?;
+    newest_release(index, platform).filter(|(version, _)| parse_release(version).is_some_and(|v| v > builtin))
+}
+
+fn fetch_index() -> Result<String, Box<dyn Error>> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(15))
+        .build()?;
+    let resp = client.get(DISTRO_INDEX_URL).send()?;
+    if !resp.status().is_success() {
+        return Err(format!("{} returned {}", DISTRO_INDEX_URL, resp.status()).into());
+    }
+    Ok(resp.text()?)
+}
+
+/// Queries the QIIME2 distribution index (`--check-updates`) and tells the
+/// user if a newer amplicon distribution than the built-in one exists.
+/// Never fails: a network error is only reported.
+pub fn check_for_updates() {
+    print_info(&format!("Checking {} for newer QIIME2 distributions...", DISTRO_INDEX_URL));
+    let index = match fetch_index() {
+        Ok(index) => index,
+        Err(e) => {
+            print_error(&format!("Warning: could not check for QIIME2 updates: {}", e));
+            return;
+        }
+    };
+    let platform = if cfg!(target_os = "macos") { "osx" } else { "linux" };
+    match newer_release(&index, platform) {
+        Some((version, file)) => {
+            let msg = format!(
+                "QIIME2 amplicon {} is available (Windchime installs {}).",
+                version, BUILTIN_DISTRO
+            );
+            log_action(&msg);
+            print_info(&msg);
+            let env_file = match file {
+                Some(file) => format!("{}{}", DISTRO_INDEX_URL, file),
+                None => format!("the {}{} environment file from {}", DISTRO_PREFIX, version, DISTRO_INDEX_URL),
+            };
+            print_info(&format!(
+                "To use it, run `conda env create -n {}{} --file {}` and pass that name as --env-name.",
+                DISTRO_PREFIX, version, env_file
+            ));
+        }
+        None => print_success(&format!("QIIME2 amplicon {} is the newest distribution.", BUILTIN_DISTRO)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An excerpt of the distribution index's HTML listing.
+    const INDEX: &str = r#"<html><body><pre>
+<a href="qiime2-amplicon-2024.5-py39-linux-conda.yml">qiime2-amplicon-2024.5-py39-linux-conda.yml</a>
+<a href="qiime2-amplicon-2024.10-py310-linux-conda.yml">qiime2-amplicon-2024.10-py310-linux-conda.yml</a>
+<a href="qiime2-amplicon-2025.4-py310-osx-conda.yml">qiime2-amplicon-2025.4-py310-osx-conda.yml</a>
+<a href="qiime2-amplicon-2025.4-py310-linux-conda.yml">qiime2-amplicon-2025.4-py310-linux-conda.yml</a>
+<a href="qiime2-amplicon-2025.10-py310-osx-conda.yml">qiime2-amplicon-2025.10-py310-osx-conda.yml</a>
+</pre></body></html>"#;
+
+    #[test]
+    fn newest_release_compares_months_numerically() {
+        // 2025.10 is newer than 2025.4, though it sorts before it as text
+        assert_eq!(
+            newer_release(INDEX, "osx"),
+            Some(("2025.10".to_string(), Some("qiime2-amplicon-2025.10-py310-osx-conda.yml".to_string())))
+        );
+        // There is no Linux file for it yet
+        assert_eq!(newer_release(INDEX, "linux"), Some(("2025.10".to_string(), None)));
+    }
+
+    #[test]
+    fn no_newer_release_than_the_builtin_one() {
+        let index = &INDEX[..INDEX.find("2025.4").unwrap()];
+        assert_eq!(
+            newest_release(index, "linux"),
+            Some(("2024.10".to_string(), Some("qiime2-amplicon-2024.10-py310-linux-conda.yml".to_string())))
+        );
+        assert_eq!(newer_release(index, "linux"), None);
+        assert_eq!(newer_release("<html>nothing published</html>", "linux"), None);
+    }
+}
@@ -0,0 +1,111 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::archive::collect_files;
+use crate::color_print::{print_error, print_info, print_success, status_text};
+use crate::logger::{log_action, log_event, LogLevel};
+use crate::pipeline;
+use crate::output_dir;
+
+/// Every `.qza`/`.qzv` under OUTPUT_DIR, in sorted order.
+fn find_artifacts() -> Result<Vec<String>, Box<dyn Error>> {
+    let root = Path::new(output_dir());
+    if !root.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files: Vec<PathBuf> = Vec::new();
+    collect_files(root, root, &mut files)?;
+    Ok(files
+        .iter()
+        .map(|relative| root.join(relative).to_string_lossy().into_owned())
+        .filter(|path| path.ends_with(".qza") || path.ends_with(".qzv"))
+        .collect())
+}
+
+/// Runs `qiime tools validate` on `artifact`, returning QIIME's reason if it
+/// is rejected.
+fn validation_failure(env_name: &str, artifact: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let output = pipeline::run_conda_qiime_capture(env_name, &["tools", "validate", artifact])?;
+    if output.success {
+        return Ok(None);
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let reason = if stderr.trim().is_empty() { stdout.trim() } else { stderr.trim() };
+    // QIIME's explanation ends in its last non-empty line
+    let reason = reason.lines().rev().find(|line| !line.trim().is_empty()).unwrap_or("validation failed");
+    Ok(Some(reason.trim().to_string()))
+}
+
+/// Runs `qiime tools validate` on every `.qza`/`.qzv` in OUTPUT_DIR and
+/// prints a pass/fail line for each as it goes. Artifacts that fail are
+/// reported and the rest still checked; fails at the end if any did.
+pub fn verify_outputs(env_name: &str) -> Result<(), Box<dyn Error>> {
+    let artifacts = find_artifacts()?;
+    if artifacts.is_empty() {
+        return Err(format!("No .qza or .qzv files found in '{}'", output_dir()).into());
+    }
+    print_info(&format!("Validating {} artifacts in '{}' with QIIME ({})...", artifacts.len(), output_dir(), env_name));
+
+    println!("{:<6} Artifact", "Result");
+    let mut failed = 0;
+    for artifact in &artifacts {
+        match validation_failure(env_name, artifact)? {
+            None => {
+                println!("{} {}", status_text(&format!("{:<6}", "PASS"), true), artifact);
+                log_action(&format!("Verify: {} is valid", artifact));
+            }
+            Some(reason) => {
+                failed += 1;
+                println!("{} {} ({})", status_text(&format!("{:<6}", "FAIL"), false), artifact, reason);
+                log_event(LogLevel::Error, None, &format!("Verify: {} failed QIIME validation: {}", artifact, reason));
+            }
+        }
+    }
+
+    if failed > 0 {
+        print_error(&format!("{} of {} artifacts failed validation.", failed, artifacts.len()));
+        return Err(format!("{} invalid artifact(s) in '{}'", failed, output_dir()).into());
+    }
+    print_success(&format!("All {} artifacts are valid.", artifacts.len()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use std::fs;
+
+    #[test]
+    fn every_artifact_is_validated_and_failures_are_reported_together() {
+        let _state = test_support::global_state();
+        crate::logger::init_log(false);
+        let out = test_support::output_dir();
+        let err = verify_outputs("qiime-env").unwrap_err();
+        assert_eq!(err.to_string(), format!("No .qza or .qzv files found in '{}'", output_dir()));
+
+        fs::create_dir_all(out.join("asvs")).unwrap();
+        for name in ["asvs/bad.qza", "asvs/table.qza", "demux.qzv", "notes.txt", "table.qza.bak"] {
+            fs::write(out.join(name), "artifact").unwrap();
+        }
+        assert_eq!(
+            find_artifacts().unwrap(),
+            ["asvs/bad.qza", "asvs/table.qza", "demux.qzv"].map(|name| format!("{}/{}", output_dir(), name))
+        );
+        verify_outputs("qiime-env").unwrap();
+        assert_eq!(test_support::conda_calls().len(), 3);
+
+        // A failure doesn't stop the others being checked
+        test_support::conda_fails_until("bad.qza", "never", "Traceback...\n\nThere was a problem: truncated archive\n\n");
+        let err = verify_outputs("qiime-env").unwrap_err();
+        assert_eq!(err.to_string(), format!("1 invalid artifact(s) in '{}'", output_dir()));
+        let calls = test_support::conda_calls();
+        assert_eq!(calls.len(), 6);
+        assert!(calls[3..].iter().all(|call| call.starts_with("run -n qiime-env qiime tools validate ")), "{:?}", calls);
+        let log = fs::read_to_string(out.join("windchime.log")).unwrap();
+        let bad = format!("{}/asvs/bad.qza", output_dir());
+        assert!(log.contains(&format!("Verify: {} failed QIIME validation: There was a problem: truncated archive", bad)));
+        assert_eq!(log.matches(&format!("Verify: {}/demux.qzv is valid", output_dir())).count(), 2);
+    }
+}
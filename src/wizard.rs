@@ -1,8 +1,7 @@
 use dialoguer::{theme::ColorfulTheme, Input, Confirm};
 use std::error::Error;
-use crate::{pipeline, demultiplex, OUTPUT_DIR};
-use crate::color_print::{print_info, print_success, print_error};
-use std::fs;
+use crate::{pipeline, demultiplex};
+use crate::color_print::{print_info, print_success};
 
 /// Example interactive wizard that prompts the user for typical pipeline steps.
 pub fn run_wizard() -> Result<(), Box<dyn Error>> {
@@ -34,7 +33,7 @@ pub fn run_wizard() -> Result<(), Box<dyn Error>> {
     let do_demux = !barcodes_file.trim().is_empty();
     if do_demux {
         print_info("Running demultiplex step...");
-        demultiplex::run_demultiplex_combined(&barcodes_file, false)?;
+        demultiplex::run_demultiplex_combined(std::slice::from_ref(&barcodes_file), &demultiplex::DemuxOptions::default())?;
         print_success("Demultiplexing complete.");
 
         // Generate manifest?
@@ -43,7 +42,7 @@ pub fn run_wizard() -> Result<(), Box<dyn Error>> {
             .default(true)
             .interact()?;
         if generate_manifest {
-            demultiplex::generate_qiime_manifest(&barcodes_file, "manifest.tsv")?;
+            demultiplex::generate_qiime_manifest(std::slice::from_ref(&barcodes_file), "manifest.tsv", None, false, None, false)?;
             print_success("Manifest file created in output directory (manifest.tsv).");
         }
     }
@@ -54,7 +53,7 @@ pub fn run_wizard() -> Result<(), Box<dyn Error>> {
         .default(true)
         .interact()?;
     if download_dbs {
-        pipeline::download_databases(false)?;
+        pipeline::download_databases(pipeline::Database::Pr2, false, false, pipeline::DbComponent::All)?;
         print_success("Reference databases downloaded!");
     }
 
@@ -89,29 +88,25 @@ pub fn run_wizard() -> Result<(), Box<dyn Error>> {
             .parse()?;
 
         let target: String = Input::with_theme(&ColorfulTheme::default())
-            .with_prompt("Target region (16s/18sv4/18sv9)")
+            .with_prompt("Target region (16s/18sv4/18sv9/its)")
             .default("18sv9".into())
             .validate_with(|input: &String| -> Result<(), &str> {
                 let lower = input.to_lowercase();
-                if lower == "16s" || lower == "18sv4" || lower == "18sv9" || lower == "18s" {
+                if lower == "16s" || lower == "18sv4" || lower == "18sv9" || lower == "18s" || lower == "its" {
                     Ok(())
                 } else {
-                    Err("Must be '16s', '18sv4', or '18sv9' (or '18s' for backward compatibility with 18sv9)")
+                    Err("Must be '16s', '18sv4', '18sv9', or 'its' (or '18s' for backward compatibility with 18sv9)")
                 }
             })
             .interact_text()?;
 
         // Truncation lengths for DADA2 - set defaults based on target region
-        let (default_trunc_f, default_trunc_r) = match target.to_lowercase().as_str() {
-            "16s" => ("219", "194"),
-            "18sv4" => ("262", "223"),
-            "18sv9" | "18s" => ("123", "91"),
-            _ => ("219", "194"), // fallback to 16s defaults
-        };
+        let region = pipeline::region_primers(&target)?;
+        let (default_trunc_f, default_trunc_r) = (region.trunc_len_f.to_string(), region.trunc_len_r.to_string());
 
         let trunc_len_f: usize = Input::with_theme(&ColorfulTheme::default())
             .with_prompt("Forward read trunc length for DADA2 (0 = no truncation)")
-            .default(default_trunc_f.into())
+            .default(default_trunc_f)
             .validate_with(|input: &String| -> Result<(), &str> {
                 match input.parse::<usize>() {
                     Ok(_) => Ok(()),
@@ -123,7 +118,7 @@ pub fn run_wizard() -> Result<(), Box<dyn Error>> {
 
         let trunc_len_r: usize = Input::with_theme(&ColorfulTheme::default())
             .with_prompt("Reverse read trunc length for DADA2 (0 = no truncation)")
-            .default(default_trunc_r.into())
+            .default(default_trunc_r)
             .validate_with(|input: &String| -> Result<(), &str> {
                 match input.parse::<usize>() {
                     Ok(_) => Ok(()),
@@ -147,16 +142,17 @@ pub fn run_wizard() -> Result<(), Box<dyn Error>> {
 
         // Run pipeline
         print_info("Launching pipeline...");
-        pipeline::run_pipeline(
-            &env_name,
-            &manifest,
+        pipeline::run_pipeline(&pipeline::PipelineOptions {
+            env_name: env_name.clone(),
+            manifest,
             cores,
-            &target,
+            target,
             skip_existing,
             use_pretrained_classifier,
-            trunc_len_f,
-            trunc_len_r
-        )?;
+            trunc_len_f: Some(trunc_len_f),
+            trunc_len_r: Some(trunc_len_r),
+            ..pipeline::PipelineOptions::default()
+        })?;
         print_success("Pipeline completed!");
     }
 